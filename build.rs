@@ -1,14 +1,27 @@
 extern crate cbindgen;
 
 use std::env;
+use std::fs;
+use std::path::Path;
 
 fn main() {
     let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
     let target = env::var("TARGET").unwrap();
+    let profile = env::var("PROFILE").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
 
+    // The header covers every `#[no_mangle]` function and public constant; see
+    // cbindgen.toml for what is left out.
+    let config = cbindgen::Config::from_root_or_default(Path::new(&crate_dir));
+    let generated_header = Path::new(&out_dir).join("nxfatdrive.h");
     cbindgen::Builder::new()
-        .with_crate(crate_dir)
+        .with_crate(&crate_dir)
+        .with_config(config)
         .generate()
         .expect("Unable to generate bindings")
-        .write_to_file(format!("target/{}/release/libnx_fatdrive.h", target));
+        .write_to_file(&generated_header);
+
+    let dist_dir = Path::new(&crate_dir).join("target").join(&target).join(&profile);
+    fs::create_dir_all(&dist_dir).expect("Unable to create the header output directory");
+    fs::copy(&generated_header, dist_dir.join("libnx_fatdrive.h")).expect("Unable to copy the generated header");
 }
@@ -4,9 +4,137 @@ use scsi::Buffer;
 
 use std::io;
 use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Storage that is read and written a whole block at a time, such as a USB mass
+/// storage drive or a disk image.
+pub trait BlockDevice {
+    fn block_size(&self) -> u32;
+    /// Reads from the byte `offset` onwards into `buffer` until it is full.
+    fn read(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize>;
+    /// Writes the contents of `buffer` starting at the byte `offset`, draining it.
+    fn write(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize>;
+    /// How many blocks the device holds.
+    fn block_count(&mut self) -> io::Result<u64>;
+    fn is_connected(&self) -> bool;
+    /// Keeps `block`, cached for `offset` on the device, after it could not be
+    /// written because the device went away, so that it can still be written if
+    /// the same drive comes back.
+    fn keep_unwritten(&mut self, offset: u32, block: Vec<u8>);
+    /// Hands back every block given to `keep_unwritten`, oldest first.
+    fn take_unwritten(&mut self) -> Vec<(u32, Vec<u8>)>;
+    /// How many bytes are waiting in blocks given to `keep_unwritten`.
+    fn unwritten_bytes(&self) -> u64;
+}
+
+type UsbScsiDevice = scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>;
+
+/// Reports a failed command as `NotConnected` if it failed because the drive is gone.
+fn command_error(device: &UsbScsiDevice, err: io::Error) -> io::Error {
+    if device.comm_channel.is_connected() {
+        err
+    } else {
+        io::Error::from(io::ErrorKind::NotConnected)
+    }
+}
+
+impl BlockDevice for UsbScsiDevice {
+    fn block_size(&self) -> u32 {
+        scsi::scsi::ScsiBlockDevice::block_size(self)
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize> {
+        scsi::scsi::ScsiBlockDevice::read(self, offset, buffer).map_err(|e| match e.cause {
+            scsi::ErrorCause::BufferTooSmallError { expected, actual } => io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Buffer too small: wanted {} but only have {}.",
+                    expected, actual
+                ),
+            ),
+            e => command_error(self, io::Error::new(io::ErrorKind::Other, format!("Unmatched error : {:?}", e))),
+        })
+    }
+
+    fn write(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize> {
+        scsi::scsi::ScsiBlockDevice::write(self, offset, buffer).map_err(|_e| {
+            command_error(self, io::Error::from(io::ErrorKind::Other))
+        })
+    }
+
+    fn block_count(&mut self) -> io::Result<u64> {
+        let capacity = scsi::scsi::ScsiBlockDevice::read_capacity(self).map_err(|_e| {
+            command_error(self, io::Error::from(io::ErrorKind::Other))
+        })?;
+        // READ CAPACITY reports the address of the last block rather than how many there are.
+        Ok(u64::from(capacity.logical_block_address) + 1)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.comm_channel.is_connected()
+    }
+
+    fn keep_unwritten(&mut self, offset: u32, block: Vec<u8>) {
+        self.comm_channel.keep_unwritten(offset, block)
+    }
+
+    fn take_unwritten(&mut self) -> Vec<(u32, Vec<u8>)> {
+        self.comm_channel.take_unwritten()
+    }
+
+    fn unwritten_bytes(&self) -> u64 {
+        self.comm_channel.unwritten_bytes()
+    }
+}
+
+/// Locks a shared device. A thread that panicked while holding it cannot have left
+/// it any worse off than a failed command would, so a poisoned lock is used as is.
+fn lock_device<T: ?Sized>(device: &Mutex<T>) -> MutexGuard<T> {
+    device.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A drive shared between the partitions on it and raw access to it.
+pub type SharedBlockDevice = Arc<Mutex<dyn BlockDevice + Send>>;
+
+/// Lets every partition on a drive read through the same device, from whichever
+/// thread the drive is used on.
+impl<T: BlockDevice + ?Sized> BlockDevice for Arc<Mutex<T>> {
+    fn block_size(&self) -> u32 {
+        lock_device(self).block_size()
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize> {
+        lock_device(self).read(offset, buffer)
+    }
+
+    fn write(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize> {
+        lock_device(self).write(offset, buffer)
+    }
+
+    fn block_count(&mut self) -> io::Result<u64> {
+        lock_device(self).block_count()
+    }
+
+    fn is_connected(&self) -> bool {
+        lock_device(self).is_connected()
+    }
+
+    fn keep_unwritten(&mut self, offset: u32, block: Vec<u8>) {
+        lock_device(self).keep_unwritten(offset, block)
+    }
+
+    fn take_unwritten(&mut self) -> Vec<(u32, Vec<u8>)> {
+        lock_device(self).take_unwritten()
+    }
+
+    fn unwritten_bytes(&self) -> u64 {
+        lock_device(self).unwritten_bytes()
+    }
+}
 
 pub struct OffsetScsiDevice {
-    pub device: scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
+    pub device: Box<dyn BlockDevice + Send>,
     block_buffer: VecNewtype,
     partition_start: usize, //bytes
     partition_idx: usize,   //bytes from partition_start
@@ -16,19 +144,22 @@ pub struct OffsetScsiDevice {
 
 impl Drop for OffsetScsiDevice {
     fn drop(&mut self) {
-        self.flush();
+        // Writing to a drive that is gone would only fail, so the block is handed to
+        // the device without trying.
+        if self.needs_flush && (!self.device.is_connected() || self.flush().is_err()) {
+            let offset = self.buffered_block_raw_idx() as u32;
+            let block = mem::replace(&mut self.block_buffer.inner, Vec::new());
+            self.device.keep_unwritten(offset, block);
+        }
     }
 }
 
 impl OffsetScsiDevice {
-    pub fn new(
-        device: scsi::scsi::ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
-        partition_start: usize,
-    ) -> Self {
+    pub fn new<D: BlockDevice + Send + 'static>(device: D, partition_start: usize) -> Self {
         let block_size = device.block_size() as usize;
 
         OffsetScsiDevice {
-            device,
+            device: Box::new(device),
             block_buffer: VecNewtype::with_fake_capacity(block_size),
             partition_start,
             partition_idx: 0,
@@ -75,22 +206,18 @@ impl BufRead for OffsetScsiDevice {
         }
         let block_idx = self.cur_block_raw_idx() as u32;
         if self.block_buffer.is_empty() {
-            let red = self
-                .device
-                .read(block_idx, &mut self.block_buffer)
-                .map_err(|e| match e.cause {
-                    scsi::ErrorCause::BufferTooSmallError { expected, actual } => io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        format!(
-                            "Buffer too small: wanted {} but only have {}.",
-                            expected, actual
-                        ),
-                    ),
-                    e => io::Error::new(io::ErrorKind::Other, format!("Unmatched error : {:?}", e)),
-                })?;
+            if let Err(e) = self.device.read(block_idx, &mut self.block_buffer) {
+                // Whatever part of the block did arrive must not pass for all of it later.
+                self.block_buffer.inner.clear();
+                return Err(e);
+            }
             self.loaded_block_number = self.cur_block_number();
         }
-        Ok(&self.block_buffer.inner.as_slice()[self.offset_from_cur_block()..])
+        let offset = self.offset_from_cur_block();
+        if offset > self.block_buffer.inner.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(&self.block_buffer.inner.as_slice()[offset..])
     }
 
     fn consume(&mut self, amt: usize) {
@@ -144,12 +271,7 @@ impl Write for OffsetScsiDevice {
             return Ok(());
         }
         let raw_idx = self.buffered_block_raw_idx();
-        let _ = self
-            .device
-            .write(raw_idx as u32, &mut self.block_buffer)
-            .map_err(|_e| {
-                (io::Error::from(io::ErrorKind::Other))
-            })?;
+        let _ = self.device.write(raw_idx as u32, &mut self.block_buffer)?;
         self.needs_flush = false;
         Ok(())
     }
@@ -163,7 +285,7 @@ impl Seek for OffsetScsiDevice {
             }
             SeekFrom::Current(off) => {
                 let absr = if off < 0 {
-                    self.partition_idx - off.abs() as usize
+                    self.partition_idx.checked_sub(off.abs() as usize).ok_or(io::Error::from(io::ErrorKind::InvalidInput))?
                 } else {
                     self.partition_idx + off.abs() as usize
                 };
@@ -171,7 +293,8 @@ impl Seek for OffsetScsiDevice {
                 self.partition_idx = absr;
                 Ok(absr as u64)
             }
-            _ => unimplemented!(),
+            // The partition's end is not known here.
+            SeekFrom::End(_) => Err(io::Error::from(io::ErrorKind::InvalidInput)),
         }
     }
 }
@@ -4,6 +4,8 @@ use std::sync::PoisonError;
 use libnx_rs::LibnxError;
 use scsi::ScsiError;
 use mbr_nostd::MbrError;
+use filesystem::{self, FsErrorKind};
+use filesystem::path::PathError;
 pub const SUCCESS : u32 = 0;
 
 pub const NX_FATDRIVE_ERR_MODULE : u32 = 0xFA;
@@ -14,14 +16,31 @@ pub const NX_FATDRIVE_ERR_NOT_INITIALIZED : u32 = 0x2FA;
 pub const NX_FATDRIVE_ERR_DRIVE_NOT_FOUND : u32 = 0x3FA;
 pub const NX_FATDRIVE_ERR_POISSENED_MUTEX : u32 = 0x4FA;
 pub const NX_FATDRIVE_ERR_DRIVE_DISCONNECTED : u32 = 0x6FA;
+/// The file or directory ID is not open, or was closed and its slot reused.
+pub const NX_FATDRIVE_ERR_INVALID_HANDLE : u32 = 0x7FA;
+/// A raw sector access reaches past the end of its scope or of the drive.
+pub const NX_FATDRIVE_ERR_OUT_OF_RANGE : u32 = 0x8FA;
+/// Raw writes are refused until they are unlocked for the drive.
+pub const NX_FATDRIVE_ERR_WRITE_PROTECTED : u32 = 0x9FA;
+/// The drive went away before cached writes reached it, and did not come back for them.
+pub const NX_FATDRIVE_ERR_DATA_LOST : u32 = 0xAFA;
 
 pub const NX_FATDRIVE_ERR_STDIO_PREFIX : u32 = 0x2_0000;
 
 pub const NX_FATDRIVE_ERR_FS_PREFIX : u32 = 0x4_0000;
 pub const NX_FATDRIVE_ERR_FILE_NOT_FOUND : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 1) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+pub const NX_FATDRIVE_ERR_NO_SPACE : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 2) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+pub const NX_FATDRIVE_ERR_IS_A_DIRECTORY : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 3) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+pub const NX_FATDRIVE_ERR_NOT_A_DIRECTORY : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 4) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+pub const NX_FATDRIVE_ERR_DIRECTORY_NOT_EMPTY : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 5) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+pub const NX_FATDRIVE_ERR_BUSY : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 6) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+pub const NX_FATDRIVE_ERR_READ_ONLY : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 7) << 8 ) + NX_FATDRIVE_ERR_MODULE;
+/// The drive has partitions, but none of them holds a filesystem the backend can mount.
+pub const NX_FATDRIVE_ERR_UNSUPPORTED_FS : u32 = ( (NX_FATDRIVE_ERR_FS_PREFIX + 8) << 8 ) + NX_FATDRIVE_ERR_MODULE;
 
 pub const NX_FATDRIVE_ERR_SCSI_PREFIX : u32 = 0x5_0000;
 pub const NX_FATDRIVE_ERR_MBR_PREFIX : u32 = 0x6_0000;
+pub const NX_FATDRIVE_ERR_PATH_PREFIX : u32 = 0x7_0000;
 
 
 
@@ -34,6 +53,20 @@ pub trait LibnxErrMapper {
 
 impl LibnxErrMapper for io::Error {
     fn map(err : io::Error) -> u32 {
+        if let Some(kind) = filesystem::fs_error_kind(&err) {
+            return match kind {
+                FsErrorKind::NoSpace => NX_FATDRIVE_ERR_NO_SPACE,
+                FsErrorKind::IsADirectory => NX_FATDRIVE_ERR_IS_A_DIRECTORY,
+                FsErrorKind::NotADirectory => NX_FATDRIVE_ERR_NOT_A_DIRECTORY,
+                FsErrorKind::DirectoryNotEmpty => NX_FATDRIVE_ERR_DIRECTORY_NOT_EMPTY,
+                FsErrorKind::Busy => NX_FATDRIVE_ERR_BUSY,
+                FsErrorKind::ReadOnly => NX_FATDRIVE_ERR_READ_ONLY,
+                FsErrorKind::Unsupported => NX_FATDRIVE_ERR_NOT_IMPLEMENTED,
+            };
+        }
+        if err.kind() == io::ErrorKind::NotConnected {
+            return NX_FATDRIVE_ERR_DRIVE_DISCONNECTED;
+        }
         let offset : u32 = match err.kind() {
                 io::ErrorKind::NotFound => 1,
                 io::ErrorKind::PermissionDenied => 2,
@@ -109,6 +142,19 @@ impl LibnxErrMapper for MbrError {
     }
 }
 
+impl LibnxErrMapper for PathError {
+    fn map(err : PathError) -> u32 {
+        let desc : u32 = match err {
+            PathError::NullPointer => 1,
+            PathError::InvalidUtf8 => 2,
+            PathError::Empty => 3,
+            PathError::InvalidCharacter(_) => 4,
+            PathError::ComponentTooLong(_) => 5,
+            PathError::PathTooLong(_) => 6,
+        };
+        ((desc + NX_FATDRIVE_ERR_PATH_PREFIX) << 8) + NX_FATDRIVE_ERR_MODULE
+    }
+}
 
 #[macro_export]
 macro_rules! err_wrap {
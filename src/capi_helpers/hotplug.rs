@@ -0,0 +1,687 @@
+use libnx_rs::libnx::{self, Event, UsbHsInterfaceFilter, UsbHsInterfaceFilterFlags};
+use libnx_rs::usbhs::UsbHsContext;
+use std::mem;
+use std::os::raw::c_void;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use capi_helpers::*;
+
+/// The interface available event slot the hotplug thread keeps; `acquire_drives` uses slot 0.
+pub(crate) const hotplug_event_index : u8 = 1;
+
+/// How long the hotplug thread waits before trying again if waiting on its events failed.
+pub(crate) const hotplug_wait_retry_interval : Duration = Duration::from_secs(1);
+
+/// What `svcWaitSynchronization` returns when its timeout is up.
+const KERNEL_TIMED_OUT : u32 = 0xEA01;
+
+/// How long the hotplug thread waits to acquire a drive it has already seen attached, in nanoseconds.
+pub(crate) const hotplug_acquire_timeout : u64 = 1000;
+
+/// A drive was attached and mounted; `device` is its new handle.
+pub const NX_FATDRIVE_HOTPLUG_MOUNTED : u32 = 1;
+/// A drive was removed, or the hotplug service stopped; `device` has been closed.
+/// `result` is `NX_FATDRIVE_ERR_DATA_LOST` if writes it still had cached never reached it.
+pub const NX_FATDRIVE_HOTPLUG_UNMOUNTED : u32 = 2;
+/// A drive was attached but could not be mounted, for the reason in `result`.
+pub const NX_FATDRIVE_HOTPLUG_MOUNT_FAILED : u32 = 3;
+/// A mounted drive was removed. `device` stays open for the grace period it was
+/// mounted with, and calls on it fail with `NX_FATDRIVE_ERR_DRIVE_DISCONNECTED`
+/// until it comes back or is unmounted.
+pub const NX_FATDRIVE_HOTPLUG_DETACHED : u32 = 4;
+/// A removed drive was plugged back in, and `device` carries on where it left off.
+pub const NX_FATDRIVE_HOTPLUG_REATTACHED : u32 = 5;
+
+/// What the hotplug service noticed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HotplugEvent {
+    Mounted(NxFatdriveDeviceHandle),
+    Unmounted(NxFatdriveDeviceHandle, u32),
+    MountFailed(u32),
+    Detached(NxFatdriveDeviceHandle),
+    Reattached(NxFatdriveDeviceHandle),
+}
+
+impl HotplugEvent {
+    /// The `NX_FATDRIVE_HOTPLUG_*` value for the event.
+    pub fn code(&self) -> u32 {
+        match self {
+            HotplugEvent::Mounted(_) => NX_FATDRIVE_HOTPLUG_MOUNTED,
+            HotplugEvent::Unmounted(..) => NX_FATDRIVE_HOTPLUG_UNMOUNTED,
+            HotplugEvent::MountFailed(_) => NX_FATDRIVE_HOTPLUG_MOUNT_FAILED,
+            HotplugEvent::Detached(_) => NX_FATDRIVE_HOTPLUG_DETACHED,
+            HotplugEvent::Reattached(_) => NX_FATDRIVE_HOTPLUG_REATTACHED,
+        }
+    }
+
+    /// The drive the event is about, or 0 if it never got a handle.
+    pub fn device(&self) -> NxFatdriveDeviceHandle {
+        match self {
+            HotplugEvent::Mounted(handle) | HotplugEvent::Unmounted(handle, _) | HotplugEvent::Detached(handle) | HotplugEvent::Reattached(handle) => *handle,
+            HotplugEvent::MountFailed(_) => 0,
+        }
+    }
+
+    pub fn result(&self) -> u32 {
+        match self {
+            HotplugEvent::Unmounted(_, err) | HotplugEvent::MountFailed(err) => *err,
+            _ => SUCCESS,
+        }
+    }
+}
+
+/// Called on the hotplug thread for every event, with `userdata` as it was registered.
+pub type NxFatdriveHotplugCallback = Option<unsafe extern "C" fn(event : u32, device : NxFatdriveDeviceHandle, result : u32, userdata : *mut c_void)>;
+
+type HotplugListener = Arc<Mutex<dyn FnMut(HotplugEvent) + Send>>;
+
+lazy_static! {
+    static ref hotplug_listeners : Mutex<Vec<(u64, HotplugListener)>> = Mutex::new(Vec::new());
+    static ref next_listener_id : Mutex<u64> = Mutex::new(1);
+    /// The callback set with `nxFatdriveSetHotplugCallback`, along with its userdata pointer.
+    static ref hotplug_callback : Mutex<(NxFatdriveHotplugCallback, usize)> = Mutex::new((None, 0));
+    static ref hotplug_thread : Mutex<Option<HotplugThread>> = Mutex::new(None);
+}
+
+/// Has `listener` called on the hotplug thread for every event from now on, until
+/// `remove_hotplug_listener` is called with the returned ID.
+pub fn add_hotplug_listener<F : FnMut(HotplugEvent) + Send + 'static>(listener : F) -> Result<u64, u32> {
+    let mut listeners = hotplug_listeners.lock().map_err(LibnxErrMapper::map)?;
+    let mut next_id = next_listener_id.lock().map_err(LibnxErrMapper::map)?;
+    let id = *next_id;
+    *next_id += 1;
+    let listener : HotplugListener = Arc::new(Mutex::new(listener));
+    listeners.push((id, listener));
+    Ok(id)
+}
+
+/// Whether a listener with the ID was registered.
+pub fn remove_hotplug_listener(id : u64) -> Result<bool, u32> {
+    let mut listeners = hotplug_listeners.lock().map_err(LibnxErrMapper::map)?;
+    let before = listeners.len();
+    listeners.retain(|(listener_id, _)| *listener_id != id);
+    Ok(listeners.len() != before)
+}
+
+fn notify(event : HotplugEvent) {
+    // Called without the registry lock held, so that listeners can add or remove
+    // listeners, or wait on a thread that does.
+    let listeners : Vec<HotplugListener> = match hotplug_listeners.lock() {
+        Ok(listeners) => listeners.iter().map(|(_, listener)| Arc::clone(listener)).collect(),
+        Err(_) => Vec::new(),
+    };
+    for listener in listeners {
+        if let Ok(mut listener) = listener.lock() {
+            (&mut *listener)(event);
+        }
+    }
+    let (callback, userdata) = match hotplug_callback.lock() {
+        Ok(guard) => *guard,
+        Err(_) => {
+            return;
+        }
+    };
+    if let Some(callback) = callback {
+        unsafe { callback(event.code(), event.device(), event.result(), userdata as *mut c_void) };
+    }
+}
+
+/// Where the hotplug thread finds drives, and what it waits on for them to come and go.
+trait DriveBus {
+    /// A drive acquired from the bus, before anything on it is mounted.
+    type Drive;
+
+    /// Blocks until a drive may have been attached or removed, or until `timeout` is up.
+    /// `None` waits for as long as it takes.
+    fn wait(&mut self, timeout : Option<Duration>) -> Result<(), u32>;
+
+    /// Whether a drive nobody has acquired yet is attached.
+    fn has_available(&mut self) -> bool;
+
+    fn acquire(&mut self) -> Result<Self::Drive, u32>;
+
+    /// Whether an acquired drive is still attached.
+    fn is_attached(&mut self, drive : &mut Self::Drive) -> bool;
+
+    /// Hands `drive` to whichever of the drives in `detached` it is, like `reattach_drive`.
+    fn reattach(&mut self, drive : Self::Drive, detached : &[NxFatdriveDeviceHandle]) -> Result<NxFatdriveDeviceHandle, (Self::Drive, u32)>;
+
+    /// Mounts `drive` like `DriveContext::mount`.
+    fn mount(&mut self, drive : Self::Drive, options : &MountOptions) -> Result<DriveContext, (Self::Drive, u32)>;
+}
+
+/// The mass storage drives attached over USB. Arrivals are signalled by an interface
+/// available event, removals by the usb:hs interface state change event, and `stop`
+/// is fired to wake the thread up when it should stop.
+struct UsbBus {
+    usb_hs_ctx : UsbHsContext,
+    available : Event,
+    stop : Event,
+}
+
+impl UsbBus {
+    fn new(usb_hs_ctx : UsbHsContext, stop : Event) -> Result<UsbBus, u32> {
+        let mut filter : UsbHsInterfaceFilter = unsafe { mem::zeroed() };
+        filter.Flags = (UsbHsInterfaceFilterFlags::UsbHsInterfaceFilterFlags_bInterfaceClass.0
+            | UsbHsInterfaceFilterFlags::UsbHsInterfaceFilterFlags_bInterfaceSubClass.0
+            | UsbHsInterfaceFilterFlags::UsbHsInterfaceFilterFlags_bInterfaceProtocol.0) as u16;
+        filter.bInterfaceClass = 8;
+        filter.bInterfaceSubClass = 6;
+        filter.bInterfaceProtocol = 80;
+        let mut available : Event = unsafe { mem::zeroed() };
+        let rc = unsafe { libnx::usbHsCreateInterfaceAvailableEvent(&mut available, false, hotplug_event_index, &filter) };
+        if rc != SUCCESS {
+            return Err(rc);
+        }
+        Ok(UsbBus { usb_hs_ctx, available, stop })
+    }
+}
+
+impl Drop for UsbBus {
+    fn drop(&mut self) {
+        unsafe { libnx::usbHsDestroyInterfaceAvailableEvent(&mut self.available, hotplug_event_index) };
+    }
+}
+
+impl DriveBus for UsbBus {
+    type Drive = AcquiredDrive;
+
+    fn wait(&mut self, timeout : Option<Duration>) -> Result<(), u32> {
+        let state_change = unsafe { libnx::usbHsGetInterfaceStateChangeEvent() };
+        let handles = [self.stop.revent, self.available.revent, unsafe { (*state_change).revent }];
+        let timeout_ns = timeout.map_or(u64::max_value(), |t| t.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(t.subsec_nanos())));
+        let mut idx = 0;
+        let rc = unsafe { libnx::svcWaitSynchronization(&mut idx, handles.as_ptr(), handles.len() as i32, timeout_ns) };
+        if rc != SUCCESS && rc != KERNEL_TIMED_OUT {
+            return Err(rc);
+        }
+        // Everything that changed is looked at before the next wait, so none of them
+        // needs to stay signalled.
+        unsafe {
+            libnx::eventClear(&mut self.available);
+            libnx::eventClear(state_change);
+        }
+        Ok(())
+    }
+
+    fn has_available(&mut self) -> bool {
+        match self.usb_hs_ctx.query_available_interfaces(mass_storage_filter(), 1) {
+            Ok(found) => !found.is_empty(),
+            Err(_) => false,
+        }
+    }
+
+    fn acquire(&mut self) -> Result<AcquiredDrive, u32> {
+        AcquiredDrive::acquire(hotplug_acquire_timeout)
+    }
+
+    fn is_attached(&mut self, drive : &mut AcquiredDrive) -> bool {
+        drive.connection.is_attached().unwrap_or(false)
+    }
+
+    fn reattach(&mut self, drive : AcquiredDrive, detached : &[NxFatdriveDeviceHandle]) -> Result<NxFatdriveDeviceHandle, (AcquiredDrive, u32)> {
+        reattach_drive(drive, detached)
+    }
+
+    fn mount(&mut self, drive : AcquiredDrive, options : &MountOptions) -> Result<DriveContext, (AcquiredDrive, u32)> {
+        DriveContext::mount(drive, options)
+    }
+}
+
+/// A drive the hotplug thread acquired.
+enum WatchedDrive<D> {
+    Mounted(NxFatdriveDeviceHandle),
+    /// Unplugged, but kept open in case it comes back before its grace period is up.
+    Detached(NxFatdriveDeviceHandle),
+    /// Nothing on it could be mounted. It stays acquired, so that it is not tried
+    /// again until it is unplugged and plugged back in.
+    Unmountable(D),
+}
+
+impl <D> WatchedDrive<D> {
+    /// Whether the drive is still there. A mounted drive the application closed
+    /// itself counts as gone, and is picked up again as a new arrival.
+    fn is_attached<B : DriveBus<Drive = D>>(&mut self, bus : &mut B) -> bool {
+        match self {
+            WatchedDrive::Mounted(handle) => unsafe { nxFatdriveIsDeviceReady(*handle) == SUCCESS },
+            WatchedDrive::Detached(_) => false,
+            WatchedDrive::Unmountable(drive) => bus.is_attached(drive),
+        }
+    }
+
+    /// Checks on the drive, returning what is left to watch of it.
+    fn update<B : DriveBus<Drive = D>>(mut self, bus : &mut B) -> Option<WatchedDrive<D>> {
+        if self.is_attached(bus) {
+            return Some(self);
+        }
+        match self {
+            WatchedDrive::Mounted(handle) if drive_awaits_reattach(handle) => {
+                notify(HotplugEvent::Detached(handle));
+                Some(WatchedDrive::Detached(handle))
+            },
+            WatchedDrive::Detached(handle) if drive_awaits_reattach(handle) => Some(self),
+            _ => {
+                self.release();
+                None
+            }
+        }
+    }
+
+    fn release(self) {
+        if let WatchedDrive::Mounted(handle) | WatchedDrive::Detached(handle) = self {
+            // A drive the application closed itself was already reported to it.
+            let closed = match unsafe { nxFatdriveCloseDevice(handle) } {
+                NX_FATDRIVE_ERR_DRIVE_NOT_FOUND => SUCCESS,
+                res => res,
+            };
+            notify(HotplugEvent::Unmounted(handle, closed));
+        }
+    }
+}
+
+struct HotplugWatcher<B : DriveBus> {
+    bus : B,
+    options : MountOptions,
+    drives : Vec<WatchedDrive<B::Drive>>,
+    /// Why acquiring the last drive failed, so a drive that keeps failing is only reported once.
+    last_acquire_error : Option<u32>,
+}
+
+impl <B : DriveBus> HotplugWatcher<B> {
+    fn new(bus : B, options : MountOptions) -> HotplugWatcher<B> {
+        HotplugWatcher {
+            bus,
+            options,
+            drives : Vec::new(),
+            last_acquire_error : None,
+        }
+    }
+
+    /// Catches up with the drives that were removed or attached since the last call.
+    fn check(&mut self) {
+        for drive in mem::replace(&mut self.drives, Vec::new()) {
+            if let Some(drive) = drive.update(&mut self.bus) {
+                self.drives.push(drive);
+            }
+        }
+        while self.bus.has_available() && self.mount_next() {}
+    }
+
+    /// How long until a detached drive's grace period is up, or `None` if no drive is
+    /// waiting to be plugged back in.
+    fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.drives.iter().filter_map(|watched| match watched {
+            WatchedDrive::Detached(handle) => drive_reattach_deadline(*handle),
+            _ => None,
+        }).min().map(|deadline| if deadline > now { deadline - now } else { Duration::from_secs(0) })
+    }
+
+    /// Acquires the next attached drive and mounts it, or hands it back to the drive it
+    /// was unplugged from. Returns whether a drive was acquired.
+    fn mount_next(&mut self) -> bool {
+        let drive = match self.bus.acquire() {
+            Ok(d) => d,
+            Err(e) => {
+                if self.last_acquire_error != Some(e) {
+                    self.last_acquire_error = Some(e);
+                    notify(HotplugEvent::MountFailed(e));
+                }
+                return false;
+            }
+        };
+        self.last_acquire_error = None;
+        let detached : Vec<NxFatdriveDeviceHandle> = self.drives.iter().filter_map(|watched| match watched {
+            WatchedDrive::Detached(handle) => Some(*handle),
+            _ => None,
+        }).collect();
+        let drive = match self.bus.reattach(drive, &detached) {
+            Ok(handle) => {
+                for watched in self.drives.iter_mut() {
+                    if let WatchedDrive::Detached(detached_handle) = *watched {
+                        if detached_handle == handle {
+                            *watched = WatchedDrive::Mounted(handle);
+                        }
+                    }
+                }
+                notify(HotplugEvent::Reattached(handle));
+                return true;
+            },
+            Err((drive, _)) => drive,
+        };
+        match self.bus.mount(drive, &self.options) {
+            Ok(ctx) => match register_drive(ctx) {
+                Ok(handle) => {
+                    self.drives.push(WatchedDrive::Mounted(handle));
+                    notify(HotplugEvent::Mounted(handle));
+                },
+                Err(e) => notify(HotplugEvent::MountFailed(e)),
+            },
+            Err((drive, e)) => {
+                self.drives.push(WatchedDrive::Unmountable(drive));
+                notify(HotplugEvent::MountFailed(e));
+            }
+        }
+        true
+    }
+
+    /// Closes every drive the watcher mounted.
+    fn release_all(&mut self) {
+        for drive in mem::replace(&mut self.drives, Vec::new()) {
+            drive.release();
+        }
+    }
+
+    fn run(mut self, stop : Arc<AtomicBool>) {
+        while !stop.load(Ordering::SeqCst) {
+            self.check();
+            let timeout = self.timeout();
+            if self.bus.wait(timeout).is_err() {
+                thread::sleep(hotplug_wait_retry_interval);
+            }
+        }
+        self.release_all();
+    }
+}
+
+/// The running hotplug thread.
+struct HotplugThread {
+    stop : Arc<AtomicBool>,
+    /// Fired to wake the thread up once `stop` is set.
+    wake : Event,
+    thread : JoinHandle<()>,
+}
+
+/// Starts a thread that mounts mass storage drives as they are attached and
+/// closes them when they are removed, reporting both through the hotplug
+/// listeners and callback. A removed drive is only closed once the
+/// `reattach_grace_ms` in `options` is up; if it comes back before then, it
+/// carries on under the same handle. Drives are mounted as `nxFatdriveOpenDevice` would
+/// with `options`, or with the defaults if it is null. Drives that are already
+/// attached are mounted right away. The thread sleeps until usb:hs reports an
+/// interface being attached or removed.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveStartHotplug(options : *const NxFatdriveMountOptions) -> u32 {
+    let mut defaults : NxFatdriveMountOptions = mem::zeroed();
+    nxFatdriveDefaultMountOptions(&mut defaults);
+    let options = err_wrap!(MountOptions::from_raw(options.as_ref().unwrap_or(&defaults)));
+    let mut running = err_wrap!(hotplug_thread.lock());
+    if running.is_some() {
+        return NX_FATDRIVE_ERR_BUSY;
+    }
+
+    let mut wake : Event = mem::zeroed();
+    let rc = libnx::eventCreate(&mut wake, false);
+    if rc != SUCCESS {
+        return rc;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let (started_send, started_recv) = mpsc::channel();
+    let spawned = thread::Builder::new().name("nx-fatdrive hotplug".to_owned()).spawn(move || {
+        let bus = UsbHsContext::initialize()
+            .map_err(LibnxErrMapper::map)
+            .and_then(|usb_hs_ctx| UsbBus::new(usb_hs_ctx, wake));
+        let bus = match bus {
+            Ok(bus) => bus,
+            Err(e) => {
+                let _ = started_send.send(Err(e));
+                return;
+            }
+        };
+        let _ = started_send.send(Ok(()));
+        HotplugWatcher::new(bus, options).run(thread_stop);
+    });
+    let thread = match spawned {
+        Ok(t) => t,
+        Err(e) => {
+            libnx::eventClose(&mut wake);
+            return LibnxErrMapper::map(e);
+        }
+    };
+    if let Err(e) = started_recv.recv().unwrap_or(Err(NX_FATDRIVE_ERR_UNKNOWN)) {
+        let _ = thread.join();
+        libnx::eventClose(&mut wake);
+        return e;
+    }
+    *running = Some(HotplugThread { stop, wake, thread });
+    SUCCESS
+}
+
+/// Stops the hotplug thread, closing every drive it mounted first.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveStopHotplug() -> u32 {
+    let mut running = match err_wrap!(hotplug_thread.lock()).take() {
+        Some(r) => r,
+        None => {
+            return NX_FATDRIVE_ERR_NOT_INITIALIZED;
+        }
+    };
+    running.stop.store(true, Ordering::SeqCst);
+    libnx::eventFire(&mut running.wake);
+    let joined = running.thread.join();
+    libnx::eventClose(&mut running.wake);
+    if joined.is_err() {
+        return NX_FATDRIVE_ERR_UNKNOWN;
+    }
+    SUCCESS
+}
+
+/// Sets the function called for every hotplug event, replacing any set before. A
+/// null `callback` removes it. `userdata` is passed to it unchanged.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveSetHotplugCallback(callback : NxFatdriveHotplugCallback, userdata : *mut c_void) -> u32 {
+    *err_wrap!(hotplug_callback.lock()) = (callback, userdata as usize);
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buf_scsi::BlockDevice;
+    use capi_helpers::usbfs::tests::{unpluggable_image, image_context};
+    use image_device::ImageDevice;
+    use std::io::Cursor;
+    use std::ptr;
+
+    lazy_static! {
+        /// Held by every test that sends events, since listeners and the callback are global.
+        static ref EVENT_LOCK : Mutex<()> = Mutex::new(());
+    }
+
+    fn lock_events() -> ::std::sync::MutexGuard<'static, ()> {
+        EVENT_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    type Image = Arc<Mutex<ImageDevice<Cursor<Vec<u8>>>>>;
+
+    /// Disk images standing in for drives attached over USB.
+    struct ImageBus {
+        /// Attached, but not acquired yet.
+        attached : Vec<Image>,
+        reattach_grace : Duration,
+    }
+
+    impl DriveBus for ImageBus {
+        type Drive = Image;
+
+        fn wait(&mut self, _timeout : Option<Duration>) -> Result<(), u32> {
+            Ok(())
+        }
+
+        fn has_available(&mut self) -> bool {
+            !self.attached.is_empty()
+        }
+
+        fn acquire(&mut self) -> Result<Image, u32> {
+            self.attached.pop().ok_or(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND)
+        }
+
+        fn is_attached(&mut self, drive : &mut Image) -> bool {
+            drive.lock().unwrap().is_connected()
+        }
+
+        fn reattach(&mut self, drive : Image, _detached : &[NxFatdriveDeviceHandle]) -> Result<NxFatdriveDeviceHandle, (Image, u32)> {
+            Err((drive, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND))
+        }
+
+        fn mount(&mut self, drive : Image, _options : &MountOptions) -> Result<DriveContext, (Image, u32)> {
+            match image_context(&drive, self.reattach_grace) {
+                Ok(ctx) => Ok(ctx),
+                Err(e) => Err((drive, e)),
+            }
+        }
+    }
+
+    /// A watcher on an `ImageBus`, along with the events it sends.
+    fn image_watcher(reattach_grace : Duration) -> (HotplugWatcher<ImageBus>, mpsc::Receiver<HotplugEvent>, u64) {
+        let (send, recv) = mpsc::channel();
+        let id = add_hotplug_listener(move |event| {
+            let _ = send.send(event);
+        }).unwrap();
+        let options = unsafe {
+            let mut defaults : NxFatdriveMountOptions = mem::zeroed();
+            nxFatdriveDefaultMountOptions(&mut defaults);
+            MountOptions::from_raw(&defaults).unwrap()
+        };
+        let bus = ImageBus { attached : Vec::new(), reattach_grace };
+        (HotplugWatcher::new(bus, options), recv, id)
+    }
+
+    unsafe extern "C" fn record_event(event : u32, device : NxFatdriveDeviceHandle, result : u32, userdata : *mut c_void) {
+        let seen = &*(userdata as *const Mutex<Vec<(u32, u64, u32)>>);
+        seen.lock().unwrap().push((event, device, result));
+    }
+
+    #[test]
+    fn events_reach_listeners_and_the_callback() {
+        let _events = lock_events();
+        let (send, recv) = mpsc::channel();
+        let id = add_hotplug_listener(move |event| {
+            let _ = send.send(event);
+        }).unwrap();
+        let seen : Mutex<Vec<(u32, u64, u32)>> = Mutex::new(Vec::new());
+        unsafe {
+            assert_eq!(nxFatdriveSetHotplugCallback(Some(record_event), &seen as *const _ as *mut c_void), SUCCESS);
+        }
+
+        notify(HotplugEvent::Mounted(0xF00D));
+        notify(HotplugEvent::MountFailed(NX_FATDRIVE_ERR_UNSUPPORTED_FS));
+        notify(HotplugEvent::Reattached(0xF00D));
+        unsafe {
+            assert_eq!(nxFatdriveSetHotplugCallback(None, ptr::null_mut()), SUCCESS);
+        }
+        assert!(remove_hotplug_listener(id).unwrap());
+        assert!(!remove_hotplug_listener(id).unwrap());
+        notify(HotplugEvent::Unmounted(0xF00D, NX_FATDRIVE_ERR_DATA_LOST));
+
+        let received : Vec<HotplugEvent> = recv.try_iter().collect();
+        assert_eq!(received, vec![HotplugEvent::Mounted(0xF00D), HotplugEvent::MountFailed(NX_FATDRIVE_ERR_UNSUPPORTED_FS), HotplugEvent::Reattached(0xF00D)]);
+        assert_eq!(*seen.lock().unwrap(), vec![
+            (NX_FATDRIVE_HOTPLUG_MOUNTED, 0xF00D, SUCCESS),
+            (NX_FATDRIVE_HOTPLUG_MOUNT_FAILED, 0, NX_FATDRIVE_ERR_UNSUPPORTED_FS),
+            (NX_FATDRIVE_HOTPLUG_REATTACHED, 0xF00D, SUCCESS),
+        ]);
+    }
+
+    #[test]
+    fn listeners_can_remove_themselves() {
+        let _events = lock_events();
+        let own_id = Arc::new(Mutex::new(0));
+        let (send, recv) = mpsc::channel();
+        let listener_id = Arc::clone(&own_id);
+        let id = add_hotplug_listener(move |event| {
+            let removed = remove_hotplug_listener(*listener_id.lock().unwrap()).unwrap();
+            let _ = send.send((event, removed));
+        }).unwrap();
+        *own_id.lock().unwrap() = id;
+
+        notify(HotplugEvent::Mounted(0xF00D));
+        notify(HotplugEvent::Detached(0xF00D));
+        let received : Vec<(HotplugEvent, bool)> = recv.try_iter().collect();
+        assert_eq!(received, vec![(HotplugEvent::Mounted(0xF00D), true)]);
+    }
+
+    #[test]
+    fn stopping_without_starting_fails() {
+        unsafe {
+            assert_eq!(nxFatdriveStopHotplug(), NX_FATDRIVE_ERR_NOT_INITIALIZED);
+        }
+    }
+
+    #[test]
+    fn attached_drives_are_mounted_detached_and_unmounted() {
+        let _events = lock_events();
+        let (mut watcher, events, id) = image_watcher(Duration::from_millis(200));
+        watcher.check();
+        assert_eq!(watcher.timeout(), None);
+        assert!(events.try_recv().is_err());
+
+        let image = unpluggable_image();
+        watcher.bus.attached.push(image.clone());
+        watcher.check();
+        let handle = match events.try_recv() {
+            Ok(HotplugEvent::Mounted(handle)) => handle,
+            other => panic!("Expected a mount, got {:?}", other),
+        };
+        unsafe {
+            assert_eq!(nxFatdriveIsDeviceReady(handle), SUCCESS);
+        }
+        watcher.check();
+        assert!(events.try_recv().is_err());
+
+        // Removed, the drive waits out its grace period before it is closed.
+        image.lock().unwrap().disconnect();
+        watcher.check();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![HotplugEvent::Detached(handle)]);
+        let timeout = watcher.timeout().unwrap();
+        assert!(timeout <= Duration::from_millis(200));
+        thread::sleep(timeout);
+        watcher.check();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![HotplugEvent::Unmounted(handle, SUCCESS)]);
+        assert_eq!(watcher.timeout(), None);
+        unsafe {
+            assert_eq!(nxFatdriveIsDeviceReady(handle), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+        }
+        assert!(remove_hotplug_listener(id).unwrap());
+    }
+
+    #[test]
+    fn stopping_unmounts_every_drive() {
+        let _events = lock_events();
+        let (mut watcher, events, id) = image_watcher(Duration::from_secs(60));
+        watcher.bus.attached.push(unpluggable_image());
+        watcher.bus.attached.push(unpluggable_image());
+        watcher.check();
+        let mounted : Vec<HotplugEvent> = events.try_iter().collect();
+        assert_eq!(mounted.len(), 2);
+
+        watcher.release_all();
+        let unmounted : Vec<HotplugEvent> = events.try_iter().collect();
+        let expected : Vec<HotplugEvent> = mounted.iter().map(|event| HotplugEvent::Unmounted(event.device(), SUCCESS)).collect();
+        assert_eq!(unmounted, expected);
+        assert!(remove_hotplug_listener(id).unwrap());
+    }
+
+    #[test]
+    fn unmountable_drives_are_reported_once_and_dropped_when_removed() {
+        let _events = lock_events();
+        let (mut watcher, events, id) = image_watcher(Duration::from_secs(60));
+        let blank : Image = Arc::new(Mutex::new(ImageDevice::new(Cursor::new(vec![0u8; 8 * 1024 * 1024]))));
+        watcher.bus.attached.push(blank.clone());
+        watcher.check();
+        watcher.check();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![HotplugEvent::MountFailed(NX_FATDRIVE_ERR_UNSUPPORTED_FS)]);
+        assert_eq!(watcher.drives.len(), 1);
+
+        blank.lock().unwrap().disconnect();
+        watcher.check();
+        assert!(watcher.drives.is_empty());
+        assert!(events.try_recv().is_err());
+        assert!(remove_hotplug_listener(id).unwrap());
+    }
+}
@@ -1,230 +1,302 @@
-use crate::OffsetScsiDevice;
 use super::*;
 use super::err;
 use super::err::LibnxErrMapper;
-use fatfs::{Dir, DirEntry, File, FileSystem, ReadWriteSeek};
-use crate::get_filesystem;
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use crate::filesystem::{FileSystem, FileSystemOps, FileOps, DirectoryOps, DirEntryType, FatTimestamp, File, OpenOptions};
+use crate::filesystem::split;
+use crate::filesystem::path;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 
 pub struct DirEntryData {
-    pub name : String, 
-    pub type_val : u64, 
+    pub name : String,
+    pub type_val : u64,
     pub size : u64,
+    /// Seconds since the epoch, or 0 if the backend does not record the time.
+    /// FAT stores local time without a zone, so these are read as if it were UTC.
+    pub modified : i64,
+    pub created : i64,
+    pub accessed : i64,
 }
+
+/// A file opened through the ID API. Nothing borrowed from the filesystem is kept
+/// between calls; the file is opened again by path for each operation, so it never
+/// outlives the filesystem and every handle on a path sees the same size and clusters.
+struct FileHandle {
+    path : String,
+    options : OpenOptions,
+    position : u64,
+}
+
+/// The file behind a handle for the length of one operation, with the handle's
+/// own access mode and append setting.
+pub struct HandleFile<'f, 'a : 'f> {
+    file : &'f mut File<'a>,
+    options : OpenOptions,
+}
+
+impl <'f, 'a> Read for HandleFile<'f, 'a> {
+    fn read(&mut self, buf : &mut [u8]) -> Result<usize, io::Error> {
+        self.options.check_read()?;
+        self.file.read(buf)
+    }
+}
+
+impl <'f, 'a> Write for HandleFile<'f, 'a> {
+    fn write(&mut self, buf : &[u8]) -> Result<usize, io::Error> {
+        self.options.check_write()?;
+        if self.options.append {
+            self.file.seek(SeekFrom::End(0))?;
+        }
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.file.flush()
+    }
+}
+
+impl <'f, 'a> Seek for HandleFile<'f, 'a> {
+    fn seek(&mut self, pos : SeekFrom) -> Result<u64, io::Error> {
+        self.file.seek(pos)
+    }
+}
+
+impl <'f, 'a> FileOps for HandleFile<'f, 'a> {
+    fn truncate(&mut self) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        self.file.truncate()
+    }
+    fn set_len(&mut self, len : u64) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        self.file.set_len(len)
+    }
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        self.file.preallocate(len, contiguous)
+    }
+}
+
+/// A directory opened through the ID API, along with how far it has been read.
+struct DirHandle {
+    cursor : split::DirCursor,
+}
+
+enum Handle {
+    File(FileHandle),
+    Dir(DirHandle),
+}
+
+impl Handle {
+    fn path(&self) -> &str {
+        match self {
+            Handle::File(f) => &f.path,
+            Handle::Dir(d) => d.cursor.path(),
+        }
+    }
+}
+
+struct Slot {
+    /// Bumped every time the slot is freed, so IDs handed out for earlier
+    /// occupants stop matching it.
+    generation : u32,
+    handle : Option<Handle>,
+}
+
+/// The files and directories open on one filesystem, by ID. An ID holds the slot
+/// index in its low 32 bits and the slot's generation in the high 32 bits, so
+/// using one after it has been closed fails instead of reaching whatever reuses
+/// the slot. Handles only remember paths and positions, and every operation
+/// borrows the filesystem just for its own duration, so the filesystem can be
+/// dropped while handles are still open.
 pub struct IdStore {
-    next_id : u64,
-    dir_handle_map : HashMap<u64, Dir<'static, OffsetScsiDevice>>,
-    dir_name_map : HashMap<u64, String>, 
-    dir_iter_map : HashMap<u64, u64>,
-    file_handle_map : HashMap<u64, File<'static, OffsetScsiDevice>>,
-    file_name_map : HashMap<u64, String>,
+    slots : Vec<Slot>,
+    free_slots : Vec<usize>,
 }
 
 impl IdStore {
 
     const _DT_DIR : u64 = 0x4;
     const _DT_REG : u64 = 0x1;
-    
+
     pub fn new() -> IdStore {
         IdStore {
-            next_id : 0, 
-            dir_handle_map : HashMap::new(),
-            dir_name_map : HashMap::new(),
-            dir_iter_map : HashMap::new(),
-            file_handle_map : HashMap::new(),
-            file_name_map : HashMap::new(),
+            slots : Vec::new(),
+            free_slots : Vec::new(),
         }
     }
 
-    pub fn has_file(&self, path : &String) -> Option<u64> {
-        self.file_name_map.iter().find_map(|kv| {
-            let (k, v) = kv;
-            if v == path {
-                Some(*k)
-            }
-            else {
-                None
-            }
+    fn open_handles(&self) -> impl Iterator<Item=(u64, &Handle)> {
+        self.slots.iter().enumerate().filter_map(|(idx, slot)| {
+            slot.handle.as_ref().map(|handle| (Self::make_id(idx, slot.generation), handle))
         })
     }
 
-    pub fn has_dir(&self, path : &String) -> Option<u64> {
-        self.dir_name_map.iter().find_map(|kv| {
-            let (k, v) = kv;
-            if v == path {
-                Some(*k)
-            }
-            else {
-                None
-            }
-        })
+    fn make_id(slot : usize, generation : u32) -> u64 {
+        (u64::from(generation) << 32) | slot as u64
     }
 
-    pub fn insert_file(&mut self, path : String, fl : File<'static, OffsetScsiDevice>) -> u64 {
-        let id = self.next_id;
-        self.next_id = if id == u64::max_value() { 0 } else { id + 1 };
-        self.file_handle_map.insert(id, fl);
-        self.file_name_map.insert(id, path);
-        id
+    fn insert(&mut self, handle : Handle) -> u64 {
+        let idx = match self.free_slots.pop() {
+            Some(idx) => idx,
+            None => {
+                // Generations start at 1 so that no ID is ever 0.
+                self.slots.push(Slot { generation : 1, handle : None });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[idx];
+        slot.handle = Some(handle);
+        Self::make_id(idx, slot.generation)
     }
 
-    pub fn insert_dir(&mut self, path : String, dir : Dir<'static, OffsetScsiDevice>) -> u64 {
-        let id = self.next_id;
-        self.next_id = if id == u64::max_value() { 0 } else { id + 1 };
-        self.dir_handle_map.insert(id, dir);
-        self.dir_name_map.insert(id, path);
-        self.dir_iter_map.insert(id, 0);
-        id
+    fn handle(&self, id : u64) -> Result<&Handle, u32> {
+        let idx = (id & 0xFFFF_FFFF) as usize;
+        let generation = (id >> 32) as u32;
+        match self.slots.get(idx) {
+            Some(Slot { generation : current, handle : Some(handle) }) if *current == generation => Ok(handle),
+            _ => Err(NX_FATDRIVE_ERR_INVALID_HANDLE),
+        }
     }
 
-    pub unsafe fn open_file(&mut self, path : &str) -> Result<u64, u32> {
-        let path = path.to_owned();
-        if let Some(existing) = self.has_file(&path) {
-            return Ok(existing);
+    fn slot_mut(&mut self, id : u64) -> Result<&mut Slot, u32> {
+        let idx = (id & 0xFFFF_FFFF) as usize;
+        let generation = (id >> 32) as u32;
+        match self.slots.get_mut(idx) {
+            Some(slot) if slot.generation == generation && slot.handle.is_some() => Ok(slot),
+            _ => Err(NX_FATDRIVE_ERR_INVALID_HANDLE),
         }
-        let (fs, _guard) = get_filesystem()?;
-        let new_fl = fs.root_dir().open_file(&path).map_err(LibnxErrMapper::map)?;
-        Ok(self.insert_file(path, new_fl))
     }
 
-    pub unsafe fn open_dir(&mut self, path : &str) -> Result<u64, u32> {
-        let path = path.to_owned();
-        if let Some(existing) = self.has_dir(&path) {
-            return Ok(existing);
+    fn remove(&mut self, id : u64) -> Result<Handle, u32> {
+        let slot = self.slot_mut(id)?;
+        let handle = slot.handle.take().ok_or(NX_FATDRIVE_ERR_INVALID_HANDLE)?;
+        slot.generation = if slot.generation == u32::max_value() { 1 } else { slot.generation + 1 };
+        self.free_slots.push((id & 0xFFFF_FFFF) as usize);
+        Ok(handle)
+    }
+
+    fn file_mut(&mut self, id : u64) -> Result<&mut FileHandle, u32> {
+        match self.slot_mut(id)?.handle {
+            Some(Handle::File(ref mut f)) => Ok(f),
+            _ => Err(NX_FATDRIVE_ERR_INVALID_HANDLE),
         }
-        let (fs, _guard) = get_filesystem()?;
-        let new_fl = if path == "/" || path == "" {
-            fs.root_dir()
+    }
+
+    fn dir_mut(&mut self, id : u64) -> Result<&mut DirHandle, u32> {
+        match self.slot_mut(id)?.handle {
+            Some(Handle::Dir(ref mut d)) => Ok(d),
+            _ => Err(NX_FATDRIVE_ERR_INVALID_HANDLE),
         }
-        else {
-            fs.root_dir().open_dir(&path).map_err(LibnxErrMapper::map)? 
-        };
-        Ok(self.insert_dir(path, new_fl))
     }
 
-    pub fn close_file(&mut self, id : u64) -> Result<(), u32> {
-        let mut f = match self.file_handle_map.remove(&id) {
-            Some(f) => f,
-            None => {
-                return Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND);
-            }
-        };
-        f.flush().map_err(LibnxErrMapper::map)?;
-        self.file_name_map.remove(&id);
-        Ok(())
+    pub fn has_file(&self, path : &String) -> Option<u64> {
+        self.open_handles().find_map(|(id, handle)| match handle {
+            Handle::File(f) if &f.path == path => Some(id),
+            _ => None,
+        })
     }
 
-    pub fn close_dir(&mut self, id : u64) -> Result<(), u32> {
-        let mut f = match self.dir_handle_map.remove(&id) {
-            Some(f) => f,
-            None => {
-                return Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND);
-            }
-        };
-        self.dir_name_map.remove(&id);
-        self.dir_iter_map.remove(&id);
-        Ok(())
+    pub fn has_dir(&self, path : &String) -> Option<u64> {
+        self.open_handles().find_map(|(id, handle)| match handle {
+            Handle::Dir(d) if d.cursor.path() == path.as_str() => Some(id),
+            _ => None,
+        })
     }
 
-    pub fn get_file_handle<'a>(&'a mut self, id : u64) -> Result<&'a mut File<'static, OffsetScsiDevice>, u32> {
-        let existing = match self.file_handle_map.get_mut(&id) {
-            Some(f) => f,
-            None => {
-                return Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND);
-            }
-        };
-        Ok(existing)
+    /// Whether any open file or directory handle refers to `path`, ignoring case like FAT does.
+    pub fn is_path_open(&self, path : &str) -> bool {
+        let lower_path = path.to_lowercase();
+        self.open_handles().any(|(_, handle)| handle.path().to_lowercase() == lower_path)
     }
 
-    pub fn read_next_dirent(&mut self, id : u64) -> Result<Option<DirEntryData>, u32> {
-        let dir = match self.dir_handle_map.get_mut(&id) {
-            Some(d) => d, 
-            None => {
-                return Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND);
-            }
-        };
+    /// Opens a file on `fs`, creating or truncating it as `options` ask. Every call
+    /// gets a new ID with its own position and access mode, even if the path is
+    /// already open. Since each operation opens the file again, descriptors sharing
+    /// a path always see each other's changes to its size and clusters.
+    pub fn open_file(&mut self, fs : &FileSystem, path : &str, options : OpenOptions) -> Result<u64, u32> {
+        let path = path.to_owned();
+        let root = fs.root().map_err(LibnxErrMapper::map)?;
+        let mut new_fl = split::open_file_or_split(root, &path, options).map_err(LibnxErrMapper::map)?;
+        new_fl.flush().map_err(LibnxErrMapper::map)?;
+        Ok(self.insert(Handle::File(FileHandle { path, options, position : 0 })))
+    }
 
-        let idx_ref = match self.dir_iter_map.get_mut(&id) {
-            Some(idx) => idx, 
-            None => {
-                return Err(NX_FATDRIVE_ERR_UNKNOWN);
-            }
-        };
-        let idx = *idx_ref;
-        *idx_ref += 1;
+    /// Opens a directory on `fs`, with its own read position like `open_file`.
+    pub fn open_dir(&mut self, fs : &FileSystem, path : &str) -> Result<u64, u32> {
+        let path = path.to_owned();
+        if !path::is_root(&path) {
+            let mut root = fs.root().map_err(LibnxErrMapper::map)?;
+            root.open_directory(&path).map_err(LibnxErrMapper::map)?;
+        }
+        Ok(self.insert(Handle::Dir(DirHandle { cursor : split::DirCursor::new(path) })))
+    }
 
-        let mut dir_iter = dir.iter().skip(idx as usize);
+    pub fn close_file(&mut self, id : u64) -> Result<(), u32> {
+        self.file_mut(id)?;
+        self.remove(id).map(|_| ())
+    }
 
-        let retval_source = match dir_iter.next() {
-            Some(Ok(r)) => r,
-            Some(Err(e)) => {
-                return Err(LibnxErrMapper::map(e));
-            },
-            None => {
-                return Ok(None);
-            }
-        };
-        
+    pub fn close_dir(&mut self, id : u64) -> Result<(), u32> {
+        self.dir_mut(id)?;
+        self.remove(id).map(|_| ())
+    }
+
+    /// Opens the file behind `id` on `fs` again and runs `op` on it at the handle's
+    /// position, which is then saved for the next call. The file is flushed before
+    /// it is closed, so whatever `op` wrote reaches the drive. A handle whose file
+    /// has been renamed or deleted since, for instance while its drive was unplugged,
+    /// is stale and fails with `NX_FATDRIVE_ERR_INVALID_HANDLE`.
+    pub fn with_file<T, F>(&mut self, fs : &FileSystem, id : u64, op : F) -> Result<T, u32>
+        where F : FnOnce(&mut HandleFile) -> Result<T, io::Error>
+    {
+        let handle = self.file_mut(id)?;
+        let root = fs.root().map_err(LibnxErrMapper::map)?;
+        let mut opened = split::open_file_or_split(root, &handle.path, handle.options.for_reopen()).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => NX_FATDRIVE_ERR_INVALID_HANDLE,
+            _ => LibnxErrMapper::map(e),
+        })?;
+        let mut file = HandleFile { file : &mut opened, options : handle.options };
+        file.seek(SeekFrom::Start(handle.position)).map_err(LibnxErrMapper::map)?;
+        let retval = op(&mut file).map_err(LibnxErrMapper::map)?;
+        handle.position = file.seek(SeekFrom::Current(0)).map_err(LibnxErrMapper::map)?;
+        file.flush().map_err(LibnxErrMapper::map)?;
+        Ok(retval)
+    }
 
-        Ok(Some(DirEntryData {
-            name : retval_source.file_name(),
-            type_val : if retval_source.is_file() { Self::_DT_REG } else { Self::_DT_DIR },
-            size : retval_source.len(),
+    /// The next entry of the directory behind `id`. The listing is read once, on
+    /// the first call, and kept until `rewind_dir`.
+    pub fn read_next_dirent(&mut self, fs : &FileSystem, id : u64) -> Result<Option<DirEntryData>, u32> {
+        let handle = self.dir_mut(id)?;
+        let mut root = fs.root().map_err(LibnxErrMapper::map)?;
+        let next = handle.cursor.next_entry(&mut root).map_err(LibnxErrMapper::map)?;
+        let unix_time = |ts : Option<FatTimestamp>| ts.map_or(0, |ts| ts.to_unix(0));
+        Ok(next.map(|ent| DirEntryData {
+            type_val : if ent.entry_type() == DirEntryType::Directory { Self::_DT_DIR } else { Self::_DT_REG },
+            size : ent.len as u64,
+            modified : unix_time(ent.modified),
+            created : unix_time(ent.created),
+            accessed : unix_time(ent.accessed),
+            name : ent.name,
         }))
     }
 
+    /// Starts the directory behind `id` over from its first entry.
+    pub fn rewind_dir(&mut self, id : u64) -> Result<(), u32> {
+        self.dir_mut(id)?.cursor.rewind();
+        Ok(())
+    }
+
     pub fn get_path_for_id(&self, id : u64) -> Result<&str, u32> {
-        if let Some(p) = self.file_name_map.get(&id) {
-            return Ok(&p);
-        }
-        else if let Some(p) = self.dir_name_map.get(&id) {
-            return Ok(&p);
-        }
-        else {
-            return Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND);
-        }
+        self.handle(id).map(Handle::path)
     }
 
-    pub unsafe fn stat_path(&self, path : &str) -> Result<(u64, u64), u32> {
-        let stripped_path = path.replace("//", "/").trim_end_matches('/').to_owned();
-        let mut path_itr = stripped_path.rsplitn(2, '/');
-        let ent_name = match path_itr.next() {
-            Some(e) => e,
-            None => {
-                return Err(NX_FATDRIVE_ERR_UNKNOWN);
-            }
-        };
-        let parent_name = path_itr.next();
-        let mut parent_handle_itr = if let Some(id) = parent_name.and_then(|nm| self.has_dir(&nm.to_owned())) {
-            match self.dir_handle_map.get(&id) {
-                Some(h) => h.iter(),
-                None => {
-                    return Err(NX_FATDRIVE_ERR_UNKNOWN);
-                }
-            }
-        }
-        else if !(parent_name.map(|nm| nm.is_empty()).unwrap_or(true)) {
-            let (mut fs, _fs_guard) = get_filesystem().map_err(LibnxErrMapper::map)?;
-            fs.root_dir().open_dir(parent_name.unwrap()).map_err(LibnxErrMapper::map)?.iter()
+    /// The size and FAT attributes of the entry at `path`, with split files showing
+    /// up as the regular file they represent.
+    pub fn stat_path(&self, fs : &FileSystem, path : &str) -> Result<(u64, u64), u32> {
+        let mut root = fs.root().map_err(LibnxErrMapper::map)?;
+        match split::lookup(&mut root, path) {
+            Ok(ent) => Ok((ent.len as u64, ent.attributes as u64)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND),
+            Err(e) => Err(LibnxErrMapper::map(e)),
         }
-        else {
-            let (mut fs, _fs_guard) = get_filesystem().map_err(LibnxErrMapper::map)?;
-            fs.root_dir().iter()
-        };
-
-        parent_handle_itr.find_map(|entres| {
-            let ent = match entres.map_err(LibnxErrMapper::map) {
-                Ok(e) => e,
-                Err(err) => {
-                    return Some(Err(err));
-                }
-            };
-            if ent.file_name().trim_end_matches('/') != ent_name {
-                return None;
-            }
-            return Some( Ok( (ent.len(), ent.attributes().bits() as u64)));
-        }).unwrap_or(Err(NX_FATDRIVE_ERR_FILE_NOT_FOUND))
     }
-}
\ No newline at end of file
+}
@@ -3,239 +3,493 @@ use libnx_rs::usbhs::InterfaceAvailableEvent;
 use libnx_rs::usbhs::{Interface, InterfaceFilter, ClientInterfaceSession, InterfaceInfo, UsbHsContext};
 use mbr_nostd::{PartitionTableEntry, PartitionTable};
 use scsi::scsi::ScsiBlockDevice;
-use fatfs::{Dir, DirEntry, File, FileSystem, ReadWriteSeek};
 use vecwrapper::VecNewtype;
-use buf_scsi::OffsetScsiDevice;
+use buf_scsi::{BlockDevice, OffsetScsiDevice};
+use filesystem::{self, FileSystem, FileSystemOps, DirectoryOps, DirEntryData, DirEntryType, FatTimestamp, FileOps, FsErrorKind, FsStats, File, OpenOptions, ATTR_READ_ONLY};
+use filesystem::split;
+use filesystem::buffered::{self, WriteBackFile};
+use filesystem::path::{self, PathError};
 use std::collections::HashMap;
 use std::convert::AsRef;
+use std::io;
 use std::io::{ErrorKind, Read, Write, Seek, SeekFrom};
 use std::path::{Component, Components, Path};
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::slice;
-use std::ffi::{CStr, CString};
+use std::ffi::CString;
 use std::ptr;
 use std::mem;
 use std::os::raw::c_void;
 use capi_helpers::*;
 use self::iosupport_bindings::*;
-use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use usb_comm::UsbClient;
 
+/// One mounted partition, registered with newlib as its own devoptab device.
 struct NewlibContext {
-    usb_hs_ctx : Option<UsbHsContext>,
-    client_state : ClientState, 
+    /// The devoptab name, without the trailing colon.
+    device_name : CString,
+    // Declared before `drive` so the filesystem lets go of the block device first.
+    fs : FileSystem,
+    partition_in_use : PartitionTableEntry,
+    /// The USB drive the partition lives on; `None` for disk images.
+    drive : Option<Rc<UsbDrive>>,
     current_working_directory : Option<String>,
+    /// How many seconds the drive's FAT timestamps are ahead of UTC.
+    utc_offset : i32,
+    /// Counting free clusters can mean scanning the whole FAT, so `statvfs` reuses
+    /// the last result until something changes the allocation.
+    stats_cache : Option<FsStats>,
+    /// Refuses every change to the volume with `EROFS`.
+    read_only : bool,
+    /// The write-back buffer capacity given to each file opened on the device.
+    cache_size : usize,
+    /// How many devoptab file and directory handles are open on each path, keyed
+    /// by lowercased path since FAT names are case-insensitive.
+    open_paths : HashMap<String, usize>,
 }
 
-enum ClientState {
-    Uninitialized, 
-    Acquired {
-        iface : Interface,
-        client : ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>,
-    },
-    Opened {
-        iface : Interface,
-        fs : FileSystem<OffsetScsiDevice>,
-        partition_in_use : PartitionTableEntry,
+/// A USB mass storage drive, shared by the devices mounted from its partitions.
+struct UsbDrive {
+    device : Arc<Mutex<UsbScsiDevice>>,
+    iface : Interface,
+    usb_hs_ctx : Rc<UsbHsContext>,
+}
+
+impl UsbDrive {
+    fn is_connected(&self) -> bool {
+        self.device.is_connected()
     }
 }
 
 struct FileStruct {
-    fatfl : fatfs::DirEntry<'static, OffsetScsiDevice>,
-    offset : u64, 
+    ctx : *mut NewlibContext,
+    path : String,
+    options : OpenOptions,
+    /// Borrows the context's filesystem, which the handle cannot name a lifetime for since
+    /// it lives in memory newlib owns. The filesystem outlives it all the same: every handle
+    /// is counted in `open_paths` until it is closed, and `remove_device` will not free a
+    /// context while that count is non-zero.
+    file : WriteBackFile<File<'static>>,
 }
 
 struct DirStruct {
-    dir : fatfs::DirEntry<'static, OffsetScsiDevice>,
-    index : usize, 
+    ctx : *mut NewlibContext,
+    cursor : split::DirCursor,
 }
 
-impl NewlibContext {
+lazy_static! {
+    /// The addresses of every devoptab this crate has registered with newlib.
+    static ref mounted_devices : Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
 
-    pub unsafe fn get_global() -> Result<&'static mut NewlibContext, u32> {
-        let device_ptr = GetDeviceOpTab(device_name as *const _ as *const u8);
-        let ctx_ptr = match device_ptr.as_ref() {
-            Some(d) => d.deviceData, 
-            None => {
-                return Err(NX_FATDRIVE_ERR_NOT_INITIALIZED);
-            }
-        };
-        let g_context : &'static mut NewlibContext = match (ctx_ptr as *mut NewlibContext).as_mut() {
-            Some(c) => c, 
-            None => {
-                return Err(NX_FATDRIVE_ERR_UNKNOWN);
-            }
-        };
-        Ok(g_context)
-    }
+impl NewlibContext {
 
-    const fn new() -> NewlibContext {
+    pub fn new(device_name : CString, fs : FileSystem, partition_in_use : PartitionTableEntry, drive : Option<Rc<UsbDrive>>) -> NewlibContext {
         NewlibContext {
-            usb_hs_ctx : None, 
-            client_state : ClientState::Uninitialized, 
+            device_name,
+            fs,
+            partition_in_use,
+            drive,
             current_working_directory : None, 
+            utc_offset : 0,
+            stats_cache : None,
+            read_only : false,
+            cache_size : buffered::DEFAULT_BUFFER_CAPACITY,
+            open_paths : HashMap::new(),
         }
     }
 
-    pub fn init_usb_hs_ctx(&mut self) -> Result<(), u32> {
-        if self.usb_hs_ctx.is_some() {
-            return Ok(())
+    /// Finds the context of the device newlib routes `path_ptr` to: the one named by
+    /// its prefix, or the default device for paths without one.
+    pub unsafe fn for_path(path_ptr : *const u8) -> Result<&'static mut NewlibContext, u32> {
+        let lookup_ptr = if path_ptr.is_null() { b"\0".as_ptr() } else { path_ptr };
+        let device_ptr = GetDeviceOpTab(lookup_ptr);
+        NewlibContext::from_devoptab(device_ptr)
+    }
+
+    unsafe fn from_devoptab(device_ptr : *mut devoptab_t) -> Result<&'static mut NewlibContext, u32> {
+        if device_ptr.is_null() || !is_registered(device_ptr)? {
+            return Err(NX_FATDRIVE_ERR_NOT_INITIALIZED);
+        }
+        match ((*device_ptr).deviceData as *mut NewlibContext).as_mut() {
+            Some(c) => Ok(c),
+            None => Err(NX_FATDRIVE_ERR_UNKNOWN),
         }
-        let newctx = UsbHsContext::initialize().map_err(LibnxErrMapper::map)?;
-        self.usb_hs_ctx = Some(newctx);
-        Ok(())
     }
 
-    pub fn wait_for_usb_drive(&mut self, timeout : u64) -> Result<(), u32> {
-        match self.client_state {
-            ref Uninitialized => {
+    pub fn is_connected(&self) -> bool {
+        self.drive.as_ref().map_or(true, |drive| drive.is_connected())
+    }
 
-            },
-            _ => {
-                return Ok(());
-            },
-        };
-        let mut ctx = self.usb_hs_ctx.as_mut().ok_or(NX_FATDRIVE_ERR_NOT_INITIALIZED)?;
-        let filter : InterfaceFilter = InterfaceFilter::new()
-            .with_interface_class(8)
-            .with_interface_subclass(6)
-            .with_interface_protocol(80);
-        
-        let evt = InterfaceAvailableEvent::create(true, 0, filter).map_err(LibnxErrMapper::map)?;
-        evt.wait(timeout).map_err(LibnxErrMapper::map)?;
-        let mut interfaces = ctx.query_available_interfaces(filter, 3).map_err(LibnxErrMapper::map)?;
-        let iface = interfaces.pop().ok_or(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND)?;
-        let mut session = ctx.acquire_interface(&iface).map_err(LibnxErrMapper::map)?;
-
-        let (read_ep, write_ep) = UsbClient::retrieve_iface_endpoints(&session.interface()).map_err(LibnxErrMapper::map)?;
-        let client = UsbClient::new(session, read_ep, write_ep).map_err(LibnxErrMapper::map)?;
-        let mut scsi_wrapper = ScsiBlockDevice::new(client, VecNewtype::new(), VecNewtype::new(), VecNewtype::new()).map_err(LibnxErrMapper::map)?;
-        
-        self.client_state = ClientState::Acquired {
-            iface,
-            client: scsi_wrapper,
-        };
-        Ok(())
+    /// The working directory as a normalized absolute path; the root until `chdir` is called.
+    pub fn current_dir(&self) -> &str {
+        self.current_working_directory.as_ref().map_or("/", |cwd| cwd.as_str())
+    }
+
+    pub fn resolve_path(&self, path : &str) -> Result<String, PathError> {
+        path::resolve(self.current_dir(), path)
+    }
+
+    pub fn filesystem(&mut self) -> Result<&mut FileSystem, u32> {
+        if !self.is_connected() {
+            return Err(NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+        }
+        Ok(&mut self.fs)
+    }
+
+    /// Like `filesystem`, but fails if the device was mounted read-only.
+    pub fn writable_filesystem(&mut self) -> Result<&mut FileSystem, u32> {
+        if self.read_only {
+            return Err(NX_FATDRIVE_ERR_READ_ONLY);
+        }
+        self.filesystem()
+    }
+
+    pub fn stats(&mut self) -> Result<FsStats, u32> {
+        if let Some(cached) = self.stats_cache {
+            return Ok(cached);
+        }
+        let stats = self.filesystem()?.stats().map_err(LibnxErrMapper::map)?;
+        self.stats_cache = Some(stats);
+        Ok(stats)
+    }
+
+    pub fn retain_path(&mut self, path : &str) {
+        *self.open_paths.entry(path.to_lowercase()).or_insert(0) += 1;
     }
 
-    pub fn get_partitions(&mut self) -> Result<Vec<PartitionTableEntry>, u32>{
-        let mut scsi_wrapper : &mut ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype> = match self.client_state {
-            ClientState::Acquired {ref mut client, ..} => client, 
-            ClientState::Opened {..} => {
-                return Err(NX_FATDRIVE_ERR_UNKNOWN)
+    pub fn release_path(&mut self, path : &str) {
+        let key = path.to_lowercase();
+        let remaining = match self.open_paths.get_mut(&key) {
+            Some(count) => {
+                *count -= 1;
+                *count
             },
-            ClientState::Uninitialized => {
-                return Err(NX_FATDRIVE_ERR_NOT_INITIALIZED)
+            None => {
+                return;
             }
         };
-        let mut mbr_buff = VecNewtype::with_fake_capacity(512.max(scsi_wrapper.block_size() as usize));
-        let mut mbr_read_count = 0;
-        while mbr_buff.inner.len() < 512 {
-            let _bt = scsi_wrapper.read(mbr_buff.inner.len() as u32, &mut mbr_buff).map_err(LibnxErrMapper::map)?;
-            mbr_read_count += 1;
+        if remaining == 0 {
+            self.open_paths.remove(&key);
         }
+    }
 
-        let mbr_entry = mbr_nostd::MasterBootRecord::from_bytes(&mut mbr_buff.inner).map_err(LibnxErrMapper::map)?;
-        Ok(mbr_entry.partition_table_entries().iter().map(|e| e.clone()).collect())
+    pub fn is_path_open(&self, path : &str) -> bool {
+        self.open_paths.contains_key(&path.to_lowercase())
     }
 
-    pub fn open_partition(&mut self, idx : usize) -> Result<(), u32> {
-        let partition_list = self.get_partitions()?;
-        let (mut scsi_wrapper, iface) = match std::mem::replace(&mut self.client_state, ClientState::Uninitialized) {
-            ClientState::Acquired {client, iface} => (client, iface),
-            _ => {
-                return Err(NX_FATDRIVE_ERR_UNKNOWN);
-            }
-        };
-        let ent = partition_list.get(idx).ok_or(NX_FATDRIVE_ERR_UNKNOWN)?.clone();
-        let raw_offset : usize = (ent.logical_block_address * scsi_wrapper.block_size()) as usize; 
+    /// Whether a handle is open on `path` or on anything under it.
+    pub fn is_tree_open(&self, path : &str) -> bool {
+        let key = path.to_lowercase();
+        let prefix = if key.ends_with('/') { key.clone() } else { format!("{}/", key) };
+        self.open_paths.keys().any(|open| *open == key || open.starts_with(&prefix))
+    }
 
-        let mut device = OffsetScsiDevice::new(scsi_wrapper, raw_offset);
-        let mut fs = fatfs::FileSystem::new(device, fatfs::FsOptions::new()).map_err(LibnxErrMapper::map)?;
+    pub fn invalidate_stats(&mut self) {
+        self.stats_cache = None;
+    }
 
-        self.client_state = ClientState::Opened {
-            iface,
-            partition_in_use : ent, 
-            fs,
-        };
+    pub fn open_file(&mut self, path : &str, options : OpenOptions) -> Result<File, u32> {
+        let fs = if options.modifies_volume() { self.writable_filesystem()? } else { self.filesystem()? };
+        let root = fs.root().map_err(LibnxErrMapper::map)?;
+        split::open_file_or_split(root, path, options).map_err(LibnxErrMapper::map)
+    }
+
+    /// Opens a file for a devoptab handle and counts it in `open_paths`, which keeps the
+    /// device mounted until the handle is closed.
+    unsafe fn open_handle(&mut self, path : String, options : OpenOptions) -> Result<FileStruct, u32> {
+        let cache_size = self.cache_size;
+        let fl = self.open_file(&path, options)?;
+        let fl = mem::transmute::<File, File<'static>>(fl);
+        self.retain_path(&path);
+        Ok(FileStruct {
+            ctx : self as *mut NewlibContext,
+            path,
+            options,
+            file : WriteBackFile::with_capacity(fl, options.append, cache_size),
+        })
+    }
+
+    /// Whether any file or directory handle is still open on the device.
+    pub fn has_open_handles(&self) -> bool {
+        !self.open_paths.is_empty()
+    }
+}
+
+fn is_registered(device_ptr : *mut devoptab_t) -> Result<bool, u32> {
+    let devices = mounted_devices.lock().map_err(LibnxErrMapper::map)?;
+    Ok(devices.contains(&(device_ptr as usize)))
+}
+
+/// The first name of the form `{prefix}{n}` that no registered device is using.
+fn next_device_name(prefix : &str) -> Result<CString, u32> {
+    let taken = device_names()?;
+    let name = (0 ..).map(|idx| format!("{}{}", prefix, idx))
+        .find(|candidate| !taken.iter().any(|existing| existing == candidate))
+        .ok_or(NX_FATDRIVE_ERR_UNKNOWN)?;
+    CString::new(name).map_err(|_| NX_FATDRIVE_ERR_UNKNOWN)
+}
 
-        Ok(())
+/// The names of every registered device, in the order they were mounted.
+fn device_names() -> Result<Vec<String>, u32> {
+    let devices = mounted_devices.lock().map_err(LibnxErrMapper::map)?;
+    Ok(devices.iter()
+        .map(|addr| unsafe {
+            let ctx = (*(*addr as *const devoptab_t)).deviceData as *const NewlibContext;
+            (*ctx).device_name.to_string_lossy().into_owned()
+        })
+        .collect())
+}
+
+/// Mounts the partitions `options` selects on every attached drive as `{prefix}0`,
+/// `{prefix}1`, ... Partitions that do not hold a filesystem we can read are skipped.
+unsafe fn mount_all(options : &MountOptions) -> Result<usize, u32> {
+    let mut usb_hs_ctx = UsbHsContext::initialize().map_err(LibnxErrMapper::map)?;
+    let drives = acquire_drives(&mut usb_hs_ctx, options.timeout, usize::max_value())?;
+    let usb_hs_ctx = Rc::new(usb_hs_ctx);
+    let mut mounted = 0;
+    let mut last_err = NX_FATDRIVE_ERR_DRIVE_NOT_FOUND;
+    for (iface, scsi_wrapper) in drives {
+        let device = Arc::new(Mutex::new(scsi_wrapper));
+        let filesystems = match open_partitions(&device, options, usize::max_value()) {
+            Ok(f) => f,
+            Err(e) => {
+                last_err = e;
+                continue;
+            }
+        };
+        let drive = Rc::new(UsbDrive {
+            device,
+            iface,
+            usb_hs_ctx : usb_hs_ctx.clone(),
+        });
+        for (ent, fs) in filesystems {
+            let name = next_device_name(&options.device_prefix)?;
+            let mut ctx = NewlibContext::new(name, fs, ent, Some(drive.clone()));
+            ctx.read_only = options.read_only;
+            ctx.cache_size = options.cache_size;
+            if add_device(ctx) < 0 {
+                last_err = NX_FATDRIVE_ERR_UNKNOWN;
+                continue;
+            }
+            mounted += 1;
+        }
+    }
+    if mounted == 0 {
+        return Err(last_err);
     }
+    Ok(mounted)
 }
 
 use std::default::Default;
-pub fn stat_dirent<'a, T : Read + Write + Seek>(ent : &DirEntry<'a, T>) -> stat {
+pub fn stat_dirent(ent : &DirEntryData, utc_offset : i32) -> stat {
     let mut retval = stat::default();
     retval.st_nlink = 1; //Do not support symlinks 
 
 
     const BLOCK_SIZE : u64 = 512; //TODO: Get from device
     retval.st_blksize = BLOCK_SIZE;
-    retval.st_size = ent.len();
-    retval.st_blocks = 1 + ent.len()/BLOCK_SIZE;
+    retval.st_size = ent.len as u64;
+    retval.st_blocks = 1 + (ent.len as u64)/BLOCK_SIZE;
 
     // Only valid modes are RW or R for everyone; set the X bit as well since some 
     // might interpret opening a directory as "executing" it.
     let read_bits = stat::OWNER_READ | stat::GROUP_READ | stat::OTHER_READ;
-    let write_bits = if ent.attributes().contains(fatfs::FileAttributes::READ_ONLY) { 0 } else { stat::OWNER_WRITE | stat::GROUP_WRITE | stat::OTHER_WRITE };
+    let write_bits = if ent.has_attributes(ATTR_READ_ONLY) { 0 } else { stat::OWNER_WRITE | stat::GROUP_WRITE | stat::OTHER_WRITE };
     let exec_bits = stat::OWNER_EXEC | stat::GROUP_EXEC | stat::OTHER_EXEC;
-    let type_bits = if ent.is_dir() { stat::DIRECTORY } else { stat::FILE };
+    let type_bits = if ent.entry_type() == DirEntryType::Directory { stat::DIRECTORY } else { stat::FILE };
     retval.st_mode = read_bits | write_bits | exec_bits | type_bits;
 
-    let atime = fatfs::DateTime {
-        date : ent.accessed() ,
-        time : fatfs::Time { hour : 12, min : 0,  sec : 0, millis : 0},
-    };
-    retval.st_atime = fat_time_to_unix(atime);
-    retval.st_ctime = fat_time_to_unix(ent.created());
-    retval.st_mtime = fat_time_to_unix(ent.modified());
+    retval.st_mtime = fat_time_to_unix(ent.modified, utc_offset);
+    retval.st_atime = ent.accessed.map_or(retval.st_mtime, |accessed| fat_time_to_unix(Some(accessed), utc_offset));
+    retval.st_ctime = ent.created.map_or(retval.st_mtime, |created| fat_time_to_unix(Some(created), utc_offset));
 
     retval
 } 
 
-fn fat_time_to_unix(fat_time : fatfs::DateTime) -> u64 {
-    unimplemented!()
+/// Converts a FAT timestamp to a `time_t`, using 0 for unset timestamps.
+/// Dates before the epoch are clamped to it, since `time_t` is unsigned here.
+fn fat_time_to_unix(fat_time : Option<FatTimestamp>, utc_offset : i32) -> time_t {
+    fat_time.map_or(0, |ts| ts.to_unix(utc_offset).max(0) as time_t)
 }
 
-fn path_to_dirent<'a, T : Read + Write + Seek>(fs : &'a FileSystem<T>, path : &str) -> DirEntry<'a, T> {
-    unimplemented!()
+/// Reads a path argument and resolves it against the working directory,
+/// setting `errno` if it is unusable.
+unsafe fn read_path(r : *mut _reent, ctx : &NewlibContext, path_ptr : *const u8) -> Option<String> {
+    match path::c_path_str(path_ptr).and_then(|raw| ctx.resolve_path(raw)) {
+        Ok(p) => Some(p),
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            None
+        }
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn _fatdrive_diropen_r(r : *mut _reent, dir_state_ptr : *mut DIR_ITER, path_ptr : *const u8) -> *mut DIR_ITER {
-    let path : &str = match CStr::from_ptr(path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno =  NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return ptr::null_mut();
+
+/// Looks up the entry at the normalized `path`; errors are errno values.
+fn path_to_dirent(fs : &mut FileSystem, path : &str) -> Result<DirEntryData, i32> {
+    let mut root = fs.root().map_err(ErrnoMapper::errno)?;
+    split::lookup(&mut root, path).map_err(ErrnoMapper::errno)
+}
+
+/// Converts errors from the filesystem and the rest of the crate into the POSIX
+/// `errno` values newlib callers expect.
+trait ErrnoMapper {
+    fn errno(err : Self) -> i32;
+}
+
+impl ErrnoMapper for io::Error {
+    fn errno(err : io::Error) -> i32 {
+        if let Some(kind) = filesystem::fs_error_kind(&err) {
+            return match kind {
+                FsErrorKind::NoSpace => errno::NX_FATDRIVE_ERRNO_ENOSPC,
+                FsErrorKind::IsADirectory => errno::NX_FATDRIVE_ERRNO_EISDIR,
+                FsErrorKind::NotADirectory => errno::NX_FATDRIVE_ERRNO_ENOTDIR,
+                FsErrorKind::DirectoryNotEmpty => errno::NX_FATDRIVE_ERRNO_ENOTEMPTY,
+                FsErrorKind::Busy => errno::NX_FATDRIVE_ERRNO_EBUSY,
+                FsErrorKind::ReadOnly => errno::NX_FATDRIVE_ERRNO_EROFS,
+                FsErrorKind::Unsupported => errno::NX_FATDRIVE_ERRNO_ENOSYS,
+            };
         }
-    };
+        if let Some(path_err) = err.get_ref().and_then(|inner| inner.downcast_ref::<PathError>()) {
+            return ErrnoMapper::errno(path_err.clone());
+        }
+        io_kind_errno(err.kind())
+    }
+}
+
+fn io_kind_errno(kind : ErrorKind) -> i32 {
+    match kind {
+        ErrorKind::NotFound => errno::NX_FATDRIVE_ERRNO_ENOENT,
+        ErrorKind::PermissionDenied => errno::NX_FATDRIVE_ERRNO_EACCES,
+        ErrorKind::AlreadyExists => errno::NX_FATDRIVE_ERRNO_EEXIST,
+        ErrorKind::InvalidInput => errno::NX_FATDRIVE_ERRNO_EINVAL,
+        ErrorKind::WriteZero => errno::NX_FATDRIVE_ERRNO_ENOSPC,
+        ErrorKind::Interrupted => errno::NX_FATDRIVE_ERRNO_EINTR,
+        ErrorKind::WouldBlock => errno::NX_FATDRIVE_ERRNO_EAGAIN,
+        ErrorKind::TimedOut => errno::NX_FATDRIVE_ERRNO_ETIMEDOUT,
+        // The FatFs backend reports running out of file objects this way.
+        ErrorKind::AddrInUse => errno::NX_FATDRIVE_ERRNO_EMFILE,
+        // The drive went away underneath us.
+        ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => errno::NX_FATDRIVE_ERRNO_ENODEV,
+        _ => errno::NX_FATDRIVE_ERRNO_EIO,
+    }
+}
+
+impl ErrnoMapper for PathError {
+    fn errno(err : PathError) -> i32 {
+        match err {
+            PathError::NullPointer => errno::NX_FATDRIVE_ERRNO_EFAULT,
+            PathError::Empty => errno::NX_FATDRIVE_ERRNO_ENOENT,
+            PathError::InvalidUtf8 | PathError::InvalidCharacter(_) => errno::NX_FATDRIVE_ERRNO_EINVAL,
+            PathError::ComponentTooLong(_) | PathError::PathTooLong(_) => errno::NX_FATDRIVE_ERRNO_ENAMETOOLONG,
+        }
+    }
+}
+
+/// Crate result codes, as produced by `LibnxErrMapper`.
+impl ErrnoMapper for u32 {
+    fn errno(err : u32) -> i32 {
+        match err {
+            NX_FATDRIVE_ERR_NOT_IMPLEMENTED => errno::NX_FATDRIVE_ERRNO_ENOSYS,
+            NX_FATDRIVE_ERR_NOT_INITIALIZED | NX_FATDRIVE_ERR_DRIVE_NOT_FOUND | NX_FATDRIVE_ERR_DRIVE_DISCONNECTED | NX_FATDRIVE_ERR_UNSUPPORTED_FS => errno::NX_FATDRIVE_ERRNO_ENODEV,
+            NX_FATDRIVE_ERR_INVALID_HANDLE => errno::NX_FATDRIVE_ERRNO_EBADF,
+            NX_FATDRIVE_ERR_OUT_OF_RANGE => errno::NX_FATDRIVE_ERRNO_EINVAL,
+            NX_FATDRIVE_ERR_WRITE_PROTECTED => errno::NX_FATDRIVE_ERRNO_EACCES,
+            NX_FATDRIVE_ERR_DATA_LOST => errno::NX_FATDRIVE_ERRNO_EIO,
+            NX_FATDRIVE_ERR_FILE_NOT_FOUND => errno::NX_FATDRIVE_ERRNO_ENOENT,
+            NX_FATDRIVE_ERR_NO_SPACE => errno::NX_FATDRIVE_ERRNO_ENOSPC,
+            NX_FATDRIVE_ERR_IS_A_DIRECTORY => errno::NX_FATDRIVE_ERRNO_EISDIR,
+            NX_FATDRIVE_ERR_NOT_A_DIRECTORY => errno::NX_FATDRIVE_ERRNO_ENOTDIR,
+            NX_FATDRIVE_ERR_DIRECTORY_NOT_EMPTY => errno::NX_FATDRIVE_ERRNO_ENOTEMPTY,
+            NX_FATDRIVE_ERR_BUSY => errno::NX_FATDRIVE_ERRNO_EBUSY,
+            NX_FATDRIVE_ERR_READ_ONLY => errno::NX_FATDRIVE_ERRNO_EROFS,
+            _ => {
+                let prefix = (err >> 8) & 0xFFFF_0000;
+                let desc = (err >> 8) & 0xFFFF;
+                if err & 0xFF != NX_FATDRIVE_ERR_MODULE {
+                    return errno::NX_FATDRIVE_ERRNO_EIO;
+                }
+                match prefix {
+                    NX_FATDRIVE_ERR_STDIO_PREFIX => io_kind_errno(stdio_offset_kind(desc)),
+                    NX_FATDRIVE_ERR_PATH_PREFIX => match desc {
+                        1 => errno::NX_FATDRIVE_ERRNO_EFAULT,
+                        3 => errno::NX_FATDRIVE_ERRNO_ENOENT,
+                        5 | 6 => errno::NX_FATDRIVE_ERRNO_ENAMETOOLONG,
+                        _ => errno::NX_FATDRIVE_ERRNO_EINVAL,
+                    },
+                    _ => errno::NX_FATDRIVE_ERRNO_EIO,
+                }
+            }
+        }
+    }
+}
+
+/// Inverts the `io::ErrorKind` offsets `LibnxErrMapper` stores under `NX_FATDRIVE_ERR_STDIO_PREFIX`.
+fn stdio_offset_kind(offset : u32) -> ErrorKind {
+    match offset {
+        1 => ErrorKind::NotFound,
+        2 => ErrorKind::PermissionDenied,
+        3 => ErrorKind::ConnectionRefused,
+        4 => ErrorKind::ConnectionReset,
+        5 => ErrorKind::NotConnected,
+        6 => ErrorKind::AddrInUse,
+        7 => ErrorKind::AddrNotAvailable,
+        8 => ErrorKind::BrokenPipe,
+        9 => ErrorKind::AlreadyExists,
+        10 => ErrorKind::WouldBlock,
+        11 => ErrorKind::InvalidInput,
+        12 => ErrorKind::InvalidData,
+        13 => ErrorKind::TimedOut,
+        14 => ErrorKind::WriteZero,
+        15 => ErrorKind::Interrupted,
+        17 => ErrorKind::UnexpectedEof,
+        18 => ErrorKind::ConnectionAborted,
+        _ => ErrorKind::Other,
+    }
+}
 
-    let mut ctx = match NewlibContext::get_global() {
+unsafe extern "C" fn _fatdrive_diropen_r(r : *mut _reent, dir_state_ptr : *mut DIR_ITER, path_ptr : *const u8) -> *mut DIR_ITER {
+    let mut ctx = match NewlibContext::for_path(path_ptr) {
         Ok(c) => c,
         Err(e) => {
-            (*r).errno = e as i32;
+            (*r).errno = ErrnoMapper::errno(e);
             return ptr::null_mut();
         }
     };
 
-    let fs : &'static mut FileSystem<OffsetScsiDevice> = match &mut ctx.client_state {
-        ClientState::Opened {ref mut fs, ..} => fs, 
-        _ => {
-            (*r).errno = NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
+    let path = match read_path(r, ctx, path_ptr) {
+        Some(p) => p,
+        None => {
             return ptr::null_mut();
         }
     };
 
-    let ent = path_to_dirent(fs, path);
-    if !ent.is_dir() {
-        (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOENT;
-        return ptr::null_mut();
+    let fs = match ctx.filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return ptr::null_mut();
+        }
+    };
+
+    match path_to_dirent(fs, &path) {
+        Ok(ref ent) if ent.entry_type() == DirEntryType::Directory => {},
+        Ok(_) => {
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOTDIR;
+            return ptr::null_mut();
+        },
+        Err(e) => {
+            (*r).errno = e;
+            return ptr::null_mut();
+        }
     }
     let nstruct = DirStruct {
-        index : 0, 
-        dir : ent,
+        ctx : ctx as *mut NewlibContext,
+        cursor : split::DirCursor::new(path),
     };
     
     let state : &mut DIR_ITER = match dir_state_ptr.as_mut() {
@@ -251,258 +505,319 @@ pub unsafe extern "C" fn _fatdrive_diropen_r(r : *mut _reent, dir_state_ptr : *m
         return ptr::null_mut();
     };
 
+    ctx.retain_path(nstruct.cursor.path());
     let dir_struct_ptr = state.dirStruct as *mut DirStruct;
-    *dir_struct_ptr = nstruct;
+    ptr::write(dir_struct_ptr, nstruct);
     return dir_state_ptr;
 }
 
 unsafe extern "C" fn _fatdrive_open_r(r: *mut _reent, fd: *mut c_void, path_ptr: * const u8, flags: u32, mode: u32) -> i32 {
-    let path : &str = match CStr::from_ptr(path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno =  NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return (*r).errno;
-        }
-    };
-
-    let mut ctx = match NewlibContext::get_global() {
+    let mut ctx = match NewlibContext::for_path(path_ptr) {
         Ok(c) => c,
         Err(e) => {
-            (*r).errno = e as i32;
-            return e as i32;
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
 
-    let fs : &mut FileSystem<OffsetScsiDevice> = match &mut ctx.client_state {
-        ClientState::Opened {ref mut fs, ..} => fs, 
-        _ => {
-            (*r).errno = NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
-            return NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
+    let path = match read_path(r, ctx, path_ptr) {
+        Some(p) => p,
+        None => {
+            return -1;
         }
     };
 
-    let ent = path_to_dirent(fs, path);
-    if !ent.is_file() {
+    if fd.is_null() {
         (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOENT;
         return -1;
     }
-    let nstruct = FileStruct {
-        offset : 0, 
-        fatfl : ent,
-    };
 
-    if fd.is_null() {
-        (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOENT;
-        return -1;
+    let options = OpenOptions::from_posix_flags(flags);
+    if options.create || options.create_new || options.truncate {
+        ctx.invalidate_stats();
     }
+    let nstruct = match ctx.open_handle(path, options) {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
 
     let fl_struct_ptr = fd as *const FileStruct as *mut FileStruct;
-    (*fl_struct_ptr) = nstruct;
+    ptr::write(fl_struct_ptr, nstruct);
     return 0;
 }
 
 unsafe extern "C" fn _fatdrive_write_r ( r: *mut _reent, fd: *mut c_void, buff_ptr: * const u8, len: usize) -> isize {
-    let fl_ctx : &mut FileStruct = match (fd as *mut FileStruct).as_mut() {
+    let fl_ctx : &mut FileStruct = match file_handle(fd) {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_FILE_NOT_FOUND as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
-    let base = fl_ctx.offset;
-    let mut fl = fl_ctx.fatfl.to_file();
-    if let Err(e) = fl.seek(SeekFrom::Start(base)).map_err(LibnxErrMapper::map) {
-        (*r).errno = e as i32;
+    if buff_ptr.is_null() {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EINVAL;
         return -1;
     }
+    if !fl_ctx.options.write {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+        return -1;
+    }
+    // Anything that can allocate or free clusters makes the cached free space stale.
+    (*fl_ctx.ctx).invalidate_stats();
 
     let buff = slice::from_raw_parts(buff_ptr, len);
-    let writecount = match fl.write(buff).map_err(LibnxErrMapper::map) {
-        Ok(ln) => ln as u64, 
+    match fl_ctx.file.write(buff) {
+        Ok(ln) => ln as isize, 
         Err(e) => {
-            (*r).errno = e as i32;
-            return -1;
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
         }
-    };
-    if let Err(e) = fl.flush().map_err(LibnxErrMapper::map) {
-            (*r).errno = e as i32;
-            return -1;
-    };
-    fl_ctx.offset += writecount;
-    writecount as isize
+    }
 }
 
 unsafe extern "C" fn _fatdrive_read_r ( r: *mut _reent, fd: *mut c_void, buff_ptr: * mut u8, len: usize) -> isize {
-    let fl_ctx : &mut FileStruct = match (fd as *mut FileStruct).as_mut() {
+    let fl_ctx : &mut FileStruct = match file_handle(fd) {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_FILE_NOT_FOUND as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
-    let base = fl_ctx.offset;
-    let mut fl = fl_ctx.fatfl.to_file();
-    if let Err(e) = fl.seek(SeekFrom::Start(base)).map_err(LibnxErrMapper::map) {
-        (*r).errno = e as i32;
+    if buff_ptr.is_null() {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EINVAL;
+        return -1;
+    }
+    if !fl_ctx.options.read {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
         return -1;
     }
 
     let buff = slice::from_raw_parts_mut(buff_ptr, len);
-    let readcount = match fl.read(buff).map_err(LibnxErrMapper::map) {
-        Ok(ln) => ln as u64, 
+    match fl_ctx.file.read(buff) {
+        Ok(ln) => ln as isize, 
         Err(e) => {
-            (*r).errno = e as i32;
-            return -1;
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
         }
-    };
-
-    fl_ctx.offset += readcount;
-    readcount as isize
+    }
 }
 
+/// The `whence` values from newlib's `stdio.h`.
+const SEEK_SET : i32 = 0;
+const SEEK_CUR : i32 = 1;
+const SEEK_END : i32 = 2;
+
 unsafe extern "C" fn _fatdrive_seek_r(r: *mut _reent, fd: *mut c_void, pos: off_t, dir: i32) -> off_t {
-    let fl_ctx : &mut FileStruct = match (fd as *mut FileStruct).as_mut() {
+    let fl_ctx : &mut FileStruct = match file_handle(fd) {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_FILE_NOT_FOUND as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
-    let base = fl_ctx.offset;
-    let mut fl = fl_ctx.fatfl.to_file();
-    if let Err(e) = fl.seek(SeekFrom::Start(base)).map_err(LibnxErrMapper::map) {
-        (*r).errno = e as i32;
-        return -1;
-    }
     
     let sk = match dir {
-        1 => SeekFrom::Start(pos as u64),
-        2 => SeekFrom::Current(pos),
-        3 => SeekFrom::End(pos),
-
+        SEEK_SET => {
+            if pos < 0 {
+                (*r).errno = errno::NX_FATDRIVE_ERRNO_EINVAL;
+                return -1;
+            }
+            SeekFrom::Start(pos as u64)
+        },
+        SEEK_CUR => SeekFrom::Current(pos),
+        SEEK_END => SeekFrom::End(pos),
         _ => {
-            (*r).errno = NX_FATDRIVE_ERR_UNKNOWN as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EINVAL;
             return -1;
         }
     };
 
-    let newoff = match fl.seek(sk).map_err(LibnxErrMapper::map) {
-        Ok(ln) => ln, 
+    match fl_ctx.file.seek(sk) {
+        Ok(ln) => ln as off_t, 
         Err(e) => {
-            (*r).errno = e as i32;
-            return -1;
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
         }
-    };
-    fl_ctx.offset = newoff;
-    newoff as off_t
+    }
 }
 
 unsafe extern "C" fn _fatdrive_rename_r( r: *mut _reent, old_path_ptr: * const u8, new_path_ptr: * const u8) -> i32 {
-    let old_path : &str = match CStr::from_ptr(old_path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno = NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return (*r).errno;
+    let mut ctx = match NewlibContext::for_path(old_path_ptr) {
+        Ok(c) => c,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
+    // Both paths have to be on this device; newlib may not have checked the new one.
+    let ctx_ptr = &*ctx as *const NewlibContext;
+    let same_device = NewlibContext::for_path(new_path_ptr).map_or(false, |other| other as *const NewlibContext == ctx_ptr);
+    if !same_device {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EXDEV;
+        return -1;
+    }
+    ctx.invalidate_stats();
 
-    let new_path : &str = match CStr::from_ptr(new_path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno =  NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return (*r).errno;
+    let old_path = match read_path(r, ctx, old_path_ptr) {
+        Some(p) => p,
+        None => {
+            return -1;
         }
     };
 
-    let mut ctx = match NewlibContext::get_global() {
-        Ok(c) => c,
-        Err(e) => {
-            (*r).errno = e as i32;
-            return e as i32;
+    let new_path = match read_path(r, ctx, new_path_ptr) {
+        Some(p) => p,
+        None => {
+            return -1;
         }
     };
+    // Open handles find their file by path, so whatever they have open has to stay put.
+    if ctx.is_tree_open(&old_path) || ctx.is_path_open(&new_path) {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBUSY;
+        return -1;
+    }
     
-    let mut fs = match &mut ctx.client_state {
-        ClientState::Opened {ref mut fs, ..} => fs, 
-        _ => {
-            (*r).errno = NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
-            return NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
+    let mut fs = match ctx.writable_filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
 
-    let root = fs.root_dir();
-    match root.rename(old_path, &root, new_path).map_err(LibnxErrMapper::map) {
+    let renamed = fs.root().and_then(|mut root| root.rename(old_path, new_path));
+    match renamed {
         Ok(_) => 0,
         Err(e) => {
-            (*r).errno = e as i32;
-            e as i32
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
         },
     }
 }
 unsafe extern "C" fn _fatdrive_chmod_r(r: *mut _reent, path: * const u8, mode: mode_t) -> i32 {
     (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
-    return errno::NX_FATDRIVE_ERRNO_ENOSYS;
+    -1
 }
 unsafe extern "C" fn _fatdrive_fchmod_r(r: *mut _reent, fd: *mut c_void, mode: mode_t) -> i32 {
     (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
-    return errno::NX_FATDRIVE_ERRNO_ENOSYS;
+    -1
 }
 unsafe extern "C" fn _fatdrive_link_r(r: *mut _reent, existing: * const u8, newLink: * const u8) -> i32{
     (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
-    return errno::NX_FATDRIVE_ERRNO_ENOSYS;
+    -1
 }
 
 unsafe extern "C" fn _fatdrive_unlink_r(r: *mut _reent, name: * const u8) -> i32 {
-    (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
-    return errno::NX_FATDRIVE_ERRNO_ENOSYS;
-}
-unsafe extern "C" fn _fatdrive_rmdir_r(r: *mut _reent, path_ptr: * const u8) -> i32 {
-    let path : &str = match CStr::from_ptr(path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno =  NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return (*r).errno;
+    let mut ctx = match NewlibContext::for_path(name) {
+        Ok(c) => c,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    ctx.invalidate_stats();
+
+    let path = match read_path(r, ctx, name) {
+        Some(p) => p,
+        None => {
+            return -1;
         }
     };
+    if ctx.is_path_open(&path) {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBUSY;
+        return -1;
+    }
 
-    let mut ctx = match NewlibContext::get_global() {
+    let fs = match ctx.writable_filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    match fs.root().and_then(|mut root| split::remove_file_or_split(&mut root, &path)) {
+        Ok(_) => 0,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
+        }
+    }
+}
+unsafe extern "C" fn _fatdrive_rmdir_r(r: *mut _reent, path_ptr: * const u8) -> i32 {
+    let mut ctx = match NewlibContext::for_path(path_ptr) {
         Ok(c) => c,
         Err(e) => {
-            (*r).errno = e as i32;
-            return e as i32;
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
+    ctx.invalidate_stats();
 
-    let mut fs = match &mut ctx.client_state {
-        ClientState::Opened {ref mut fs, ..} => fs, 
-        _ => {
-            (*r).errno = NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
-            return NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
+    let path = match read_path(r, ctx, path_ptr) {
+        Some(p) => p,
+        None => {
+            return -1;
         }
     };
 
-    match fs.root_dir().remove(path).map_err(LibnxErrMapper::map) {
-        Ok(_) => {
+    if ctx.is_path_open(&path) {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBUSY;
+        return -1;
+    }
 
-        },
+    let mut fs = match ctx.writable_filesystem() {
+        Ok(f) => f,
         Err(e) => {
-            (*r).errno = e as i32;
-            return e as i32;
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
-    return 0;
+
+    match fs.root().and_then(|mut root| split::remove_empty_directory(&mut root, &path)) {
+        Ok(_) => 0,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
+        }
+    }
 }
 
 unsafe extern "C" fn _fatdrive_fstat_r(r : *mut _reent, fd : *mut c_void, st : *mut stat) -> i32 {
-    let fl_ctx : &mut FileStruct = match (fd as *mut FileStruct).as_mut() {
+    let fl_ctx : &mut FileStruct = match file_handle(fd) {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_FILE_NOT_FOUND as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
-    (*st) = stat_dirent(&fl_ctx.fatfl);
-    return 0;
+    let ctx = &mut *fl_ctx.ctx;
+    // The size in the directory entry has to include whatever is still buffered,
+    // and the backends only update the entry when flushed.
+    if let Err(e) = fl_ctx.file.flush() {
+        (*r).errno = ErrnoMapper::errno(e);
+        return -1;
+    }
+    let utc_offset = ctx.utc_offset;
+    let fs = match ctx.filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    match path_to_dirent(fs, &fl_ctx.path) {
+        Ok(ent) => {
+            (*st) = stat_dirent(&ent, utc_offset);
+            0
+        },
+        Err(e) => {
+            (*r).errno = e;
+            -1
+        }
+    }
 }
 
 
@@ -510,27 +825,38 @@ unsafe extern "C" fn _fatdrive_dirreset_r(r: *mut _reent, dirState: *mut DIR_ITE
     let dir_itr : &mut DIR_ITER = match dirState.as_mut() {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_UNKNOWN as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
 
-    let dir_struct : &mut DirStruct = match (dir_itr.dirStruct as *mut DirStruct).as_mut() {
-        Some(p) => p, 
-        None => {
-            (*r).errno = NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return -1;
-        }
-    };
+    let dir_struct_ptr = dir_itr.dirStruct as *mut DirStruct;
+    if is_zeroed(dir_struct_ptr as *const DirStruct) != 0 {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+        return -1;
+    }
+    let dir_struct : &mut DirStruct = &mut *dir_struct_ptr;
 
-    dir_struct.index = 0;
+    dir_struct.cursor.rewind();
     return 0;
 }
 
 unsafe extern "C" fn _fatdrive_dirclose_r(r: *mut _reent, dirState: *mut DIR_ITER) -> i32 {
-    // Currently there is no need to explicitly "close" a directory handle since all changes are
-    // non-buffered; heck, the DirEntry struct doesn't even implement Drop.
-
+    let dir_struct_ptr = match dirState.as_mut() {
+        Some(d) if !d.dirStruct.is_null() => d.dirStruct as *mut DirStruct,
+        _ => {
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+            return -1;
+        }
+    };
+    if is_zeroed(dir_struct_ptr as *const DirStruct) != 0 {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+        return -1;
+    }
+    // Move the struct out so its path is freed, then zero the slot so later calls see it as closed.
+    let closed = ptr::read(dir_struct_ptr);
+    ptr::write_bytes(dir_struct_ptr, 0, 1);
+    (*closed.ctx).release_path(closed.cursor.path());
     0
 }
 
@@ -538,58 +864,82 @@ unsafe extern "C" fn _fatdrive_dirnext_r( r: *mut _reent, dirState: *mut DIR_ITE
     let dir_itr : &mut DIR_ITER = match dirState.as_mut() {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_UNKNOWN as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
 
     let dir_struct_ptr = dir_itr.dirStruct as *mut DirStruct;
-    let ended = is_zeroed(dir_struct_ptr as *const DirStruct);
-    if ended != 0 {
-        (*r).errno = ended;
+    if is_zeroed(dir_struct_ptr as *const DirStruct) != 0 {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
         return -1;
     }
 
     let mut dir_struct : &mut DirStruct = match dir_struct_ptr.as_mut() {
         Some(p) => p, 
         None => {
-            (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOENT as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };  
 
-    let root_dir = dir_struct.dir.to_dir();
+    let ctx = &mut *dir_struct.ctx;
+    let utc_offset = ctx.utc_offset;
+    let fs = match ctx.filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    let mut root_dir = match fs.root() {
+        Ok(d) => d,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    let next_res = dir_struct.cursor.next_entry(&mut root_dir);
 
-    let next_itm = match root_dir.iter().skip(dir_struct.index).next() {
-        Some(Ok(p)) => {
+    match next_res {
+        Ok(Some(p)) => {
             if let Some(stat_ref) = filestat.as_mut() {
-                stat_ref.clone_from(&stat_dirent(&p));
+                stat_ref.clone_from(&stat_dirent(&p, utc_offset));
             }
             if !filename_ptr.is_null() {
-                let full_name = p.file_name();
-                let name_bytes = full_name.as_bytes();
+                // newlib hands us a NAME_MAX + 1 byte buffer, leaving room for the terminator.
+                let name_bytes = p.name.as_bytes();
                 let retlen = name_bytes.len().min(NX_FATDRIVE_NAME_MAX);
-                let mut filename_slice = slice::from_raw_parts_mut(filename_ptr, retlen);
-                filename_slice.copy_from_slice(&name_bytes[0 .. retlen]);
+                let mut filename_slice = slice::from_raw_parts_mut(filename_ptr, retlen + 1);
+                filename_slice[.. retlen].copy_from_slice(&name_bytes[0 .. retlen]);
+                filename_slice[retlen] = 0;
             }
-            p
         },
-        Some(Err(e)) => {
-            (*r).errno = NX_FATDRIVE_ERR_UNKNOWN as i32;
+        Ok(None) => {
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOENT;
+            return -1;
+        },
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
             return -1;
-        }
-        None => {
-            mem::zeroed()
         }
     };
-    dir_struct.index += 1;
     return 0;
 }
 
-fn is_zeroed<T>(struct_ptr : *const T) -> i32 {
-    let struct_size = mem::size_of::<T>();
+/// The open file behind a devoptab handle, or `None` if it is null or already closed.
+unsafe fn file_handle<'a>(fd : *mut c_void) -> Option<&'a mut FileStruct> {
+    let fl_struct_ptr = fd as *mut FileStruct;
+    if is_zeroed(fl_struct_ptr as *const FileStruct) != 0 {
+        return None;
+    }
+    fl_struct_ptr.as_mut()
+}
+
+fn is_zeroed<T>(struct_ptr : *const T) -> i32 {
+    let struct_size = mem::size_of::<T>();
     if struct_ptr.is_null() {
-        return errno::NX_FATDRIVE_ERRNO_ENOENT;
+        return errno::NX_FATDRIVE_ERRNO_EBADF;
     }
 
     let struct_data : &[u8] = unsafe { slice::from_raw_parts(struct_ptr as *const u8, struct_size) };
@@ -609,35 +959,39 @@ fn is_zeroed<T>(struct_ptr : *const T) -> i32 {
 
 
 unsafe extern "C" fn _fatdrive_mkdir_r(r: *mut _reent, path_ptr: * const u8, mode: u32) -> i32 {
-    let path : &str = match CStr::from_ptr(path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno =  NX_FATDRIVE_ERR_UNKNOWN as i32;
-            return (*r).errno;
+    let mut ctx = match NewlibContext::for_path(path_ptr) {
+        Ok(c) => c,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
+    ctx.invalidate_stats();
 
-    let mut ctx = match NewlibContext::get_global() {
-        Ok(c) => c,
-        Err(e) => {
-            (*r).errno = e as i32;
-            return e as i32;
+    let path = match read_path(r, ctx, path_ptr) {
+        Some(p) => p,
+        None => {
+            return -1;
         }
     };
 
-    let mut ctx_state = &mut ctx.client_state;
-    let fs : &mut FileSystem<OffsetScsiDevice> = match ctx_state {
-        ClientState::Opened {ref mut fs, ..} => fs, 
-        _ => {
-            (*r).errno = NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
-            return NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
+    let fs : &mut FileSystem = match ctx.writable_filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
         }
     };
 
-    let retval = match fs.root_dir().create_dir(path) {
+    // The backends happily open an existing directory instead of creating one.
+    if path_to_dirent(fs, &path).is_ok() {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EEXIST;
+        return -1;
+    }
+    let retval = match fs.root().and_then(|mut root| root.create_directory(path)) {
         Ok(_) => 0, 
         Err(e) => {
-            (*r).errno = e.raw_os_error().unwrap_or(0xFFFFFFFF); 
+            (*r).errno = ErrnoMapper::errno(e);
             -1 
         }
     };
@@ -646,58 +1000,108 @@ unsafe extern "C" fn _fatdrive_mkdir_r(r: *mut _reent, path_ptr: * const u8, mod
 }
 
 unsafe extern "C" fn _fatdrive_ftruncate_r(r: *mut _reent, fd: *mut ::std::os::raw::c_void, len: off_t) -> i32 {
-    let fl_ctx : &mut FileStruct = match (fd as *mut FileStruct).as_mut() {
+    let fl_ctx : &mut FileStruct = match file_handle(fd) {
         Some(p) => p, 
         None => {
-            (*r).errno = NX_FATDRIVE_ERR_FILE_NOT_FOUND as i32;
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
             return -1;
         }
     };
-
-    match fl_ctx.fatfl.to_file().truncate() {
+    if !fl_ctx.options.write {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+        return -1;
+    }
+    if len < 0 {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EINVAL;
+        return -1;
+    }
+    (*fl_ctx.ctx).invalidate_stats();
+    match fl_ctx.file.set_len(len as u64).and_then(|_| fl_ctx.file.flush()) {
         Ok(_) => 0, 
         Err(e) => { 
-            (*r).errno = e.raw_os_error().unwrap_or(0xFFFFFFFF);
+            (*r).errno = ErrnoMapper::errno(e);
             -1
         }
     }
 }
 
 unsafe extern "C" fn _fatdrive_fsync_r(r: *mut _reent, fd: *mut ::std::os::raw::c_void) -> i32 {
-    //Right now we immediately flush to the output as we write, so we don't need this to do anything.
-    0
+    let fl_ctx : &mut FileStruct = match file_handle(fd) {
+        Some(p) => p, 
+        None => {
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+            return -1;
+        }
+    };
+    match fl_ctx.file.flush() {
+        Ok(_) => 0,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
+        }
+    }
 }
 unsafe extern "C" fn _fatdrive_close_r( r: *mut _reent, fd : *mut c_void) -> i32 {
-    //Since we flush on write, we don't need any special closing code. 
-    0
+    let fl_struct_ptr = fd as *mut FileStruct;
+    if fl_struct_ptr.is_null() || is_zeroed(fl_struct_ptr as *const FileStruct) != 0 {
+        (*r).errno = errno::NX_FATDRIVE_ERRNO_EBADF;
+        return -1;
+    }
+    // Move the handle out so the file is closed and its path freed, then zero the slot.
+    let mut closed = ptr::read(fl_struct_ptr);
+    ptr::write_bytes(fl_struct_ptr, 0, 1);
+    let had_unwritten = closed.file.has_unwritten();
+    let flushed = closed.file.flush();
+    // The file has to be gone before the count that keeps its filesystem alive drops.
+    let FileStruct { ctx, path, file, .. } = closed;
+    drop(file);
+    (*ctx).release_path(&path);
+    match flushed {
+        Ok(_) => 0,
+        Err(_) if had_unwritten => {
+            (*r).errno = ErrnoMapper::errno(NX_FATDRIVE_ERR_DATA_LOST);
+            -1
+        }
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            -1
+        }
+    }
 }
 unsafe extern "C" fn _fatdrive_stat_r(r: *mut _reent, path_ptr: * const u8, st: *mut stat ) -> i32 {
-    let path : &str = match CStr::from_ptr(path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            (*r).errno =  NX_FATDRIVE_ERR_UNKNOWN as i32;
+    let mut ctx = match NewlibContext::for_path(path_ptr) {
+        Ok(c) => c,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
             return -1;
         }
     };
 
-    let mut ctx = match NewlibContext::get_global() {
-        Ok(c) => c,
-        Err(e) => {
-            (*r).errno = e as i32;
+    let path = match read_path(r, ctx, path_ptr) {
+        Some(p) => p,
+        None => {
             return -1;
         }
     };
 
-    let mut fs = match &mut ctx.client_state {
-        ClientState::Opened {ref mut fs, ..} => fs, 
-        _ => {
-            (*r).errno = NX_FATDRIVE_ERR_NOT_INITIALIZED as i32;
+    let utc_offset = ctx.utc_offset;
+    let mut fs = match ctx.filesystem() {
+        Ok(f) => f,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
             return -1;
         }
     };
-    let ent = path_to_dirent(fs, path);
-    (*st) = stat_dirent(&ent);
-    return 0;
+    match path_to_dirent(fs, &path) {
+        Ok(ent) => {
+            (*st) = stat_dirent(&ent, utc_offset);
+            0
+        },
+        Err(e) => {
+            (*r).errno = e;
+            -1
+        }
+    }
 }
 
 unsafe extern "C" fn _fatdrive_lstat_r(r: *mut _reent, path_str: * const u8, st: *mut stat ) -> i32 {
@@ -705,54 +1109,314 @@ unsafe extern "C" fn _fatdrive_lstat_r(r: *mut _reent, path_str: * const u8, st:
 }
 
 unsafe extern "C" fn _fatdrive_chdir_r(r: *mut _reent, name: * const u8) -> i32 {
-    //TODO: This
-    (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
-    return -1;
+    let mut ctx = match NewlibContext::for_path(name) {
+        Ok(c) => c,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+
+    let path = match read_path(r, ctx, name) {
+        Some(p) => p,
+        None => {
+            return -1;
+        }
+    };
+
+    let is_dir = {
+        let fs = match ctx.filesystem() {
+            Ok(f) => f,
+            Err(e) => {
+                (*r).errno = ErrnoMapper::errno(e);
+                return -1;
+            }
+        };
+        let mut root = match fs.root() {
+            Ok(d) => d,
+            Err(e) => {
+                (*r).errno = ErrnoMapper::errno(e);
+                return -1;
+            }
+        };
+        // Split files are directories on disk, but files to everyone using this device.
+        path::is_root(&path) || (root.open_directory(&path).is_ok() && !split::is_split_directory(&mut root, &path))
+    };
+    if !is_dir {
+        let (parent, name) = path::split_parent(&path);
+        let exists = ctx.filesystem().ok()
+            .and_then(|fs| fs.root().ok())
+            .and_then(|mut root| split::list_directory(&mut root, parent).ok())
+            .map_or(false, |children| children.iter().any(|ent| ent.name == name));
+        (*r).errno = if exists { errno::NX_FATDRIVE_ERRNO_ENOTDIR } else { errno::NX_FATDRIVE_ERRNO_ENOENT };
+        return -1;
+    }
+    ctx.current_working_directory = Some(path);
+    0
 }
 unsafe extern "C" fn _fatdrive_utimes_r(r: *mut _reent, filename: * const u8, times: *const timeval) -> i32 {
     //TODO: This
     (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
     return -1;
 }
+/// `ST_RDONLY` from newlib's `sys/statvfs.h`.
+const ST_RDONLY : u64 = 0x1;
+
 unsafe extern "C" fn _fatdrive_stat_vfs_r( r: *mut _reent, path: * const u8, buf: *mut statvfs) -> i32 {
-    //TODO: This
-    (*r).errno = errno::NX_FATDRIVE_ERRNO_ENOSYS;
-    return -1;
+    let mut ctx = match NewlibContext::for_path(path) {
+        Ok(c) => c,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    let out = match buf.as_mut() {
+        Some(b) => b,
+        None => {
+            (*r).errno = errno::NX_FATDRIVE_ERRNO_EFAULT;
+            return -1;
+        }
+    };
+    let fsid = ctx.partition_in_use.logical_block_address as u64;
+    let stats = match ctx.stats() {
+        Ok(s) => s,
+        Err(e) => {
+            (*r).errno = ErrnoMapper::errno(e);
+            return -1;
+        }
+    };
+    out.f_bsize = stats.cluster_size as usize;
+    out.f_frsize = stats.cluster_size as usize;
+    out.f_blocks = stats.total_clusters;
+    out.f_bfree = stats.free_clusters;
+    out.f_bavail = stats.free_clusters;
+    // FAT has no inode table, so there is no separate limit on the number of files.
+    out.f_files = 0;
+    out.f_ffree = 0;
+    out.f_favail = 0;
+    out.f_fsid = fsid;
+    out.f_flag = if ctx.read_only { ST_RDONLY } else { 0 };
+    out.f_namemax = path::MAX_COMPONENT_LEN;
+    0
 }
 
-const device_name : &[u8] = b"usbfs";
-
 const dir_state_size : usize = mem::size_of::<DirStruct>();
 const file_struct_size : usize = mem::size_of::<FileStruct>();
 
+/// Mounts every FAT partition on every attached USB drive as `usbfs0:`, `usbfs1:`, ...
 #[no_mangle]
-unsafe extern "C" fn nxFatdriveMount() -> u32 {
-    let mut ctx = Box::new(NewlibContext::new());
-    err_wrap!(ctx.init_usb_hs_ctx());
-    err_wrap!(ctx.wait_for_usb_drive(0x800000));
-    err_wrap!(ctx.open_partition(0));
-    let mut device = Box::new(nxfatdrive_devoptab());
-    device.deviceData = Box::into_raw(ctx) as *mut c_void;
-    let add_err = AddDevice(Box::into_raw(device));
-    add_err as u32
+pub unsafe extern "C" fn nxFatdriveMount() -> u32 {
+    nxFatdriveMountWithOptions(ptr::null())
 }
 
+/// Like `nxFatdriveMount`, but names the devices `{prefix}0:`, `{prefix}1:`, ...
+/// A null `prefix` uses `usbfs`. Does nothing if devices are already mounted.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveMountWithPrefix(prefix : *const u8) -> u32 {
+    let mut options : NxFatdriveMountOptions = mem::zeroed();
+    nxFatdriveDefaultMountOptions(&mut options);
+    options.device_prefix = prefix;
+    nxFatdriveMountWithOptions(&options)
+}
 
+/// Mounts the partitions `options` selects on every attached USB drive. A null
+/// `options` mounts the way `nxFatdriveMount` does. Does nothing if devices are
+/// already mounted.
 #[no_mangle]
-unsafe extern "C" fn nxFatdriveUnmount() -> u32 {
-    let device_ptr = GetDeviceOpTab(device_name as *const _ as *const u8);
-    let device = Box::from_raw(device_ptr);
-    let ctx = Box::from_raw(device.deviceData as *mut NewlibContext);
-    let rem_err = RemoveDevice(device_name as *const _ as *const u8);
+pub unsafe extern "C" fn nxFatdriveMountWithOptions(options : *const NxFatdriveMountOptions) -> u32 {
+    let mut defaults : NxFatdriveMountOptions = mem::zeroed();
+    nxFatdriveDefaultMountOptions(&mut defaults);
+    let options = err_wrap!(MountOptions::from_raw(options.as_ref().unwrap_or(&defaults)));
+    if !err_wrap!(device_names()).is_empty() {
+        return SUCCESS;
+    }
+    err_wrap!(mount_all(&options));
+    SUCCESS
+}
+
+/// Registers a devoptab for `ctx`, named after its `device_name`, handing it
+/// ownership of the context. Returns whatever `AddDevice` does.
+unsafe fn add_device(ctx : NewlibContext) -> i32 {
+    let ctx = Box::into_raw(Box::new(ctx));
+    let mut device = Box::new(nxfatdrive_devoptab((*ctx).device_name.as_ptr() as *const u8));
+    device.deviceData = ctx as *mut c_void;
+    let device_ptr = Box::into_raw(device);
+    let add_err = AddDevice(device_ptr);
+    if add_err < 0 {
+        drop(Box::from_raw(device_ptr));
+        drop(Box::from_raw(ctx));
+        return add_err;
+    }
+    if let Ok(mut devices) = mounted_devices.lock() {
+        devices.push(device_ptr as usize);
+    }
+    add_err
+}
+
+/// Unregisters a device added by `add_device` and frees its context. Fails with
+/// `NX_FATDRIVE_ERR_BUSY`, leaving the device mounted, while any file or directory
+/// handle is open on it, since those handles use the context and its filesystem.
+unsafe fn remove_device(device_ptr : *mut devoptab_t) -> u32 {
+    let name = {
+        let ctx = err_wrap!(NewlibContext::from_devoptab(device_ptr));
+        if ctx.has_open_handles() {
+            return NX_FATDRIVE_ERR_BUSY;
+        }
+        // newlib looks devices up by the part of the path before the colon.
+        err_wrap!(CString::new(format!("{}:", ctx.device_name.to_string_lossy())).map_err(|_| NX_FATDRIVE_ERR_UNKNOWN))
+    };
+    let rem_err = RemoveDevice(name.as_ptr() as *const u8);
     if rem_err != 0 {
         return rem_err as u32;
     }
-    0
+    err_wrap!(mounted_devices.lock()).retain(|addr| *addr != device_ptr as usize);
+    let device = Box::from_raw(device_ptr);
+    let ctx = Box::from_raw(device.deviceData as *mut NewlibContext);
+    let usb_device = ctx.drive.as_ref().map(|drive| drive.device.clone());
+    drop(ctx);
+    if usb_device.map_or(false, |dev| dev.unwritten_bytes() > 0) {
+        // The drive went away before the filesystem's cache could reach it.
+        return NX_FATDRIVE_ERR_DATA_LOST;
+    }
+    SUCCESS
 }
 
-pub fn nxfatdrive_devoptab() -> devoptab_t {
+/// Every registered devoptab, in the order they were mounted.
+fn registered_devices() -> Result<Vec<*mut devoptab_t>, u32> {
+    let devices = mounted_devices.lock().map_err(LibnxErrMapper::map)?;
+    Ok(devices.iter().map(|addr| *addr as *mut devoptab_t).collect())
+}
+
+/// The number of devices currently mounted.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveDeviceCount() -> usize {
+    device_names().map_or(0, |names| names.len())
+}
+
+/// Copies the name of the `idx`th mounted device, without the trailing colon, into
+/// `buf` as a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveGetDeviceName(idx : usize, buf : *mut u8, size : usize) -> u32 {
+    let names = err_wrap!(device_names());
+    let name = match names.get(idx) {
+        Some(n) => n,
+        None => {
+            return NX_FATDRIVE_ERR_DRIVE_NOT_FOUND;
+        }
+    };
+    if buf.is_null() || name.len() + 1 > size {
+        return LibnxErrMapper::map(io::Error::from(ErrorKind::InvalidInput));
+    }
+    let out = slice::from_raw_parts_mut(buf, name.len() + 1);
+    out[.. name.len()].copy_from_slice(name.as_bytes());
+    out[name.len()] = 0;
+    SUCCESS
+}
+
+/// Copies the working directory into `buf` the way `getcwd` would, including the
+/// device prefix. Uses newlib's default device if it is one of ours, and the first
+/// mounted device otherwise. Returns `buf`, or null if nothing is mounted or the
+/// buffer cannot hold the path and its terminator.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveGetCwd(buf : *mut u8, size : usize) -> *mut u8 {
+    let ctx = match NewlibContext::for_path(ptr::null()) {
+        Ok(c) => c,
+        Err(_) => match registered_devices().ok().and_then(|devices| devices.first().cloned()) {
+            Some(device_ptr) => match NewlibContext::from_devoptab(device_ptr) {
+                Ok(c) => c,
+                Err(_) => {
+                    return ptr::null_mut();
+                }
+            },
+            None => {
+                return ptr::null_mut();
+            }
+        }
+    };
+    if buf.is_null() {
+        return ptr::null_mut();
+    }
+    let cwd = format!("{}:{}", ctx.device_name.to_string_lossy(), ctx.current_dir());
+    let cwd_bytes = cwd.as_bytes();
+    if cwd_bytes.len() + 1 > size {
+        return ptr::null_mut();
+    }
+    let out = slice::from_raw_parts_mut(buf, cwd_bytes.len() + 1);
+    out[.. cwd_bytes.len()].copy_from_slice(cwd_bytes);
+    out[cwd_bytes.len()] = 0;
+    buf
+}
+
+/// Sets how many seconds the drives' FAT timestamps are ahead of UTC on every
+/// mounted device, since FAT stores local time with no zone. Defaults to 0,
+/// treating them as UTC.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveSetUtcOffset(seconds : i32) -> u32 {
+    let devices = err_wrap!(registered_devices());
+    if devices.is_empty() {
+        return NX_FATDRIVE_ERR_NOT_INITIALIZED;
+    }
+    for device_ptr in devices {
+        let ctx = err_wrap!(NewlibContext::from_devoptab(device_ptr));
+        ctx.utc_offset = seconds;
+    }
+    SUCCESS
+}
+
+/// Unmounts every device mounted by `nxFatdriveMount`. Devices with files or
+/// directories still open on them stay mounted and `NX_FATDRIVE_ERR_BUSY` is returned.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveUnmount() -> u32 {
+    let mut retval = SUCCESS;
+    for device_ptr in err_wrap!(registered_devices()) {
+        let err = remove_device(device_ptr);
+        if err != SUCCESS {
+            retval = err;
+        }
+    }
+    retval
+}
+
+/// Unmounts the single device called `name`, with or without its trailing colon.
+/// Returns `NX_FATDRIVE_ERR_BUSY` without unmounting it while files or directories
+/// are open on it.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveUnmountDevice(name : *const u8) -> u32 {
+    let name = err_wrap!(path::c_path_str(name));
+    let name = name.trim_end_matches(':');
+    for device_ptr in err_wrap!(registered_devices()) {
+        let ctx = err_wrap!(NewlibContext::from_devoptab(device_ptr));
+        if ctx.device_name.to_string_lossy() == name {
+            return remove_device(device_ptr);
+        }
+    }
+    NX_FATDRIVE_ERR_DRIVE_NOT_FOUND
+}
+
+/// Unmounts every device whose drive has been unplugged. Their callbacks already
+/// fail with `ENODEV`; this frees them and their names for the next mount.
+/// Returns `NX_FATDRIVE_ERR_DATA_LOST` if any of them still had writes cached, and
+/// `NX_FATDRIVE_ERR_BUSY` if any are left mounted because their handles are still
+/// open; closing those handles fails on an unplugged drive but still releases them.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveUnmountDisconnected() -> u32 {
+    let mut retval = SUCCESS;
+    for device_ptr in err_wrap!(registered_devices()) {
+        let ctx = err_wrap!(NewlibContext::from_devoptab(device_ptr));
+        if ctx.is_connected() {
+            continue;
+        }
+        let err = remove_device(device_ptr);
+        if err != SUCCESS {
+            retval = err;
+        }
+    }
+    retval
+}
+
+pub fn nxfatdrive_devoptab(name : *const u8) -> devoptab_t {
     devoptab_t {
-        name: device_name as *const _ as *const u8,
+        name,
         structSize: file_struct_size,
         open_r: Some(_fatdrive_open_r),
         close_r: Some(_fatdrive_close_r),
@@ -783,130 +1447,1007 @@ pub fn nxfatdrive_devoptab() -> devoptab_t {
     }
 }
 mod errno {
-    pub const NX_FATDRIVE_ERRNO_EPERM :i32 = 1;   /* Not owner */
-    pub const NX_FATDRIVE_ERRNO_ENOENT :i32 = 2;   /* No such file or directory */
-    pub const NX_FATDRIVE_ERRNO_ESRCH :i32 = 3;   /* No such process */
-    pub const NX_FATDRIVE_ERRNO_EINTR :i32 = 4;   /* Interrupted system call */
-    pub const NX_FATDRIVE_ERRNO_EIO :i32 = 5;   /* I/O error */
-    pub const NX_FATDRIVE_ERRNO_ENXIO :i32 = 6;   /* No such device or address */
-    pub const NX_FATDRIVE_ERRNO_E2BIG :i32 = 7;   /* Arg list too long */
-    pub const NX_FATDRIVE_ERRNO_ENOEXEC :i32 = 8;   /* Exec format error */
-    pub const NX_FATDRIVE_ERRNO_EBADF :i32 = 9;   /* Bad file number */
-    pub const NX_FATDRIVE_ERRNO_ECHILD :i32 = 10;   /* No children */
-    pub const NX_FATDRIVE_ERRNO_EAGAIN :i32 = 11;   /* No more processes */
-    pub const NX_FATDRIVE_ERRNO_ENOMEM :i32 = 12;   /* Not enough space */
-    pub const NX_FATDRIVE_ERRNO_EACCES :i32 = 13;   /* Permission denied */
-    pub const NX_FATDRIVE_ERRNO_EFAULT :i32 = 14;   /* Bad address */
-    pub const NX_FATDRIVE_ERRNO_ENOTBLK :i32 = 15;   /* Block device required */
-    pub const NX_FATDRIVE_ERRNO_EBUSY :i32 = 16;   /* Device or resource busy */
-    pub const NX_FATDRIVE_ERRNO_EEXIST :i32 = 17;   /* File exists */
-    pub const NX_FATDRIVE_ERRNO_EXDEV :i32 = 18;   /* Cross-device link */
-    pub const NX_FATDRIVE_ERRNO_ENODEV :i32 = 19;   /* No such device */
-    pub const NX_FATDRIVE_ERRNO_ENOTDIR :i32 = 20;   /* Not a directory */
-    pub const NX_FATDRIVE_ERRNO_EISDIR :i32 = 21;   /* Is a directory */
-    pub const NX_FATDRIVE_ERRNO_EINVAL :i32 = 22;   /* Invalid argument */
-    pub const NX_FATDRIVE_ERRNO_ENFILE :i32 = 23;   /* Too many open files in system */
-    pub const NX_FATDRIVE_ERRNO_EMFILE :i32 = 24;   /* File descriptor value too large */
-    pub const NX_FATDRIVE_ERRNO_ENOTTY :i32 = 25;   /* Not a character device */
-    pub const NX_FATDRIVE_ERRNO_ETXTBSY :i32 = 26;   /* Text file busy */
-    pub const NX_FATDRIVE_ERRNO_EFBIG :i32 = 27;   /* File too large */
-    pub const NX_FATDRIVE_ERRNO_ENOSPC :i32 = 28;   /* No space left on device */
-    pub const NX_FATDRIVE_ERRNO_ESPIPE :i32 = 29;   /* Illegal seek */
-    pub const NX_FATDRIVE_ERRNO_EROFS :i32 = 30;   /* Read-only file system */
-    pub const NX_FATDRIVE_ERRNO_EMLINK :i32 = 31;   /* Too many links */
-    pub const NX_FATDRIVE_ERRNO_EPIPE :i32 = 32;   /* Broken pipe */
-    pub const NX_FATDRIVE_ERRNO_EDOM :i32 = 33;   /* Mathematics argument out of domain of function */
-    pub const NX_FATDRIVE_ERRNO_ERANGE :i32 = 34;   /* Result too large */
-    pub const NX_FATDRIVE_ERRNO_ENOMSG :i32 = 35;   /* No message of desired type */
-    pub const NX_FATDRIVE_ERRNO_EIDRM :i32 = 36;   /* Identifier removed */
-    pub const NX_FATDRIVE_ERRNO_ECHRNG :i32 = 37;   /* Channel number out of range */
-    pub const NX_FATDRIVE_ERRNO_EL2NSYNC :i32 = 38;   /* Level 2 not synchronized */
-    pub const NX_FATDRIVE_ERRNO_EL3HLT :i32 = 39;   /* Level 3 halted */
-    pub const NX_FATDRIVE_ERRNO_EL3RST :i32 = 40;   /* Level 3 reset */
-    pub const NX_FATDRIVE_ERRNO_ELNRNG :i32 = 41;   /* Link number out of range */
-    pub const NX_FATDRIVE_ERRNO_EUNATCH :i32 = 42;   /* Protocol driver not attached */
-    pub const NX_FATDRIVE_ERRNO_ENOCSI :i32 = 43;   /* No CSI structure available */
-    pub const NX_FATDRIVE_ERRNO_EL2HLT :i32 = 44;   /* Level 2 halted */
-    pub const NX_FATDRIVE_ERRNO_EDEADLK :i32 = 45;   /* Deadlock */
-    pub const NX_FATDRIVE_ERRNO_ENOLCK :i32 = 46;   /* No lock */
-    pub const NX_FATDRIVE_ERRNO_EBADE :i32 = 50;   /* Invalid exchange */
-    pub const NX_FATDRIVE_ERRNO_EBADR :i32 = 51;   /* Invalid request descriptor */
-    pub const NX_FATDRIVE_ERRNO_EXFULL :i32 = 52;   /* Exchange full */
-    pub const NX_FATDRIVE_ERRNO_ENOANO :i32 = 53;   /* No anode */
-    pub const NX_FATDRIVE_ERRNO_EBADRQC :i32 = 54;   /* Invalid request code */
-    pub const NX_FATDRIVE_ERRNO_EBADSLT :i32 = 55;   /* Invalid slot */
-    pub const NX_FATDRIVE_ERRNO_EDEADLOCK :i32 = 56;   /* File locking deadlock error */
-    pub const NX_FATDRIVE_ERRNO_EBFONT :i32 = 57;   /* Bad font file fmt */
-    pub const NX_FATDRIVE_ERRNO_ENOSTR :i32 = 60;   /* Not a stream */
-    pub const NX_FATDRIVE_ERRNO_ENODATA :i32 = 61;   /* No data (for no delay io) */
-    pub const NX_FATDRIVE_ERRNO_ETIME :i32 = 62;   /* Stream ioctl timeout */
-    pub const NX_FATDRIVE_ERRNO_ENOSR :i32 = 63;   /* No stream resources */
-    pub const NX_FATDRIVE_ERRNO_ENONET :i32 = 64;   /* Machine is not on the network */
-    pub const NX_FATDRIVE_ERRNO_ENOPKG :i32 = 65;   /* Package not installed */
-    pub const NX_FATDRIVE_ERRNO_EREMOTE :i32 = 66;   /* The object is remote */
-    pub const NX_FATDRIVE_ERRNO_ENOLINK :i32 = 67;   /* Virtual circuit is gone */
-    pub const NX_FATDRIVE_ERRNO_EADV :i32 = 68;   /* Advertise error */
-    pub const NX_FATDRIVE_ERRNO_ESRMNT :i32 = 69;   /* Srmount error */
-    pub const NX_FATDRIVE_ERRNO_ECOMM :i32 = 70;   /* Communication error on send */
-    pub const NX_FATDRIVE_ERRNO_EPROTO :i32 = 71;   /* Protocol error */
-    pub const NX_FATDRIVE_ERRNO_EMULTIHOP :i32 = 74;   /* Multihop attempted */
-    pub const NX_FATDRIVE_ERRNO_ELBIN :i32 = 75;   /* Inode is remote (not really error) */
-    pub const NX_FATDRIVE_ERRNO_EDOTDOT :i32 = 76;   /* Cross mount point (not really error) */
-    pub const NX_FATDRIVE_ERRNO_EBADMSG :i32 = 77;   /* Bad message */
-    pub const NX_FATDRIVE_ERRNO_EFTYPE :i32 = 79;   /* Inappropriate file type or format */
-    pub const NX_FATDRIVE_ERRNO_ENOTUNIQ :i32 = 80;   /* Given log. name not unique */
-    pub const NX_FATDRIVE_ERRNO_EBADFD :i32 = 81;   /* f.d. invalid for this operation */
-    pub const NX_FATDRIVE_ERRNO_EREMCHG :i32 = 82;   /* Remote address changed */
-    pub const NX_FATDRIVE_ERRNO_ELIBACC :i32 = 83;   /* Can't access a needed shared lib */
-    pub const NX_FATDRIVE_ERRNO_ELIBBAD :i32 = 84;   /* Accessing a corrupted shared lib */
-    pub const NX_FATDRIVE_ERRNO_ELIBSCN :i32 = 85;   /* .lib section in a.out corrupted */
-    pub const NX_FATDRIVE_ERRNO_ELIBMAX :i32 = 86;   /* Attempting to link in too many libs */
-    pub const NX_FATDRIVE_ERRNO_ELIBEXEC :i32 = 87;   /* Attempting to exec a shared library */
-    pub const NX_FATDRIVE_ERRNO_ENOSYS :i32 = 88;   /* Function not implemented */
-    pub const NX_FATDRIVE_ERRNO_ENMFILE :i32 = 89;   /* No more files */
-    pub const NX_FATDRIVE_ERRNO_ENOTEMPTY :i32 = 90;   /* Directory not empty */
-    pub const NX_FATDRIVE_ERRNO_ENAMETOOLONG :i32 = 91;   /* File or path name too long */
-    pub const NX_FATDRIVE_ERRNO_ELOOP :i32 = 92;   /* Too many symbolic links */
-    pub const NX_FATDRIVE_ERRNO_EOPNOTSUPP :i32 = 95;   /* Operation not supported on socket */
-    pub const NX_FATDRIVE_ERRNO_EPFNOSUPPORT :i32 = 96;   /* Protocol family not supported */
-    pub const NX_FATDRIVE_ERRNO_ECONNRESET :i32 = 104;   /* Connection reset by peer */
-    pub const NX_FATDRIVE_ERRNO_ENOBUFS :i32 = 105;   /* No buffer space available */
-    pub const NX_FATDRIVE_ERRNO_EAFNOSUPPORT :i32 = 106;   /* Address family not supported by protocol family */
-    pub const NX_FATDRIVE_ERRNO_EPROTOTYPE :i32 = 107;   /* Protocol wrong type for socket */
-    pub const NX_FATDRIVE_ERRNO_ENOTSOCK :i32 = 108;   /* Socket operation on non-socket */
-    pub const NX_FATDRIVE_ERRNO_ENOPROTOOPT :i32 = 109;   /* Protocol not available */
-    pub const NX_FATDRIVE_ERRNO_ESHUTDOWN :i32 = 110;   /* Can't send after socket shutdown */
-    pub const NX_FATDRIVE_ERRNO_ECONNREFUSED :i32 = 111;   /* Connection refused */
-    pub const NX_FATDRIVE_ERRNO_EADDRINUSE :i32 = 112;   /* Address already in use */
-    pub const NX_FATDRIVE_ERRNO_ECONNABORTED :i32 = 113;   /* Software caused connection abort */
-    pub const NX_FATDRIVE_ERRNO_ENETUNREACH :i32 = 114;   /* Network is unreachable */
-    pub const NX_FATDRIVE_ERRNO_ENETDOWN :i32 = 115;   /* Network interface is not configured */
-    pub const NX_FATDRIVE_ERRNO_ETIMEDOUT :i32 = 116;   /* Connection timed out */
-    pub const NX_FATDRIVE_ERRNO_EHOSTDOWN :i32 = 117;   /* Host is down */
-    pub const NX_FATDRIVE_ERRNO_EHOSTUNREACH :i32 = 118;   /* Host is unreachable */
-    pub const NX_FATDRIVE_ERRNO_EINPROGRESS :i32 = 119;   /* Connection already in progress */
-    pub const NX_FATDRIVE_ERRNO_EALREADY :i32 = 120;   /* Socket already connected */
-    pub const NX_FATDRIVE_ERRNO_EDESTADDRREQ :i32 = 121;   /* Destination address required */
-    pub const NX_FATDRIVE_ERRNO_EMSGSIZE :i32 = 122;   /* Message too long */
-    pub const NX_FATDRIVE_ERRNO_EPROTONOSUPPORT :i32 = 123;   /* Unknown protocol */
-    pub const NX_FATDRIVE_ERRNO_ESOCKTNOSUPPORT :i32 = 124;   /* Socket type not supported */
-    pub const NX_FATDRIVE_ERRNO_EADDRNOTAVAIL :i32 = 125;   /* Address not available */
-    pub const NX_FATDRIVE_ERRNO_ENETRESET :i32 = 126;   /* Connection aborted by network */
-    pub const NX_FATDRIVE_ERRNO_EISCONN :i32 = 127;   /* Socket is already connected */
-    pub const NX_FATDRIVE_ERRNO_ENOTCONN :i32 = 128;   /* Socket is not connected */
-    pub const NX_FATDRIVE_ERRNO_ETOOMANYREFS :i32 = 129;
-    pub const NX_FATDRIVE_ERRNO_EPROCLIM :i32 = 130;
-    pub const NX_FATDRIVE_ERRNO_EUSERS :i32 = 131;
-    pub const NX_FATDRIVE_ERRNO_EDQUOT :i32 = 132;
-    pub const NX_FATDRIVE_ERRNO_ESTALE :i32 = 133;
-    pub const NX_FATDRIVE_ERRNO_ENOTSUP :i32 = 134;   /* Not supported */
-    pub const NX_FATDRIVE_ERRNO_ENOMEDIUM :i32 = 135;   /* No medium (in tape drive) */
-    pub const NX_FATDRIVE_ERRNO_ENOSHARE :i32 = 136;   /* No such host or network path */
-    pub const NX_FATDRIVE_ERRNO_ECASECLASH :i32 = 137;   /* Filename exists with different case */
-    pub const NX_FATDRIVE_ERRNO_EILSEQ :i32 = 138;   /* Illegal byte sequence */
-    pub const NX_FATDRIVE_ERRNO_EOVERFLOW :i32 = 139;   /* Value too large for defined data type */
-    pub const NX_FATDRIVE_ERRNO_ECANCELED :i32 = 140;   /* Operation canceled */
-    pub const NX_FATDRIVE_ERRNO_ENOTRECOVERABLE :i32 = 141;   /* State not recoverable */
-    pub const NX_FATDRIVE_ERRNO_EOWNERDEAD :i32 = 142;   /* Previous owner died */
-    pub const NX_FATDRIVE_ERRNO_ESTRPIPE :i32 = 143;   /* Streams pipe error */
-}
\ No newline at end of file
+    pub(super) const NX_FATDRIVE_ERRNO_EPERM :i32 = 1;   /* Not owner */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOENT :i32 = 2;   /* No such file or directory */
+    pub(super) const NX_FATDRIVE_ERRNO_ESRCH :i32 = 3;   /* No such process */
+    pub(super) const NX_FATDRIVE_ERRNO_EINTR :i32 = 4;   /* Interrupted system call */
+    pub(super) const NX_FATDRIVE_ERRNO_EIO :i32 = 5;   /* I/O error */
+    pub(super) const NX_FATDRIVE_ERRNO_ENXIO :i32 = 6;   /* No such device or address */
+    pub(super) const NX_FATDRIVE_ERRNO_E2BIG :i32 = 7;   /* Arg list too long */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOEXEC :i32 = 8;   /* Exec format error */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADF :i32 = 9;   /* Bad file number */
+    pub(super) const NX_FATDRIVE_ERRNO_ECHILD :i32 = 10;   /* No children */
+    pub(super) const NX_FATDRIVE_ERRNO_EAGAIN :i32 = 11;   /* No more processes */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOMEM :i32 = 12;   /* Not enough space */
+    pub(super) const NX_FATDRIVE_ERRNO_EACCES :i32 = 13;   /* Permission denied */
+    pub(super) const NX_FATDRIVE_ERRNO_EFAULT :i32 = 14;   /* Bad address */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTBLK :i32 = 15;   /* Block device required */
+    pub(super) const NX_FATDRIVE_ERRNO_EBUSY :i32 = 16;   /* Device or resource busy */
+    pub(super) const NX_FATDRIVE_ERRNO_EEXIST :i32 = 17;   /* File exists */
+    pub(super) const NX_FATDRIVE_ERRNO_EXDEV :i32 = 18;   /* Cross-device link */
+    pub(super) const NX_FATDRIVE_ERRNO_ENODEV :i32 = 19;   /* No such device */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTDIR :i32 = 20;   /* Not a directory */
+    pub(super) const NX_FATDRIVE_ERRNO_EISDIR :i32 = 21;   /* Is a directory */
+    pub(super) const NX_FATDRIVE_ERRNO_EINVAL :i32 = 22;   /* Invalid argument */
+    pub(super) const NX_FATDRIVE_ERRNO_ENFILE :i32 = 23;   /* Too many open files in system */
+    pub(super) const NX_FATDRIVE_ERRNO_EMFILE :i32 = 24;   /* File descriptor value too large */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTTY :i32 = 25;   /* Not a character device */
+    pub(super) const NX_FATDRIVE_ERRNO_ETXTBSY :i32 = 26;   /* Text file busy */
+    pub(super) const NX_FATDRIVE_ERRNO_EFBIG :i32 = 27;   /* File too large */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOSPC :i32 = 28;   /* No space left on device */
+    pub(super) const NX_FATDRIVE_ERRNO_ESPIPE :i32 = 29;   /* Illegal seek */
+    pub(super) const NX_FATDRIVE_ERRNO_EROFS :i32 = 30;   /* Read-only file system */
+    pub(super) const NX_FATDRIVE_ERRNO_EMLINK :i32 = 31;   /* Too many links */
+    pub(super) const NX_FATDRIVE_ERRNO_EPIPE :i32 = 32;   /* Broken pipe */
+    pub(super) const NX_FATDRIVE_ERRNO_EDOM :i32 = 33;   /* Mathematics argument out of domain of function */
+    pub(super) const NX_FATDRIVE_ERRNO_ERANGE :i32 = 34;   /* Result too large */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOMSG :i32 = 35;   /* No message of desired type */
+    pub(super) const NX_FATDRIVE_ERRNO_EIDRM :i32 = 36;   /* Identifier removed */
+    pub(super) const NX_FATDRIVE_ERRNO_ECHRNG :i32 = 37;   /* Channel number out of range */
+    pub(super) const NX_FATDRIVE_ERRNO_EL2NSYNC :i32 = 38;   /* Level 2 not synchronized */
+    pub(super) const NX_FATDRIVE_ERRNO_EL3HLT :i32 = 39;   /* Level 3 halted */
+    pub(super) const NX_FATDRIVE_ERRNO_EL3RST :i32 = 40;   /* Level 3 reset */
+    pub(super) const NX_FATDRIVE_ERRNO_ELNRNG :i32 = 41;   /* Link number out of range */
+    pub(super) const NX_FATDRIVE_ERRNO_EUNATCH :i32 = 42;   /* Protocol driver not attached */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOCSI :i32 = 43;   /* No CSI structure available */
+    pub(super) const NX_FATDRIVE_ERRNO_EL2HLT :i32 = 44;   /* Level 2 halted */
+    pub(super) const NX_FATDRIVE_ERRNO_EDEADLK :i32 = 45;   /* Deadlock */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOLCK :i32 = 46;   /* No lock */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADE :i32 = 50;   /* Invalid exchange */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADR :i32 = 51;   /* Invalid request descriptor */
+    pub(super) const NX_FATDRIVE_ERRNO_EXFULL :i32 = 52;   /* Exchange full */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOANO :i32 = 53;   /* No anode */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADRQC :i32 = 54;   /* Invalid request code */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADSLT :i32 = 55;   /* Invalid slot */
+    pub(super) const NX_FATDRIVE_ERRNO_EDEADLOCK :i32 = 56;   /* File locking deadlock error */
+    pub(super) const NX_FATDRIVE_ERRNO_EBFONT :i32 = 57;   /* Bad font file fmt */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOSTR :i32 = 60;   /* Not a stream */
+    pub(super) const NX_FATDRIVE_ERRNO_ENODATA :i32 = 61;   /* No data (for no delay io) */
+    pub(super) const NX_FATDRIVE_ERRNO_ETIME :i32 = 62;   /* Stream ioctl timeout */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOSR :i32 = 63;   /* No stream resources */
+    pub(super) const NX_FATDRIVE_ERRNO_ENONET :i32 = 64;   /* Machine is not on the network */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOPKG :i32 = 65;   /* Package not installed */
+    pub(super) const NX_FATDRIVE_ERRNO_EREMOTE :i32 = 66;   /* The object is remote */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOLINK :i32 = 67;   /* Virtual circuit is gone */
+    pub(super) const NX_FATDRIVE_ERRNO_EADV :i32 = 68;   /* Advertise error */
+    pub(super) const NX_FATDRIVE_ERRNO_ESRMNT :i32 = 69;   /* Srmount error */
+    pub(super) const NX_FATDRIVE_ERRNO_ECOMM :i32 = 70;   /* Communication error on send */
+    pub(super) const NX_FATDRIVE_ERRNO_EPROTO :i32 = 71;   /* Protocol error */
+    pub(super) const NX_FATDRIVE_ERRNO_EMULTIHOP :i32 = 74;   /* Multihop attempted */
+    pub(super) const NX_FATDRIVE_ERRNO_ELBIN :i32 = 75;   /* Inode is remote (not really error) */
+    pub(super) const NX_FATDRIVE_ERRNO_EDOTDOT :i32 = 76;   /* Cross mount point (not really error) */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADMSG :i32 = 77;   /* Bad message */
+    pub(super) const NX_FATDRIVE_ERRNO_EFTYPE :i32 = 79;   /* Inappropriate file type or format */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTUNIQ :i32 = 80;   /* Given log. name not unique */
+    pub(super) const NX_FATDRIVE_ERRNO_EBADFD :i32 = 81;   /* f.d. invalid for this operation */
+    pub(super) const NX_FATDRIVE_ERRNO_EREMCHG :i32 = 82;   /* Remote address changed */
+    pub(super) const NX_FATDRIVE_ERRNO_ELIBACC :i32 = 83;   /* Can't access a needed shared lib */
+    pub(super) const NX_FATDRIVE_ERRNO_ELIBBAD :i32 = 84;   /* Accessing a corrupted shared lib */
+    pub(super) const NX_FATDRIVE_ERRNO_ELIBSCN :i32 = 85;   /* .lib section in a.out corrupted */
+    pub(super) const NX_FATDRIVE_ERRNO_ELIBMAX :i32 = 86;   /* Attempting to link in too many libs */
+    pub(super) const NX_FATDRIVE_ERRNO_ELIBEXEC :i32 = 87;   /* Attempting to exec a shared library */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOSYS :i32 = 88;   /* Function not implemented */
+    pub(super) const NX_FATDRIVE_ERRNO_ENMFILE :i32 = 89;   /* No more files */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTEMPTY :i32 = 90;   /* Directory not empty */
+    pub(super) const NX_FATDRIVE_ERRNO_ENAMETOOLONG :i32 = 91;   /* File or path name too long */
+    pub(super) const NX_FATDRIVE_ERRNO_ELOOP :i32 = 92;   /* Too many symbolic links */
+    pub(super) const NX_FATDRIVE_ERRNO_EOPNOTSUPP :i32 = 95;   /* Operation not supported on socket */
+    pub(super) const NX_FATDRIVE_ERRNO_EPFNOSUPPORT :i32 = 96;   /* Protocol family not supported */
+    pub(super) const NX_FATDRIVE_ERRNO_ECONNRESET :i32 = 104;   /* Connection reset by peer */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOBUFS :i32 = 105;   /* No buffer space available */
+    pub(super) const NX_FATDRIVE_ERRNO_EAFNOSUPPORT :i32 = 106;   /* Address family not supported by protocol family */
+    pub(super) const NX_FATDRIVE_ERRNO_EPROTOTYPE :i32 = 107;   /* Protocol wrong type for socket */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTSOCK :i32 = 108;   /* Socket operation on non-socket */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOPROTOOPT :i32 = 109;   /* Protocol not available */
+    pub(super) const NX_FATDRIVE_ERRNO_ESHUTDOWN :i32 = 110;   /* Can't send after socket shutdown */
+    pub(super) const NX_FATDRIVE_ERRNO_ECONNREFUSED :i32 = 111;   /* Connection refused */
+    pub(super) const NX_FATDRIVE_ERRNO_EADDRINUSE :i32 = 112;   /* Address already in use */
+    pub(super) const NX_FATDRIVE_ERRNO_ECONNABORTED :i32 = 113;   /* Software caused connection abort */
+    pub(super) const NX_FATDRIVE_ERRNO_ENETUNREACH :i32 = 114;   /* Network is unreachable */
+    pub(super) const NX_FATDRIVE_ERRNO_ENETDOWN :i32 = 115;   /* Network interface is not configured */
+    pub(super) const NX_FATDRIVE_ERRNO_ETIMEDOUT :i32 = 116;   /* Connection timed out */
+    pub(super) const NX_FATDRIVE_ERRNO_EHOSTDOWN :i32 = 117;   /* Host is down */
+    pub(super) const NX_FATDRIVE_ERRNO_EHOSTUNREACH :i32 = 118;   /* Host is unreachable */
+    pub(super) const NX_FATDRIVE_ERRNO_EINPROGRESS :i32 = 119;   /* Connection already in progress */
+    pub(super) const NX_FATDRIVE_ERRNO_EALREADY :i32 = 120;   /* Socket already connected */
+    pub(super) const NX_FATDRIVE_ERRNO_EDESTADDRREQ :i32 = 121;   /* Destination address required */
+    pub(super) const NX_FATDRIVE_ERRNO_EMSGSIZE :i32 = 122;   /* Message too long */
+    pub(super) const NX_FATDRIVE_ERRNO_EPROTONOSUPPORT :i32 = 123;   /* Unknown protocol */
+    pub(super) const NX_FATDRIVE_ERRNO_ESOCKTNOSUPPORT :i32 = 124;   /* Socket type not supported */
+    pub(super) const NX_FATDRIVE_ERRNO_EADDRNOTAVAIL :i32 = 125;   /* Address not available */
+    pub(super) const NX_FATDRIVE_ERRNO_ENETRESET :i32 = 126;   /* Connection aborted by network */
+    pub(super) const NX_FATDRIVE_ERRNO_EISCONN :i32 = 127;   /* Socket is already connected */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTCONN :i32 = 128;   /* Socket is not connected */
+    pub(super) const NX_FATDRIVE_ERRNO_ETOOMANYREFS :i32 = 129;
+    pub(super) const NX_FATDRIVE_ERRNO_EPROCLIM :i32 = 130;
+    pub(super) const NX_FATDRIVE_ERRNO_EUSERS :i32 = 131;
+    pub(super) const NX_FATDRIVE_ERRNO_EDQUOT :i32 = 132;
+    pub(super) const NX_FATDRIVE_ERRNO_ESTALE :i32 = 133;
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTSUP :i32 = 134;   /* Not supported */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOMEDIUM :i32 = 135;   /* No medium (in tape drive) */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOSHARE :i32 = 136;   /* No such host or network path */
+    pub(super) const NX_FATDRIVE_ERRNO_ECASECLASH :i32 = 137;   /* Filename exists with different case */
+    pub(super) const NX_FATDRIVE_ERRNO_EILSEQ :i32 = 138;   /* Illegal byte sequence */
+    pub(super) const NX_FATDRIVE_ERRNO_EOVERFLOW :i32 = 139;   /* Value too large for defined data type */
+    pub(super) const NX_FATDRIVE_ERRNO_ECANCELED :i32 = 140;   /* Operation canceled */
+    pub(super) const NX_FATDRIVE_ERRNO_ENOTRECOVERABLE :i32 = 141;   /* State not recoverable */
+    pub(super) const NX_FATDRIVE_ERRNO_EOWNERDEAD :i32 = 142;   /* Previous owner died */
+    pub(super) const NX_FATDRIVE_ERRNO_ESTRPIPE :i32 = 143;   /* Streams pipe error */
+}
+#[cfg(test)]
+mod tests {
+    //! Drives the devoptab callbacks the way newlib would, against a freshly formatted
+    //! FAT image registered as its own device.
+
+    use super::*;
+    use filesystem::{O_RDONLY, O_WRONLY, O_RDWR, O_APPEND, O_CREAT, O_TRUNC, O_EXCL, ATTR_ARCHIVE};
+    use image_device::ImageDevice;
+    use mbr_nostd::PartitionType;
+    use std::io::Cursor;
+
+    const IMAGE_SIZE : usize = 8 * 1024 * 1024;
+
+    lazy_static! {
+        /// Every test mounts the same device name, so they have to take turns.
+        static ref DEVICE_LOCK : Mutex<()> = Mutex::new(());
+    }
+
+    /// The device paths without a device prefix are sent to.
+    const TEST_DEVICE : &str = "imgfs0";
+
+    struct MountedImage {
+        _guard : MutexGuard<'static, ()>,
+    }
+
+    impl MountedImage {
+        fn new() -> MountedImage {
+            MountedImage::with_devices(&[TEST_DEVICE])
+        }
+
+        /// Mounts a freshly formatted image under each of `names`.
+        fn with_devices(names : &[&str]) -> MountedImage {
+            let guard = DEVICE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            for name in names {
+                let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+                fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+                let partition = PartitionTableEntry::new(PartitionType::Fat16(0x06), 0, (IMAGE_SIZE / 512) as u32);
+                let device = OffsetScsiDevice::new(ImageDevice::new(image), 0);
+                let fs = FileSystem::Fatfs(FileSystemOps::from_device(device, partition.clone()).unwrap());
+                let ctx = NewlibContext::new(CString::new(*name).unwrap(), fs, partition, None);
+                assert!(unsafe { add_device(ctx) } >= 0);
+            }
+            MountedImage { _guard : guard }
+        }
+    }
+
+    impl Drop for MountedImage {
+        fn drop(&mut self) {
+            assert_eq!(unsafe { nxFatdriveUnmount() }, SUCCESS);
+            assert_eq!(unsafe { nxFatdriveDeviceCount() }, 0);
+        }
+    }
+
+    /// Calls a callback with a fresh `_reent`, returning its result and `errno`.
+    fn call<T, F : FnOnce(*mut _reent) -> T>(f : F) -> (T, i32) {
+        let mut r : _reent = unsafe { mem::zeroed() };
+        let retval = f(&mut r);
+        (retval, r.errno)
+    }
+
+    /// Paths that do not name a device are put on `TEST_DEVICE`.
+    fn c_path(path : &str) -> CString {
+        if path.contains(':') {
+            CString::new(path).unwrap()
+        }
+        else {
+            CString::new(format!("{}:{}", TEST_DEVICE, path)).unwrap()
+        }
+    }
+
+    /// Zeroed storage for one handle, as newlib allocates it.
+    fn handle_storage(size : usize) -> Vec<u64> {
+        vec![0u64; (size + 7) / 8]
+    }
+
+    struct Fd {
+        storage : Vec<u64>,
+    }
+
+    impl Fd {
+        fn ptr(&mut self) -> *mut c_void {
+            self.storage.as_mut_ptr() as *mut c_void
+        }
+    }
+
+    fn open(path : &str, flags : u32) -> Result<Fd, i32> {
+        let mut fd = Fd { storage : handle_storage(file_struct_size) };
+        let path = c_path(path);
+        let fd_ptr = fd.ptr();
+        match call(|r| unsafe { _fatdrive_open_r(r, fd_ptr, path.as_ptr() as *const u8, flags, 0o666) }) {
+            (0, _) => Ok(fd),
+            (-1, e) => Err(e),
+            (other, _) => panic!("open returned {}", other),
+        }
+    }
+
+    fn close(fd : &mut Fd) -> (i32, i32) {
+        let fd_ptr = fd.ptr();
+        call(|r| unsafe { _fatdrive_close_r(r, fd_ptr) })
+    }
+
+    fn write(fd : &mut Fd, data : &[u8]) -> (isize, i32) {
+        let fd_ptr = fd.ptr();
+        call(|r| unsafe { _fatdrive_write_r(r, fd_ptr, data.as_ptr(), data.len()) })
+    }
+
+    fn read(fd : &mut Fd, len : usize) -> Result<Vec<u8>, i32> {
+        let fd_ptr = fd.ptr();
+        let mut buf = vec![0u8; len];
+        let buf_ptr = buf.as_mut_ptr();
+        match call(|r| unsafe { _fatdrive_read_r(r, fd_ptr, buf_ptr, len) }) {
+            (-1, e) => Err(e),
+            (n, _) => {
+                buf.truncate(n as usize);
+                Ok(buf)
+            }
+        }
+    }
+
+    fn seek(fd : &mut Fd, pos : off_t, whence : i32) -> (off_t, i32) {
+        let fd_ptr = fd.ptr();
+        call(|r| unsafe { _fatdrive_seek_r(r, fd_ptr, pos, whence) })
+    }
+
+    fn ftruncate(fd : &mut Fd, len : off_t) -> (i32, i32) {
+        let fd_ptr = fd.ptr();
+        call(|r| unsafe { _fatdrive_ftruncate_r(r, fd_ptr, len) })
+    }
+
+    fn fstat(fd : &mut Fd) -> Result<stat, i32> {
+        let fd_ptr = fd.ptr();
+        let mut st = stat::default();
+        match call(|r| unsafe { _fatdrive_fstat_r(r, fd_ptr, &mut st) }) {
+            (0, _) => Ok(st),
+            (_, e) => Err(e),
+        }
+    }
+
+    fn stat_path(path : &str) -> Result<stat, i32> {
+        let path = c_path(path);
+        let mut st = stat::default();
+        match call(|r| unsafe { _fatdrive_stat_r(r, path.as_ptr() as *const u8, &mut st) }) {
+            (0, _) => Ok(st),
+            (_, e) => Err(e),
+        }
+    }
+
+    fn stat_vfs(path : &str) -> Result<statvfs, i32> {
+        let path = c_path(path);
+        let mut out = statvfs::default();
+        match call(|r| unsafe { _fatdrive_stat_vfs_r(r, path.as_ptr() as *const u8, &mut out) }) {
+            (0, _) => Ok(out),
+            (_, e) => Err(e),
+        }
+    }
+
+    fn path_call(path : &str, f : unsafe extern "C" fn(*mut _reent, *const u8) -> i32) -> (i32, i32) {
+        let path = c_path(path);
+        call(|r| unsafe { f(r, path.as_ptr() as *const u8) })
+    }
+
+    fn mkdir(path : &str) -> (i32, i32) {
+        let path = c_path(path);
+        call(|r| unsafe { _fatdrive_mkdir_r(r, path.as_ptr() as *const u8, 0o777) })
+    }
+
+    fn rename(from : &str, to : &str) -> (i32, i32) {
+        let (from, to) = (c_path(from), c_path(to));
+        call(|r| unsafe { _fatdrive_rename_r(r, from.as_ptr() as *const u8, to.as_ptr() as *const u8) })
+    }
+
+    fn create_file(path : &str, contents : &[u8]) {
+        let mut fd = open(path, O_WRONLY | O_CREAT | O_TRUNC).unwrap();
+        assert_eq!(write(&mut fd, contents).0, contents.len() as isize);
+        assert_eq!(close(&mut fd).0, 0);
+    }
+
+    struct Dir {
+        iter : DIR_ITER,
+        _storage : Vec<u64>,
+    }
+
+    fn diropen(path : &str) -> Result<Dir, i32> {
+        let mut storage = handle_storage(dir_state_size);
+        let mut dir = Dir {
+            iter : DIR_ITER { device : 0, dirStruct : storage.as_mut_ptr() as *mut c_void },
+            _storage : storage,
+        };
+        let path = c_path(path);
+        let iter_ptr : *mut DIR_ITER = &mut dir.iter;
+        match call(|r| unsafe { _fatdrive_diropen_r(r, iter_ptr, path.as_ptr() as *const u8) }) {
+            (p, _) if p == iter_ptr => Ok(dir),
+            (p, e) => {
+                assert!(p.is_null());
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the next entry's name and the raw name buffer it was written to.
+    fn dirnext(dir : &mut Dir) -> Result<(String, Vec<u8>), i32> {
+        let mut name_buf = vec![0xFFu8; NX_FATDRIVE_NAME_MAX + 1];
+        let mut st = stat::default();
+        let iter_ptr : *mut DIR_ITER = &mut dir.iter;
+        let name_ptr = name_buf.as_mut_ptr();
+        match call(|r| unsafe { _fatdrive_dirnext_r(r, iter_ptr, name_ptr, &mut st) }) {
+            (0, _) => {
+                let len = name_buf.iter().position(|b| *b == 0).expect("name was not NUL-terminated");
+                Ok((String::from_utf8(name_buf[.. len].to_vec()).unwrap(), name_buf))
+            },
+            (_, e) => Err(e),
+        }
+    }
+
+    fn dirclose(dir : &mut Dir) -> (i32, i32) {
+        let iter_ptr : *mut DIR_ITER = &mut dir.iter;
+        call(|r| unsafe { _fatdrive_dirclose_r(r, iter_ptr) })
+    }
+
+    fn list(path : &str) -> Vec<String> {
+        let mut dir = diropen(path).unwrap();
+        let mut names = Vec::new();
+        loop {
+            match dirnext(&mut dir) {
+                Ok((name, _)) => names.push(name),
+                Err(e) => {
+                    assert_eq!(e, errno::NX_FATDRIVE_ERRNO_ENOENT);
+                    break;
+                }
+            }
+        }
+        assert_eq!(dirclose(&mut dir).0, 0);
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn errors_map_to_posix_errno_values() {
+        let fs_errors = [
+            (FsErrorKind::NoSpace, errno::NX_FATDRIVE_ERRNO_ENOSPC),
+            (FsErrorKind::IsADirectory, errno::NX_FATDRIVE_ERRNO_EISDIR),
+            (FsErrorKind::NotADirectory, errno::NX_FATDRIVE_ERRNO_ENOTDIR),
+            (FsErrorKind::DirectoryNotEmpty, errno::NX_FATDRIVE_ERRNO_ENOTEMPTY),
+            (FsErrorKind::Busy, errno::NX_FATDRIVE_ERRNO_EBUSY),
+            (FsErrorKind::ReadOnly, errno::NX_FATDRIVE_ERRNO_EROFS),
+            (FsErrorKind::Unsupported, errno::NX_FATDRIVE_ERRNO_ENOSYS),
+        ];
+        for (kind, expected) in fs_errors.iter() {
+            assert_eq!(ErrnoMapper::errno(filesystem::fs_error(*kind)), *expected);
+        }
+        assert_eq!(ErrnoMapper::errno(io::Error::from(ErrorKind::NotFound)), errno::NX_FATDRIVE_ERRNO_ENOENT);
+        assert_eq!(ErrnoMapper::errno(io::Error::from(ErrorKind::AlreadyExists)), errno::NX_FATDRIVE_ERRNO_EEXIST);
+        assert_eq!(ErrnoMapper::errno(io::Error::from(ErrorKind::NotConnected)), errno::NX_FATDRIVE_ERRNO_ENODEV);
+        assert_eq!(ErrnoMapper::errno(io::Error::from(ErrorKind::InvalidData)), errno::NX_FATDRIVE_ERRNO_EIO);
+        // Path errors keep their meaning when a backend wraps them in an io::Error.
+        assert_eq!(ErrnoMapper::errno(io::Error::from(PathError::ComponentTooLong(300))), errno::NX_FATDRIVE_ERRNO_ENAMETOOLONG);
+        assert_eq!(ErrnoMapper::errno(PathError::NullPointer), errno::NX_FATDRIVE_ERRNO_EFAULT);
+        assert_eq!(ErrnoMapper::errno(PathError::Empty), errno::NX_FATDRIVE_ERRNO_ENOENT);
+    }
+
+    #[test]
+    fn crate_result_codes_map_to_the_same_errno_as_their_source() {
+        let kinds = [
+            ErrorKind::NotFound, ErrorKind::PermissionDenied, ErrorKind::AlreadyExists, ErrorKind::InvalidInput,
+            ErrorKind::InvalidData, ErrorKind::TimedOut, ErrorKind::WriteZero, ErrorKind::Interrupted,
+            ErrorKind::WouldBlock, ErrorKind::AddrInUse, ErrorKind::NotConnected, ErrorKind::BrokenPipe,
+            ErrorKind::ConnectionReset, ErrorKind::UnexpectedEof, ErrorKind::Other,
+        ];
+        for kind in kinds.iter() {
+            assert_eq!(ErrnoMapper::errno(LibnxErrMapper::map(io::Error::from(*kind))), io_kind_errno(*kind), "{:?}", kind);
+        }
+        let path_errors = [
+            PathError::NullPointer, PathError::InvalidUtf8, PathError::Empty,
+            PathError::InvalidCharacter('?'), PathError::ComponentTooLong(300), PathError::PathTooLong(5000),
+        ];
+        for err in path_errors.iter() {
+            assert_eq!(ErrnoMapper::errno(LibnxErrMapper::map(err.clone())), ErrnoMapper::errno(err.clone()), "{:?}", err);
+        }
+        assert_eq!(ErrnoMapper::errno(NX_FATDRIVE_ERR_DRIVE_DISCONNECTED), errno::NX_FATDRIVE_ERRNO_ENODEV);
+        assert_eq!(ErrnoMapper::errno(NX_FATDRIVE_ERR_INVALID_HANDLE), errno::NX_FATDRIVE_ERRNO_EBADF);
+        assert_eq!(ErrnoMapper::errno(NX_FATDRIVE_ERR_READ_ONLY), errno::NX_FATDRIVE_ERRNO_EROFS);
+        assert_eq!(ErrnoMapper::errno(NX_FATDRIVE_ERR_NOT_IMPLEMENTED), errno::NX_FATDRIVE_ERRNO_ENOSYS);
+        // Anything from another module, such as a raw libnx result, is an I/O error.
+        assert_eq!(ErrnoMapper::errno(0x2A8u32), errno::NX_FATDRIVE_ERRNO_EIO);
+        assert_eq!(ErrnoMapper::errno(((NX_FATDRIVE_ERR_SCSI_PREFIX + 0x3001) << 8) + NX_FATDRIVE_ERR_MODULE), errno::NX_FATDRIVE_ERRNO_EIO);
+    }
+
+    #[test]
+    fn open_missing_file_without_create_fails_with_enoent() {
+        let _mnt = MountedImage::new();
+        assert_eq!(open("/missing.txt", O_RDONLY).err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(open("/missing/file.txt", O_RDWR | O_CREAT).err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+    }
+
+    #[test]
+    fn open_exclusive_on_existing_file_fails_with_eexist() {
+        let _mnt = MountedImage::new();
+        create_file("/a.txt", b"a");
+        assert_eq!(open("/a.txt", O_RDWR | O_CREAT | O_EXCL).err(), Some(errno::NX_FATDRIVE_ERRNO_EEXIST));
+    }
+
+    #[test]
+    fn open_directory_as_file_fails_with_eisdir() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/dir"), (0, 0));
+        assert_eq!(open("/dir", O_RDONLY).err(), Some(errno::NX_FATDRIVE_ERRNO_EISDIR));
+        assert_eq!(open("/", O_RDONLY).err(), Some(errno::NX_FATDRIVE_ERRNO_EISDIR));
+    }
+
+    #[test]
+    fn open_rejects_invalid_paths() {
+        let _mnt = MountedImage::new();
+        assert_eq!(open("/bad?name", O_RDWR | O_CREAT).err(), Some(errno::NX_FATDRIVE_ERRNO_EINVAL));
+        let long_name = format!("/{}", "a".repeat(path::MAX_COMPONENT_LEN + 1));
+        assert_eq!(open(&long_name, O_RDWR | O_CREAT).err(), Some(errno::NX_FATDRIVE_ERRNO_ENAMETOOLONG));
+    }
+
+    #[test]
+    fn written_data_reads_back() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/data.bin", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(write(&mut fd, b"hello, world"), (12, 0));
+        assert_eq!(seek(&mut fd, 0, SEEK_SET), (0, 0));
+        assert_eq!(read(&mut fd, 64), Ok(b"hello, world".to_vec()));
+        assert_eq!(read(&mut fd, 64), Ok(Vec::new()));
+        assert_eq!(close(&mut fd), (0, 0));
+
+        let mut fd = open("/data.bin", O_RDONLY).unwrap();
+        assert_eq!(read(&mut fd, 5), Ok(b"hello".to_vec()));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn seek_uses_posix_whence_values() {
+        let _mnt = MountedImage::new();
+        create_file("/seek.bin", b"0123456789");
+        let mut fd = open("/seek.bin", O_RDONLY).unwrap();
+        assert_eq!(seek(&mut fd, 4, SEEK_SET), (4, 0));
+        assert_eq!(seek(&mut fd, 2, SEEK_CUR), (6, 0));
+        assert_eq!(read(&mut fd, 1), Ok(b"6".to_vec()));
+        assert_eq!(seek(&mut fd, -3, SEEK_END), (7, 0));
+        assert_eq!(read(&mut fd, 10), Ok(b"789".to_vec()));
+        assert_eq!(seek(&mut fd, 0, SEEK_END), (10, 0));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn seek_rejects_bad_whence_and_negative_offsets() {
+        let _mnt = MountedImage::new();
+        create_file("/seek.bin", b"0123456789");
+        let mut fd = open("/seek.bin", O_RDONLY).unwrap();
+        assert_eq!(seek(&mut fd, 0, 3), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        assert_eq!(seek(&mut fd, -1, SEEK_SET), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        assert_eq!(seek(&mut fd, -11, SEEK_END), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        // A failed seek leaves the position alone.
+        assert_eq!(seek(&mut fd, 0, SEEK_CUR), (0, 0));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn seek_past_end_then_write_zero_fills_the_gap() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/gap.bin", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(seek(&mut fd, 4, SEEK_SET), (4, 0));
+        assert_eq!(write(&mut fd, b"x"), (1, 0));
+        assert_eq!(seek(&mut fd, 0, SEEK_SET), (0, 0));
+        assert_eq!(read(&mut fd, 10), Ok(b"\0\0\0\0x".to_vec()));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn access_mode_is_enforced_with_ebadf() {
+        let _mnt = MountedImage::new();
+        create_file("/mode.txt", b"abc");
+        let mut fd = open("/mode.txt", O_RDONLY).unwrap();
+        assert_eq!(write(&mut fd, b"x"), (-1, errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(ftruncate(&mut fd, 0), (-1, errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(close(&mut fd), (0, 0));
+
+        let mut fd = open("/mode.txt", O_WRONLY).unwrap();
+        assert_eq!(read(&mut fd, 1), Err(errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(close(&mut fd), (0, 0));
+        assert_eq!(stat_path("/mode.txt").unwrap().st_size, 3);
+    }
+
+    #[test]
+    fn append_writes_go_to_the_end() {
+        let _mnt = MountedImage::new();
+        create_file("/log.txt", b"one");
+        let mut fd = open("/log.txt", O_WRONLY | O_APPEND).unwrap();
+        assert_eq!(seek(&mut fd, 0, SEEK_SET), (0, 0));
+        assert_eq!(write(&mut fd, b"two"), (3, 0));
+        assert_eq!(close(&mut fd), (0, 0));
+        let mut fd = open("/log.txt", O_RDONLY).unwrap();
+        assert_eq!(read(&mut fd, 10), Ok(b"onetwo".to_vec()));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn truncate_on_open_empties_the_file() {
+        let _mnt = MountedImage::new();
+        create_file("/t.txt", b"contents");
+        let mut fd = open("/t.txt", O_RDWR | O_TRUNC).unwrap();
+        assert_eq!(read(&mut fd, 10), Ok(Vec::new()));
+        assert_eq!(close(&mut fd), (0, 0));
+        assert_eq!(stat_path("/t.txt").unwrap().st_size, 0);
+    }
+
+    #[test]
+    fn null_buffers_fail_with_einval() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/file.txt", O_RDWR | O_CREAT).unwrap();
+        let fd_ptr = fd.ptr();
+        assert_eq!(call(|r| unsafe { _fatdrive_write_r(r, fd_ptr, ptr::null(), 0) }), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        assert_eq!(call(|r| unsafe { _fatdrive_read_r(r, fd_ptr, ptr::null_mut(), 0) }), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn closed_handles_fail_with_ebadf() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/c.txt", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(close(&mut fd), (0, 0));
+        assert_eq!(close(&mut fd), (-1, errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(write(&mut fd, b"x"), (-1, errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(read(&mut fd, 1), Err(errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(seek(&mut fd, 0, SEEK_SET), (-1, errno::NX_FATDRIVE_ERRNO_EBADF));
+    }
+
+    #[test]
+    fn stat_reports_type_and_size() {
+        let _mnt = MountedImage::new();
+        create_file("/s.txt", b"12345");
+        assert_eq!(mkdir("/sdir"), (0, 0));
+
+        let st = stat_path("/s.txt").unwrap();
+        assert_eq!(st.st_mode & 0xF000, stat::FILE);
+        assert_eq!(st.st_size, 5);
+        assert_eq!(stat_path("/sdir").unwrap().st_mode & 0xF000, stat::DIRECTORY);
+        assert_eq!(stat_path("/").unwrap().st_mode & 0xF000, stat::DIRECTORY);
+        // FAT names are case-insensitive.
+        assert_eq!(stat_path("/S.TXT").unwrap().st_size, 5);
+    }
+
+    #[test]
+    fn stat_errors() {
+        let _mnt = MountedImage::new();
+        create_file("/s.txt", b"12345");
+        assert_eq!(stat_path("/nope").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(stat_path("/nope/child").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(stat_path("/s.txt/child").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOTDIR));
+    }
+
+    #[test]
+    fn stat_converts_fat_timestamps_to_utc() {
+        let modified = FatTimestamp { year : 2019, month : 7, day : 14, hour : 13, minute : 45, second : 30 };
+        let mut ent = DirEntryData {
+            name : "t.txt".to_owned(),
+            len : 0,
+            flags : (u8::from(DirEntryType::RegularFile) as u64) << 12,
+            attributes : ATTR_ARCHIVE,
+            modified : Some(modified),
+            created : None,
+            accessed : Some(FatTimestamp { hour : 0, minute : 0, second : 0, ..modified }),
+        };
+        let st = stat_dirent(&ent, 2 * 3600);
+        assert_eq!(st.st_mtime, 1_563_111_930 - 7200);
+        assert_eq!(st.st_atime, 1_563_062_400 - 7200);
+        // Without a creation time, the modification time stands in for it.
+        assert_eq!(st.st_ctime, st.st_mtime);
+
+        ent.modified = None;
+        assert_eq!(stat_dirent(&ent, 0).st_mtime, 0);
+        // time_t is unsigned, so anything before the epoch ends up at it.
+        ent.modified = Some(FatTimestamp { year : 1980, month : 1, day : 1, hour : 0, minute : 0, second : 0 });
+        assert_eq!(stat_dirent(&ent, 400_000_000).st_mtime, 0);
+    }
+
+    #[test]
+    fn stat_times_follow_the_utc_offset() {
+        let _mnt = MountedImage::new();
+        create_file("/t.txt", b"");
+        let local = stat_path("/t.txt").unwrap();
+        assert!(local.st_mtime > 0);
+
+        assert_eq!(unsafe { nxFatdriveSetUtcOffset(3600) }, SUCCESS);
+        let shifted = stat_path("/t.txt").unwrap();
+        assert_eq!(shifted.st_mtime, local.st_mtime - 3600);
+        assert_eq!(shifted.st_atime, local.st_atime - 3600);
+        assert_eq!(shifted.st_ctime, local.st_ctime - 3600);
+        let mut fd = open("/t.txt", O_RDONLY).unwrap();
+        assert_eq!(fstat(&mut fd).unwrap().st_mtime, shifted.st_mtime);
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn fstat_includes_buffered_writes() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/f.txt", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(write(&mut fd, b"buffered"), (8, 0));
+        assert_eq!(fstat(&mut fd).unwrap().st_size, 8);
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn ftruncate_grows_with_zeros_and_shrinks() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/tr.bin", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(write(&mut fd, b"abcdef"), (6, 0));
+        assert_eq!(ftruncate(&mut fd, 3), (0, 0));
+        assert_eq!(fstat(&mut fd).unwrap().st_size, 3);
+        assert_eq!(ftruncate(&mut fd, 5), (0, 0));
+        assert_eq!(seek(&mut fd, 0, SEEK_SET), (0, 0));
+        assert_eq!(read(&mut fd, 10), Ok(b"abc\0\0".to_vec()));
+        assert_eq!(ftruncate(&mut fd, -1), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn ftruncate_keeps_the_file_position() {
+        let _mnt = MountedImage::new();
+        let mut fd = open("/tr.bin", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(write(&mut fd, b"abcdef"), (6, 0));
+        assert_eq!(ftruncate(&mut fd, 2), (0, 0));
+        assert_eq!(seek(&mut fd, 0, SEEK_CUR), (6, 0));
+        assert_eq!(close(&mut fd), (0, 0));
+    }
+
+    #[test]
+    fn statvfs_reports_the_volume_in_clusters() {
+        let _mnt = MountedImage::new();
+        let device_ptr = registered_devices().unwrap()[0];
+        let ctx = unsafe { NewlibContext::from_devoptab(device_ptr) }.unwrap();
+        let stats = ctx.fs.stats().unwrap();
+
+        let vfs = stat_vfs("/").unwrap();
+        assert_eq!(vfs.f_bsize as u64, stats.cluster_size);
+        assert_eq!(vfs.f_frsize as u64, stats.cluster_size);
+        assert_eq!(vfs.f_blocks, stats.total_clusters);
+        assert_eq!(vfs.f_bfree, stats.free_clusters);
+        assert_eq!(vfs.f_bavail, stats.free_clusters);
+        assert_eq!(vfs.f_namemax, path::MAX_COMPONENT_LEN);
+        assert_eq!(vfs.f_flag, 0);
+
+        ctx.read_only = true;
+        assert_eq!(stat_vfs("/").unwrap().f_flag, ST_RDONLY);
+        let path = c_path("/");
+        assert_eq!(call(|r| unsafe { _fatdrive_stat_vfs_r(r, path.as_ptr() as *const u8, ptr::null_mut()) }), (-1, errno::NX_FATDRIVE_ERRNO_EFAULT));
+    }
+
+    #[test]
+    fn statvfs_caches_free_space_until_the_device_changes_it() {
+        let _mnt = MountedImage::new();
+        let before = stat_vfs("/").unwrap();
+        let cluster = before.f_bsize;
+
+        // Clusters allocated behind the device's back do not show up until it changes something itself.
+        let device_ptr = registered_devices().unwrap()[0];
+        let ctx = unsafe { NewlibContext::from_devoptab(device_ptr) }.unwrap();
+        ctx.fs.root().unwrap().create_file("behind.bin").unwrap().write_all(&vec![1u8; cluster]).unwrap();
+        assert_eq!(stat_vfs("/").unwrap().f_bfree, before.f_bfree);
+
+        create_file("/two.bin", &vec![2u8; 2 * cluster]);
+        assert_eq!(stat_vfs("/").unwrap().f_bfree, before.f_bfree - 3);
+        assert_eq!(path_call("/two.bin", _fatdrive_unlink_r), (0, 0));
+        assert_eq!(stat_vfs("/").unwrap().f_bfree, before.f_bfree - 1);
+
+        let mut fd = open("/behind.bin", O_RDWR).unwrap();
+        assert_eq!(ftruncate(&mut fd, 0), (0, 0));
+        assert_eq!(close(&mut fd), (0, 0));
+        assert_eq!(stat_vfs("/").unwrap().f_bfree, before.f_bfree);
+    }
+
+    #[test]
+    fn readdir_lists_entries_with_terminated_names() {
+        let _mnt = MountedImage::new();
+        create_file("/a.txt", b"");
+        create_file("/longer_name.txt", b"");
+        assert_eq!(mkdir("/sub"), (0, 0));
+        create_file("/sub/inner.txt", b"");
+
+        assert_eq!(list("/"), vec!["a.txt", "longer_name.txt", "sub"]);
+        assert_eq!(list("/sub"), vec!["inner.txt"]);
+
+        let mut dir = diropen("/").unwrap();
+        let (name, raw) = dirnext(&mut dir).unwrap();
+        assert_eq!(raw[name.len()], 0);
+        assert_eq!(dirclose(&mut dir), (0, 0));
+    }
+
+    #[test]
+    fn readdir_reset_and_close() {
+        let _mnt = MountedImage::new();
+        create_file("/only.txt", b"");
+        let mut dir = diropen("/").unwrap();
+        assert_eq!(dirnext(&mut dir).unwrap().0, "only.txt");
+        assert_eq!(dirnext(&mut dir).err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+
+        let iter_ptr : *mut DIR_ITER = &mut dir.iter;
+        assert_eq!(call(|r| unsafe { _fatdrive_dirreset_r(r, iter_ptr) }), (0, 0));
+        assert_eq!(dirnext(&mut dir).unwrap().0, "only.txt");
+
+        assert_eq!(dirclose(&mut dir), (0, 0));
+        assert_eq!(dirclose(&mut dir), (-1, errno::NX_FATDRIVE_ERRNO_EBADF));
+        assert_eq!(dirnext(&mut dir).err(), Some(errno::NX_FATDRIVE_ERRNO_EBADF));
+    }
+
+    #[test]
+    fn readdir_keeps_its_listing_until_reset() {
+        let _mnt = MountedImage::new();
+        create_file("/first.txt", b"");
+        let mut dir = diropen("/").unwrap();
+        assert_eq!(dirnext(&mut dir).unwrap().0, "first.txt");
+
+        create_file("/second.txt", b"");
+        assert_eq!(dirnext(&mut dir).err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+
+        let iter_ptr : *mut DIR_ITER = &mut dir.iter;
+        assert_eq!(call(|r| unsafe { _fatdrive_dirreset_r(r, iter_ptr) }), (0, 0));
+        assert_eq!(dirnext(&mut dir).unwrap().0, "first.txt");
+        assert_eq!(dirnext(&mut dir).unwrap().0, "second.txt");
+        assert_eq!(dirclose(&mut dir), (0, 0));
+    }
+
+    #[test]
+    fn diropen_errors() {
+        let _mnt = MountedImage::new();
+        create_file("/file.txt", b"");
+        assert_eq!(diropen("/missing").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(diropen("/file.txt").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOTDIR));
+    }
+
+    #[test]
+    fn mkdir_errors() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/dir"), (0, 0));
+        assert_eq!(mkdir("/dir"), (-1, errno::NX_FATDRIVE_ERRNO_EEXIST));
+        assert_eq!(mkdir("/"), (-1, errno::NX_FATDRIVE_ERRNO_EEXIST));
+        assert_eq!(mkdir("/missing/dir"), (-1, errno::NX_FATDRIVE_ERRNO_ENOENT));
+    }
+
+    #[test]
+    fn rmdir_removes_only_empty_directories() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/full"), (0, 0));
+        create_file("/full/x.txt", b"");
+        create_file("/file.txt", b"");
+
+        assert_eq!(path_call("/full", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOTEMPTY));
+        assert_eq!(path_call("/file.txt", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOTDIR));
+        assert_eq!(path_call("/missing", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(path_call("/", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+
+        assert_eq!(path_call("/full/x.txt", _fatdrive_unlink_r), (0, 0));
+        assert_eq!(path_call("/full", _fatdrive_rmdir_r), (0, 0));
+        assert_eq!(stat_path("/full").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+    }
+
+    #[test]
+    fn rmdir_of_open_directory_fails_with_ebusy() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/open"), (0, 0));
+        let mut dir = diropen("/open").unwrap();
+        assert_eq!(path_call("/open", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        assert_eq!(dirclose(&mut dir), (0, 0));
+        assert_eq!(path_call("/open", _fatdrive_rmdir_r), (0, 0));
+    }
+
+    #[test]
+    fn unlink_errors() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/dir"), (0, 0));
+        assert_eq!(path_call("/dir", _fatdrive_unlink_r), (-1, errno::NX_FATDRIVE_ERRNO_EISDIR));
+        assert_eq!(path_call("/missing", _fatdrive_unlink_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOENT));
+
+        let mut fd = open("/busy.txt", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(path_call("/busy.txt", _fatdrive_unlink_r), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        assert_eq!(close(&mut fd), (0, 0));
+        assert_eq!(path_call("/busy.txt", _fatdrive_unlink_r), (0, 0));
+    }
+
+    #[test]
+    fn open_handles_keep_their_entry_under_any_case() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/Dir"), (0, 0));
+        let mut fd = open("/Dir/File.txt", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(path_call("/dir/FILE.TXT", _fatdrive_unlink_r), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        assert_eq!(path_call("/DIR", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOTEMPTY));
+        assert_eq!(close(&mut fd), (0, 0));
+
+        let mut dir = diropen("/dir").unwrap();
+        assert_eq!(path_call("/dir/file.txt", _fatdrive_unlink_r), (0, 0));
+        assert_eq!(path_call("/DIR", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        assert_eq!(dirclose(&mut dir), (0, 0));
+        assert_eq!(path_call("/DIR", _fatdrive_rmdir_r), (0, 0));
+    }
+
+    #[test]
+    fn rename_moves_files_and_directories() {
+        let _mnt = MountedImage::new();
+        create_file("/old.txt", b"moved");
+        assert_eq!(mkdir("/dir"), (0, 0));
+
+        assert_eq!(rename("/old.txt", "/dir/new.txt"), (0, 0));
+        assert_eq!(stat_path("/old.txt").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(stat_path("/dir/new.txt").unwrap().st_size, 5);
+
+        assert_eq!(rename("/dir", "/renamed"), (0, 0));
+        assert_eq!(list("/renamed"), vec!["new.txt"]);
+    }
+
+    #[test]
+    fn rename_of_open_entries_fails_with_ebusy() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/dir"), (0, 0));
+        create_file("/dir/kept.txt", b"kept");
+        create_file("/other.txt", b"other");
+        let mut fd = open("/Dir/Kept.txt", O_RDWR).unwrap();
+        assert_eq!(rename("/dir/kept.txt", "/moved.txt"), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        assert_eq!(rename("/DIR", "/moved"), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        assert_eq!(rename("/other.txt", "/dir/kept.txt"), (-1, errno::NX_FATDRIVE_ERRNO_EBUSY));
+        // A sibling whose name only starts the same way is not in the tree.
+        assert_eq!(rename("/other.txt", "/dirt.txt"), (0, 0));
+        assert_eq!(fstat(&mut fd).unwrap().st_size, 4);
+        assert_eq!(close(&mut fd), (0, 0));
+
+        assert_eq!(rename("/dir", "/moved"), (0, 0));
+        assert_eq!(list("/moved"), vec!["kept.txt"]);
+    }
+
+    #[test]
+    fn rename_of_missing_entry_fails_with_enoent() {
+        let _mnt = MountedImage::new();
+        assert_eq!(rename("/missing", "/other"), (-1, errno::NX_FATDRIVE_ERRNO_ENOENT));
+    }
+
+    #[test]
+    fn relative_paths_follow_chdir() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/work"), (0, 0));
+        assert_eq!(path_call("/work", _fatdrive_chdir_r), (0, 0));
+        let relative = CString::new("rel.txt").unwrap();
+        let mut fd = Fd { storage : handle_storage(file_struct_size) };
+        let fd_ptr = fd.ptr();
+        assert_eq!(call(|r| unsafe { _fatdrive_open_r(r, fd_ptr, relative.as_ptr() as *const u8, O_RDWR | O_CREAT, 0o666) }), (0, 0));
+        assert_eq!(close(&mut fd), (0, 0));
+        assert!(stat_path("/work/rel.txt").is_ok());
+    }
+
+    /// The working directory as `nxFatdriveGetCwd` reports it, given a buffer of `size` bytes.
+    fn getcwd(size : usize) -> Option<String> {
+        let mut buf = vec![0xFFu8; size];
+        let ret = unsafe { nxFatdriveGetCwd(buf.as_mut_ptr(), buf.len()) };
+        if ret.is_null() {
+            return None;
+        }
+        assert_eq!(ret, buf.as_mut_ptr());
+        let len = buf.iter().position(|b| *b == 0).expect("cwd was not NUL-terminated");
+        Some(String::from_utf8(buf[.. len].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn chdir_only_accepts_existing_directories() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/dir"), (0, 0));
+        create_file("/file.txt", b"");
+        assert_eq!(path_call("/missing", _fatdrive_chdir_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(path_call("/file.txt", _fatdrive_chdir_r), (-1, errno::NX_FATDRIVE_ERRNO_ENOTDIR));
+        assert_eq!(path_call("/bad?dir", _fatdrive_chdir_r), (-1, errno::NX_FATDRIVE_ERRNO_EINVAL));
+        assert_eq!(getcwd(64).unwrap(), format!("{}:/", TEST_DEVICE));
+
+        assert_eq!(path_call("/DIR/", _fatdrive_chdir_r), (0, 0));
+        assert_eq!(getcwd(64).unwrap(), format!("{}:/DIR", TEST_DEVICE));
+        assert_eq!(path_call("..", _fatdrive_chdir_r), (0, 0));
+        assert_eq!(path_call("..", _fatdrive_chdir_r), (0, 0));
+        assert_eq!(getcwd(64).unwrap(), format!("{}:/", TEST_DEVICE));
+    }
+
+    #[test]
+    fn getcwd_needs_room_for_the_terminator() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/a"), (0, 0));
+        assert_eq!(mkdir("/a/b"), (0, 0));
+        assert_eq!(path_call("/a/b", _fatdrive_chdir_r), (0, 0));
+        let cwd = format!("{}:/a/b", TEST_DEVICE);
+        assert_eq!(getcwd(cwd.len()), None);
+        assert_eq!(getcwd(cwd.len() + 1), Some(cwd));
+        assert_eq!(unsafe { nxFatdriveGetCwd(ptr::null_mut(), 64) }, ptr::null_mut());
+    }
+
+    #[test]
+    fn every_callback_resolves_relative_paths() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/work"), (0, 0));
+        assert_eq!(path_call("/work", _fatdrive_chdir_r), (0, 0));
+
+        assert_eq!(mkdir("sub"), (0, 0));
+        create_file("./new.txt", b"relative");
+        assert_eq!(stat_path("new.txt").unwrap().st_size, 8);
+        assert_eq!(stat_path("../work/new.txt").unwrap().st_size, 8);
+        assert_eq!(rename("new.txt", "sub/moved.txt"), (0, 0));
+        assert_eq!(list("."), vec!["sub"]);
+        assert_eq!(list("sub"), vec!["moved.txt"]);
+        assert_eq!(path_call("sub/moved.txt", _fatdrive_unlink_r), (0, 0));
+        assert_eq!(path_call("sub", _fatdrive_rmdir_r), (0, 0));
+        assert_eq!(list("/work"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn each_mounted_partition_is_its_own_device() {
+        let _mnt = MountedImage::with_devices(&["imgfs0", "imgfs1"]);
+        assert_eq!(unsafe { nxFatdriveDeviceCount() }, 2);
+        let mut name = [0xFFu8; 16];
+        assert_eq!(unsafe { nxFatdriveGetDeviceName(1, name.as_mut_ptr(), name.len()) }, SUCCESS);
+        assert_eq!(&name[.. 7], b"imgfs1\0");
+        assert_eq!(unsafe { nxFatdriveGetDeviceName(2, name.as_mut_ptr(), name.len()) }, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+
+        create_file("imgfs0:/first.txt", b"on the first device");
+        assert_eq!(stat_path("imgfs1:/first.txt").err(), Some(errno::NX_FATDRIVE_ERRNO_ENOENT));
+        assert_eq!(rename("imgfs0:/first.txt", "imgfs1:/moved.txt"), (-1, errno::NX_FATDRIVE_ERRNO_EXDEV));
+        assert_eq!(stat_path("imgfs0:/first.txt").unwrap().st_size, 19);
+    }
+
+    #[test]
+    fn unmounting_one_device_leaves_the_others() {
+        let _mnt = MountedImage::with_devices(&["imgfs0", "imgfs1"]);
+        create_file("imgfs1:/kept.txt", b"kept");
+        let name = CString::new("imgfs0:").unwrap();
+        assert_eq!(unsafe { nxFatdriveUnmountDevice(name.as_ptr() as *const u8) }, SUCCESS);
+        assert_eq!(unsafe { nxFatdriveUnmountDevice(name.as_ptr() as *const u8) }, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+        assert_eq!(unsafe { nxFatdriveDeviceCount() }, 1);
+
+        assert_eq!(stat_path("imgfs0:/").err(), Some(errno::NX_FATDRIVE_ERRNO_ENODEV));
+        assert_eq!(stat_path("imgfs1:/kept.txt").unwrap().st_size, 4);
+    }
+
+    #[test]
+    fn unmounting_a_device_with_open_handles_fails_with_busy() {
+        let _mnt = MountedImage::new();
+        assert_eq!(mkdir("/dir"), (0, 0));
+        let name = CString::new(TEST_DEVICE).unwrap();
+        let mut fd = open("/open.txt", O_RDWR | O_CREAT).unwrap();
+        assert_eq!(write(&mut fd, b"still here"), (10, 0));
+        let mut dir = diropen("/dir").unwrap();
+
+        assert_eq!(unsafe { nxFatdriveUnmountDevice(name.as_ptr() as *const u8) }, NX_FATDRIVE_ERR_BUSY);
+        assert_eq!(unsafe { nxFatdriveUnmount() }, NX_FATDRIVE_ERR_BUSY);
+        assert_eq!(unsafe { nxFatdriveDeviceCount() }, 1);
+        assert_eq!(seek(&mut fd, 0, SEEK_SET), (0, 0));
+        assert_eq!(read(&mut fd, 16), Ok(b"still here".to_vec()));
+
+        assert_eq!(close(&mut fd), (0, 0));
+        assert_eq!(unsafe { nxFatdriveUnmountDevice(name.as_ptr() as *const u8) }, NX_FATDRIVE_ERR_BUSY);
+        assert_eq!(dirclose(&mut dir), (0, 0));
+    }
+
+    #[test]
+    fn read_only_devices_refuse_changes_with_erofs() {
+        let _mnt = MountedImage::new();
+        create_file("/kept.txt", b"kept");
+        mkdir("/dir");
+        let device_ptr = registered_devices().unwrap()[0];
+        unsafe { NewlibContext::from_devoptab(device_ptr) }.unwrap().read_only = true;
+
+        let mut fd = open("/kept.txt", O_RDONLY).unwrap();
+        assert_eq!(read(&mut fd, 16).unwrap(), b"kept");
+        assert_eq!(close(&mut fd).0, 0);
+
+        assert_eq!(open("/kept.txt", O_WRONLY).err(), Some(errno::NX_FATDRIVE_ERRNO_EROFS));
+        assert_eq!(open("/new.txt", O_RDONLY | O_CREAT).err(), Some(errno::NX_FATDRIVE_ERRNO_EROFS));
+        assert_eq!(mkdir("/other"), (-1, errno::NX_FATDRIVE_ERRNO_EROFS));
+        assert_eq!(rename("/kept.txt", "/moved.txt"), (-1, errno::NX_FATDRIVE_ERRNO_EROFS));
+        assert_eq!(path_call("/kept.txt", _fatdrive_unlink_r), (-1, errno::NX_FATDRIVE_ERRNO_EROFS));
+        assert_eq!(path_call("/dir", _fatdrive_rmdir_r), (-1, errno::NX_FATDRIVE_ERRNO_EROFS));
+        assert_eq!(stat_path("/kept.txt").unwrap().st_size, 4);
+    }
+}
@@ -5,8 +5,117 @@ pub use self::err::*;
 mod idstore;
 pub use self::idstore::*;
 
+mod mount;
+pub use self::mount::*;
+
+mod raw;
+pub use self::raw::*;
+
 mod usbfs;
 pub use self::usbfs::*;
 
+mod hotplug;
+pub use self::hotplug::*;
+
 mod iosupport_bindings;
-mod iosupport;
\ No newline at end of file
+mod iosupport;
+
+#[cfg(test)]
+mod tests {
+    //! Checks the header build.rs generates against what the crate actually exports.
+
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process::{self, Command};
+
+    const HEADER : &str = include_str!(concat!(env!("OUT_DIR"), "/nxfatdrive.h"));
+
+    /// Every file with `#[no_mangle]` functions in it.
+    const EXPORTING_SOURCES : [&str ; 4] = [include_str!("mount.rs"), include_str!("usbfs.rs"), include_str!("hotplug.rs"), include_str!("iosupport.rs")];
+
+    fn exported_functions() -> Vec<String> {
+        let mut names = Vec::new();
+        for source in EXPORTING_SOURCES.iter() {
+            let mut lines = source.lines();
+            while let Some(line) = lines.next() {
+                if line.trim() != "#[no_mangle]" {
+                    continue;
+                }
+                let decl = lines.next().unwrap();
+                let name = decl.split("fn ").nth(1).and_then(|rest| rest.split('(').next()).unwrap();
+                names.push(name.trim().to_owned());
+            }
+        }
+        names.sort();
+        names
+    }
+
+    /// The names of the functions declared at the top level of the header.
+    fn declared_functions() -> Vec<String> {
+        let mut names : Vec<String> = HEADER.lines()
+            .filter(|line| !line.starts_with(|c : char| c.is_whitespace() || c == '#' || c == '/' || c == '}'))
+            .filter(|line| !line.starts_with("typedef") && line.contains('('))
+            .map(|line| {
+                let before_args = line.split('(').next().unwrap();
+                before_args.split_whitespace().last().unwrap().trim_start_matches('*').to_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn header_declares_exactly_the_exported_functions() {
+        let exported = exported_functions();
+        assert!(exported.iter().any(|name| name == "usbFsOpenFile"));
+        assert!(exported.iter().any(|name| name == "nxFatdriveMountWithOptions"));
+        assert_eq!(declared_functions(), exported);
+    }
+
+    #[test]
+    fn header_defines_error_codes_and_mount_types() {
+        let err_codes = include_str!("err.rs").lines()
+            .filter(|line| line.starts_with("pub const "))
+            .map(|line| line["pub const ".len() ..].split_whitespace().next().unwrap());
+        for name in err_codes {
+            assert!(HEADER.contains(&format!("#define {} ", name)), "{} is missing from the header", name);
+        }
+        for name in &["USBFS_UNMOUNTED", "USBFS_MOUNTED", "USBFS_UNSUPPORTED_FS", "USBFS_STATE_NO_DEVICE", "USBFS_STATE_ERROR", "NX_FATDRIVE_PARTITION_LABEL", "NX_FATDRIVE_BACKEND_FATFS_SYS", "NX_FATDRIVE_DIR_ENTRY_NAME_SIZE", "NX_FATDRIVE_RAW_SCOPE_PARTITION", "NX_FATDRIVE_HOTPLUG_MOUNT_FAILED", "NX_FATDRIVE_HOTPLUG_REATTACHED"] {
+            assert!(HEADER.contains(&format!("#define {} ", name)), "{} is missing from the header", name);
+        }
+        assert!(HEADER.contains("} NxFatdriveMountOptions;"));
+        assert!(HEADER.contains("} NxFatdriveDirEntry;"));
+    }
+
+    /// Needs a C compiler for the host, which the target toolchain doesn't provide, so
+    /// this only runs when one is configured through `HOST_CC`.
+    #[test]
+    fn header_compiles_as_c() {
+        let compiler = match env::var("HOST_CC") {
+            Ok(compiler) => compiler,
+            Err(_) => {
+                eprintln!("HOST_CC is not set; not compiling the header");
+                return;
+            }
+        };
+        let out_dir = env!("OUT_DIR");
+        let source_path = Path::new(out_dir).join(format!("nxfatdrive_header_test_{}.c", process::id()));
+        // Included twice to check the include guard.
+        fs::write(&source_path, "#include \"nxfatdrive.h\"\n\
+            #include \"nxfatdrive.h\"\n\
+            int main(void) {\n\
+                NxFatdriveMountOptions options;\n\
+                nxFatdriveDefaultMountOptions(&options);\n\
+                options.read_only = true;\n\
+                return nxFatdriveMountWithOptions(&options) == SUCCESS && usbFsDeviceGetMountStatus() == USBFS_MOUNTED;\n\
+            }\n").unwrap();
+        let output = Command::new(&compiler)
+            .args(&["-std=c99", "-Wall", "-Wextra", "-Werror", "-fsyntax-only", "-I", out_dir])
+            .arg(&source_path)
+            .output()
+            .expect("Unable to run the C compiler");
+        let _ = fs::remove_file(&source_path);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+}
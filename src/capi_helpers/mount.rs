@@ -0,0 +1,351 @@
+use libnx_rs::usbhs::{Interface, InterfaceAvailableEvent, InterfaceFilter, UsbHsContext};
+use mbr_nostd::{PartitionTableEntry, PartitionType};
+use scsi::scsi::ScsiBlockDevice;
+use buf_scsi::OffsetScsiDevice;
+use filesystem::{self, FileSystem, FileSystemOps};
+use filesystem::buffered;
+use filesystem::path;
+use usb_comm::UsbClient;
+use vecwrapper::VecNewtype;
+use std::io::{self, ErrorKind};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use capi_helpers::*;
+
+/// The prefix devices are named with unless the caller picks another.
+pub(crate) const default_device_prefix : &str = "usbfs";
+
+/// How long mounting waits for a drive to be attached, in nanoseconds, by default.
+pub(crate) const default_mount_timeout : u64 = 0x800000;
+
+/// How long a drive that was unplugged can take to come back, in milliseconds, by default.
+pub(crate) const default_reattach_grace_ms : u32 = 5000;
+
+/// Mount every partition the drives have; the other fields of the selector are ignored.
+pub const NX_FATDRIVE_PARTITION_ALL : u32 = 0;
+/// Mount the partition at `partition_index` in each drive's partition table.
+pub const NX_FATDRIVE_PARTITION_INDEX : u32 = 1;
+/// Mount the partitions whose volume label is `partition_label`, ignoring case.
+pub const NX_FATDRIVE_PARTITION_LABEL : u32 = 2;
+/// Mount the partitions whose filesystem is `partition_type`, one of the
+/// `NX_FATDRIVE_PARTITION_TYPE_*` values.
+pub const NX_FATDRIVE_PARTITION_TYPE : u32 = 3;
+
+pub const NX_FATDRIVE_PARTITION_TYPE_FAT12 : u32 = 1;
+pub const NX_FATDRIVE_PARTITION_TYPE_FAT16 : u32 = 2;
+pub const NX_FATDRIVE_PARTITION_TYPE_FAT32 : u32 = 3;
+pub const NX_FATDRIVE_PARTITION_TYPE_EXFAT : u32 = 4;
+
+/// The pure Rust rust-fatfs backend.
+pub const NX_FATDRIVE_BACKEND_FATFS_RS : u32 = 0;
+/// The FatFs C library, which also reads exFAT.
+pub const NX_FATDRIVE_BACKEND_FATFS_SYS : u32 = 1;
+
+/// Settings for `nxFatdriveMountWithOptions` and `nxFatdriveOpenDevice`. Fill it in with
+/// `nxFatdriveDefaultMountOptions` first; the defaults mount the way `nxFatdriveMount` does.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct NxFatdriveMountOptions {
+    /// How long to wait for a drive to be attached, in nanoseconds.
+    pub timeout_ns : u64,
+    /// Which partitions to mount; one of the `NX_FATDRIVE_PARTITION_*` selectors.
+    pub partition_select : u32,
+    pub partition_index : u32,
+    pub partition_type : u32,
+    /// NUL-terminated; only read when selecting by label.
+    pub partition_label : *const u8,
+    pub read_only : bool,
+    /// The devices are named `{device_prefix}0:`, `{device_prefix}1:`, ... Null means `usbfs`.
+    pub device_prefix : *const u8,
+    /// One of the `NX_FATDRIVE_BACKEND_*` values.
+    pub backend : u32,
+    /// The write-back buffer each open file gets, in bytes. 0 means the default of 64 KiB.
+    pub cache_size : usize,
+    /// How long, in milliseconds, a drive opened with `nxFatdriveOpenDevice` keeps its
+    /// files and directories open after it is unplugged. If the same volume on the
+    /// same drive is plugged back in before then, it carries on where it left off.
+    /// 0 closes them for good straight away.
+    pub reattach_grace_ms : u32,
+}
+
+pub(crate) enum PartitionSelector {
+    All,
+    Index(usize),
+    Label(String),
+    Type(u32),
+}
+
+impl PartitionSelector {
+    fn partition_type(ent : &PartitionTableEntry) -> Option<u32> {
+        match ent.partition_type {
+            PartitionType::Fat12(_) => Some(NX_FATDRIVE_PARTITION_TYPE_FAT12),
+            PartitionType::Fat16(_) => Some(NX_FATDRIVE_PARTITION_TYPE_FAT16),
+            PartitionType::Fat32(_) => Some(NX_FATDRIVE_PARTITION_TYPE_FAT32),
+            PartitionType::NtfsExfat(_) => Some(NX_FATDRIVE_PARTITION_TYPE_EXFAT),
+            _ => None,
+        }
+    }
+
+    /// Whether the partition at `idx` in the partition table should be mounted.
+    /// `device` is only read from when selecting by label.
+    pub(crate) fn matches(&self, idx : usize, ent : &PartitionTableEntry, device : &mut OffsetScsiDevice) -> Result<bool, io::Error> {
+        match self {
+            PartitionSelector::All => Ok(true),
+            PartitionSelector::Index(wanted) => Ok(*wanted == idx),
+            PartitionSelector::Type(wanted) => Ok(PartitionSelector::partition_type(ent) == Some(*wanted)),
+            PartitionSelector::Label(wanted) => {
+                let label = filesystem::boot_sector_label(device)?;
+                Ok(label.eq_ignore_ascii_case(wanted))
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub(crate) enum Backend {
+    FatfsRs,
+    FatfsSys,
+}
+
+impl Backend {
+    pub(crate) fn open(&self, device : OffsetScsiDevice, ent : PartitionTableEntry) -> Result<FileSystem, io::Error> {
+        match self {
+            Backend::FatfsRs => FileSystemOps::from_device(device, ent).map(FileSystem::Fatfs),
+            Backend::FatfsSys => FileSystemOps::from_device(device, ent).map(FileSystem::FatfsSys),
+        }
+    }
+}
+
+/// `NxFatdriveMountOptions` after validation.
+pub(crate) struct MountOptions {
+    pub(crate) timeout : u64,
+    pub(crate) partitions : PartitionSelector,
+    pub(crate) read_only : bool,
+    pub(crate) device_prefix : String,
+    pub(crate) backend : Backend,
+    pub(crate) cache_size : usize,
+    pub(crate) reattach_grace : Duration,
+}
+
+impl MountOptions {
+    pub(crate) unsafe fn from_raw(raw : &NxFatdriveMountOptions) -> Result<MountOptions, u32> {
+        let invalid = || LibnxErrMapper::map(io::Error::from(ErrorKind::InvalidInput));
+        let partitions = match raw.partition_select {
+            NX_FATDRIVE_PARTITION_ALL => PartitionSelector::All,
+            NX_FATDRIVE_PARTITION_INDEX => PartitionSelector::Index(raw.partition_index as usize),
+            NX_FATDRIVE_PARTITION_LABEL => {
+                let label = path::c_path_str(raw.partition_label).map_err(LibnxErrMapper::map)?;
+                PartitionSelector::Label(label.to_owned())
+            },
+            NX_FATDRIVE_PARTITION_TYPE => match raw.partition_type {
+                NX_FATDRIVE_PARTITION_TYPE_FAT12 | NX_FATDRIVE_PARTITION_TYPE_FAT16 | NX_FATDRIVE_PARTITION_TYPE_FAT32 | NX_FATDRIVE_PARTITION_TYPE_EXFAT => PartitionSelector::Type(raw.partition_type),
+                _ => {
+                    return Err(invalid());
+                }
+            },
+            _ => {
+                return Err(invalid());
+            }
+        };
+        let backend = match raw.backend {
+            NX_FATDRIVE_BACKEND_FATFS_RS => Backend::FatfsRs,
+            NX_FATDRIVE_BACKEND_FATFS_SYS => Backend::FatfsSys,
+            _ => {
+                return Err(invalid());
+            }
+        };
+        let device_prefix = if raw.device_prefix.is_null() {
+            default_device_prefix
+        }
+        else {
+            path::c_path_str(raw.device_prefix).map_err(LibnxErrMapper::map)?
+        };
+        if device_prefix.is_empty() || device_prefix.contains(|c : char| c == ':' || c == '/') {
+            return Err(invalid());
+        }
+        Ok(MountOptions {
+            timeout : raw.timeout_ns,
+            partitions,
+            read_only : raw.read_only,
+            device_prefix : device_prefix.to_owned(),
+            backend,
+            cache_size : if raw.cache_size == 0 { buffered::DEFAULT_BUFFER_CAPACITY } else { raw.cache_size },
+            reattach_grace : Duration::from_millis(u64::from(raw.reattach_grace_ms)),
+        })
+    }
+}
+
+/// Fills `out` with the options `nxFatdriveMount` uses.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveDefaultMountOptions(out : *mut NxFatdriveMountOptions) {
+    if let Some(out) = out.as_mut() {
+        *out = NxFatdriveMountOptions {
+            timeout_ns : default_mount_timeout,
+            partition_select : NX_FATDRIVE_PARTITION_ALL,
+            partition_index : 0,
+            partition_type : 0,
+            partition_label : ptr::null(),
+            read_only : false,
+            device_prefix : ptr::null(),
+            backend : NX_FATDRIVE_BACKEND_FATFS_RS,
+            cache_size : 0,
+            reattach_grace_ms : default_reattach_grace_ms,
+        };
+    }
+}
+
+/// A USB mass storage drive's SCSI interface.
+pub(crate) type UsbScsiDevice = ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>;
+
+/// Matches USB mass storage interfaces using the SCSI command set over bulk-only transport.
+pub(crate) fn mass_storage_filter() -> InterfaceFilter {
+    InterfaceFilter::new()
+        .with_interface_class(8)
+        .with_interface_subclass(6)
+        .with_interface_protocol(80)
+}
+
+/// Waits up to `timeout` nanoseconds for a mass storage drive to show up, then
+/// acquires up to `limit` of the ones that are attached and not yet in use.
+pub(crate) fn acquire_drives(usb_hs_ctx : &mut UsbHsContext, timeout : u64, limit : usize) -> Result<Vec<(Interface, UsbScsiDevice)>, u32> {
+    let filter = mass_storage_filter();
+    let evt = InterfaceAvailableEvent::create(true, 0, filter).map_err(LibnxErrMapper::map)?;
+    evt.wait(timeout).map_err(LibnxErrMapper::map)?;
+    let interfaces = usb_hs_ctx.query_available_interfaces(filter, 3).map_err(LibnxErrMapper::map)?;
+    if interfaces.is_empty() {
+        return Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+    }
+    let mut retval = Vec::with_capacity(interfaces.len());
+    for iface in interfaces.into_iter().take(limit) {
+        let session = usb_hs_ctx.acquire_interface(&iface).map_err(LibnxErrMapper::map)?;
+        let (read_ep, write_ep) = UsbClient::retrieve_iface_endpoints(&session.interface()).map_err(LibnxErrMapper::map)?;
+        let client = UsbClient::new(session, read_ep, write_ep).map_err(LibnxErrMapper::map)?;
+        let scsi_wrapper = ScsiBlockDevice::new(client, VecNewtype::new(), VecNewtype::new(), VecNewtype::new()).map_err(LibnxErrMapper::map)?;
+        retval.push((iface, scsi_wrapper));
+    }
+    Ok(retval)
+}
+
+/// The used entries of the drive's partition table, along with their index in it.
+pub(crate) fn read_partitions(scsi_wrapper : &mut UsbScsiDevice) -> Result<Vec<(usize, PartitionTableEntry)>, u32> {
+    let mut mbr_buff = VecNewtype::with_fake_capacity(512.max(scsi_wrapper.block_size() as usize));
+    while mbr_buff.inner.len() < 512 {
+        let _bt = scsi_wrapper.read(mbr_buff.inner.len() as u32, &mut mbr_buff).map_err(LibnxErrMapper::map)?;
+    }
+
+    let mbr_entry = mbr_nostd::MasterBootRecord::from_bytes(&mut mbr_buff.inner).map_err(LibnxErrMapper::map)?;
+    Ok(mbr_entry.partition_table_entries().iter()
+        .cloned()
+        .enumerate()
+        .filter(|(_, e)| e.sector_count != 0)
+        .collect())
+}
+
+/// Opens the filesystems on the partitions of `device` that `options` selects, in
+/// partition table order, stopping after `limit` of them. Partitions that do not
+/// hold a filesystem we can read are skipped; if that leaves none, this fails with
+/// the last error seen, which is `NX_FATDRIVE_ERR_UNSUPPORTED_FS` if the backend
+/// could not mount a partition.
+pub(crate) fn open_partitions(device : &Arc<Mutex<UsbScsiDevice>>, options : &MountOptions, limit : usize) -> Result<Vec<(PartitionTableEntry, FileSystem)>, u32> {
+    // Released again before the partitions are opened, since they lock it themselves.
+    let (partitions, block_size) = {
+        let mut locked = device.lock().map_err(LibnxErrMapper::map)?;
+        (read_partitions(&mut locked)?, locked.block_size())
+    };
+    let mut retval = Vec::new();
+    let mut last_err = NX_FATDRIVE_ERR_DRIVE_NOT_FOUND;
+    for (idx, ent) in partitions {
+        if retval.len() >= limit {
+            break;
+        }
+        let raw_offset : usize = (ent.logical_block_address * block_size) as usize; 
+        let mut partition = OffsetScsiDevice::new(device.clone(), raw_offset);
+        match options.partitions.matches(idx, &ent, &mut partition) {
+            Ok(true) => {},
+            Ok(false) => {
+                continue;
+            },
+            Err(e) => {
+                last_err = LibnxErrMapper::map(e);
+                continue;
+            }
+        }
+        match options.backend.open(partition, ent.clone()) {
+            Ok(fs) => retval.push((ent, fs)),
+            Err(_) => {
+                last_err = NX_FATDRIVE_ERR_UNSUPPORTED_FS;
+            }
+        }
+    }
+    if retval.is_empty() {
+        return Err(last_err);
+    }
+    Ok(retval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image_device::ImageDevice;
+    use std::ffi::CString;
+    use std::io::Cursor;
+    use std::mem;
+
+    const IMAGE_SIZE : usize = 8 * 1024 * 1024;
+
+    fn default_options() -> NxFatdriveMountOptions {
+        let mut options : NxFatdriveMountOptions = unsafe { mem::zeroed() };
+        unsafe { nxFatdriveDefaultMountOptions(&mut options) };
+        options
+    }
+
+    #[test]
+    fn default_mount_options_match_nxfatdrivemount() {
+        let options = unsafe { MountOptions::from_raw(&default_options()) }.unwrap();
+        assert_eq!(options.timeout, 0x800000);
+        assert!(match options.partitions { PartitionSelector::All => true, _ => false });
+        assert!(!options.read_only);
+        assert_eq!(options.device_prefix, "usbfs");
+        assert!(match options.backend { Backend::FatfsRs => true, _ => false });
+        assert_eq!(options.cache_size, buffered::DEFAULT_BUFFER_CAPACITY);
+        assert_eq!(options.reattach_grace, Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn invalid_mount_options_are_rejected() {
+        let invalid = LibnxErrMapper::map(io::Error::from(ErrorKind::InvalidInput));
+        let bad_prefix = CString::new("usb:fs").unwrap();
+        let cases = [
+            NxFatdriveMountOptions { partition_select : 7, ..default_options() },
+            NxFatdriveMountOptions { partition_select : NX_FATDRIVE_PARTITION_TYPE, partition_type : 0, ..default_options() },
+            NxFatdriveMountOptions { backend : 2, ..default_options() },
+            NxFatdriveMountOptions { device_prefix : bad_prefix.as_ptr() as *const u8, ..default_options() },
+        ];
+        for raw in cases.iter() {
+            assert_eq!(unsafe { MountOptions::from_raw(raw) }.err(), Some(invalid));
+        }
+        let null_label = NxFatdriveMountOptions { partition_select : NX_FATDRIVE_PARTITION_LABEL, ..default_options() };
+        assert!(unsafe { MountOptions::from_raw(&null_label) }.is_err());
+    }
+
+    #[test]
+    fn partition_selectors_match_index_type_and_label() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new().volume_label(*b"SWITCHDATA ")).unwrap();
+        let ent = PartitionTableEntry::new(PartitionType::Fat16(0x06), 0, (IMAGE_SIZE / 512) as u32);
+        let mut device = OffsetScsiDevice::new(ImageDevice::new(image), 0);
+
+        assert!(PartitionSelector::All.matches(2, &ent, &mut device).unwrap());
+        assert!(PartitionSelector::Index(1).matches(1, &ent, &mut device).unwrap());
+        assert!(!PartitionSelector::Index(0).matches(1, &ent, &mut device).unwrap());
+        assert!(PartitionSelector::Type(NX_FATDRIVE_PARTITION_TYPE_FAT16).matches(0, &ent, &mut device).unwrap());
+        assert!(!PartitionSelector::Type(NX_FATDRIVE_PARTITION_TYPE_FAT32).matches(0, &ent, &mut device).unwrap());
+        assert!(PartitionSelector::Label("SwitchData".to_owned()).matches(0, &ent, &mut device).unwrap());
+        assert!(!PartitionSelector::Label("SWITCH".to_owned()).matches(0, &ent, &mut device).unwrap());
+
+        // Reading the label must leave the device where the backend expects to start.
+        let fs = Backend::FatfsRs.open(device, ent).unwrap();
+        assert!(fs.stats().is_ok());
+    }
+}
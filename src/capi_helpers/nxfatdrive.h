@@ -1,5 +0,0 @@
-#include <stdint.h>
-
-uint32_t nxFatdriveMount();
-
-uint32_t nxFatdriveUnmount();
\ No newline at end of file
@@ -0,0 +1,281 @@
+use mbr_nostd::PartitionTableEntry;
+use buf_scsi::{BlockDevice, OffsetScsiDevice, SharedBlockDevice};
+use filesystem::FileSystem;
+use vecwrapper::VecNewtype;
+use std::io::{self, ErrorKind};
+use capi_helpers::*;
+
+/// Raw sector numbers count from the start of the drive.
+pub const NX_FATDRIVE_RAW_SCOPE_DEVICE : u32 = 0;
+/// Raw sector numbers count from the start of the mounted partition, and stop at its end.
+pub const NX_FATDRIVE_RAW_SCOPE_PARTITION : u32 = 1;
+
+pub(crate) enum RawScope {
+    Device,
+    Partition,
+}
+
+impl RawScope {
+    pub(crate) fn from_raw(scope : u32) -> Result<RawScope, u32> {
+        match scope {
+            NX_FATDRIVE_RAW_SCOPE_DEVICE => Ok(RawScope::Device),
+            NX_FATDRIVE_RAW_SCOPE_PARTITION => Ok(RawScope::Partition),
+            _ => Err(LibnxErrMapper::map(io::Error::from(ErrorKind::InvalidInput))),
+        }
+    }
+}
+
+/// The whole drive under a mounted partition, for reading and writing its sectors
+/// directly. Sectors are the drive's blocks, whatever size READ CAPACITY says
+/// they are.
+pub(crate) struct RawDevice {
+    device : SharedBlockDevice,
+    partition : PartitionTableEntry,
+    backend : Backend,
+    writes_unlocked : bool,
+}
+
+impl RawDevice {
+    pub(crate) fn new(device : SharedBlockDevice, partition : PartitionTableEntry, backend : Backend) -> RawDevice {
+        RawDevice {
+            device,
+            partition,
+            backend,
+            writes_unlocked : false,
+        }
+    }
+
+    pub(crate) fn sector_size(&self) -> u32 {
+        self.device.block_size()
+    }
+
+    /// Whether the drive is still there. Once it is gone it stays gone, even if it is
+    /// plugged back in.
+    pub(crate) fn is_connected(&self) -> bool {
+        self.device.is_connected()
+    }
+
+    /// How many cached bytes are waiting for a drive that went away to come back.
+    pub(crate) fn unwritten_bytes(&self) -> u64 {
+        self.device.unwritten_bytes()
+    }
+
+    /// The same partition on `device`, which should be the drive this one was
+    /// opened from, plugged back in.
+    pub(crate) fn reopen(&self, device : SharedBlockDevice) -> RawDevice {
+        RawDevice {
+            device,
+            partition : self.partition.clone(),
+            backend : self.backend,
+            writes_unlocked : self.writes_unlocked,
+        }
+    }
+
+    /// Writes out the blocks `old` could not write before its drive went away. `old`
+    /// keeps them all the same, so that they can be written again if this device
+    /// turns out to be unusable; they go once `old` itself does.
+    pub(crate) fn restore_unwritten(&mut self, old : &mut RawDevice) -> Result<(), u32> {
+        let pending = old.device.take_unwritten();
+        let mut retval = Ok(());
+        for &(offset, ref block) in pending.iter() {
+            if let Err(e) = self.device.write(offset, &mut VecNewtype::from(block.clone())) {
+                retval = Err(LibnxErrMapper::map(e));
+                break;
+            }
+        }
+        for (offset, block) in pending {
+            old.device.keep_unwritten(offset, block);
+        }
+        retval
+    }
+
+    /// The serial number the partition's boot sector gives its volume, which stays
+    /// the same until it is formatted again.
+    pub(crate) fn volume_serial(&mut self) -> Result<u32, u32> {
+        let mut boot_sector = vec![0u8; self.sector_size() as usize];
+        let first = u64::from(self.partition.logical_block_address);
+        self.read_at(first, &mut boot_sector)?;
+        if boot_sector.len() < 0x68 {
+            return Err(NX_FATDRIVE_ERR_UNSUPPORTED_FS);
+        }
+        let offset = if &boot_sector[3 .. 11] == b"EXFAT   " {
+            0x64
+        }
+        // FAT32 leaves the 16-bit FAT size empty, and has a longer BPB before the ID.
+        else if boot_sector[0x16] == 0 && boot_sector[0x17] == 0 {
+            0x43
+        }
+        else {
+            0x27
+        };
+        let id = &boot_sector[offset .. offset + 4];
+        Ok(u32::from(id[0]) | u32::from(id[1]) << 8 | u32::from(id[2]) << 16 | u32::from(id[3]) << 24)
+    }
+
+    /// Opens the partition's filesystem again, picking up whatever changed on the drive.
+    pub(crate) fn mount(&self) -> Result<FileSystem, u32> {
+        let raw_offset = (self.partition.logical_block_address * self.sector_size()) as usize;
+        let device = OffsetScsiDevice::new(self.device.clone(), raw_offset);
+        self.backend.open(device, self.partition.clone()).map_err(|_| NX_FATDRIVE_ERR_UNSUPPORTED_FS)
+    }
+
+    /// Whether the partition's filesystem can be unmounted and mounted again. The
+    /// fatfs-sys backend keeps its volumes registered for as long as the process runs.
+    pub(crate) fn can_remount(&self) -> bool {
+        match self.backend {
+            Backend::FatfsRs => true,
+            Backend::FatfsSys => false,
+        }
+    }
+
+    pub(crate) fn writes_unlocked(&self) -> bool {
+        self.writes_unlocked
+    }
+
+    pub(crate) fn set_writes_unlocked(&mut self, unlocked : bool) {
+        self.writes_unlocked = unlocked;
+    }
+
+    /// The first sector of `scope` on the drive and how many sectors it has.
+    pub(crate) fn extent(&mut self, scope : &RawScope) -> Result<(u64, u64), u32> {
+        match scope {
+            RawScope::Device => Ok((0, self.device.block_count().map_err(LibnxErrMapper::map)?)),
+            RawScope::Partition => Ok((u64::from(self.partition.logical_block_address), u64::from(self.partition.sector_count))),
+        }
+    }
+
+    /// Turns `count` sectors from `sector` on in `scope` into the first of them on
+    /// the drive, failing if any lies outside the scope or past what READ CAPACITY
+    /// reports.
+    pub(crate) fn locate(&mut self, scope : &RawScope, sector : u64, count : u64) -> Result<u64, u32> {
+        let (start, len) = self.extent(scope)?;
+        let capacity = match scope {
+            RawScope::Device => len,
+            RawScope::Partition => self.device.block_count().map_err(LibnxErrMapper::map)?,
+        };
+        let end = sector.checked_add(count).ok_or(NX_FATDRIVE_ERR_OUT_OF_RANGE)?;
+        if end > len || start + end > capacity {
+            return Err(NX_FATDRIVE_ERR_OUT_OF_RANGE);
+        }
+        // Block devices take byte offsets as a u32, so nothing past 4 GiB can be reached.
+        if (start + end) * u64::from(self.sector_size()) > u64::from(u32::max_value()) + 1 {
+            return Err(NX_FATDRIVE_ERR_OUT_OF_RANGE);
+        }
+        Ok(start + sector)
+    }
+
+    /// Whether any of `count` drive sectors from `first` on belong to the partition.
+    pub(crate) fn overlaps_partition(&self, first : u64, count : u64) -> bool {
+        let part_start = u64::from(self.partition.logical_block_address);
+        let part_end = part_start + u64::from(self.partition.sector_count);
+        first < part_end && part_start < first + count
+    }
+
+    /// Fills `buffer` from the drive sectors starting at `first`, which `locate`
+    /// has checked. `buffer` holds a whole number of sectors.
+    pub(crate) fn read_at(&mut self, first : u64, buffer : &mut [u8]) -> Result<(), u32> {
+        let sector_size = self.sector_size() as usize;
+        for (idx, chunk) in buffer.chunks_mut(sector_size).enumerate() {
+            let offset = (first + idx as u64) * sector_size as u64;
+            let mut block = VecNewtype::with_fake_capacity(sector_size);
+            while block.inner.len() < sector_size {
+                let red = self.device.read((offset as usize + block.inner.len()) as u32, &mut block).map_err(LibnxErrMapper::map)?;
+                if red == 0 {
+                    return Err(LibnxErrMapper::map(io::Error::from(ErrorKind::UnexpectedEof)));
+                }
+            }
+            chunk.copy_from_slice(&block.inner[.. sector_size]);
+        }
+        Ok(())
+    }
+
+    /// Writes `buffer` to the drive sectors starting at `first`, like `read_at`.
+    pub(crate) fn write_at(&mut self, first : u64, buffer : &[u8]) -> Result<(), u32> {
+        if !self.writes_unlocked {
+            return Err(NX_FATDRIVE_ERR_WRITE_PROTECTED);
+        }
+        let sector_size = self.sector_size() as usize;
+        for (idx, chunk) in buffer.chunks(sector_size).enumerate() {
+            let offset = (first + idx as u64) * sector_size as u64;
+            let mut block = VecNewtype::from(chunk.to_vec());
+            self.device.write(offset as u32, &mut block).map_err(LibnxErrMapper::map)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image_device::ImageDevice;
+    use mbr_nostd::PartitionType;
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex};
+
+    fn raw_device(sectors : usize) -> RawDevice {
+        let image = ImageDevice::new(Cursor::new(vec![0u8; sectors * 512]));
+        let partition = PartitionTableEntry::new(PartitionType::Fat16(0x06), 8, (sectors - 16) as u32);
+        RawDevice::new(Arc::new(Mutex::new(image)), partition, Backend::FatfsRs)
+    }
+
+    #[test]
+    fn accesses_are_checked_against_their_scope() {
+        let mut raw = raw_device(64);
+        assert_eq!(raw.locate(&RawScope::Device, 0, 64), Ok(0));
+        assert_eq!(raw.locate(&RawScope::Device, 63, 2), Err(NX_FATDRIVE_ERR_OUT_OF_RANGE));
+        assert_eq!(raw.locate(&RawScope::Partition, 0, 48), Ok(8));
+        assert_eq!(raw.locate(&RawScope::Partition, 47, 2), Err(NX_FATDRIVE_ERR_OUT_OF_RANGE));
+        assert_eq!(raw.locate(&RawScope::Device, u64::max_value(), 2), Err(NX_FATDRIVE_ERR_OUT_OF_RANGE));
+
+        assert!(raw.overlaps_partition(0, 9));
+        assert!(!raw.overlaps_partition(0, 8));
+        assert!(!raw.overlaps_partition(56, 8));
+    }
+
+    #[test]
+    fn partitions_past_the_end_of_the_drive_are_cut_short() {
+        let mut raw = raw_device(64);
+        raw.partition.sector_count = 100;
+        assert_eq!(raw.locate(&RawScope::Partition, 0, 56), Ok(8));
+        assert_eq!(raw.locate(&RawScope::Partition, 0, 57), Err(NX_FATDRIVE_ERR_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn writes_wait_for_an_unlock() {
+        let mut raw = raw_device(64);
+        let sector = [0xA5u8; 512];
+        assert_eq!(raw.write_at(3, &sector), Err(NX_FATDRIVE_ERR_WRITE_PROTECTED));
+        raw.set_writes_unlocked(true);
+        assert_eq!(raw.write_at(3, &sector), Ok(()));
+
+        let mut read_back = [0u8; 1024];
+        raw.read_at(2, &mut read_back).unwrap();
+        assert!(read_back[.. 512].iter().all(|&b| b == 0));
+        assert!(read_back[512 ..].iter().all(|&b| b == 0xA5));
+    }
+
+    #[test]
+    fn blocks_cached_when_the_drive_goes_away_are_kept_for_its_return() {
+        let image = Arc::new(Mutex::new(ImageDevice::new(Cursor::new(vec![0u8; 64 * 512]))));
+        let partition = PartitionTableEntry::new(PartitionType::Fat16(0x06), 8, 48);
+        let mut raw = RawDevice::new(image.clone(), partition, Backend::FatfsRs);
+        let mut cached = OffsetScsiDevice::new(image.clone(), 8 * 512);
+        cached.write_all(b"never written").unwrap();
+
+        image.lock().unwrap().disconnect();
+        assert!(!raw.is_connected());
+        drop(cached);
+        assert_eq!(raw.unwritten_bytes(), 512);
+
+        let mut sector = [0u8; 512];
+        assert_eq!(raw.read_at(8, &mut sector), Err(NX_FATDRIVE_ERR_DRIVE_DISCONNECTED));
+        assert!(sector.iter().all(|&b| b == 0));
+
+        let contents = image.lock().unwrap().get_ref().get_ref().clone();
+        let mut returned = raw.reopen(Arc::new(Mutex::new(ImageDevice::new(Cursor::new(contents)))));
+        assert_eq!(returned.restore_unwritten(&mut raw), Ok(()));
+        assert_eq!(raw.unwritten_bytes(), 0);
+        returned.read_at(8, &mut sector).unwrap();
+        assert!(sector.starts_with(b"never written"));
+    }
+}
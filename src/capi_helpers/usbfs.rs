@@ -1,289 +1,1002 @@
-
-use libnx_rs::LibnxError;
-use libnx_rs::usbhs::InterfaceAvailableEvent;
-use libnx_rs::usbhs::{Interface, InterfaceFilter, ClientInterfaceSession, InterfaceInfo, UsbHsContext};
-use mbr_nostd::{PartitionTableEntry, PartitionTable};
-use scsi::scsi::ScsiBlockDevice;
-use fatfs::{Dir, DirEntry, File, FileSystem, ReadWriteSeek};
-use vecwrapper::VecNewtype;
-use buf_scsi::OffsetScsiDevice;
+use libnx_rs::usbhs::{InterfaceInfo, UsbHsContext};
+use buf_scsi::SharedBlockDevice;
 use filesystem;
+use filesystem::{FileSystem, FileSystemOps, DirectoryOps, FileOps};
+use filesystem::path;
+use filesystem::split;
 use std::collections::HashMap;
-use std::convert::AsRef;
 use std::io::{ErrorKind, Read, Write, Seek, SeekFrom};
-use std::path::{Component, Components, Path};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
 use std::slice;
-use std::ffi::{CStr, CString};
 use std::ptr;
+use std::mem;
+use std::time::{Duration, Instant};
+use capi_helpers::*;
+
+/// An opaque handle to a drive opened with `nxFatdriveOpenDevice`. 0 is never a valid handle.
+pub type NxFatdriveDeviceHandle = u64;
+
+/// The USB side of an opened drive.
+pub(crate) struct UsbConnection {
+    device_info : InterfaceInfo,
+    usb_hs_ctx : UsbHsContext,
+}
 
-use std::time::Duration;
-use usb_comm::UsbClient;
-fn wait_for_usb_drive(ctx : &mut UsbHsContext, timeout : u64) -> Result<ClientInterfaceSession, LibnxError> {
-    let filter : InterfaceFilter = InterfaceFilter::new()
-        .with_interface_class(8)
-        .with_interface_subclass(6)
-        .with_interface_protocol(80);
-    
-    let evt = InterfaceAvailableEvent::create(true, 0, filter)?;
-    evt.wait(timeout)?;
-    let mut interfaces = ctx.query_available_interfaces(filter, 3)?;
-    let mut iface = interfaces.pop().ok_or(LibnxError::from_raw(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND))?;
-    ctx.acquire_interface(&iface)
+impl UsbConnection {
+    /// Whether the interface we acquired is still attached.
+    pub(crate) fn is_attached(&mut self) -> Result<bool, u32> {
+        let acquired = self.usb_hs_ctx.query_acquired_interfaces(4).map_err(LibnxErrMapper::map)?;
+        Ok(acquired.iter().any(|iface| iface.info() == self.device_info))
+    }
 }
 
-fn parse_drive(ctx : &mut UsbHsContext, session : ClientInterfaceSession) -> Result<ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>, LibnxError> {
-    let (read_ep, write_ep) = UsbClient::retrieve_iface_endpoints(&session.interface())?;
-    let client = UsbClient::new(session, read_ep, write_ep)?;
-    scsi::scsi::ScsiBlockDevice::new(client, VecNewtype::new(), VecNewtype::new(), VecNewtype::new()).map_err(|e| LibnxError::from_raw(LibnxErrMapper::map(e)))
+/// What tells a drive that comes back after being unplugged apart from any other.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DriveIdentity {
+    usb_serial : String,
+    volume_serial : u32,
 }
 
-fn open_partition(mut scsi_wrapper : ScsiBlockDevice<UsbClient, VecNewtype, VecNewtype, VecNewtype>, idx : usize) -> Result<OffsetScsiDevice, LibnxError> {
+/// A drive acquired over USB, before anything on it is mounted.
+pub(crate) struct AcquiredDrive {
+    // Declared first so the USB session is closed before the context it came from.
+    device : Arc<Mutex<UsbScsiDevice>>,
+    pub(crate) connection : UsbConnection,
+}
 
-    let mut mbr_buff = VecNewtype::with_fake_capacity(512.max(scsi_wrapper.block_size() as usize));
-    let mut mbr_read_count = 0;
-    while mbr_buff.inner.len() < 512 {
-        let _bt = scsi_wrapper.read(mbr_buff.inner.len() as u32, &mut mbr_buff).map_err(|e| LibnxError::from_raw(LibnxErrMapper::map(e)))?;
-        mbr_read_count += 1;
+impl AcquiredDrive {
+    /// Acquires the first attached drive nobody else is using, waiting up to
+    /// `timeout` nanoseconds for one to show up.
+    pub(crate) fn acquire(timeout : u64) -> Result<AcquiredDrive, u32> {
+        let mut usb_hs_ctx = UsbHsContext::initialize().map_err(LibnxErrMapper::map)?;
+        let (iface, scsi_wrapper) = acquire_drives(&mut usb_hs_ctx, timeout, 1)?.pop().ok_or(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND)?;
+        Ok(AcquiredDrive {
+            device : Arc::new(Mutex::new(scsi_wrapper)),
+            connection : UsbConnection {
+                device_info : iface.info(),
+                usb_hs_ctx,
+            },
+        })
     }
 
-    let mbr_entry = mbr_nostd::MasterBootRecord::from_bytes(&mut mbr_buff.inner).map_err(LibnxErrMapper::map).map_err(LibnxError::from_raw)?;
+    /// The serial number the drive reports over USB, if it has one.
+    fn usb_serial(&self) -> Option<String> {
+        let mut device = self.device.lock().ok()?;
+        device.comm_channel.serial_number().unwrap_or(None)
+    }
+}
 
+/// A drive opened with `nxFatdriveOpenDevice`, along with the files and directories
+/// opened on it. File and directory IDs are only meaningful together with the drive.
+pub(crate) struct DriveContext {
+    id_store : IdStore,
+    /// `None` if mounting the partition again after a raw write failed.
+    fs : Option<FileSystem>,
+    read_only : bool,
+    /// `None` for drives registered from a filesystem alone.
+    raw : Option<RawDevice>,
+    /// `None` for disk images.
+    usb : Option<UsbConnection>,
+    /// `None` if the drive could not be recognised again after being unplugged.
+    identity : Option<DriveIdentity>,
+    /// How long the drive waits to be plugged back in before it is gone for good.
+    reattach_grace : Duration,
+    /// When the drive was found unplugged, if it has not come back since.
+    gone_since : Option<Instant>,
+}
 
-    let ent : &PartitionTableEntry = &mbr_entry.partition_table_entries()[idx];
-    let raw_offset : usize = (ent.logical_block_address * scsi_wrapper.block_size()) as usize; 
+impl DriveContext {
+    pub(crate) fn new(fs : FileSystem, read_only : bool) -> DriveContext {
+        DriveContext {
+            id_store : IdStore::new(),
+            fs : Some(fs),
+            read_only,
+            raw : None,
+            usb : None,
+            identity : None,
+            reattach_grace : Duration::from_secs(0),
+            gone_since : None,
+        }
+    }
 
-    Ok(OffsetScsiDevice::new(scsi_wrapper, raw_offset))
-}
+    /// Gives the drive raw sector access through `raw`, which must be the device `fs` was opened from.
+    pub(crate) fn with_raw_device(mut self, raw : RawDevice) -> DriveContext {
+        self.raw = Some(raw);
+        self
+    }
 
+    /// Acquires the first attached drive nobody else is using and opens the first
+    /// partition on it that `options` selects.
+    fn open_usb(options : &MountOptions) -> Result<DriveContext, u32> {
+        let drive = AcquiredDrive::acquire(options.timeout)?;
+        DriveContext::mount(drive, options).map_err(|(_, e)| e)
+    }
 
+    /// Opens the first partition on `drive` that `options` selects. On failure the
+    /// drive is handed back along with the error.
+    pub(crate) fn mount(drive : AcquiredDrive, options : &MountOptions) -> Result<DriveContext, (AcquiredDrive, u32)> {
+        let (partition, fs) = match open_partitions(&drive.device, options, 1) {
+            Ok(mut opened) => match opened.pop() {
+                Some(opened) => opened,
+                None => {
+                    return Err((drive, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND));
+                }
+            },
+            Err(e) => {
+                return Err((drive, e));
+            }
+        };
+        let device : SharedBlockDevice = drive.device.clone();
+        let mut raw = RawDevice::new(device, partition, options.backend);
+        let identity = match (drive.usb_serial(), raw.volume_serial()) {
+            (Some(usb_serial), Ok(volume_serial)) => Some(DriveIdentity { usb_serial, volume_serial }),
+            _ => None,
+        };
+        let mut retval = DriveContext::new(fs, options.read_only).with_raw_device(raw);
+        retval.usb = Some(drive.connection);
+        retval.identity = identity;
+        retval.reattach_grace = options.reattach_grace;
+        Ok(retval)
+    }
 
+    fn check_writable(&self) -> Result<(), u32> {
+        if self.read_only { Err(NX_FATDRIVE_ERR_READ_ONLY) } else { Ok(()) }
+    }
 
-pub struct UsbFsServiceContext {
-    usb_hs_ctx : UsbHsContext, 
-    device_info : InterfaceInfo,
+    fn raw_device(&mut self) -> Result<&mut RawDevice, u32> {
+        self.raw.as_mut().ok_or(NX_FATDRIVE_ERR_NOT_IMPLEMENTED)
+    }
+
+    /// Runs `op` on the `count` drive sectors from `first` on. If they overlap the
+    /// mounted partition, its filesystem is unmounted for the duration, so that
+    /// everything it still holds reaches the drive first and whatever `op` changes
+    /// is read afresh when it is mounted again.
+    fn with_raw_range<T, F>(&mut self, first : u64, count : u64, writes : bool, op : F) -> Result<T, u32>
+        where F : FnOnce(&mut RawDevice) -> Result<T, u32>
+    {
+        let raw = self.raw.as_mut().ok_or(NX_FATDRIVE_ERR_NOT_IMPLEMENTED)?;
+        if !raw.overlaps_partition(first, count) {
+            return op(raw);
+        }
+        if !raw.can_remount() {
+            // fatfs-sys syncs each file as it is closed, so reading under it is safe,
+            // but it would never notice a write.
+            return if writes { Err(NX_FATDRIVE_ERR_BUSY) } else { op(raw) };
+        }
+        self.fs = None;
+        let retval = op(raw);
+        self.fs = raw.mount().ok();
+        retval
+    }
+
+    /// Fails with `NX_FATDRIVE_ERR_DRIVE_DISCONNECTED` once the transport has seen the
+    /// drive go away. Every file and directory open on it fails the same way from then on.
+    fn check_attached(&mut self) -> Result<(), u32> {
+        let connected = self.raw.as_ref().map_or(true, |raw| raw.is_connected());
+        if connected { Ok(()) } else { Err(self.mark_gone()) }
+    }
+
+    /// Notes when the drive was first found unplugged.
+    fn mark_gone(&mut self) -> u32 {
+        if self.gone_since.is_none() {
+            self.gone_since = Some(Instant::now());
+        }
+        NX_FATDRIVE_ERR_DRIVE_DISCONNECTED
+    }
+
+    fn is_ready(&mut self) -> Result<(), u32> {
+        self.check_attached()?;
+        let attached = match self.usb.as_mut() {
+            Some(usb) => usb.is_attached()?,
+            None => true,
+        };
+        if attached { Ok(()) } else { Err(self.mark_gone()) }
+    }
+
+    /// Whether the drive is unplugged but can still be picked up where it left off
+    /// if it comes back.
+    fn awaits_reattach(&mut self) -> bool {
+        let remountable = match self.raw.as_ref() {
+            Some(raw) => !raw.is_connected() && raw.can_remount(),
+            None => false,
+        };
+        if !remountable || self.identity.is_none() {
+            return false;
+        }
+        self.mark_gone();
+        self.gone_since.map_or(false, |since| since.elapsed() < self.reattach_grace)
+    }
+
+    /// Carries on with `device` in place of the drive that was unplugged, if it is
+    /// the same drive with the same volume on it. Writes that were still cached when
+    /// it went away are written out, and the files and directories open on it pick
+    /// up where they were. If that fails, the drive is left unplugged and waiting,
+    /// so that it can be tried again.
+    fn resume(&mut self, usb_serial : &str, device : SharedBlockDevice) -> Result<(), u32> {
+        if !self.awaits_reattach() {
+            return Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+        }
+        let mut raw = self.raw_device()?.reopen(device);
+        let volume_serial = raw.volume_serial()?;
+        let same_drive = self.identity.as_ref().map_or(false, |identity| identity.usb_serial == usb_serial && identity.volume_serial == volume_serial);
+        if !same_drive {
+            return Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+        }
+        // The old filesystem is no use either way; unmounting it hands whatever it still
+        // had cached to the old device, which holds on to it until the new one is in use.
+        self.fs = None;
+        raw.restore_unwritten(self.raw_device()?)?;
+        let fs = raw.mount()?;
+        self.raw = Some(raw);
+        self.fs = Some(fs);
+        self.gone_since = None;
+        Ok(())
+    }
+
+    /// Unmounts the drive, failing with `NX_FATDRIVE_ERR_DATA_LOST` if writes it
+    /// still had cached could not reach it.
+    fn close(mut self) -> Result<(), u32> {
+        self.id_store = IdStore::new();
+        self.fs = None;
+        match self.raw.as_ref() {
+            Some(raw) if raw.unwritten_bytes() > 0 => Err(NX_FATDRIVE_ERR_DATA_LOST),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The filesystem of a drive, if it is mounted.
+fn mounted(fs : &Option<FileSystem>) -> Result<&FileSystem, u32> {
+    fs.as_ref().ok_or(NX_FATDRIVE_ERR_UNSUPPORTED_FS)
 }
 
+/// A drive is used from whichever thread calls into it, and from the hotplug thread,
+/// one at a time.
+type SharedDrive = Arc<Mutex<DriveContext>>;
 
 // -----------------------------------
-#[macro_use]
-use capi_helpers::*;
 lazy_static! {
-    static ref usb_hs_ctx_ptr : Mutex<usize> = Mutex::new(ptr::null_mut::<UsbFsServiceContext>() as usize);
-    static ref fs_ptr : Mutex<usize> = Mutex::new(ptr::null_mut::<FileSystem<OffsetScsiDevice>>() as usize);
-    static ref id_store_ptr : Mutex<usize> = Mutex::new(ptr::null_mut::<IdStore>() as usize);
+    /// Every open drive by handle. The registry is only locked long enough to find a
+    /// drive, so calls on different drives do not wait on each other.
+    static ref open_drives : Mutex<HashMap<NxFatdriveDeviceHandle, SharedDrive>> = Mutex::new(HashMap::new());
+    static ref next_drive_handle : Mutex<NxFatdriveDeviceHandle> = Mutex::new(1);
+    /// The `UsbFsService` behind the `usbFs*` functions, or null until `usbFsInitialize`
+    /// or `usbFsDeviceRegister` starts it.
+    static ref usbfs_service : Mutex<usize> = Mutex::new(ptr::null_mut::<UsbFsService>() as usize);
 }
 
-pub unsafe fn get_usb_hs_ctx<'a>() -> Result<(&'a mut UsbHsContext, MutexGuard<'a, usize>), u32> {
-    get_service_ctx().map(|(ctx, guard)| (&mut ctx.usb_hs_ctx, guard))
+pub(crate) fn register_drive(drive : DriveContext) -> Result<NxFatdriveDeviceHandle, u32> {
+    let mut drives = open_drives.lock().map_err(LibnxErrMapper::map)?;
+    let mut next_handle = next_drive_handle.lock().map_err(LibnxErrMapper::map)?;
+    let handle = *next_handle;
+    *next_handle = if handle == u64::max_value() { 1 } else { handle + 1 };
+    drives.insert(handle, Arc::new(Mutex::new(drive)));
+    Ok(handle)
 }
 
-pub unsafe fn get_service_ctx<'a>() -> Result<(&'a mut UsbFsServiceContext, MutexGuard<'a, usize>), u32> {
-    let usb_hs_ptr_guard = usb_hs_ctx_ptr.lock().map_err(LibnxErrMapper::map)?;
-    let usb_hs_ptr_raw = *usb_hs_ptr_guard;
-    let usb_hs = match (usb_hs_ptr_raw as *mut UsbFsServiceContext).as_mut() {
-        Some(r) => r, 
-        None => {
-            return Err(NX_FATDRIVE_ERR_NOT_INITIALIZED);
-        }
-    };
+fn get_drive(handle : NxFatdriveDeviceHandle) -> Result<SharedDrive, u32> {
+    let drives = open_drives.lock().map_err(LibnxErrMapper::map)?;
+    drives.get(&handle).cloned().ok_or(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND)
+}
 
-    return Ok((usb_hs, usb_hs_ptr_guard))
+/// Runs `f` on the drive behind `handle`, holding only that drive's lock. Fails
+/// without running `f` if the drive has been unplugged.
+fn with_drive<T, F : FnOnce(&mut DriveContext) -> Result<T, u32>>(handle : NxFatdriveDeviceHandle, f : F) -> Result<T, u32> {
+    with_any_drive(handle, |drive| {
+        drive.check_attached()?;
+        f(drive)
+    })
+}
+
+/// Like `with_drive`, but also runs `f` on drives that were unplugged, so that
+/// what is open on them can still be closed, or the drive picked up again.
+fn with_any_drive<T, F : FnOnce(&mut DriveContext) -> Result<T, u32>>(handle : NxFatdriveDeviceHandle, f : F) -> Result<T, u32> {
+    let drive = get_drive(handle)?;
+    let mut guard = drive.lock().map_err(LibnxErrMapper::map)?;
+    f(&mut guard)
+}
 
+/// Whether the drive behind `handle` is unplugged but still waiting to be plugged back in.
+pub(crate) fn drive_awaits_reattach(handle : NxFatdriveDeviceHandle) -> bool {
+    with_any_drive(handle, |drive| Ok(drive.awaits_reattach())).unwrap_or(false)
 }
-pub unsafe fn get_filesystem<'a>() -> Result<(&'a mut FileSystem<OffsetScsiDevice>, MutexGuard<'a, usize>), u32> {
-    let fs_ptr_guard = fs_ptr.lock().map_err(LibnxErrMapper::map)?;
-    let fs_ptr_raw : usize = *fs_ptr_guard;
-    let fs = match (fs_ptr_raw as *mut FileSystem<OffsetScsiDevice>).as_mut() {
-        Some(r) => r, 
+
+/// When the drive behind `handle` stops waiting to be plugged back in, if it is waiting.
+pub(crate) fn drive_reattach_deadline(handle : NxFatdriveDeviceHandle) -> Option<Instant> {
+    with_any_drive(handle, |drive| {
+        if !drive.awaits_reattach() {
+            return Ok(None);
+        }
+        Ok(drive.gone_since.map(|since| since + drive.reattach_grace))
+    }).unwrap_or(None)
+}
+
+/// Hands `drive` to whichever of the drives in `candidates` it is, if that one was
+/// unplugged and is still waiting for it, returning the handle it carries on under.
+/// Otherwise the drive is handed back along with the error.
+pub(crate) fn reattach_drive(drive : AcquiredDrive, candidates : &[NxFatdriveDeviceHandle]) -> Result<NxFatdriveDeviceHandle, (AcquiredDrive, u32)> {
+    if candidates.is_empty() {
+        return Err((drive, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND));
+    }
+    let usb_serial = match drive.usb_serial() {
+        Some(serial) => serial,
         None => {
-            return Err(NX_FATDRIVE_ERR_NOT_INITIALIZED);
+            return Err((drive, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND));
         }
     };
+    let AcquiredDrive { device, connection } = drive;
+    for &handle in candidates {
+        let shared = match get_drive(handle) {
+            Ok(s) => s,
+            Err(_) => { continue; }
+        };
+        let mut ctx = match shared.lock() {
+            Ok(c) => c,
+            Err(_) => { continue; }
+        };
+        let blocks : SharedBlockDevice = device.clone();
+        if ctx.resume(&usb_serial, blocks).is_ok() {
+            // The connection is only handed over once the drive has carried on with it.
+            ctx.usb = Some(connection);
+            return Ok(handle);
+        }
+    }
+    Err((AcquiredDrive { device, connection }, NX_FATDRIVE_ERR_DRIVE_NOT_FOUND))
+}
 
-    return Ok((fs, fs_ptr_guard))
+//---------------------------------------------------------------------------------
+/// Opens the first attached drive that is not already open, mounting the first
+/// partition on it that `options` selects, and stores its handle in `handle`.
+/// A null `options` uses the defaults from `nxFatdriveDefaultMountOptions`. The
+/// devoptab-only `device_prefix` and `cache_size` fields are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveOpenDevice(options : *const NxFatdriveMountOptions, handle : *mut NxFatdriveDeviceHandle) -> u32 {
+    let mut defaults : NxFatdriveMountOptions = mem::zeroed();
+    nxFatdriveDefaultMountOptions(&mut defaults);
+    let options = err_wrap!(MountOptions::from_raw(options.as_ref().unwrap_or(&defaults)));
+    let drive = err_wrap!(DriveContext::open_usb(&options));
+    *handle = err_wrap!(register_drive(drive));
+    SUCCESS
 }
 
-unsafe fn get_id_store<'a>() -> Result<(&'a mut IdStore, MutexGuard<'a, usize>), u32> {
-    let id_store_guard = id_store_ptr.lock().map_err(LibnxErrMapper::map)?;
-    let id_ptr_raw : usize = *id_store_guard;
-    let id_store = match (id_ptr_raw as *mut IdStore).as_mut() {
-        Some(r) => r,
+/// Closes a drive and every file and directory still open on it. Calls already in
+/// progress on the drive finish first. Closing a drive that was unplugged with
+/// writes still cached returns `NX_FATDRIVE_ERR_DATA_LOST`, but closes it all the same.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveCloseDevice(handle : NxFatdriveDeviceHandle) -> u32 {
+    let shared = match err_wrap!(open_drives.lock()).remove(&handle) {
+        Some(s) => s,
         None => {
-            return Err(NX_FATDRIVE_ERR_NOT_INITIALIZED);
+            return NX_FATDRIVE_ERR_DRIVE_NOT_FOUND;
         }
     };
-
-    Ok((id_store, id_store_guard))
+    // Whoever still holds the drive closes it when they let go; nothing is left to report to.
+    if let Ok(drive) = Arc::try_unwrap(shared) {
+        let drive = match drive.into_inner() {
+            Ok(d) => d,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        err_wrap!(drive.close());
+    }
+    SUCCESS
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveIsDeviceReady(device : NxFatdriveDeviceHandle) -> u32 {
+    err_wrap!(with_drive(device, |drive| drive.is_ready()));
+    SUCCESS
+}
 
-//---------------------------------------------------------------------------------
+/// Waits up to `timeout_ns` nanoseconds for `device`, which was unplugged, to be
+/// plugged back in, then carries on with it: files and directories open on it
+/// work again from where they were, and writes still cached when it went away are
+/// written out. Only the same volume on the same drive is taken for it, and only
+/// within the `reattach_grace_ms` it was opened with; after that this fails with
+/// `NX_FATDRIVE_ERR_DRIVE_DISCONNECTED`. Succeeds straight away if the drive was never unplugged.
 #[no_mangle]
-pub unsafe extern "C" fn usbFsIsInitialized() -> u32 {
-    let mut id_store_guard = err_wrap!(id_store_ptr.lock());
-    let mut usb_hs_ptr_guard = err_wrap!(usb_hs_ctx_ptr.lock());
-    let mut fs_ptr_guard = err_wrap!(fs_ptr.lock());
-    if *id_store_guard != 0 && *usb_hs_ptr_guard != 0 && *fs_ptr_guard != 0 {
-        SUCCESS
+pub unsafe extern "C" fn nxFatdriveReattachDevice(device : NxFatdriveDeviceHandle, timeout_ns : u64) -> u32 {
+    let waiting = err_wrap!(with_any_drive(device, |drive| Ok(drive.awaits_reattach())));
+    if !waiting {
+        err_wrap!(with_drive(device, |_| Ok(())));
+        return SUCCESS;
     }
-    else {
-        NX_FATDRIVE_ERR_NOT_INITIALIZED
+    let drive = err_wrap!(AcquiredDrive::acquire(timeout_ns));
+    // A different drive is released again on the way out.
+    err_wrap!(reattach_drive(drive, &[device]).map_err(|(_, e)| e));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveOpenFile(device : NxFatdriveDeviceHandle, fileid : *mut u64, filepath : *const u8, mode : u64) -> u32 {
+    let path = err_wrap!(path::parse_c_path(filepath));
+    let options = filesystem::OpenOptions::from_posix_flags(mode as u32);
+    *fileid = err_wrap!(with_drive(device, |drive| {
+        if options.modifies_volume() {
+            drive.check_writable()?;
+        }
+        drive.id_store.open_file(mounted(&drive.fs)?, &path, options)
+    }));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveCloseFile(device : NxFatdriveDeviceHandle, fileid : u64) -> u32 {
+    err_wrap!(with_any_drive(device, |drive| drive.id_store.close_file(fileid)));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveReadFile(device : NxFatdriveDeviceHandle, fileid : u64, buffer : *mut u8, size : usize, retsize : *mut usize) -> u32 {
+    if buffer.is_null() || retsize.is_null() {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
     }
+    let buf_slice = slice::from_raw_parts_mut(buffer, size);
+    *retsize = err_wrap!(with_drive(device, |drive| {
+        drive.id_store.with_file(mounted(&drive.fs)?, fileid, |file| file.read(buf_slice))
+    }));
+    SUCCESS
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn usbFsInitialize() -> u32 {
-    if usbFsIsInitialized() == 0 {
-        return 0;
+pub unsafe extern "C" fn nxFatdriveWriteFile(device : NxFatdriveDeviceHandle, fileid : u64, buffer : *const u8, size : usize, retsize : *mut usize) -> u32 {
+    if buffer.is_null() || retsize.is_null() {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
     }
+    let buf_slice = slice::from_raw_parts(buffer, size);
+    *retsize = err_wrap!(with_drive(device, |drive| {
+        drive.id_store.with_file(mounted(&drive.fs)?, fileid, |file| file.write(buf_slice))
+    }));
+    SUCCESS
+}
 
-    let mut usb_hs_ctx = err_wrap!(UsbHsContext::initialize());
-    let mut session = err_wrap!(wait_for_usb_drive(&mut usb_hs_ctx, 1000));
-    let mut ctx = UsbFsServiceContext { 
-        usb_hs_ctx, 
-        device_info : session.interface().info(),
+/// Moves the file position like `lseek`, with `whence` being 0, 1 or 2 for
+/// `SEEK_SET`, `SEEK_CUR` and `SEEK_END`.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveSeekFile(device : NxFatdriveDeviceHandle, fileid : u64, pos : i64, whence : u32, retpos : *mut u64) -> u32 {
+    let sf = match whence {
+        0 if pos >= 0 => SeekFrom::Start(pos as u64),
+        1 => SeekFrom::Current(pos),
+        2 => SeekFrom::End(pos),
+        _ => {
+            return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+        }
     };
-    let mut inner_device = err_wrap!(parse_drive(&mut ctx.usb_hs_ctx, session));
+    *retpos = err_wrap!(with_drive(device, |drive| {
+        drive.id_store.with_file(mounted(&drive.fs)?, fileid, |file| file.seek(sf))
+    }));
+    SUCCESS
+}
 
-    let ctx_ptr_nval = Box::into_raw(Box::new(ctx));
-    let mut usb_hs_ptr_guard = err_wrap!(usb_hs_ctx_ptr.lock());
-    *usb_hs_ptr_guard = ctx_ptr_nval as usize;
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveSyncFile(device : NxFatdriveDeviceHandle, fileid : u64) -> u32 {
+    err_wrap!(with_drive(device, |drive| {
+        drive.id_store.with_file(mounted(&drive.fs)?, fileid, |file| file.flush())
+    }));
+    SUCCESS
+}
 
-    let mut partition = err_wrap!(open_partition(inner_device, 0));
-    let mut fs = err_wrap!(fatfs::FileSystem::new(partition, fatfs::FsOptions::new()));
-    let fs_ptr_nval = Box::into_raw(Box::new(fs));
-    let mut fs_ptr_guard = err_wrap!(fs_ptr.lock());
-    *fs_ptr_guard = fs_ptr_nval as usize;
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveTruncateFile(device : NxFatdriveDeviceHandle, fileid : u64, size : u64) -> u32 {
+    err_wrap!(with_drive(device, |drive| {
+        drive.id_store.with_file(mounted(&drive.fs)?, fileid, |file| file.set_len(size))
+    }));
+    SUCCESS
+}
 
-    let mut store = IdStore::new();
-    let id_store_ptr_nval = Box::into_raw(Box::new(store));
-    let mut id_store_guard = err_wrap!(id_store_ptr.lock());
-    *id_store_guard = id_store_ptr_nval as usize;
-    
-    return SUCCESS;
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdrivePreallocateFile(device : NxFatdriveDeviceHandle, fileid : u64, size : u64, contiguous : bool) -> u32 {
+    err_wrap!(with_drive(device, |drive| {
+        drive.id_store.with_file(mounted(&drive.fs)?, fileid, |file| file.preallocate(size, contiguous))
+    }));
+    SUCCESS
 }
 
-use std::ops::Drop;
-use std::mem::drop;
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveDeleteFile(device : NxFatdriveDeviceHandle, filepath : *const u8) -> u32 {
+    let path = err_wrap!(path::parse_c_path(filepath));
+    err_wrap!(with_drive(device, |drive| {
+        drive.check_writable()?;
+        if drive.id_store.is_path_open(&path) {
+            return Err(NX_FATDRIVE_ERR_BUSY);
+        }
+        let mut root = mounted(&drive.fs)?.root().map_err(LibnxErrMapper::map)?;
+        split::remove_file_or_split(&mut root, &path).map_err(LibnxErrMapper::map)
+    }));
+    SUCCESS
+}
 
 #[no_mangle]
-pub unsafe extern "C" fn usbFsExit() {
-    use std::os::unix::fs::OpenOptionsExt;
-    use std::fs::OpenOptions;
-    let mut outfile = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .custom_flags(0x0080)
-        .open("UsbfsLog.txt"){
-            Ok(f) => f,
-            Err(_) => {
-                return;
+pub unsafe extern "C" fn nxFatdriveStatFile(device : NxFatdriveDeviceHandle, fileid : u64, size : *mut u64, mode : *mut u64) -> u32 {
+    let (nsize, nmode) = err_wrap!(with_drive(device, |drive| {
+        let path = drive.id_store.get_path_for_id(fileid)?.to_owned();
+        drive.id_store.stat_path(mounted(&drive.fs)?, &path)
+    }));
+    *size = nsize;
+    *mode = nmode;
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveStatPath(device : NxFatdriveDeviceHandle, path_ptr : *const u8, size : *mut u64, mode : *mut u64) -> u32 {
+    let path = err_wrap!(path::parse_c_path(path_ptr));
+    let (nsize, nmode) = err_wrap!(with_drive(device, |drive| drive.id_store.stat_path(mounted(&drive.fs)?, &path)));
+    *size = nsize;
+    *mode = nmode;
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveStatFilesystem(device : NxFatdriveDeviceHandle, totalsize : *mut u64, freesize : *mut u64) -> u32 {
+    let fsinfo = err_wrap!(with_drive(device, |drive| mounted(&drive.fs)?.stats().map_err(LibnxErrMapper::map)));
+    *totalsize = fsinfo.cluster_size * fsinfo.total_clusters;
+    *freesize = fsinfo.cluster_size * fsinfo.free_clusters;
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveOpenDir(device : NxFatdriveDeviceHandle, dirid : *mut u64, dirpath : *const u8) -> u32 {
+    let path = err_wrap!(path::parse_c_path(dirpath));
+    *dirid = err_wrap!(with_drive(device, |drive| drive.id_store.open_dir(mounted(&drive.fs)?, &path)));
+    SUCCESS
+}
+
+/// Reads the next entry of a directory. At the end of the directory `type_ptr` is
+/// set to 0xF and `name` to an empty string. Names longer than `namemax - 1` bytes
+/// are cut short. Every pointer must be non-null and `namemax` at least 1.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveReadDir(
+    device : NxFatdriveDeviceHandle,
+    dirid : u64,
+    type_ptr : *mut u64,
+    size : *mut u64,
+    name : *mut u8,
+    namemax : usize,
+) -> u32 {
+    if type_ptr.is_null() || size.is_null() || name.is_null() || namemax == 0 {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+    }
+    let current = err_wrap!(with_drive(device, |drive| drive.id_store.read_next_dirent(mounted(&drive.fs)?, dirid)));
+    match current {
+        Some(ent) => {
+            let bytes = ent.name.as_bytes();
+            let name_len = (namemax - 1).min(bytes.len());
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), name, name_len);
+            std::ptr::write(name.offset(name_len as isize), 0);
+            *type_ptr = ent.type_val;
+            *size = ent.size;
+        },
+        None => {
+            *type_ptr = 0xF;
+            *size = 0;
+            *name = 0;
+        }
+    }
+    SUCCESS
+}
+
+/// The size of `NxFatdriveDirEntry::name`, including the terminator.
+pub const NX_FATDRIVE_DIR_ENTRY_NAME_SIZE : usize = 256;
+
+/// One directory entry as returned by `nxFatdriveReadDirEntries`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NxFatdriveDirEntry {
+    /// NUL-terminated; longer names are cut short.
+    pub name : [u8; NX_FATDRIVE_DIR_ENTRY_NAME_SIZE],
+    /// The same type codes `nxFatdriveReadDir` reports.
+    pub entry_type : u64,
+    pub size : u64,
+    /// Seconds since the epoch, or 0 if the filesystem does not record the time.
+    /// FAT stores local time without a zone, so these are read as if it were UTC.
+    pub modified : i64,
+    pub created : i64,
+    pub accessed : i64,
+}
+
+impl NxFatdriveDirEntry {
+    fn from_dirent(ent : &DirEntryData) -> NxFatdriveDirEntry {
+        let mut name = [0u8; NX_FATDRIVE_DIR_ENTRY_NAME_SIZE];
+        let bytes = ent.name.as_bytes();
+        let name_len = (NX_FATDRIVE_DIR_ENTRY_NAME_SIZE - 1).min(bytes.len());
+        name[.. name_len].copy_from_slice(&bytes[.. name_len]);
+        NxFatdriveDirEntry {
+            name,
+            entry_type : ent.type_val,
+            size : ent.size,
+            modified : ent.modified,
+            created : ent.created,
+            accessed : ent.accessed,
+        }
+    }
+}
+
+/// Reads up to `count` entries of a directory into `entries` and stores how many
+/// were read in `retcount`. Fewer than `count` means the end of the directory was reached.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveReadDirEntries(
+    device : NxFatdriveDeviceHandle,
+    dirid : u64,
+    entries : *mut NxFatdriveDirEntry,
+    count : usize,
+    retcount : *mut usize,
+) -> u32 {
+    if (entries.is_null() && count != 0) || retcount.is_null() {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+    }
+    let read = err_wrap!(with_drive(device, |drive| {
+        let mut read = 0;
+        while read < count {
+            match drive.id_store.read_next_dirent(mounted(&drive.fs)?, dirid)? {
+                Some(ent) => {
+                    ptr::write(entries.offset(read as isize), NxFatdriveDirEntry::from_dirent(&ent));
+                    read += 1;
+                },
+                None => {
+                    break;
+                }
             }
-        };
-    outfile.write_fmt(format_args!("Entered USBFS exit.\n"));
-    outfile.flush();
+        }
+        Ok(read)
+    }));
+    *retcount = read;
+    SUCCESS
+}
 
-    let mut id_store_guard = match id_store_ptr.lock().map_err(LibnxErrMapper::map) {
-        Ok(p) => p, 
-        Err(_) => {
-            return;
+/// Starts reading a directory over from its first entry. The listing is read again,
+/// so entries created or removed since it was opened are picked up.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveRewindDir(device : NxFatdriveDeviceHandle, dirid : u64) -> u32 {
+    err_wrap!(with_drive(device, |drive| drive.id_store.rewind_dir(dirid)));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveCloseDir(device : NxFatdriveDeviceHandle, dirid : u64) -> u32 {
+    err_wrap!(with_any_drive(device, |drive| drive.id_store.close_dir(dirid)));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveCreateDir(device : NxFatdriveDeviceHandle, dirpath : *const u8) -> u32 {
+    let path = err_wrap!(path::parse_c_path(dirpath));
+    err_wrap!(with_drive(device, |drive| {
+        if drive.id_store.has_dir(&path).is_some() {
+            return Ok(());
         }
-    };
-    outfile.write_fmt(format_args!("Got ID store ptr of {}", *id_store_guard));
-    outfile.flush();
-    if *id_store_guard != 0 {
-        let id_store_ptr_inner = (*id_store_guard) as *mut IdStore;
-        let mut id_store_box = Box::from_raw(id_store_ptr_inner);
-        *id_store_guard = 0;
-        drop(id_store_box);
-    }
-    outfile.write_fmt(format_args!("Unmounted ID store."));
-    outfile.flush();
-
-    let mut fs_ptr_guard = match fs_ptr.lock().map_err(LibnxErrMapper::map) {
-        Ok(p) => p, 
-        Err(_) => {
-            return;
+        drive.check_writable()?;
+        let mut root = mounted(&drive.fs)?.root().map_err(LibnxErrMapper::map)?;
+        root.create_directory(&path).map(|_| ()).map_err(LibnxErrMapper::map)
+    }));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveDeleteDir(device : NxFatdriveDeviceHandle, dirpath : *const u8) -> u32 {
+    let path = err_wrap!(path::parse_c_path(dirpath));
+    err_wrap!(with_drive(device, |drive| {
+        drive.check_writable()?;
+        if drive.id_store.is_path_open(&path) {
+            return Err(NX_FATDRIVE_ERR_BUSY);
         }
-    };
-    outfile.write_fmt(format_args!("Got FS ptr of {}", *fs_ptr_guard));
-    outfile.flush();
-    if *fs_ptr_guard != 0 {
-        let fs_ptr_inner = (*fs_ptr_guard) as *mut FileSystem<OffsetScsiDevice>;
-        let mut fs_box = Box::from_raw(fs_ptr_inner);
-        *fs_ptr_guard = 0;
-        drop(fs_box);
-    }
-    outfile.write_fmt(format_args!("Unmounted FS ptr."));
-    outfile.flush();
-
-    let mut usb_hs_ptr_guard = match usb_hs_ctx_ptr.lock().map_err(LibnxErrMapper::map) {
-        Ok(p) => p, 
-        Err(_) => {
-            return;
+        let mut root = mounted(&drive.fs)?.root().map_err(LibnxErrMapper::map)?;
+        split::remove_empty_directory(&mut root, &path).map_err(LibnxErrMapper::map)
+    }));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveCreateFile(device : NxFatdriveDeviceHandle, filepath : *const u8) -> u32 {
+    let path = err_wrap!(path::parse_c_path(filepath));
+    err_wrap!(with_drive(device, |drive| {
+        if drive.id_store.has_file(&path).is_some() {
+            return Ok(());
         }
-    };
-    outfile.write_fmt(format_args!("Got USBHS_CTX ptr of {}", *usb_hs_ptr_guard));
-    outfile.flush();
-    if *usb_hs_ptr_guard != 0 {
-        let usb_hs_ptr_inner = (*usb_hs_ptr_guard) as *mut UsbFsServiceContext;
-        let mut usb_hs_box = Box::from_raw(usb_hs_ptr_inner);
-        *usb_hs_ptr_guard = 0;
-        drop(usb_hs_box);
+        drive.check_writable()?;
+        let mut root = mounted(&drive.fs)?.root().map_err(LibnxErrMapper::map)?;
+        root.create_file(&path).map(|_| ()).map_err(LibnxErrMapper::map)
+    }));
+    SUCCESS
+}
+
+/// Stores the drive's sector size, and how many sectors `scope` covers, where
+/// `scope` is one of the `NX_FATDRIVE_RAW_SCOPE_*` values.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveGetRawGeometry(device : NxFatdriveDeviceHandle, scope : u32, sector_size : *mut u32, sector_count : *mut u64) -> u32 {
+    let scope = err_wrap!(RawScope::from_raw(scope));
+    let (nsize, ncount) = err_wrap!(with_drive(device, |drive| {
+        let raw = drive.raw_device()?;
+        let (_, count) = raw.extent(&scope)?;
+        Ok((raw.sector_size(), count))
+    }));
+    *sector_size = nsize;
+    *sector_count = ncount;
+    SUCCESS
+}
+
+/// Reads `sectorcount` sectors, numbered within `scope`, from `sector` on into
+/// `buffer`, which must hold `sectorcount` times the sector size in bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveReadRaw(device : NxFatdriveDeviceHandle, scope : u32, sector : u64, sectorcount : u64, buffer : *mut u8) -> u32 {
+    if buffer.is_null() {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
     }
-    outfile.write_fmt(format_args!("Unmounted USBHS_CTX"));
-    outfile.flush();
+    let scope = err_wrap!(RawScope::from_raw(scope));
+    err_wrap!(with_drive(device, |drive| {
+        let raw = drive.raw_device()?;
+        let first = raw.locate(&scope, sector, sectorcount)?;
+        let buf_slice = slice::from_raw_parts_mut(buffer, (sectorcount * u64::from(raw.sector_size())) as usize);
+        drive.with_raw_range(first, sectorcount, false, |raw| raw.read_at(first, buf_slice))
+    }));
+    SUCCESS
 }
 
+/// Writes sectors like `nxFatdriveReadRaw` reads them. Fails with
+/// `NX_FATDRIVE_ERR_WRITE_PROTECTED` unless `nxFatdriveUnlockRawWrites` was called first.
 #[no_mangle]
-pub unsafe extern "C" fn usbFsIsReady() -> u32 {
-    let has_initted = usbFsIsInitialized();
-    if has_initted != SUCCESS {
-        return has_initted;
+pub unsafe extern "C" fn nxFatdriveWriteRaw(device : NxFatdriveDeviceHandle, scope : u32, sector : u64, sectorcount : u64, buffer : *const u8) -> u32 {
+    if buffer.is_null() {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
     }
+    let scope = err_wrap!(RawScope::from_raw(scope));
+    err_wrap!(with_drive(device, |drive| {
+        drive.check_writable()?;
+        let raw = drive.raw_device()?;
+        if !raw.writes_unlocked() {
+            return Err(NX_FATDRIVE_ERR_WRITE_PROTECTED);
+        }
+        let first = raw.locate(&scope, sector, sectorcount)?;
+        let buf_slice = slice::from_raw_parts(buffer, (sectorcount * u64::from(raw.sector_size())) as usize);
+        drive.with_raw_range(first, sectorcount, true, |raw| raw.write_at(first, buf_slice))
+    }));
+    SUCCESS
+}
 
-    let (mut ctx, ctx_guard) = err_wrap!(get_service_ctx());
-    let mut device_is_connected = false;
-    for iface in err_wrap!(ctx.usb_hs_ctx.query_acquired_interfaces(4)) {
-        if iface.info() == ctx.device_info {
-            device_is_connected = true;
+/// Allows raw writes to the drive if `unlocked` is true, or refuses them again if
+/// it is false. Drives start out refusing them.
+#[no_mangle]
+pub unsafe extern "C" fn nxFatdriveUnlockRawWrites(device : NxFatdriveDeviceHandle, unlocked : bool) -> u32 {
+    err_wrap!(with_drive(device, |drive| {
+        if unlocked {
+            drive.check_writable()?;
+        }
+        drive.raw_device()?.set_writes_unlocked(unlocked);
+        Ok(())
+    }));
+    SUCCESS
+}
+
+//---------------------------------------------------------------------------------
+// The usbFs* API works on a single drive, mounted by usbFsInitialize or by polling
+// usbFsDeviceUpdate.
+
+/// `usbFsGetMountStatus` values.
+pub const USBFS_STATE_NO_DEVICE : u32 = 0;
+pub const USBFS_STATE_DEVICE_PRESENT : u32 = 1;
+pub const USBFS_STATE_MOUNTED : u32 = 2;
+pub const USBFS_STATE_UNSUPPORTED_FS : u32 = 3;
+pub const USBFS_STATE_ERROR : u32 = 4;
+
+/// Where the drive behind the `usbFs*` functions is, as of the last update.
+enum DeviceState {
+    NoDevice,
+    /// A drive is attached; the next update tries to mount it.
+    DevicePresent,
+    Mounted(NxFatdriveDeviceHandle),
+    /// The drive has nothing we can mount. It stays acquired, so that updates only
+    /// have to notice it going away instead of trying it again.
+    UnsupportedFs(AcquiredDrive),
+    /// Mounting failed with this result. If the drive could be acquired it is held
+    /// on to like `UnsupportedFs`; otherwise the next update starts over.
+    Error(u32, Option<AcquiredDrive>),
+}
+
+impl DeviceState {
+    fn code(&self) -> u32 {
+        match self {
+            DeviceState::NoDevice => USBFS_STATE_NO_DEVICE,
+            DeviceState::DevicePresent => USBFS_STATE_DEVICE_PRESENT,
+            DeviceState::Mounted(_) => USBFS_STATE_MOUNTED,
+            DeviceState::UnsupportedFs(_) => USBFS_STATE_UNSUPPORTED_FS,
+            DeviceState::Error(_, _) => USBFS_STATE_ERROR,
         }
     }
-    if !device_is_connected {
-        return NX_FATDRIVE_ERR_DRIVE_DISCONNECTED;
+
+    fn mount_status(&self) -> u32 {
+        match self {
+            DeviceState::Mounted(_) => USBFS_MOUNTED,
+            DeviceState::UnsupportedFs(_) => USBFS_UNSUPPORTED_FS,
+            _ => USBFS_UNMOUNTED,
+        }
+    }
+
+    /// The mounted drive, or why there is none.
+    fn mounted(&self) -> Result<NxFatdriveDeviceHandle, u32> {
+        match self {
+            DeviceState::Mounted(handle) => Ok(*handle),
+            DeviceState::NoDevice | DeviceState::DevicePresent => Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND),
+            DeviceState::UnsupportedFs(_) => Err(NX_FATDRIVE_ERR_UNSUPPORTED_FS),
+            DeviceState::Error(e, _) => Err(*e),
+        }
     }
+}
+
+/// Whether a drive that failed to mount with `err` simply holds nothing we can
+/// read, as opposed to failing to respond.
+fn is_unsupported_fs(err : u32) -> bool {
+    let prefix = (err >> 8) & 0xFFFF_0000;
+    err == NX_FATDRIVE_ERR_UNSUPPORTED_FS
+        || err == NX_FATDRIVE_ERR_DRIVE_NOT_FOUND
+        || (err & 0xFF == NX_FATDRIVE_ERR_MODULE && prefix == NX_FATDRIVE_ERR_MBR_PREFIX)
+}
 
+struct UsbFsService {
+    usb_hs_ctx : UsbHsContext,
+    state : DeviceState,
+}
+
+impl UsbFsService {
+    fn start() -> Result<UsbFsService, u32> {
+        Ok(UsbFsService {
+            usb_hs_ctx : UsbHsContext::initialize().map_err(LibnxErrMapper::map)?,
+            state : DeviceState::NoDevice,
+        })
+    }
+
+    /// Moves the state machine one step along, returning whether the state changed.
+    unsafe fn update(&mut self) -> bool {
+        let before = self.state.code();
+        let next = match mem::replace(&mut self.state, DeviceState::NoDevice) {
+            DeviceState::Mounted(handle) => {
+                if nxFatdriveIsDeviceReady(handle) == NX_FATDRIVE_ERR_DRIVE_DISCONNECTED {
+                    nxFatdriveCloseDevice(handle);
+                    DeviceState::NoDevice
+                }
+                else {
+                    DeviceState::Mounted(handle)
+                }
+            },
+            DeviceState::UnsupportedFs(mut drive) => match drive.connection.is_attached() {
+                Ok(true) => DeviceState::UnsupportedFs(drive),
+                Ok(false) => DeviceState::NoDevice,
+                Err(e) => DeviceState::Error(e, None),
+            },
+            DeviceState::Error(err, Some(mut drive)) => match drive.connection.is_attached() {
+                Ok(true) => DeviceState::Error(err, Some(drive)),
+                Ok(false) => DeviceState::NoDevice,
+                Err(e) => DeviceState::Error(e, None),
+            },
+            DeviceState::DevicePresent => self.mount(),
+            DeviceState::NoDevice | DeviceState::Error(_, None) => {
+                match self.usb_hs_ctx.query_available_interfaces(mass_storage_filter(), 1) {
+                    Ok(ref available) if available.is_empty() => DeviceState::NoDevice,
+                    Ok(_) => DeviceState::DevicePresent,
+                    Err(e) => DeviceState::Error(LibnxErrMapper::map(e), None),
+                }
+            },
+        };
+        self.state = next;
+        self.state.code() != before
+    }
+
+    /// Mounts the first partition of the attached drive, the way `usbFsInitialize` always has.
+    unsafe fn mount(&mut self) -> DeviceState {
+        let mut raw_options : NxFatdriveMountOptions = mem::zeroed();
+        nxFatdriveDefaultMountOptions(&mut raw_options);
+        raw_options.timeout_ns = 1000;
+        raw_options.partition_select = NX_FATDRIVE_PARTITION_INDEX;
+        raw_options.partition_index = 0;
+        let options = match MountOptions::from_raw(&raw_options) {
+            Ok(o) => o,
+            Err(e) => {
+                return DeviceState::Error(e, None);
+            }
+        };
+        let drive = match AcquiredDrive::acquire(options.timeout) {
+            Ok(d) => d,
+            Err(e) => {
+                return DeviceState::Error(e, None);
+            }
+        };
+        match DriveContext::mount(drive, &options) {
+            Ok(ctx) => match register_drive(ctx) {
+                Ok(handle) => DeviceState::Mounted(handle),
+                Err(e) => DeviceState::Error(e, None),
+            },
+            Err((drive, e)) if is_unsupported_fs(e) => DeviceState::UnsupportedFs(drive),
+            Err((drive, e)) => DeviceState::Error(e, Some(drive)),
+        }
+    }
+}
+
+impl Drop for UsbFsService {
+    fn drop(&mut self) {
+        if let DeviceState::Mounted(handle) = self.state {
+            unsafe { nxFatdriveCloseDevice(handle) };
+        }
+    }
+}
+
+unsafe fn with_service<T, F : FnOnce(&mut UsbFsService) -> Result<T, u32>>(f : F) -> Result<T, u32> {
+    let service_guard = usbfs_service.lock().map_err(LibnxErrMapper::map)?;
+    match (*service_guard as *mut UsbFsService).as_mut() {
+        Some(service) => f(service),
+        None => Err(NX_FATDRIVE_ERR_NOT_INITIALIZED),
+    }
+}
+
+/// Like `with_service`, but starts the service first if it is not running.
+unsafe fn with_started_service<T, F : FnOnce(&mut UsbFsService) -> Result<T, u32>>(f : F) -> Result<T, u32> {
+    let mut service_guard = usbfs_service.lock().map_err(LibnxErrMapper::map)?;
+    if *service_guard == 0 {
+        *service_guard = Box::into_raw(Box::new(UsbFsService::start()?)) as usize;
+    }
+    f(&mut *(*service_guard as *mut UsbFsService))
+}
+
+fn default_device() -> Result<NxFatdriveDeviceHandle, u32> {
+    unsafe { with_service(|service| service.state.mounted()) }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn usbFsIsInitialized() -> u32 {
+    err_wrap!(default_device());
     SUCCESS
 }
 
+/// Mounts the first partition of an attached drive. If there is none, or it cannot
+/// be mounted, this fails, but `usbFsDeviceUpdate` keeps trying afterwards.
 #[no_mangle]
-pub unsafe extern "C" fn usbFsGetMountStatus(status: *mut u64) -> u32 {
-    return NX_FATDRIVE_ERR_NOT_IMPLEMENTED;
+pub unsafe extern "C" fn usbFsInitialize() -> u32 {
+    err_wrap!(with_started_service(|service| {
+        // A drive takes one update to be noticed and another to be mounted.
+        for _ in 0 .. 2 {
+            if service.state.mounted().is_ok() {
+                break;
+            }
+            service.update();
+        }
+        service.state.mounted().map(|_| ())
+    }));
+    SUCCESS
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn usbFsOpenFile(fileid: *mut u64, filepath: *const u8, _mode: u64) -> u32 {
-    let path : &str = match CStr::from_ptr(filepath as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
+pub unsafe extern "C" fn usbFsExit() {
+    let mut service_guard = match usbfs_service.lock() {
+        Ok(g) => g,
+        Err(_) => {
+            return;
         }
     };
+    if *service_guard != 0 {
+        drop(Box::from_raw(*service_guard as *mut UsbFsService));
+        *service_guard = 0;
+    }
+}
 
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let new_id = err_wrap!(id_store.open_file(path));
+#[no_mangle]
+pub unsafe extern "C" fn usbFsIsReady() -> u32 {
+    nxFatdriveIsDeviceReady(err_wrap!(default_device()))
+}
 
-    *fileid = new_id;
+/// Stores the state as of the last update, one of the `USBFS_STATE_*` values, in `status`.
+#[no_mangle]
+pub unsafe extern "C" fn usbFsGetMountStatus(status: *mut u64) -> u32 {
+    let code = err_wrap!(with_service(|service| Ok(service.state.code())));
+    if let Some(status_ref) = status.as_mut() {
+        *status_ref = code as u64;
+    }
     SUCCESS
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn usbFsOpenFile(fileid: *mut u64, filepath: *const u8, mode: u64) -> u32 {
+    nxFatdriveOpenFile(err_wrap!(default_device()), fileid, filepath, mode)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn usbFsCloseFile(fileid: u64) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    err_wrap!(id_store.close_file(fileid));
-    SUCCESS
+    nxFatdriveCloseFile(err_wrap!(default_device()), fileid)
 }
 
 #[no_mangle]
@@ -293,12 +1006,7 @@ pub unsafe extern "C" fn usbFsReadFile(
     size: usize,
     retsize: *mut usize,
 ) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let mut buf_slice = slice::from_raw_parts_mut(buffer, size);
-    let file = err_wrap!(id_store.get_file_handle(fileid));
-    let sz = err_wrap!(file.read(&mut buf_slice));
-    *retsize = sz;
-    SUCCESS
+    nxFatdriveReadFile(err_wrap!(default_device()), fileid, buffer, size, retsize)
 }
 
 #[no_mangle]
@@ -308,126 +1016,56 @@ pub unsafe extern "C" fn usbFsWriteFile(
     size: usize,
     retsize: *mut usize,
 ) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let mut buf_slice = slice::from_raw_parts_mut(buffer, size);
-    let file = err_wrap!(id_store.get_file_handle(fileid));
-    let sz = err_wrap!(file.write(&mut buf_slice));
-    *retsize = sz;
-    SUCCESS
+    nxFatdriveWriteFile(err_wrap!(default_device()), fileid, buffer, size, retsize)
 }
 
+/// `pos` is reinterpreted as signed, so relative seeks can go backwards.
 #[no_mangle]
 pub unsafe extern "C" fn usbFsSeekFile(fileid: u64, pos: u64, whence: u64, retpos: *mut u64) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let file = err_wrap!(id_store.get_file_handle(fileid));
-
-    let sf = match whence {
-        0 => SeekFrom::Start(pos),
-        1 => {
-            let rel : i64 = if pos > i64::max_value() as u64 {
-                -1 * ((u64::max_value() - pos) as i64) //assume 2's complement
-            } else { pos as i64 };
-            SeekFrom::Current(rel)
-        }, 
-        2 => {
-            let rel : i64 = if pos > i64::max_value() as u64 {
-                -1 * ((u64::max_value() - pos) as i64) //assume 2's complement
-            } else { pos as i64 };
-            SeekFrom::End(rel)
-        },
-        _ => {
-            return (NX_FATDRIVE_ERR_NOT_IMPLEMENTED << 8) + NX_FATDRIVE_ERR_MODULE;
-        }
-    };
-
-    let retval = err_wrap!(file.seek(sf));
-    *retpos = retval;
-    SUCCESS
+    if whence > 2 {
+        return LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+    }
+    nxFatdriveSeekFile(err_wrap!(default_device()), fileid, pos as i64, whence as u32, retpos)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsSyncFile(fileid: u64) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let file = err_wrap!(id_store.get_file_handle(fileid));
-    err_wrap!(file.flush());
-    SUCCESS
+    nxFatdriveSyncFile(err_wrap!(default_device()), fileid)
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn usbFsTruncateFile(fileid: u64, _size: u64) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let file = err_wrap!(id_store.get_file_handle(fileid));
-    err_wrap!(file.truncate());
-    SUCCESS
+pub unsafe extern "C" fn usbFsTruncateFile(fileid: u64, size: u64) -> u32 {
+    nxFatdriveTruncateFile(err_wrap!(default_device()), fileid, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn usbFsPreallocateFile(fileid: u64, size: u64, contiguous: bool) -> u32 {
+    nxFatdrivePreallocateFile(err_wrap!(default_device()), fileid, size, contiguous)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsDeleteFile(filepath: *const u8) -> u32 {
-    let path : &str = match CStr::from_ptr(filepath as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
-        }
-    };
-    let (mut id_store, _guard) = err_wrap!(get_id_store());
-    if let Some(old_id) = id_store.has_file(&path.to_owned()) {
-        err_wrap!(id_store.close_file(old_id));
-    }
-    let (fs, _guard) = err_wrap!(get_filesystem());
-    err_wrap!(fs.root_dir().remove(path));
-    SUCCESS
+    nxFatdriveDeleteFile(err_wrap!(default_device()), filepath)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsStatFile(fileid: u64, size: *mut u64, mode: *mut u64) -> u32 {
-    let (id_store, guard) = err_wrap!(get_id_store());
-    let path =  err_wrap!(id_store.get_path_for_id(fileid));
-    let (nsize, nmode) = err_wrap!(id_store.stat_path(path));
-    *size = nsize;
-    *mode = nmode;
-    SUCCESS
+    nxFatdriveStatFile(err_wrap!(default_device()), fileid, size, mode)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsStatPath(path_ptr: *const u8, size: *mut u64, mode: *mut u64) -> u32 {
-    let path : &str = match CStr::from_ptr(path_ptr as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
-        }
-    };
-    let (id_store, guard) = err_wrap!(get_id_store());
-    let (nsize, nmode) = err_wrap!(id_store.stat_path(path));
-    *size = nsize;
-    *mode = nmode;
-    SUCCESS
+    nxFatdriveStatPath(err_wrap!(default_device()), path_ptr, size, mode)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsStatFilesystem(totalsize: *mut u64, freesize: *mut u64) -> u32 {
-    let (fs, _guard) = err_wrap!(get_filesystem());
-    let fsinfo = err_wrap!(fs.stats());
-    let tsize = fsinfo.cluster_size() as u64 * fsinfo.total_clusters() as u64;
-    let fsize = fsinfo.cluster_size() as u64 * fsinfo.free_clusters() as u64;
-    *totalsize = tsize;
-    *freesize = fsize;
-    SUCCESS
+    nxFatdriveStatFilesystem(err_wrap!(default_device()), totalsize, freesize)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsOpenDir(dirid: *mut u64, dirpath: *const u8) -> u32 {
-    let path : &str = match CStr::from_ptr(dirpath as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
-        }
-    };
-
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let new_id = err_wrap!(id_store.open_dir(path));
-
-    *dirid = new_id;
-    SUCCESS
+    nxFatdriveOpenDir(err_wrap!(default_device()), dirid, dirpath)
 }
 
 #[no_mangle]
@@ -438,110 +1076,860 @@ pub unsafe extern "C" fn usbFsReadDir(
     name: *mut u8,
     namemax: usize,
 ) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    let current = err_wrap!(id_store.read_next_dirent(dirid));
-    match current {
-        Some(ent) => {
-            let bytes = ent.name.as_bytes();
-            let name_len = (namemax - 1).min(bytes.len());
-            std::ptr::copy_nonoverlapping(bytes.as_ptr(), name, name_len);
-            std::ptr::write(name.offset(name_len as isize), 0);
-            *type_ptr = ent.type_val;
-            *size = ent.size;
-        },
-        None => {
-            *type_ptr = 0xF;
-            *size = 0;
-            *name = 0;
-        }
-    }
-    SUCCESS
+    nxFatdriveReadDir(err_wrap!(default_device()), dirid, type_ptr, size, name, namemax)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn usbFsReadDirEntries(
+    dirid: u64,
+    entries: *mut NxFatdriveDirEntry,
+    count: usize,
+    retcount: *mut usize,
+) -> u32 {
+    nxFatdriveReadDirEntries(err_wrap!(default_device()), dirid, entries, count, retcount)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn usbFsRewindDir(dirid: u64) -> u32 {
+    nxFatdriveRewindDir(err_wrap!(default_device()), dirid)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsCloseDir(dirid: u64) -> u32 {
-    let (id_store, _guard) = err_wrap!(get_id_store());
-    err_wrap!(id_store.close_dir(dirid));
-    SUCCESS
+    nxFatdriveCloseDir(err_wrap!(default_device()), dirid)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsCreateDir(dirpath: *const u8) -> u32 {
-    let path : &str = match CStr::from_ptr(dirpath as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
-        }
-    };
-    let (mut id_store, _guard) = err_wrap!(get_id_store());
-    if let Some(old_id) = id_store.has_dir(&path.to_owned()) {
-        return SUCCESS;
-    }
-    let (mut fs, _guard) = err_wrap!(get_filesystem());
-    err_wrap!(fs.root_dir().create_dir(path));
-    SUCCESS
+    nxFatdriveCreateDir(err_wrap!(default_device()), dirpath)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsDeleteDir(dirpath: *const u8) -> u32 {
-    let path : &str = match CStr::from_ptr(dirpath as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
-        }
-    };
-    let (mut id_store, _guard) = err_wrap!(get_id_store());
-    if let Some(old_id) = id_store.has_dir(&path.to_owned()) {
-        err_wrap!(id_store.close_dir(old_id));
-    }
-    let (fs, _guard) = err_wrap!(get_filesystem());
-    err_wrap!(fs.root_dir().remove(path));
-    SUCCESS
+    nxFatdriveDeleteDir(err_wrap!(default_device()), dirpath)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn usbFsCreateFile(filepath: *const u8) -> u32 {
-    let path : &str = match CStr::from_ptr(filepath as *const std::os::raw::c_char).to_str() {
-        Ok(s) => s,
-        Err(_e) => {
-            return NX_FATDRIVE_ERR_UNKNOWN;
-        }
-    };
-    let (mut id_store, _guard) = err_wrap!(get_id_store());
-    if let Some(old_id) = id_store.has_file(&path.to_owned()) {
-        return SUCCESS;
-    }
-    let (mut fs, _guard) = err_wrap!(get_filesystem());
-    err_wrap!(fs.root_dir().create_file(path));
-    SUCCESS
+    nxFatdriveCreateFile(err_wrap!(default_device()), filepath)
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn usbFsReadRaw(sector: u64, sectorcount: u64, buffer: *const u8) -> u32 {
-    return NX_FATDRIVE_ERR_NOT_IMPLEMENTED;
+pub unsafe extern "C" fn usbFsGetRawGeometry(scope : u32, sector_size : *mut u32, sector_count : *mut u64) -> u32 {
+    nxFatdriveGetRawGeometry(err_wrap!(default_device()), scope, sector_size, sector_count)
 }
 
-// Register "usbhdd:" device
 #[no_mangle]
-pub unsafe extern "C" fn usbFsDeviceRegister() {
+pub unsafe extern "C" fn usbFsReadRaw(scope : u32, sector : u64, sectorcount : u64, buffer : *mut u8) -> u32 {
+    nxFatdriveReadRaw(err_wrap!(default_device()), scope, sector, sectorcount, buffer)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn usbFsWriteRaw(scope : u32, sector : u64, sectorcount : u64, buffer : *const u8) -> u32 {
+    nxFatdriveWriteRaw(err_wrap!(default_device()), scope, sector, sectorcount, buffer)
+}
 
+#[no_mangle]
+pub unsafe extern "C" fn usbFsUnlockRawWrites(unlocked : bool) -> u32 {
+    nxFatdriveUnlockRawWrites(err_wrap!(default_device()), unlocked)
 }
 
-// Keep calling update periodically to check USB drive ready
-// Returns 1 if status changed
+/// Starts watching for a drive without waiting for one. Call `usbFsDeviceUpdate`
+/// periodically afterwards to have it mounted once it is attached.
+#[no_mangle]
+pub unsafe extern "C" fn usbFsDeviceRegister() {
+    let _ = with_started_service(|_| Ok(()));
+}
+
+/// Checks whether the drive was attached, mounted or removed since the last call.
+/// Keep calling this periodically; it returns 1 if the state changed and 0 otherwise.
 #[no_mangle]
 pub unsafe extern "C" fn usbFsDeviceUpdate() -> u32 {
-    usbFsIsReady()
+    match with_service(|service| Ok(service.update())) {
+        Ok(true) => 1,
+        _ => 0,
+    }
 }
 
-// If status changed, check mount status
-// Returns 0 == USBFS_UNMOUNTED, 1 == USBFS_MOUNTED, 2 == USBFS_UNSUPPORTED_FS
+pub const USBFS_UNMOUNTED : u32 = 0;
+pub const USBFS_MOUNTED : u32 = 1;
+pub const USBFS_UNSUPPORTED_FS : u32 = 2;
+
+/// Returns `USBFS_MOUNTED`, `USBFS_UNSUPPORTED_FS` or `USBFS_UNMOUNTED` for the
+/// state as of the last update.
 #[no_mangle]
 pub unsafe extern "C" fn usbFsDeviceGetMountStatus() -> u32 {
-    if usbFsIsReady() != 0 {
-        0
+    with_service(|service| Ok(service.state.mount_status())).unwrap_or(USBFS_UNMOUNTED)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use filesystem::{O_RDONLY, O_WRONLY, O_RDWR, O_APPEND, O_CREAT, O_TRUNC, O_EXCL, ATTR_ARCHIVE};
+    use buf_scsi::OffsetScsiDevice;
+    use image_device::ImageDevice;
+    use mbr_nostd::{PartitionTableEntry, PartitionType};
+    use std::ffi::CString;
+    use std::io::Cursor;
+
+    const IMAGE_SIZE : usize = 8 * 1024 * 1024;
+    const RAW_PARTITION_START : usize = 2048;
+    /// The USB serial number unpluggable images claim to have.
+    const IMAGE_SERIAL : &str = "IMAGE0001";
+
+    /// A freshly formatted image whose filesystem starts `RAW_PARTITION_START` sectors in.
+    pub(crate) fn unpluggable_image() -> Arc<Mutex<ImageDevice<Cursor<Vec<u8>>>>> {
+        let partition_sectors = IMAGE_SIZE / 512 - RAW_PARTITION_START;
+        let mut volume = Cursor::new(vec![0u8; partition_sectors * 512]);
+        fatfs::format_volume(&mut volume, fatfs::FormatVolumeOptions::new()).unwrap();
+        let mut image = vec![0u8; RAW_PARTITION_START * 512];
+        image.extend_from_slice(volume.get_ref());
+        Arc::new(Mutex::new(ImageDevice::new(Cursor::new(image))))
+    }
+
+    /// A drive with raw access for the filesystem on `image`, which waits `reattach_grace`
+    /// for `image` to come back once it is disconnected.
+    pub(crate) fn image_context(image : &Arc<Mutex<ImageDevice<Cursor<Vec<u8>>>>>, reattach_grace : Duration) -> Result<DriveContext, u32> {
+        let partition_sectors = IMAGE_SIZE / 512 - RAW_PARTITION_START;
+        let partition = PartitionTableEntry::new(PartitionType::Fat16(0x06), RAW_PARTITION_START as u32, partition_sectors as u32);
+        let device : SharedBlockDevice = image.clone();
+        let mut raw = RawDevice::new(device, partition, Backend::FatfsRs);
+        let fs = raw.mount()?;
+        let mut ctx = DriveContext::new(fs, false);
+        ctx.identity = Some(DriveIdentity { usb_serial : IMAGE_SERIAL.to_owned(), volume_serial : raw.volume_serial()? });
+        ctx.reattach_grace = reattach_grace;
+        Ok(ctx.with_raw_device(raw))
+    }
+
+    /// A freshly formatted image registered as a drive, closed on drop.
+    struct ImageDrive {
+        handle : NxFatdriveDeviceHandle,
+    }
+
+    impl ImageDrive {
+        fn new(read_only : bool) -> ImageDrive {
+            let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+            fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+            let partition = PartitionTableEntry::new(PartitionType::Fat16(0x06), 0, (IMAGE_SIZE / 512) as u32);
+            let device = OffsetScsiDevice::new(ImageDevice::new(image), 0);
+            let fs = FileSystem::Fatfs(FileSystemOps::from_device(device, partition).unwrap());
+            ImageDrive { handle : register_drive(DriveContext::new(fs, read_only)).unwrap() }
+        }
+
+        /// A drive whose filesystem starts `RAW_PARTITION_START` sectors in, with raw access.
+        fn with_raw_access() -> ImageDrive {
+            ImageDrive::unpluggable().0
+        }
+
+        /// Like `with_raw_access`, also handing back the image so it can be unplugged.
+        fn unpluggable() -> (ImageDrive, Arc<Mutex<ImageDevice<Cursor<Vec<u8>>>>>) {
+            let image = unpluggable_image();
+            let ctx = image_context(&image, Duration::from_secs(60)).unwrap();
+            (ImageDrive { handle : register_drive(ctx).unwrap() }, image)
+        }
+
+        /// The first sector of the partition whose contents start with `needle`.
+        fn find_sector(&self, needle : &[u8]) -> Option<u64> {
+            let mut sector = [0u8; 512];
+            let mut count = 0;
+            let mut sector_size = 0;
+            unsafe {
+                assert_eq!(nxFatdriveGetRawGeometry(self.handle, NX_FATDRIVE_RAW_SCOPE_PARTITION, &mut sector_size, &mut count), SUCCESS);
+            }
+            assert_eq!(sector_size, 512);
+            (0 .. count).find(|&idx| {
+                unsafe {
+                    assert_eq!(nxFatdriveReadRaw(self.handle, NX_FATDRIVE_RAW_SCOPE_PARTITION, idx, 1, sector.as_mut_ptr()), SUCCESS);
+                }
+                sector.starts_with(needle)
+            })
+        }
+
+        fn write_file(&self, path : &str, contents : &[u8]) {
+            let path = CString::new(path).unwrap();
+            let mut fileid = 0;
+            let mut written = 0;
+            unsafe {
+                assert_eq!(nxFatdriveOpenFile(self.handle, &mut fileid, path.as_ptr() as *const u8, (O_WRONLY | O_CREAT | O_TRUNC) as u64), SUCCESS);
+                assert_eq!(nxFatdriveWriteFile(self.handle, fileid, contents.as_ptr(), contents.len(), &mut written), SUCCESS);
+                assert_eq!(nxFatdriveCloseFile(self.handle, fileid), SUCCESS);
+            }
+            assert_eq!(written, contents.len());
+        }
+
+        fn read_file(&self, path : &str) -> Vec<u8> {
+            let path = CString::new(path).unwrap();
+            let mut fileid = 0;
+            let mut retval = Vec::new();
+            let mut buffer = [0u8; 100];
+            unsafe {
+                assert_eq!(nxFatdriveOpenFile(self.handle, &mut fileid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+                loop {
+                    let mut read = 0;
+                    assert_eq!(nxFatdriveReadFile(self.handle, fileid, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+                    if read == 0 {
+                        break;
+                    }
+                    retval.extend_from_slice(&buffer[.. read]);
+                }
+                assert_eq!(nxFatdriveCloseFile(self.handle, fileid), SUCCESS);
+            }
+            retval
+        }
+    }
+
+    impl Drop for ImageDrive {
+        fn drop(&mut self) {
+            unsafe { nxFatdriveCloseDevice(self.handle) };
+        }
+    }
+
+    #[test]
+    fn copies_between_two_open_drives() {
+        let source = ImageDrive::new(false);
+        let dest = ImageDrive::new(false);
+        assert_ne!(source.handle, dest.handle);
+
+        let contents : Vec<u8> = (0 .. 1000u32).map(|n| (n % 251) as u8).collect();
+        source.write_file("/data.bin", &contents);
+
+        let path = CString::new("/data.bin").unwrap();
+        let (mut srcid, mut dstid) = (0, 0);
+        let mut buffer = [0u8; 64];
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(source.handle, &mut srcid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveOpenFile(dest.handle, &mut dstid, path.as_ptr() as *const u8, (O_WRONLY | O_CREAT) as u64), SUCCESS);
+            loop {
+                let (mut read, mut written) = (0, 0);
+                assert_eq!(nxFatdriveReadFile(source.handle, srcid, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+                if read == 0 {
+                    break;
+                }
+                assert_eq!(nxFatdriveWriteFile(dest.handle, dstid, buffer.as_ptr(), read, &mut written), SUCCESS);
+                assert_eq!(written, read);
+            }
+            assert_eq!(nxFatdriveCloseFile(source.handle, srcid), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(dest.handle, dstid), SUCCESS);
+        }
+        assert_eq!(dest.read_file("/data.bin"), contents);
+    }
+
+    #[test]
+    fn file_ids_are_scoped_to_their_drive() {
+        let first = ImageDrive::new(false);
+        let second = ImageDrive::new(false);
+        first.write_file("/only_on_first.txt", b"first");
+
+        let path = CString::new("/only_on_first.txt").unwrap();
+        let mut fileid = 0;
+        let (mut size, mut mode) = (0, 0);
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(first.handle, &mut fileid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_ne!(nxFatdriveCloseFile(second.handle, fileid), SUCCESS);
+            assert_ne!(nxFatdriveStatPath(second.handle, path.as_ptr() as *const u8, &mut size, &mut mode), SUCCESS);
+            assert_eq!(nxFatdriveStatFile(first.handle, fileid, &mut size, &mut mode), SUCCESS);
+            assert_eq!(size, 5);
+            assert_eq!(nxFatdriveCloseFile(first.handle, fileid), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn closed_drive_handles_are_rejected() {
+        let drive = ImageDrive::new(false);
+        let handle = drive.handle;
+        drop(drive);
+
+        let path = CString::new("/").unwrap();
+        let mut dirid = 0;
+        unsafe {
+            assert_eq!(nxFatdriveIsDeviceReady(handle), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+            assert_eq!(nxFatdriveOpenDir(handle, &mut dirid, path.as_ptr() as *const u8), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+            assert_eq!(nxFatdriveCloseDevice(handle), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+            assert_eq!(nxFatdriveCloseDevice(0), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn stale_ids_are_rejected_after_their_slot_is_reused() {
+        let drive = ImageDrive::new(false);
+        drive.write_file("/first.txt", b"first");
+        drive.write_file("/second.txt", b"second");
+
+        let first = CString::new("/first.txt").unwrap();
+        let second = CString::new("/second.txt").unwrap();
+        let root = CString::new("/").unwrap();
+        let (mut stale, mut fresh, mut dirid) = (0, 0, 0);
+        let mut buffer = [0u8; 16];
+        let mut read = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut stale, first.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, stale), SUCCESS);
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fresh, second.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_ne!(stale, fresh);
+
+            assert_eq!(nxFatdriveReadFile(drive.handle, stale, buffer.as_mut_ptr(), buffer.len(), &mut read), NX_FATDRIVE_ERR_INVALID_HANDLE);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, stale), NX_FATDRIVE_ERR_INVALID_HANDLE);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fresh, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"second");
+
+            assert_eq!(nxFatdriveOpenDir(drive.handle, &mut dirid, root.as_ptr() as *const u8), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, dirid), NX_FATDRIVE_ERR_INVALID_HANDLE);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, fresh), NX_FATDRIVE_ERR_INVALID_HANDLE);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, dirid), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fresh), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn each_open_gets_its_own_descriptor() {
+        let drive = ImageDrive::new(false);
+        drive.write_file("/shared.txt", b"0123456789");
+
+        let path = CString::new("/shared.txt").unwrap();
+        let (mut first, mut second, mut appender) = (0, 0, 0);
+        let mut buffer = [0u8; 32];
+        let (mut read, mut written, mut pos) = (0, 0, 0);
+        let (mut size, mut mode) = (0, 0);
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut first, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut second, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut appender, path.as_ptr() as *const u8, (O_WRONLY | O_APPEND) as u64), SUCCESS);
+            assert_ne!(first, second);
+            assert_ne!(second, appender);
+
+            assert_eq!(nxFatdriveReadFile(drive.handle, first, buffer.as_mut_ptr(), 4, &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"0123");
+            assert_eq!(nxFatdriveReadFile(drive.handle, second, buffer.as_mut_ptr(), 2, &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"01");
+            assert_eq!(nxFatdriveReadFile(drive.handle, first, buffer.as_mut_ptr(), 2, &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"45");
+
+            // The other descriptors pick up the appended data and the new size.
+            assert_eq!(nxFatdriveWriteFile(drive.handle, appender, b"abc".as_ptr(), 3, &mut written), SUCCESS);
+            assert_eq!(nxFatdriveStatFile(drive.handle, first, &mut size, &mut mode), SUCCESS);
+            assert_eq!(size, 13);
+            assert_eq!(nxFatdriveReadFile(drive.handle, second, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"23456789abc");
+
+            // Each descriptor keeps the mode it was opened with.
+            assert_ne!(nxFatdriveWriteFile(drive.handle, first, b"x".as_ptr(), 1, &mut written), SUCCESS);
+
+            assert_eq!(nxFatdriveCloseFile(drive.handle, first), SUCCESS);
+            assert_eq!(nxFatdriveSeekFile(drive.handle, second, 0, 0, &mut pos), SUCCESS);
+            assert_eq!(nxFatdriveReadFile(drive.handle, second, buffer.as_mut_ptr(), 1, &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"0");
+            assert_eq!(nxFatdriveCloseFile(drive.handle, second), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, appender), SUCCESS);
+        }
+        assert_eq!(drive.read_file("/shared.txt"), b"0123456789abc".to_vec());
+    }
+
+    #[test]
+    fn open_flags_are_honoured() {
+        let drive = ImageDrive::new(false);
+        let path = CString::new("/flags.txt").unwrap();
+        let not_found = LibnxErrMapper::map(std::io::Error::from(ErrorKind::NotFound));
+        let exists = LibnxErrMapper::map(std::io::Error::from(ErrorKind::AlreadyExists));
+        let mut fileid = 0;
+        let mut buffer = [0u8; 16];
+        let (mut read, mut written) = (0, 0);
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_RDONLY as u64), not_found);
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_RDWR | O_CREAT | O_EXCL) as u64), SUCCESS);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"created".as_ptr(), 7, &mut written), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_RDWR | O_CREAT | O_EXCL) as u64), exists);
+
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_ne!(nxFatdriveWriteFile(drive.handle, fileid, b"x".as_ptr(), 1, &mut written), SUCCESS);
+            assert_ne!(nxFatdriveTruncateFile(drive.handle, fileid, 0), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_WRONLY as u64), SUCCESS);
+            assert_ne!(nxFatdriveReadFile(drive.handle, fileid, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_RDWR | O_TRUNC) as u64), SUCCESS);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileid, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+            assert_eq!(read, 0);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+        assert_eq!(drive.read_file("/flags.txt"), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn truncating_sets_the_length_and_keeps_the_position() {
+        let drive = ImageDrive::new(false);
+        drive.write_file("/len.bin", b"abcdef");
+
+        let path = CString::new("/len.bin").unwrap();
+        let mut fileid = 0;
+        let mut buffer = [0xFFu8; 16];
+        let (mut read, mut pos) = (0, 0);
+        let (mut size, mut mode) = (0, 0);
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_RDWR as u64), SUCCESS);
+            assert_eq!(nxFatdriveSeekFile(drive.handle, fileid, 2, 0, &mut pos), SUCCESS);
+            assert_eq!(nxFatdriveTruncateFile(drive.handle, fileid, 4), SUCCESS);
+            assert_eq!(nxFatdriveStatFile(drive.handle, fileid, &mut size, &mut mode), SUCCESS);
+            assert_eq!(size, 4);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileid, buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"cd");
+
+            assert_eq!(nxFatdriveTruncateFile(drive.handle, fileid, 8), SUCCESS);
+            assert_eq!(nxFatdriveSeekFile(drive.handle, fileid, 0, 1, &mut pos), SUCCESS);
+            assert_eq!(pos, 4);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+        assert_eq!(drive.read_file("/len.bin"), b"abcd\0\0\0\0".to_vec());
+    }
+
+    #[test]
+    fn preallocation_reports_what_the_backend_cannot_do() {
+        let drive = ImageDrive::new(false);
+        let path = CString::new("/reserved.bin").unwrap();
+        let (mut size, mut mode) = (0, 0);
+        let mut fileid = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_RDWR | O_CREAT) as u64), SUCCESS);
+            assert_eq!(nxFatdrivePreallocateFile(drive.handle, fileid, 4096, true), NX_FATDRIVE_ERR_NOT_IMPLEMENTED);
+            assert_eq!(nxFatdrivePreallocateFile(drive.handle, fileid, 4096, false), SUCCESS);
+            assert_eq!(nxFatdriveStatFile(drive.handle, fileid, &mut size, &mut mode), SUCCESS);
+            assert_eq!(size, 4096);
+            assert_eq!(nxFatdrivePreallocateFile(drive.handle, fileid, u64::from(u32::max_value()), false), NX_FATDRIVE_ERR_NO_SPACE);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn deleting_files_and_directories_checks_what_they_are() {
+        let drive = ImageDrive::new(false);
+        let not_found = LibnxErrMapper::map(std::io::Error::from(ErrorKind::NotFound));
+        let path = |p : &str| CString::new(p).unwrap();
+        let (file, upper_file, missing) = (path("/file.txt"), path("/FILE.TXT"), path("/missing"));
+        let (dir, full, root) = (path("/dir"), path("/full"), path("/"));
+        let (mut fileid, mut dirid) = (0, 0);
+        let (mut size, mut mode) = (0, 0);
+        unsafe {
+            assert_eq!(nxFatdriveCreateDir(drive.handle, dir.as_ptr() as *const u8), SUCCESS);
+            assert_eq!(nxFatdriveCreateDir(drive.handle, full.as_ptr() as *const u8), SUCCESS);
+        }
+        drive.write_file("/file.txt", b"file");
+        drive.write_file("/full/x.txt", b"x");
+        unsafe {
+            assert_eq!(nxFatdriveDeleteFile(drive.handle, dir.as_ptr() as *const u8), NX_FATDRIVE_ERR_IS_A_DIRECTORY);
+            assert_eq!(nxFatdriveDeleteFile(drive.handle, missing.as_ptr() as *const u8), not_found);
+            assert_eq!(nxFatdriveDeleteDir(drive.handle, file.as_ptr() as *const u8), NX_FATDRIVE_ERR_NOT_A_DIRECTORY);
+            assert_eq!(nxFatdriveDeleteDir(drive.handle, full.as_ptr() as *const u8), NX_FATDRIVE_ERR_DIRECTORY_NOT_EMPTY);
+            assert_eq!(nxFatdriveDeleteDir(drive.handle, root.as_ptr() as *const u8), NX_FATDRIVE_ERR_BUSY);
+
+            // Open handles keep their entry, whatever case they were opened with.
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, upper_file.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveDeleteFile(drive.handle, file.as_ptr() as *const u8), NX_FATDRIVE_ERR_BUSY);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+            assert_eq!(nxFatdriveDeleteFile(drive.handle, file.as_ptr() as *const u8), SUCCESS);
+
+            assert_eq!(nxFatdriveOpenDir(drive.handle, &mut dirid, dir.as_ptr() as *const u8), SUCCESS);
+            assert_eq!(nxFatdriveDeleteDir(drive.handle, dir.as_ptr() as *const u8), NX_FATDRIVE_ERR_BUSY);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, dirid), SUCCESS);
+            assert_eq!(nxFatdriveDeleteDir(drive.handle, dir.as_ptr() as *const u8), SUCCESS);
+
+            assert_eq!(nxFatdriveStatPath(drive.handle, file.as_ptr() as *const u8, &mut size, &mut mode), not_found);
+            assert_eq!(nxFatdriveStatPath(drive.handle, dir.as_ptr() as *const u8, &mut size, &mut mode), not_found);
+        }
+    }
+
+    #[test]
+    fn split_files_stat_as_regular_files() {
+        let drive = ImageDrive::new(false);
+        with_drive(drive.handle, |ctx| {
+            let fs = mounted(&ctx.fs)?;
+            let mut split = split::SplitFile::create(fs.root().map_err(LibnxErrMapper::map)?, "/big.nsp", 16).map_err(LibnxErrMapper::map)?;
+            split.write_all(&[7u8; 40]).map_err(LibnxErrMapper::map)
+        }).unwrap();
+        let path = CString::new("/big.nsp").unwrap();
+        let (mut size, mut mode) = (0, 0);
+        let mut fileid = 0;
+        unsafe {
+            assert_eq!(nxFatdriveStatPath(drive.handle, path.as_ptr() as *const u8, &mut size, &mut mode), SUCCESS);
+            assert_eq!((size, mode), (40, u64::from(ATTR_ARCHIVE)));
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveStatFile(drive.handle, fileid, &mut size, &mut mode), SUCCESS);
+            assert_eq!((size, mode), (40, u64::from(ATTR_ARCHIVE)));
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn directory_entries_are_read_in_batches() {
+        let drive = ImageDrive::new(false);
+        drive.write_file("/a.txt", b"a");
+        drive.write_file("/b.txt", b"bb");
+        drive.write_file("/c.txt", b"ccc");
+
+        let root = CString::new("/").unwrap();
+        let mut dirid = 0;
+        let mut entries = [unsafe { mem::zeroed::<NxFatdriveDirEntry>() }; 2];
+        let mut read = 0;
+        let mut listed = Vec::new();
+        unsafe {
+            assert_eq!(nxFatdriveOpenDir(drive.handle, &mut dirid, root.as_ptr() as *const u8), SUCCESS);
+            loop {
+                assert_eq!(nxFatdriveReadDirEntries(drive.handle, dirid, entries.as_mut_ptr(), entries.len(), &mut read), SUCCESS);
+                for ent in &entries[.. read] {
+                    let len = ent.name.iter().position(|b| *b == 0).unwrap();
+                    listed.push((String::from_utf8(ent.name[.. len].to_vec()).unwrap(), ent.size));
+                }
+                if read < entries.len() {
+                    break;
+                }
+            }
+        }
+        assert_eq!(listed, vec![("a.txt".to_owned(), 1), ("b.txt".to_owned(), 2), ("c.txt".to_owned(), 3)]);
+
+        // The listing is kept until a rewind, which picks up the new file.
+        drive.write_file("/d.txt", b"dddd");
+        unsafe {
+            assert_eq!(nxFatdriveReadDirEntries(drive.handle, dirid, entries.as_mut_ptr(), entries.len(), &mut read), SUCCESS);
+            assert_eq!(read, 0);
+            assert_eq!(nxFatdriveRewindDir(drive.handle, dirid), SUCCESS);
+            let mut all = [mem::zeroed::<NxFatdriveDirEntry>(); 8];
+            assert_eq!(nxFatdriveReadDirEntries(drive.handle, dirid, all.as_mut_ptr(), all.len(), &mut read), SUCCESS);
+            assert_eq!(read, 4);
+            assert_eq!(all[3].size, 4);
+            assert_eq!(all[3].entry_type, 0x1);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, dirid), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn reading_directories_checks_its_arguments() {
+        let drive = ImageDrive::new(false);
+        drive.write_file("/only.txt", b"x");
+        let root = CString::new("/").unwrap();
+        let invalid = LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+        let mut dirid = 0;
+        let (mut entry_type, mut size) = (0, 0);
+        let mut name = [0xFFu8; 16];
+        let mut read = 0;
+        unsafe {
+            let mut entries = [mem::zeroed::<NxFatdriveDirEntry>(); 1];
+            assert_eq!(nxFatdriveOpenDir(drive.handle, &mut dirid, root.as_ptr() as *const u8), SUCCESS);
+            assert_eq!(nxFatdriveReadDir(drive.handle, dirid, &mut entry_type, &mut size, name.as_mut_ptr(), 0), invalid);
+            assert_eq!(nxFatdriveReadDir(drive.handle, dirid, ptr::null_mut(), &mut size, name.as_mut_ptr(), name.len()), invalid);
+            assert_eq!(nxFatdriveReadDir(drive.handle, dirid, &mut entry_type, ptr::null_mut(), name.as_mut_ptr(), name.len()), invalid);
+            assert_eq!(nxFatdriveReadDir(drive.handle, dirid, &mut entry_type, &mut size, ptr::null_mut(), name.len()), invalid);
+            assert_eq!(nxFatdriveReadDirEntries(drive.handle, dirid, entries.as_mut_ptr(), entries.len(), ptr::null_mut()), invalid);
+            assert_eq!(nxFatdriveReadDirEntries(drive.handle, dirid, ptr::null_mut(), 1, &mut read), invalid);
+
+            // None of those used up the entry.
+            assert_eq!(nxFatdriveReadDir(drive.handle, dirid, &mut entry_type, &mut size, name.as_mut_ptr(), 4), SUCCESS);
+            assert_eq!(&name[.. 4], b"onl\0");
+            assert_eq!(size, 1);
+            assert_eq!(nxFatdriveReadDirEntries(drive.handle, dirid, ptr::null_mut(), 0, &mut read), SUCCESS);
+            assert_eq!(read, 0);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, dirid), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn reading_and_writing_files_checks_buffers() {
+        let drive = ImageDrive::new(false);
+        drive.write_file("/data.bin", b"abc");
+        let path = CString::new("/data.bin").unwrap();
+        let invalid = LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+        let mut fileid = 0;
+        let mut buffer = [0u8; 3];
+        let mut done = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_RDWR as u64), SUCCESS);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileid, ptr::null_mut(), 0, &mut done), invalid);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileid, buffer.as_mut_ptr(), buffer.len(), ptr::null_mut()), invalid);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, ptr::null(), 0, &mut done), invalid);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, buffer.as_ptr(), buffer.len(), ptr::null_mut()), invalid);
+
+            // The position didn't move.
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileid, buffer.as_mut_ptr(), buffer.len(), &mut done), SUCCESS);
+            assert_eq!(&buffer[.. done], b"abc");
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn drives_close_with_handles_still_open() {
+        let drive = ImageDrive::new(false);
+        let path = CString::new("/open.txt").unwrap();
+        let root = CString::new("/").unwrap();
+        let (mut fileid, mut dirid) = (0, 0);
+        let mut written = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_WRONLY | O_CREAT) as u64), SUCCESS);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"data".as_ptr(), 4, &mut written), SUCCESS);
+            assert_eq!(nxFatdriveOpenDir(drive.handle, &mut dirid, root.as_ptr() as *const u8), SUCCESS);
+            assert_eq!(nxFatdriveCloseDevice(drive.handle), SUCCESS);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"more".as_ptr(), 4, &mut written), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, dirid), NX_FATDRIVE_ERR_DRIVE_NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn read_only_drives_refuse_changes() {
+        let drive = ImageDrive::new(true);
+        let path = CString::new("/new.txt").unwrap();
+        let mut fileid = 0;
+        unsafe {
+            assert_eq!(nxFatdriveCreateFile(drive.handle, path.as_ptr() as *const u8), NX_FATDRIVE_ERR_READ_ONLY);
+            assert_eq!(nxFatdriveCreateDir(drive.handle, path.as_ptr() as *const u8), NX_FATDRIVE_ERR_READ_ONLY);
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_WRONLY | O_CREAT) as u64), NX_FATDRIVE_ERR_READ_ONLY);
+            assert_eq!(nxFatdriveIsDeviceReady(drive.handle), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn device_states_map_to_status_codes() {
+        let scsi_err = ((NX_FATDRIVE_ERR_SCSI_PREFIX + 1) << 8) + NX_FATDRIVE_ERR_MODULE;
+        let states = [
+            (DeviceState::NoDevice, USBFS_STATE_NO_DEVICE, USBFS_UNMOUNTED, Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND)),
+            (DeviceState::DevicePresent, USBFS_STATE_DEVICE_PRESENT, USBFS_UNMOUNTED, Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND)),
+            (DeviceState::Mounted(7), USBFS_STATE_MOUNTED, USBFS_MOUNTED, Ok(7)),
+            (DeviceState::Error(scsi_err, None), USBFS_STATE_ERROR, USBFS_UNMOUNTED, Err(scsi_err)),
+        ];
+        for (state, code, mount_status, mounted) in states.iter() {
+            assert_eq!(state.code(), *code);
+            assert_eq!(state.mount_status(), *mount_status);
+            assert_eq!(state.mounted(), *mounted);
+        }
     }
-    else {
-        1
+
+    #[test]
+    fn unmountable_drives_are_told_apart_from_failing_ones() {
+        assert!(is_unsupported_fs(NX_FATDRIVE_ERR_UNSUPPORTED_FS));
+        assert!(is_unsupported_fs(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND));
+        assert!(is_unsupported_fs(((NX_FATDRIVE_ERR_MBR_PREFIX + 1) << 8) + NX_FATDRIVE_ERR_MODULE));
+        assert!(!is_unsupported_fs(((NX_FATDRIVE_ERR_SCSI_PREFIX + 1) << 8) + NX_FATDRIVE_ERR_MODULE));
+        assert!(!is_unsupported_fs(NX_FATDRIVE_ERR_FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn usbfs_status_before_the_service_starts() {
+        let mut status = 0xFF;
+        unsafe {
+            assert_eq!(usbFsGetMountStatus(&mut status), NX_FATDRIVE_ERR_NOT_INITIALIZED);
+            assert_eq!(usbFsDeviceUpdate(), 0);
+            assert_eq!(usbFsDeviceGetMountStatus(), USBFS_UNMOUNTED);
+            assert_eq!(usbFsIsInitialized(), NX_FATDRIVE_ERR_NOT_INITIALIZED);
+        }
+        assert_eq!(status, 0xFF);
+    }
+
+    #[test]
+    fn raw_reads_are_scoped_to_the_device_or_partition() {
+        let drive = ImageDrive::with_raw_access();
+        let mut sector = [0xFFu8; 512];
+        let (mut sector_size, mut device_sectors, mut partition_sectors) = (0, 0, 0);
+        unsafe {
+            assert_eq!(nxFatdriveGetRawGeometry(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, &mut sector_size, &mut device_sectors), SUCCESS);
+            assert_eq!(nxFatdriveGetRawGeometry(drive.handle, NX_FATDRIVE_RAW_SCOPE_PARTITION, &mut sector_size, &mut partition_sectors), SUCCESS);
+            assert_eq!(device_sectors, (IMAGE_SIZE / 512) as u64);
+            assert_eq!(partition_sectors, device_sectors - RAW_PARTITION_START as u64);
+
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 0, 1, sector.as_mut_ptr()), SUCCESS);
+            assert!(sector.iter().all(|&b| b == 0));
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_PARTITION, 0, 1, sector.as_mut_ptr()), SUCCESS);
+            assert_eq!(&sector[510 ..], &[0x55, 0xAA]);
+
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, device_sectors, 1, sector.as_mut_ptr()), NX_FATDRIVE_ERR_OUT_OF_RANGE);
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_PARTITION, partition_sectors - 1, 2, sector.as_mut_ptr()), NX_FATDRIVE_ERR_OUT_OF_RANGE);
+            assert_ne!(nxFatdriveReadRaw(drive.handle, 2, 0, 1, sector.as_mut_ptr()), SUCCESS);
+        }
+    }
+
+    #[test]
+    fn raw_writes_must_be_unlocked() {
+        let drive = ImageDrive::with_raw_access();
+        let sector = [0xA5u8; 512];
+        let mut read_back = [0u8; 512];
+        unsafe {
+            assert_eq!(nxFatdriveWriteRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 1, 1, sector.as_ptr()), NX_FATDRIVE_ERR_WRITE_PROTECTED);
+            assert_eq!(nxFatdriveUnlockRawWrites(drive.handle, true), SUCCESS);
+            assert_eq!(nxFatdriveWriteRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 1, 1, sector.as_ptr()), SUCCESS);
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 1, 1, read_back.as_mut_ptr()), SUCCESS);
+            assert_eq!(&read_back[..], &sector[..]);
+            assert_eq!(nxFatdriveUnlockRawWrites(drive.handle, false), SUCCESS);
+            assert_eq!(nxFatdriveWriteRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 1, 1, sector.as_ptr()), NX_FATDRIVE_ERR_WRITE_PROTECTED);
+
+            let invalid = LibnxErrMapper::map(std::io::Error::from(ErrorKind::InvalidInput));
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 1, 0, ptr::null_mut()), invalid);
+            assert_eq!(nxFatdriveWriteRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 1, 0, ptr::null()), invalid);
+        }
+
+        let plain = ImageDrive::new(true);
+        unsafe {
+            assert_eq!(nxFatdriveUnlockRawWrites(plain.handle, true), NX_FATDRIVE_ERR_READ_ONLY);
+        }
+    }
+
+    #[test]
+    fn raw_access_stays_coherent_with_the_filesystem() {
+        let drive = ImageDrive::with_raw_access();
+        let mut contents = b"raw access marker".to_vec();
+        contents.resize(512, b'.');
+        drive.write_file("/marker.bin", &contents);
+
+        let sector = drive.find_sector(b"raw access marker").expect("file contents should be on the drive");
+        let mut replacement = b"rewritten in place".to_vec();
+        replacement.resize(512, b'!');
+        unsafe {
+            assert_eq!(nxFatdriveUnlockRawWrites(drive.handle, true), SUCCESS);
+            assert_eq!(nxFatdriveWriteRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_PARTITION, sector, 1, replacement.as_ptr()), SUCCESS);
+        }
+        assert_eq!(drive.read_file("/marker.bin"), replacement);
+    }
+
+    #[test]
+    fn unplugged_drives_fail_everything_but_closing() {
+        let (drive, image) = ImageDrive::unpluggable();
+        drive.write_file("/kept.txt", b"written before the drive went away");
+        let path = CString::new("/kept.txt").unwrap();
+        let root = CString::new("/").unwrap();
+        let (mut fileid, mut dirid) = (0, 0);
+        let mut buffer = [0u8; 16];
+        let mut sector = [0u8; 512];
+        let mut read = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            assert_eq!(nxFatdriveOpenDir(drive.handle, &mut dirid, root.as_ptr() as *const u8), SUCCESS);
+
+            image.lock().unwrap().disconnect();
+            assert_eq!(nxFatdriveIsDeviceReady(drive.handle), NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileid, buffer.as_mut_ptr(), buffer.len(), &mut read), NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+            assert_eq!(nxFatdriveReadRaw(drive.handle, NX_FATDRIVE_RAW_SCOPE_DEVICE, 0, 1, sector.as_mut_ptr()), NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+            assert_eq!(nxFatdriveCloseDir(drive.handle, dirid), SUCCESS);
+            // Everything written had already reached the drive.
+            assert_eq!(nxFatdriveCloseDevice(drive.handle), SUCCESS);
+        }
+    }
+
+    /// A copy of what is on `image`, as if it were plugged back in.
+    fn replugged(image : &Arc<Mutex<ImageDevice<Cursor<Vec<u8>>>>>) -> SharedBlockDevice {
+        let contents = image.lock().unwrap().get_ref().get_ref().clone();
+        Arc::new(Mutex::new(ImageDevice::new(Cursor::new(contents))))
+    }
+
+    #[test]
+    fn replugged_drives_carry_on_with_their_open_files() {
+        let (drive, image) = ImageDrive::unpluggable();
+        let path = CString::new("/resumed.txt").unwrap();
+        let mut fileid = 0;
+        let mut written = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_WRONLY | O_CREAT) as u64), SUCCESS);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"before ".as_ptr(), 7, &mut written), SUCCESS);
+            image.lock().unwrap().disconnect();
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"lost".as_ptr(), 4, &mut written), NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+        }
+        assert!(drive_awaits_reattach(drive.handle));
+
+        // Another drive with a copy of the volume on it is not taken for this one.
+        assert_eq!(with_any_drive(drive.handle, |ctx| ctx.resume("IMAGE0002", replugged(&image))), Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND));
+        assert_eq!(with_any_drive(drive.handle, |ctx| ctx.resume(IMAGE_SERIAL, replugged(&image))), Ok(()));
+        assert!(!drive_awaits_reattach(drive.handle));
+        unsafe {
+            assert_eq!(nxFatdriveIsDeviceReady(drive.handle), SUCCESS);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"after".as_ptr(), 5, &mut written), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+        assert_eq!(drive.read_file("/resumed.txt"), b"before after".to_vec());
+    }
+
+    #[test]
+    fn handles_go_stale_when_their_file_is_renamed_or_deleted() {
+        let (drive, image) = ImageDrive::unpluggable();
+        drive.write_file("/renamed.txt", b"renamed");
+        drive.write_file("/deleted.txt", b"deleted");
+        drive.write_file("/kept.txt", b"kept");
+        let mut fileids = [0; 3];
+        unsafe {
+            for (fileid, name) in fileids.iter_mut().zip(&["/renamed.txt", "/deleted.txt", "/kept.txt"]) {
+                let path = CString::new(*name).unwrap();
+                assert_eq!(nxFatdriveOpenFile(drive.handle, fileid, path.as_ptr() as *const u8, O_RDONLY as u64), SUCCESS);
+            }
+        }
+        image.lock().unwrap().disconnect();
+
+        // Elsewhere, while the drive is away, one file is renamed and another deleted.
+        let mut contents = image.lock().unwrap().get_ref().get_ref().clone();
+        let mut partition = Cursor::new(contents.split_off(RAW_PARTITION_START * 512));
+        {
+            let fs = fatfs::FileSystem::new(&mut partition, fatfs::FsOptions::new()).unwrap();
+            let root = fs.root_dir();
+            root.rename("renamed.txt", &root, "moved.txt").unwrap();
+            root.remove("deleted.txt").unwrap();
+        }
+        contents.extend_from_slice(partition.get_ref());
+        let changed : SharedBlockDevice = Arc::new(Mutex::new(ImageDevice::new(Cursor::new(contents))));
+        assert_eq!(with_any_drive(drive.handle, |ctx| ctx.resume(IMAGE_SERIAL, changed)), Ok(()));
+
+        let mut buffer = [0u8; 16];
+        let mut read = 0;
+        unsafe {
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileids[0], buffer.as_mut_ptr(), buffer.len(), &mut read), NX_FATDRIVE_ERR_INVALID_HANDLE);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileids[1], buffer.as_mut_ptr(), buffer.len(), &mut read), NX_FATDRIVE_ERR_INVALID_HANDLE);
+            assert_eq!(nxFatdriveReadFile(drive.handle, fileids[2], buffer.as_mut_ptr(), buffer.len(), &mut read), SUCCESS);
+            assert_eq!(&buffer[.. read], b"kept");
+            for fileid in fileids.iter() {
+                assert_eq!(nxFatdriveCloseFile(drive.handle, *fileid), SUCCESS);
+            }
+        }
+        assert_eq!(drive.read_file("/moved.txt"), b"renamed".to_vec());
+    }
+
+    #[test]
+    fn drives_stay_unplugged_when_they_cannot_be_mounted_again() {
+        let (drive, image) = ImageDrive::unpluggable();
+        let path = CString::new("/resumed.txt").unwrap();
+        let mut fileid = 0;
+        let mut written = 0;
+        unsafe {
+            assert_eq!(nxFatdriveOpenFile(drive.handle, &mut fileid, path.as_ptr() as *const u8, (O_WRONLY | O_CREAT) as u64), SUCCESS);
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"before ".as_ptr(), 7, &mut written), SUCCESS);
+        }
+        image.lock().unwrap().disconnect();
+
+        // The same volume, but with a boot sector no filesystem will take.
+        let mut contents = image.lock().unwrap().get_ref().get_ref().clone();
+        let bytes_per_sector = RAW_PARTITION_START * 512 + 11;
+        contents[bytes_per_sector] = 0;
+        contents[bytes_per_sector + 1] = 0;
+        let broken : SharedBlockDevice = Arc::new(Mutex::new(ImageDevice::new(Cursor::new(contents))));
+        assert_eq!(with_any_drive(drive.handle, |ctx| ctx.resume(IMAGE_SERIAL, broken)), Err(NX_FATDRIVE_ERR_UNSUPPORTED_FS));
+        assert!(drive_awaits_reattach(drive.handle));
+        unsafe {
+            assert_eq!(nxFatdriveIsDeviceReady(drive.handle), NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+        }
+
+        assert_eq!(with_any_drive(drive.handle, |ctx| ctx.resume(IMAGE_SERIAL, replugged(&image))), Ok(()));
+        unsafe {
+            assert_eq!(nxFatdriveWriteFile(drive.handle, fileid, b"after".as_ptr(), 5, &mut written), SUCCESS);
+            assert_eq!(nxFatdriveCloseFile(drive.handle, fileid), SUCCESS);
+        }
+        assert_eq!(drive.read_file("/resumed.txt"), b"before after".to_vec());
+    }
+
+    #[test]
+    fn drives_are_not_taken_back_after_their_grace_period() {
+        let (drive, image) = ImageDrive::unpluggable();
+        assert_eq!(with_any_drive(drive.handle, |ctx| {
+            ctx.reattach_grace = Duration::from_secs(0);
+            Ok(())
+        }), Ok(()));
+        image.lock().unwrap().disconnect();
+        assert!(!drive_awaits_reattach(drive.handle));
+        assert_eq!(with_any_drive(drive.handle, |ctx| ctx.resume(IMAGE_SERIAL, replugged(&image))), Err(NX_FATDRIVE_ERR_DRIVE_NOT_FOUND));
+        unsafe {
+            assert_eq!(nxFatdriveReattachDevice(drive.handle, 0), NX_FATDRIVE_ERR_DRIVE_DISCONNECTED);
+        }
     }
 }
@@ -0,0 +1,159 @@
+//! A write-back buffer over an open file.
+//!
+//! Every backend write can touch the FAT and the directory entry, so callers issuing
+//! many small writes (like `fwrite` through newlib) collect them here and hand the
+//! backend one large write instead.
+
+use super::{FileOps, write_zeros};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+
+pub const DEFAULT_BUFFER_CAPACITY : usize = 64 * 1024;
+
+/// Buffers consecutive writes to `inner` until the buffer fills up, the handle seeks
+/// away from the end of the buffered run, or the data is read back.
+///
+/// Buffered data is written through on `flush`, before any read, truncate or
+/// end-relative seek, and when the wrapper is dropped.
+pub struct WriteBackFile<F : FileOps> {
+    inner : F,
+    position : u64,
+    append : bool,
+    buffer : Vec<u8>,
+    buffer_start : u64,
+    capacity : usize,
+}
+
+impl <F : FileOps> WriteBackFile<F> {
+    /// Wraps a freshly opened file positioned at its start.
+    pub fn new(inner : F, append : bool) -> WriteBackFile<F> {
+        WriteBackFile::with_capacity(inner, append, DEFAULT_BUFFER_CAPACITY)
+    }
+
+    pub fn with_capacity(inner : F, append : bool, capacity : usize) -> WriteBackFile<F> {
+        WriteBackFile {
+            inner,
+            position : 0,
+            append,
+            buffer : Vec::with_capacity(capacity),
+            buffer_start : 0,
+            capacity,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Whether written data is still waiting in the buffer.
+    pub fn has_unwritten(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Writes any buffered data through to the underlying file without syncing it.
+    pub fn flush_buffer(&mut self) -> Result<(), io::Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let start = self.buffer_start;
+        self.seek_inner_for_write(start)?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Moves the underlying file to `pos` ahead of a write. If that is past the end,
+    /// the gap is filled with zeros first, since not every backend can seek there.
+    fn seek_inner_for_write(&mut self, pos : u64) -> Result<(), io::Error> {
+        let end = self.inner.seek(SeekFrom::End(0))?;
+        if pos > end {
+            write_zeros(&mut self.inner, pos - end)?;
+        }
+        self.inner.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
+impl <F : FileOps> Write for WriteBackFile<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if self.position != buffer_end || self.buffer.len() + buf.len() > self.capacity {
+            self.flush_buffer()?;
+        }
+        if self.append && self.buffer.is_empty() {
+            self.position = self.inner.seek(SeekFrom::End(0))?;
+        }
+        if buf.len() >= self.capacity {
+            // Large writes gain nothing from a copy into the buffer.
+            let pos = self.position;
+            self.seek_inner_for_write(pos)?;
+            let written = self.inner.write(buf)?;
+            self.position = self.inner.seek(SeekFrom::Current(0))?;
+            return Ok(written);
+        }
+        if self.buffer.is_empty() {
+            self.buffer_start = self.position;
+        }
+        self.buffer.extend_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.flush_buffer()?;
+        self.inner.flush()
+    }
+}
+
+impl <F : FileOps> Read for WriteBackFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.flush_buffer()?;
+        self.inner.seek(SeekFrom::Start(self.position))?;
+        let read_count = self.inner.read(buf)?;
+        self.position += read_count as u64;
+        Ok(read_count)
+    }
+}
+
+impl <F : FileOps> Seek for WriteBackFile<F> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        let new_pos = match pos {
+            SeekFrom::Start(raw) => raw as i64,
+            SeekFrom::Current(raw) => self.position as i64 + raw,
+            SeekFrom::End(raw) => {
+                self.flush_buffer()?;
+                self.inner.seek(SeekFrom::End(0))? as i64 + raw
+            },
+        };
+        if new_pos < 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+}
+
+impl <F : FileOps> FileOps for WriteBackFile<F> {
+    fn truncate(&mut self) -> Result<(), io::Error> {
+        self.flush_buffer()?;
+        self.inner.seek(SeekFrom::Start(self.position))?;
+        self.inner.truncate()
+    }
+    fn set_len(&mut self, len : u64) -> Result<(), io::Error> {
+        self.flush_buffer()?;
+        self.inner.set_len(len)
+    }
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), io::Error> {
+        self.flush_buffer()?;
+        self.inner.preallocate(len, contiguous)
+    }
+}
+
+impl <F : FileOps> Drop for WriteBackFile<F> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
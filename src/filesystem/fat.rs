@@ -0,0 +1,366 @@
+//! Direct access to the on-disk structures of a FAT volume, for the things rust-fatfs
+//! has no API for, such as rewriting the attributes of an existing entry.
+
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Where the entries of a directory are stored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum DirLocation {
+    /// The fixed root directory region of a FAT12 or FAT16 volume.
+    FixedRoot,
+    /// The cluster chain starting at the given cluster.
+    Chain(u32),
+}
+
+const FIRST_CLUSTER : u32 = 2;
+const ENTRY_SIZE : u64 = 32;
+const ATTR_OFFSET : u64 = 11;
+const CLUSTER_HI_OFFSET : u64 = 20;
+const CLUSTER_LO_OFFSET : u64 = 26;
+const SIZE_OFFSET : u64 = 28;
+const LFN_ATTRIBUTES : u8 = 0x0F;
+const DELETED_MARKER : u8 = 0xE5;
+/// How much of the FAT is read at a time while scanning it.
+const FAT_WINDOW : u64 = 4096;
+
+fn le16(buf : &[u8], offset : usize) -> u16 {
+    u16::from(buf[offset]) | u16::from(buf[offset + 1]) << 8
+}
+
+fn le32(buf : &[u8], offset : usize) -> u32 {
+    u32::from(le16(buf, offset)) | u32::from(le16(buf, offset + 2)) << 16
+}
+
+/// The geometry of a FAT volume, as described by its boot sector.
+#[derive(Clone, Debug)]
+pub(crate) struct Layout {
+    pub(crate) fat_type : FatType,
+    pub(crate) cluster_size : u64,
+    pub(crate) total_clusters : u32,
+    /// The byte offset of every copy of the FAT that is kept up to date; the first is read from.
+    fats : Vec<u64>,
+    fat_size : u64,
+    root_start : u64,
+    root_size : u64,
+    root_cluster : u32,
+    data_start : u64,
+    fs_info : Option<u64>,
+}
+
+impl Layout {
+    /// Reads the boot sector at the start of `disk`, then rewinds.
+    pub(crate) fn read<D : Read + Seek>(disk : &mut D) -> Result<Layout, io::Error> {
+        let mut boot = [0u8 ; 512];
+        disk.seek(SeekFrom::Start(0))?;
+        disk.read_exact(&mut boot)?;
+        disk.seek(SeekFrom::Start(0))?;
+
+        let bytes_per_sector = u64::from(le16(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u64::from(le16(&boot, 14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u64::from(le16(&boot, 17));
+        let total_sectors = match le16(&boot, 19) {
+            0 => u64::from(le32(&boot, 32)),
+            n => u64::from(n),
+        };
+        let fat_sectors = match le16(&boot, 22) {
+            0 => u64::from(le32(&boot, 36)),
+            n => u64::from(n),
+        };
+        if bytes_per_sector == 0 || sectors_per_cluster == 0 || fat_count == 0 || fat_sectors == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Boot sector does not describe a FAT volume."));
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+        if data_sector >= total_sectors {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Boot sector does not describe a FAT volume."));
+        }
+        let total_clusters = ((total_sectors - data_sector) / sectors_per_cluster) as u32;
+        // The cluster count alone decides the FAT type, whatever the boot sector's label says.
+        let fat_type = if total_clusters < 4085 {
+            FatType::Fat12
+        }
+        else if total_clusters < 65525 {
+            FatType::Fat16
+        }
+        else {
+            FatType::Fat32
+        };
+
+        let fat_start = reserved_sectors * bytes_per_sector;
+        let fat_size = fat_sectors * bytes_per_sector;
+        let mut fats : Vec<u64> = (0 .. fat_count).map(|idx| fat_start + idx * fat_size).collect();
+        let mut root_cluster = 0;
+        let mut fs_info = None;
+        if fat_type == FatType::Fat32 {
+            // With mirroring turned off only the active FAT is used.
+            let ext_flags = le16(&boot, 40);
+            if ext_flags & 0x80 != 0 {
+                fats = vec![fat_start + u64::from(ext_flags & 0x0F) * fat_size];
+            }
+            root_cluster = le32(&boot, 44);
+            fs_info = match le16(&boot, 48) {
+                0 | 0xFFFF => None,
+                sector => Some(u64::from(sector) * bytes_per_sector),
+            };
+        }
+
+        Ok(Layout {
+            fat_type,
+            cluster_size : sectors_per_cluster * bytes_per_sector,
+            total_clusters,
+            fats,
+            fat_size,
+            root_start : (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            root_size : root_sectors * bytes_per_sector,
+            root_cluster,
+            data_start : data_sector * bytes_per_sector,
+            fs_info,
+        })
+    }
+
+    pub(crate) fn root(&self) -> DirLocation {
+        match self.fat_type {
+            FatType::Fat32 => DirLocation::Chain(self.root_cluster),
+            _ => DirLocation::FixedRoot,
+        }
+    }
+
+    /// Where the entries of a subdirectory whose entry points at `first_cluster` are stored;
+    /// `..` entries point at cluster 0 when their parent is the root.
+    pub(crate) fn directory_at(&self, first_cluster : u32) -> DirLocation {
+        if first_cluster == 0 { self.root() } else { DirLocation::Chain(first_cluster) }
+    }
+
+    fn cluster_offset(&self, cluster : u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.cluster_size
+    }
+
+    fn end_cluster(&self) -> u32 {
+        self.total_clusters + FIRST_CLUSTER
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0x0FFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn entry_offset(&self, cluster : u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+}
+
+/// The directory entry of a file or directory, as stored on disk.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct RawEntry {
+    /// The byte offset of the entry on the volume.
+    pub(crate) offset : u64,
+    pub(crate) attributes : u8,
+    pub(crate) first_cluster : u32,
+    pub(crate) size : u32,
+}
+
+/// Turns a short name as rust-fatfs displays it, such as `README.TXT`, back into
+/// the space-padded 8.3 form stored in the directory entry.
+pub(crate) fn raw_short_name(display : &[u8]) -> [u8 ; 11] {
+    let mut retval = [b' ' ; 11];
+    let (base, ext) = match display.iter().rposition(|b| *b == b'.') {
+        Some(idx) if idx > 0 => (&display[.. idx], &display[idx + 1 ..]),
+        _ => (display, &display[.. 0]),
+    };
+    let base_len = base.len().min(8);
+    let ext_len = ext.len().min(3);
+    retval[.. base_len].copy_from_slice(&base[.. base_len]);
+    retval[8 .. 8 + ext_len].copy_from_slice(&ext[.. ext_len]);
+    // A real leading 0xE5 is stored as 0x05, since 0xE5 marks deleted entries.
+    if retval[0] == DELETED_MARKER {
+        retval[0] = 0x05;
+    }
+    retval
+}
+
+/// The FAT and directory entries of the volume on `disk`.
+pub(crate) struct FatVolume<'l, D> {
+    layout : &'l Layout,
+    disk : D,
+    window : Vec<u8>,
+    window_start : u64,
+}
+
+impl <'l, D : Read + Write + Seek> FatVolume<'l, D> {
+    pub(crate) fn new(layout : &'l Layout, disk : D) -> FatVolume<'l, D> {
+        FatVolume {
+            layout,
+            disk,
+            window : Vec::new(),
+            window_start : 0,
+        }
+    }
+
+    fn read_at(&mut self, offset : u64, buf : &mut [u8]) -> Result<(), io::Error> {
+        self.disk.seek(SeekFrom::Start(offset))?;
+        self.disk.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset : u64, buf : &[u8]) -> Result<(), io::Error> {
+        self.disk.seek(SeekFrom::Start(offset))?;
+        self.disk.write_all(buf)
+    }
+
+    /// Reads `buf.len()` bytes from the first FAT, `offset` bytes in.
+    fn read_fat(&mut self, offset : u64, buf : &mut [u8]) -> Result<(), io::Error> {
+        let end = offset + buf.len() as u64;
+        let window_end = self.window_start + self.window.len() as u64;
+        if offset < self.window_start || end > window_end {
+            let start = offset - offset % FAT_WINDOW;
+            let len = (FAT_WINDOW * 2).min(self.layout.fat_size - start) as usize;
+            let mut window = vec![0u8 ; len];
+            let fat_start = self.layout.fats[0];
+            self.read_at(fat_start + start, &mut window)?;
+            self.window = window;
+            self.window_start = start;
+        }
+        let rel = (offset - self.window_start) as usize;
+        buf.copy_from_slice(&self.window[rel .. rel + buf.len()]);
+        Ok(())
+    }
+
+    /// Reads the FAT entry for `cluster`.
+    pub(crate) fn get(&mut self, cluster : u32) -> Result<u32, io::Error> {
+        let offset = self.layout.entry_offset(cluster);
+        match self.layout.fat_type {
+            FatType::Fat12 => {
+                let mut raw = [0u8 ; 2];
+                self.read_fat(offset, &mut raw)?;
+                let packed = le16(&raw, 0);
+                Ok(u32::from(if cluster & 1 == 0 { packed & 0x0FFF } else { packed >> 4 }))
+            },
+            FatType::Fat16 => {
+                let mut raw = [0u8 ; 2];
+                self.read_fat(offset, &mut raw)?;
+                Ok(u32::from(le16(&raw, 0)))
+            },
+            FatType::Fat32 => {
+                let mut raw = [0u8 ; 4];
+                self.read_fat(offset, &mut raw)?;
+                Ok(le32(&raw, 0) & 0x0FFF_FFFF)
+            },
+        }
+    }
+
+    /// Writes the FAT entry for `cluster` in every copy of the FAT.
+    pub(crate) fn set(&mut self, cluster : u32, value : u32) -> Result<(), io::Error> {
+        let offset = self.layout.entry_offset(cluster);
+        self.window.clear();
+        let fats = self.layout.fats.clone();
+        for fat in fats {
+            match self.layout.fat_type {
+                FatType::Fat12 => {
+                    let mut raw = [0u8 ; 2];
+                    self.read_at(fat + offset, &mut raw)?;
+                    let packed = le16(&raw, 0);
+                    let value = value as u16 & 0x0FFF;
+                    let packed = if cluster & 1 == 0 { (packed & 0xF000) | value } else { (packed & 0x000F) | (value << 4) };
+                    self.write_at(fat + offset, &[packed as u8, (packed >> 8) as u8])?;
+                },
+                FatType::Fat16 => {
+                    self.write_at(fat + offset, &[value as u8, (value >> 8) as u8])?;
+                },
+                FatType::Fat32 => {
+                    // The top four bits are reserved and have to be kept as they are.
+                    let mut raw = [0u8 ; 4];
+                    self.read_at(fat + offset, &mut raw)?;
+                    let packed = (le32(&raw, 0) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write_at(fat + offset, &[packed as u8, (packed >> 8) as u8, (packed >> 16) as u8, (packed >> 24) as u8])?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn check_cluster(&self, cluster : u32) -> Result<(), io::Error> {
+        if cluster < FIRST_CLUSTER || cluster >= self.layout.end_cluster() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Cluster chain points outside the volume."));
+        }
+        Ok(())
+    }
+
+    /// Follows the cluster chain starting at `first`.
+    pub(crate) fn chain(&mut self, first : u32) -> Result<Vec<u32>, io::Error> {
+        let mut retval = Vec::new();
+        let mut cluster = first;
+        loop {
+            self.check_cluster(cluster)?;
+            if retval.len() >= self.layout.total_clusters as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Cluster chain loops."));
+            }
+            retval.push(cluster);
+            let next = self.get(cluster)?;
+            // Anything from the bad cluster marker up ends the chain.
+            if next >= self.layout.end_of_chain() - 8 {
+                return Ok(retval);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Finds the entry whose stored 8.3 name is `short_name` in the directory at `dir`.
+    pub(crate) fn find_entry(&mut self, dir : DirLocation, short_name : &[u8 ; 11]) -> Result<Option<RawEntry>, io::Error> {
+        let regions = match dir {
+            DirLocation::FixedRoot => vec![(self.layout.root_start, self.layout.root_size)],
+            DirLocation::Chain(first) => {
+                let cluster_size = self.layout.cluster_size;
+                let layout = self.layout;
+                self.chain(first)?.into_iter().map(|c| (layout.cluster_offset(c), cluster_size)).collect()
+            },
+        };
+        for (start, len) in regions {
+            let mut region = vec![0u8 ; len as usize];
+            self.read_at(start, &mut region)?;
+            for (idx, raw) in region.chunks(ENTRY_SIZE as usize).enumerate() {
+                if raw[0] == 0 {
+                    return Ok(None);
+                }
+                if raw[0] == DELETED_MARKER || raw[ATTR_OFFSET as usize] == LFN_ATTRIBUTES {
+                    continue;
+                }
+                if &raw[.. 11] == &short_name[..] {
+                    let cluster_hi = if self.layout.fat_type == FatType::Fat32 { u32::from(le16(raw, CLUSTER_HI_OFFSET as usize)) } else { 0 };
+                    return Ok(Some(RawEntry {
+                        offset : start + idx as u64 * ENTRY_SIZE,
+                        attributes : raw[ATTR_OFFSET as usize],
+                        first_cluster : cluster_hi << 16 | u32::from(le16(raw, CLUSTER_LO_OFFSET as usize)),
+                        size : le32(raw, SIZE_OFFSET as usize),
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn write_attributes(&mut self, entry : &RawEntry, attributes : u8) -> Result<(), io::Error> {
+        self.write_at(entry.offset + ATTR_OFFSET, &[attributes])
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), io::Error> {
+        self.disk.flush()
+    }
+}
@@ -1,19 +1,20 @@
 
 use fatfs_sys::{
     FIL, DIR, FRESULT, FILINFO, FATFS,
-    FA_CREATE_NEW, FA_OPEN_EXISTING,
+    AM_DIR, AM_RDO,
+    FA_READ, FA_WRITE, FA_CREATE_NEW, FA_CREATE_ALWAYS, FA_OPEN_ALWAYS, FA_OPEN_EXISTING,
     STA_NODISK, STA_NOINIT, 
     CTRL_SYNC, GET_BLOCK_SIZE, GET_SECTOR_COUNT, GET_SECTOR_SIZE, CTRL_TRIM,
     f_close, f_closedir, f_open, f_opendir, 
     f_sync, f_readdir,
     f_read, f_write, f_lseek, 
-    f_unlink, f_mkdir,
-    f_truncate, f_getfree,
+    f_unlink, f_mkdir, f_chmod, f_rename,
+    f_truncate, f_getfree, f_expand,
     FatfsDiskHandler, register_disk_handler,
     disk_ioctl,
     BYTE, DSTATUS, DWORD, UINT, DRESULT, 
 };
-use super::{FileOps, FileSystemOps, DirectoryOps, DirIterOps, File, Directory, DirIter, DirEntryData, FsStats};
+use super::{FileOps, FileSystemOps, DirectoryOps, DirIterOps, File, Directory, DirIter, DirEntryData, DirEntryType, FsStats, OpenOptions, FatTimestamp, FsErrorKind, set_len_with, fs_error, no_space};
 use buf_scsi::OffsetScsiDevice;
 use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind};
 use std::ffi::{CString, CStr};
@@ -37,7 +38,7 @@ impl FatfsSysFileSystem {
 }
 
 impl FileSystemOps for FatfsSysFileSystem {
-    fn root(&mut self) -> Result<Directory, std::io::Error> {
+    fn root(&self) -> Result<Directory, std::io::Error> {
         let mut inner = DIR::default();
         let path = "/\0";
         let err = unsafe { f_opendir(&mut inner as *mut _, path.as_ptr() as *const _)};
@@ -129,11 +130,6 @@ impl FatfsSysContext {
         self.drives[idx].as_mut()
     }
 
-    pub fn reset_filesystem(&mut self, pdrv : u8) {
-        let idx = self.get_idx(pdrv);
-        self.drives[idx] = None;
-    }
-
     pub unsafe fn initialize() {
         let ctx = FatfsSysContext {
             drives : [None, None, None, None, None, None, None, None, None, None, None, None, None, None, None],
@@ -145,7 +141,7 @@ impl FatfsSysContext {
     pub fn add_fs(&mut self, new_fs : DeviceHandle) -> BYTE {
         let mut idx = self.default_disk;
         while (idx + 1) % (self.drives.len() as BYTE) != self.default_disk {
-            let is_empty = self.drives[idx as usize].as_mut().map(|dev| !dev.device.device.comm_channel.is_connected()).unwrap_or(true);
+            let is_empty = self.drives[idx as usize].as_mut().map(|dev| !dev.device.device.is_connected()).unwrap_or(true);
             if is_empty {
                 self.drives[idx as usize] = Some(new_fs);
                 return idx;
@@ -157,11 +153,9 @@ impl FatfsSysContext {
 }
 impl FatfsDiskHandler for FatfsSysContext {
     fn disk_status(&mut self, pdrv: BYTE) -> DSTATUS { 
-        let retval = self.get_filesystem(pdrv).map(|dev| if !dev.device.device.comm_channel.is_connected() { STA_NODISK } else { 0 }).unwrap_or(STA_NOINIT);
-        if retval != 0 {
-            self.reset_filesystem(pdrv);
-        }
-        retval
+        // A drive that went away keeps its slot until `add_fs` hands it out again, since
+        // FatFs may still be in the middle of an operation on it.
+        self.get_filesystem(pdrv).map(|dev| if !dev.device.device.is_connected() { STA_NODISK } else { 0 }).unwrap_or(STA_NOINIT)
     }
 
     fn disk_initialize(&mut self, pdrv: BYTE) -> DSTATUS { 
@@ -174,7 +168,10 @@ impl FatfsDiskHandler for FatfsSysContext {
     }
 
     fn disk_read(&mut self, pdrv: BYTE, buf_ptr: *mut BYTE, sector: DWORD, count: UINT) -> DRESULT {
-        let fs = if let Some(f) = self.get_filesystem(pdrv) { f } else { return DRESULT::RES_NOTRDY };
+        let fs = match self.get_filesystem(pdrv) {
+            Some(f) if f.device.device.is_connected() => f,
+            _ => return DRESULT::RES_NOTRDY,
+        };
         let byte_count = fs.sector_size() * (count as usize);
         let buff = unsafe { std::slice::from_raw_parts_mut(buf_ptr as *mut u8, byte_count) };
         let byte_offset = fs.sector_size() * (sector as usize);
@@ -186,7 +183,10 @@ impl FatfsDiskHandler for FatfsSysContext {
     }
 
     fn disk_write(&mut self, pdrv: BYTE, buf_ptr: *const BYTE, sector: DWORD, count: UINT) -> DRESULT {
-        let fs = if let Some(f) = self.get_filesystem(pdrv) { f } else { return DRESULT::RES_NOTRDY };
+        let fs = match self.get_filesystem(pdrv) {
+            Some(f) if f.device.device.is_connected() => f,
+            _ => return DRESULT::RES_NOTRDY,
+        };
         let byte_count = fs.sector_size() * (count as usize);
         let buff = unsafe { std::slice::from_raw_parts(buf_ptr as *const u8, byte_count) };
         let byte_offset = fs.sector_size() * (sector as usize);
@@ -274,6 +274,7 @@ impl FatfsDiskHandler for FatfsSysContext {
 
 pub struct FatfsSysFile {
     inner : FIL, 
+    append : bool,
 }
 
 impl Read for FatfsSysFile {
@@ -308,6 +309,9 @@ impl Seek for FatfsSysFile {
 
 impl Write for FatfsSysFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.append {
+            self.seek(SeekFrom::End(0))?;
+        }
         let buff_ptr : *const c_void = buf.as_ptr() as *const c_void;
         let buflen : fatfs_sys::UINT = buf.len() as fatfs_sys::UINT;
         let mut retval : fatfs_sys::UINT = 0;
@@ -328,13 +332,61 @@ impl FileOps for FatfsSysFile {
         let err = unsafe { f_truncate(&mut self.inner as *mut _)};
         wrap_errors((), err)
     }
+    fn set_len(&mut self, len : u64) -> Result<(), std::io::Error> {
+        // Zero-filling has to happen at the real end of file, regardless of append mode.
+        let append = self.append;
+        self.append = false;
+        let res = set_len_with(self, len, |f| f.truncate());
+        self.append = append;
+        res
+    }
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), std::io::Error> {
+        if self.inner.flag & (FA_WRITE as BYTE) == 0 {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        let cur_len = self.inner.obj.objsize as u64;
+        if len <= cur_len {
+            return Ok(());
+        }
+        if len > u32::max_value() as u64 {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        if contiguous {
+            // f_expand can only lay out a fresh chain, so the file has to be empty.
+            if cur_len != 0 {
+                return Err(Error::new(ErrorKind::InvalidInput, "Contiguous preallocation requires an empty file."));
+            }
+            let err = unsafe { f_expand(&mut self.inner as *mut _, len as u32, 1) };
+            return match err {
+                // f_expand reports a missing contiguous run as FR_DENIED.
+                FRESULT::FR_DENIED => Err(no_space()),
+                other => wrap_errors((), other),
+            };
+        }
+        // Seeking past the end of a writable file makes FatFs stretch the cluster chain
+        // without writing any data; it stops early instead of failing when the volume fills up.
+        let position = self.inner.fptr as u64;
+        let reached = self.seek(SeekFrom::Start(len))?;
+        if reached < len {
+            self.seek(SeekFrom::Start(cur_len))?;
+            self.truncate()?;
+            self.seek(SeekFrom::Start(position))?;
+            return Err(no_space());
+        }
+        self.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
 }
 
 
 fn wrap_errors<T>(possible : T, err : FRESULT) -> std::io::Result<T> {
     match err {
         FRESULT::FR_OK => Ok(possible),
-        FRESULT::FR_WRITE_PROTECTED => Err(Error::from(ErrorKind::PermissionDenied)),
+        FRESULT::FR_WRITE_PROTECTED => Err(fs_error(FsErrorKind::ReadOnly)),
+        FRESULT::FR_DENIED => Err(Error::from(ErrorKind::PermissionDenied)),
+        FRESULT::FR_EXIST => Err(Error::from(ErrorKind::AlreadyExists)),
+        FRESULT::FR_NO_FILE | FRESULT::FR_NO_PATH => Err(Error::from(ErrorKind::NotFound)),
+        FRESULT::FR_INVALID_NAME => Err(Error::from(ErrorKind::InvalidInput)),
         FRESULT::FR_TOO_MANY_OPEN_FILES => Err(Error::from(ErrorKind::AddrInUse)),
         _ => Err(Error::from(ErrorKind::Other)),
     }
@@ -369,10 +421,24 @@ impl FatfsSysDir {
         }
         let name_cstr = unsafe { CStr::from_ptr(&rawinfo.fname as *const _ as *const _)};
         let name_str = name_cstr.to_string_lossy();
+        let type_bits = if rawinfo.fattrib & (AM_DIR as u8) != 0 {
+            (u8::from(DirEntryType::Directory) as u64) << 12
+        } else {
+            (u8::from(DirEntryType::RegularFile) as u64) << 12
+        };
+        let permissions_bits = if rawinfo.fattrib & (AM_RDO as u8) != 0 {
+            0o444
+        } else {
+            0o666
+        };
         let retval = DirEntryData {
             name : name_str.to_string(), 
             len : rawinfo.fsize as usize, 
-            flags : rawinfo.fattrib as u64,
+            flags : type_bits | permissions_bits,
+            attributes : rawinfo.fattrib,
+            modified : FatTimestamp::from_packed(rawinfo.fdate, rawinfo.ftime),
+            created : None,
+            accessed : None,
         };
         Ok(Some(retval))
     }
@@ -394,42 +460,70 @@ impl Drop for FatfsSysDir {
     }
 } 
 
-impl DirectoryOps for FatfsSysDir {
-    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, std::io::Error>{ 
+impl <'a> DirectoryOps<'a> for FatfsSysDir {
+    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, std::io::Error>{ 
         let mut inner = DIR::default();
         let cpath : CString = CString::new(path.as_ref())?;
         let err_code = unsafe {f_opendir(&mut inner as *mut _, cpath.as_ptr())};
         let retval = Directory::FatfsSys(FatfsSysDir::from_inner(inner));
         wrap_errors(retval, err_code)
     }
-    fn create_directory<PathType : AsRef<str>>(&mut self, path : PathType) -> Result<Directory, std::io::Error>{
+    fn create_directory<PathType : AsRef<str>>(&mut self, path : PathType) -> Result<Directory<'a>, std::io::Error>{
         let cpath : CString = CString::new(path.as_ref())?;
         let err_code = unsafe {f_mkdir(cpath.as_ptr())};
         wrap_errors((), err_code)?;
         self.open_directory(path)
     }
-    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, std::io::Error>{ 
-        let mode = FA_OPEN_EXISTING as u8; 
-        let mut inner = FIL::default();
-        let cpath : CString = CString::new(path.as_ref())?;
-        let err_code = unsafe {f_open(&mut inner as *mut _, cpath.as_ptr(), mode)};
-        let retval = File::FatfsSys(FatfsSysFile{ inner });
-        wrap_errors(retval, err_code)
+    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, std::io::Error>{ 
+        self.open_file_with(path, OpenOptions::read_write())
+    }
+    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, std::io::Error>{
+        self.open_file_with(path, OpenOptions::read_write().create_new(true))
     }
-    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, std::io::Error>{
-        let mode = FA_CREATE_NEW as u8; 
+    fn open_file_with<PathType : AsRef<str>>(&mut self, path :PathType, options : OpenOptions) -> Result<File<'a>, std::io::Error>{
+        options.validate()?;
+        let mut mode = 0u8;
+        if options.read {
+            mode |= FA_READ as u8;
+        }
+        if options.write {
+            mode |= FA_WRITE as u8;
+        }
+        mode |= if options.create_new {
+            FA_CREATE_NEW as u8
+        } else if options.create && options.truncate {
+            FA_CREATE_ALWAYS as u8
+        } else if options.create {
+            FA_OPEN_ALWAYS as u8
+        } else {
+            FA_OPEN_EXISTING as u8
+        };
         let mut inner = FIL::default();
         let cpath : CString = CString::new(path.as_ref())?;
         let err_code = unsafe {f_open(&mut inner as *mut _, cpath.as_ptr(), mode)};
-        let retval = File::FatfsSys(FatfsSysFile{ inner });
-        wrap_errors(retval, err_code)
+        let mut retval = wrap_errors(FatfsSysFile{ inner, append : options.append }, err_code)?;
+        if options.truncate && !options.create {
+            retval.truncate()?;
+        }
+        Ok(File::FatfsSys(retval))
     }
     fn remove_path<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<(), std::io::Error>{ 
         let cpath : CString = CString::new(path.as_ref())?;
         let err = unsafe { f_unlink(cpath.as_ptr())};
         wrap_errors((), err)
     }
-    fn iter<'a>(&'a mut self) -> DirIter<'a>{ 
+    fn rename<SrcType : AsRef<str>, DstType : AsRef<str>>(&mut self, src : SrcType, dst : DstType) -> Result<(), std::io::Error> {
+        let csrc : CString = CString::new(src.as_ref())?;
+        let cdst : CString = CString::new(dst.as_ref())?;
+        let err = unsafe { f_rename(csrc.as_ptr(), cdst.as_ptr())};
+        wrap_errors((), err)
+    }
+    fn set_attributes<PathType : AsRef<str>>(&mut self, path :PathType, attributes : u8, mask : u8) -> Result<(), std::io::Error> {
+        let cpath : CString = CString::new(path.as_ref())?;
+        let err = unsafe { f_chmod(cpath.as_ptr(), attributes, mask)};
+        wrap_errors((), err)
+    }
+    fn iter<'b>(&'b mut self) -> DirIter<'b>{ 
         self.load_children();
         DirIter::FatfsSys(FatfsSysDirIter::new(&self.children))
     }
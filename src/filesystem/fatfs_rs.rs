@@ -1,22 +1,195 @@
 use fatfs::{Dir,  FileAttributes};
 use crate::buf_scsi::OffsetScsiDevice;
-use super::{FileOps, File, DirectoryOps, FileSystemOps, DirEntryData, DirEntryType, DirIter, DirIterOps, Directory, FsStats};
+use super::{FileOps, File, DirectoryOps, FileSystemOps, DirEntryData, DirEntryType, DirIter, DirIterOps, Directory, FsStats, OpenOptions, FatTimestamp, FsErrorKind, set_len_with, no_space, fs_error};
+use super::{ATTR_READ_ONLY, ATTR_HIDDEN, ATTR_SYSTEM, ATTR_ARCHIVE};
+use super::fat;
+use super::path;
 use crate::capi_helpers::{LibnxErrMapper};
 use mbr_nostd::{PartitionTableEntry, PartitionType};
-use std::io::Write;
+use std::io::{Read, Write, Seek, SeekFrom};
 use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-impl <'a> FileOps for fatfs::File<'a, OffsetScsiDevice> {
+/// The device under a rust-fatfs volume, shared between rust-fatfs and `FatfsFileSystem`.
+/// rust-fatfs seeks before every access, so neither side depends on where the other
+/// left the position.
+#[derive(Clone)]
+pub struct SharedDisk(Arc<Mutex<OffsetScsiDevice>>);
+
+impl SharedDisk {
+    /// A panic while the device was locked leaves it no worse off than a failed
+    /// write would, so a poisoned lock is used as is.
+    fn device(&self) -> MutexGuard<OffsetScsiDevice> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for SharedDisk {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.device().read(buf)
+    }
+}
+
+impl Write for SharedDisk {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.device().write(buf)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.device().flush()
+    }
+}
+
+impl Seek for SharedDisk {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.device().seek(pos)
+    }
+}
+
+/// A rust-fatfs volume, along with direct access to the FAT structures underneath
+/// it for the operations rust-fatfs does not offer.
+pub struct FatfsFileSystem {
+    inner : fatfs::FileSystem<SharedDisk>,
+    disk : SharedDisk,
+    layout : fat::Layout,
+}
+
+impl FatfsFileSystem {
+    pub fn new(device : OffsetScsiDevice) -> Result<FatfsFileSystem, io::Error> {
+        let mut disk = SharedDisk(Arc::new(Mutex::new(device)));
+        let layout = fat::Layout::read(&mut disk)?;
+        let inner = fatfs::FileSystem::new(disk.clone(), fatfs::FsOptions::new())?;
+        Ok(FatfsFileSystem { inner, disk, layout })
+    }
+
+    fn volume(&self) -> fat::FatVolume<SharedDisk> {
+        fat::FatVolume::new(&self.layout, self.disk.clone())
+    }
+
+    /// Finds the on-disk entry of the file or directory at `path`, which is relative to the root.
+    fn locate(&self, path : &str) -> Result<fat::RawEntry, io::Error> {
+        let mut volume = self.volume();
+        let mut dir = self.inner.root_dir();
+        let mut location = self.layout.root();
+        let mut found = None;
+        let mut components = path.split('/').filter(|comp| !comp.is_empty()).peekable();
+        while let Some(name) = components.next() {
+            let wanted = name.to_uppercase();
+            let ent = dir.iter()
+                .filter_map(|ent| ent.ok())
+                .find(|ent| ent.file_name().to_uppercase() == wanted || ent.short_file_name().to_uppercase() == wanted)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            let short_name = fat::raw_short_name(ent.short_file_name_as_bytes());
+            let raw = volume.find_entry(location, &short_name)?.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            if components.peek().is_some() {
+                if !ent.is_dir() {
+                    return Err(fs_error(FsErrorKind::NotADirectory));
+                }
+                dir = ent.to_dir();
+                location = self.layout.directory_at(raw.first_cluster);
+            }
+            found = Some(raw);
+        }
+        found.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The root directory has no entry of its own."))
+    }
+
+    /// Rewrites the attribute bits in `mask` on the entry at `path`; like FatFs's `f_chmod`,
+    /// only the read-only, hidden, system and archive bits can be changed.
+    ///
+    /// rust-fatfs keeps its own copy of the entry of every file and directory it has
+    /// open and writes all of it back when one of those changes, so attributes set on
+    /// an entry that is open at the time can be lost.
+    fn set_attributes(&self, path : &str, attributes : u8, mask : u8) -> Result<(), io::Error> {
+        let entry = self.locate(path)?;
+        let mask = mask & (ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE);
+        let new_attributes = (entry.attributes & !mask) | (attributes & mask);
+        if new_attributes == entry.attributes {
+            return Ok(());
+        }
+        let mut volume = self.volume();
+        volume.write_attributes(&entry, new_attributes)?;
+        volume.flush()
+    }
+}
+
+/// A rust-fatfs file along with the options it was opened with, since rust-fatfs
+/// itself has no notion of access modes.
+pub struct FatfsFile<'a> {
+    inner : fatfs::File<'a, SharedDisk>,
+    fs : &'a FatfsFileSystem,
+    options : OpenOptions,
+}
+
+impl <'a> Read for FatfsFile<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.options.check_read()?;
+        self.inner.read(buf)
+    }
+}
+
+impl <'a> Write for FatfsFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.options.check_write()?;
+        if self.options.append {
+            self.inner.seek(SeekFrom::End(0))?;
+        }
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+impl <'a> Seek for FatfsFile<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.inner.seek(pos)
+    }
+}
+
+impl <'a> FileOps for FatfsFile<'a> {
     fn truncate(&mut self) -> Result<(), io::Error> {
-        fatfs::File::truncate(self)
+        self.options.check_write()?;
+        fatfs::File::truncate(&mut self.inner)
+    }
+    fn set_len(&mut self, len : u64) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        set_len_with(&mut self.inner, len, |f| fatfs::File::truncate(f))
+    }
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        if contiguous {
+            // rust-fatfs picks clusters itself and gives no way to request or check a run.
+            return Err(fs_error(FsErrorKind::Unsupported));
+        }
+        let position = self.inner.seek(SeekFrom::Current(0))?;
+        let cur_len = self.inner.seek(SeekFrom::End(0))?;
+        self.inner.seek(SeekFrom::Start(position))?;
+        if len <= cur_len {
+            return Ok(());
+        }
+        // rust-fatfs only allocates clusters as a file is written, so the new space is
+        // zero-filled, after checking that it fits so that a full volume fails up front.
+        let stats = self.fs.inner.stats()?;
+        let cluster_size = stats.cluster_size() as u64;
+        let needed = (len + cluster_size - 1) / cluster_size - (cur_len + cluster_size - 1) / cluster_size;
+        if needed > stats.free_clusters() as u64 {
+            return Err(no_space());
+        }
+        let res = self.set_len(len);
+        if res.is_err() {
+            let _ = self.set_len(cur_len);
+        }
+        res
     }
 }
 
 pub struct FatfsDirectory<'a> {
-    inner : Dir<'a, OffsetScsiDevice>,
+    inner : Dir<'a, SharedDisk>,
+    fs : &'a FatfsFileSystem,
+    /// Where this directory is, relative to the root.
+    path : String,
 }
 pub struct FatfsDirIter<'a> {
-    inner : fatfs::DirIter<'a, OffsetScsiDevice>,
+    inner : fatfs::DirIter<'a, SharedDisk>,
 }
 
 impl <'a> Iterator for FatfsDirIter<'a> {
@@ -28,9 +201,9 @@ impl <'a> Iterator for FatfsDirIter<'a> {
                 Err(_u) => {return None;}
             };
             let type_bits = if ent.attributes().contains(FileAttributes::DIRECTORY) {
-                (DirEntryType::Directory as u64) << 12
+                (u8::from(DirEntryType::Directory) as u64) << 12
             } else {
-                (DirEntryType::RegularFile as u64) << 12
+                (u8::from(DirEntryType::RegularFile) as u64) << 12
             };
             let permissions_bits = if ent.attributes().contains(FileAttributes::READ_ONLY) {
                 0o444
@@ -40,7 +213,11 @@ impl <'a> Iterator for FatfsDirIter<'a> {
             Some(DirEntryData {
                 name : ent.file_name(),
                 len : ent.len() as usize,
-                flags : type_bits | permissions_bits
+                flags : type_bits | permissions_bits,
+                attributes : ent.attributes().bits(),
+                modified : Some(timestamp_from_fatfs(ent.modified())),
+                created : Some(timestamp_from_fatfs(ent.created())),
+                accessed : Some(timestamp_from_fatfs(fatfs::DateTime { date : ent.accessed(), time : fatfs::Time { hour : 0, min : 0, sec : 0, millis : 0 } })),
             })
         })
     }
@@ -48,47 +225,222 @@ impl <'a> Iterator for FatfsDirIter<'a> {
 
 impl <'a> DirIterOps for FatfsDirIter<'a> { }
 
-impl <'a> DirectoryOps for FatfsDirectory<'a> {
-    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, io::Error> {
+fn timestamp_from_fatfs(raw : fatfs::DateTime) -> FatTimestamp {
+    FatTimestamp {
+        year : raw.date.year,
+        month : raw.date.month as u8,
+        day : raw.date.day as u8,
+        hour : raw.time.hour as u8,
+        minute : raw.time.min as u8,
+        second : raw.time.sec as u8,
+    }
+}
+
+impl <'a> DirectoryOps<'a> for FatfsDirectory<'a> {
+    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, io::Error> {
         let inner = self.inner.open_dir(path.as_ref())?;
-        Ok(Directory::Fatfs(FatfsDirectory{inner}))
+        Ok(Directory::Fatfs(FatfsDirectory{inner, fs : self.fs, path : path::join(&self.path, path.as_ref())}))
     }
-    fn create_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, io::Error> {
+    fn create_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, io::Error> {
         let inner = self.inner.create_dir(path.as_ref())?;
-        Ok(Directory::Fatfs(FatfsDirectory{inner}))
+        Ok(Directory::Fatfs(FatfsDirectory{inner, fs : self.fs, path : path::join(&self.path, path.as_ref())}))
     }
-    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, io::Error> {
-        Ok(File::Fatfs(self.inner.open_file(path.as_ref())?))
+    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, io::Error> {
+        self.open_file_with(path, OpenOptions::read_write())
     }
-    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, io::Error> {
-        Ok(File::Fatfs(self.inner.create_file(path.as_ref())?))
+    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, io::Error> {
+        self.open_file_with(path, OpenOptions::read_write().create(true))
+    }
+    fn open_file_with<PathType : AsRef<str>>(&mut self, path :PathType, options : OpenOptions) -> Result<File<'a>, io::Error> {
+        options.validate()?;
+        let path = path.as_ref();
+        let mut inner = match self.inner.open_file(path) {
+            Ok(_) if options.create_new => {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            },
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && (options.create || options.create_new) => {
+                self.inner.create_file(path)?
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        };
+        if options.truncate {
+            inner.truncate()?;
+        }
+        Ok(File::Fatfs(FatfsFile { inner, fs : self.fs, options }))
     }
     fn remove_path<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<(), io::Error> {
         self.inner.remove(path.as_ref())
     }
+    fn rename<SrcType : AsRef<str>, DstType : AsRef<str>>(&mut self, src : SrcType, dst : DstType) -> Result<(), io::Error> {
+        self.inner.rename(src.as_ref(), &self.inner, dst.as_ref())
+    }
+    fn set_attributes<PathType : AsRef<str>>(&mut self, path :PathType, attributes : u8, mask : u8) -> Result<(), io::Error> {
+        self.fs.set_attributes(&path::join(&self.path, path.as_ref()), attributes, mask)
+    }
     fn iter<'b>(&'b mut self) -> DirIter<'b> {
         let raw = self.inner.iter();
         DirIter::Fatfs(FatfsDirIter{ inner : raw })
     }
 }
 
-impl FileSystemOps for fatfs::FileSystem<OffsetScsiDevice> {
-    fn root(&mut self) -> Result<Directory, io::Error>  {
-        Ok(Directory::Fatfs(FatfsDirectory{ inner : self.root_dir()}))
+impl FileSystemOps for FatfsFileSystem {
+    fn root(&self) -> Result<Directory, io::Error>  {
+        Ok(Directory::Fatfs(FatfsDirectory{ inner : self.inner.root_dir(), fs : self, path : String::new() }))
     }
     fn stats(&self) -> Result<FsStats, io::Error> {
-        let inner = fatfs::FileSystem::stats(self)?;
+        let inner = self.inner.stats()?;
         let retval = FsStats {
             cluster_size : inner.cluster_size() as u64,
             total_clusters : inner.total_clusters() as u64, 
-            free_clusters : inner.free_clusters() as u64, 
+            free_clusters : inner.free_clusters() as u64,
         };
         Ok(retval)
     }
     fn from_device(dev: OffsetScsiDevice, part : PartitionTableEntry) -> Result<Self, io::Error> {
         match part.partition_type {
-            PartitionType::Fat32(_) | PartitionType::Fat16(_) | PartitionType::Fat12(_) => Self::new(dev, fatfs::FsOptions::new()),
+            PartitionType::Fat32(_) | PartitionType::Fat16(_) | PartitionType::Fat12(_) => Self::new(dev),
             _ => Err(io::Error::from(io::ErrorKind::InvalidData))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Formats an in-memory image of `size` bytes and mounts it.
+#[cfg(test)]
+pub(crate) fn format_test_volume(size : usize) -> FatfsFileSystem {
+    use image_device::ImageDevice;
+    let mut image = io::Cursor::new(vec![0u8 ; size]);
+    fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+    FatfsFileSystem::new(OffsetScsiDevice::new(ImageDevice::new(image), 0)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filesystem::fs_error_kind;
+
+    const VOLUME_SIZE : usize = 4 * 1024 * 1024;
+
+    fn chain_of(fs : &FatfsFileSystem, path : &str) -> Vec<u32> {
+        let entry = fs.locate(path).unwrap();
+        fs.volume().chain(entry.first_cluster).unwrap()
+    }
+
+    #[test]
+    fn open_options_control_creation_and_access() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let mut dir = fs.root().unwrap();
+        let err = dir.open_file_with("missing.txt", OpenOptions::new().read(true)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let mut fl = dir.open_file_with("new.txt", OpenOptions::new().write(true).create(true)).unwrap();
+        fl.write_all(b"first").unwrap();
+        assert_eq!(fl.read(&mut [0 ; 4]).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        drop(fl);
+        let err = dir.open_file_with("new.txt", OpenOptions::read_write().create(true).create_new(true)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        let mut fl = dir.open_file_with("new.txt", OpenOptions::new().read(true)).unwrap();
+        assert_eq!(fl.write(b"x").err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fl.set_len(0).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        drop(fl);
+
+        let mut fl = dir.open_file_with("new.txt", OpenOptions::new().write(true).append(true)).unwrap();
+        fl.seek(SeekFrom::Start(0)).unwrap();
+        fl.write_all(b"second").unwrap();
+        drop(fl);
+        let mut contents = Vec::new();
+        dir.open_file("new.txt").unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"firstsecond");
+
+        let mut fl = dir.open_file_with("new.txt", OpenOptions::new().write(true).truncate(true)).unwrap();
+        assert_eq!(fl.seek(SeekFrom::End(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn set_len_frees_and_zero_fills_clusters_and_keeps_the_position() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let cluster_size = fs.stats().unwrap().cluster_size;
+        let free_at_start = fs.stats().unwrap().free_clusters;
+        let mut dir = fs.root().unwrap();
+        let mut fl = dir.create_file("len.bin").unwrap();
+        fl.write_all(&vec![7 ; 3 * cluster_size as usize]).unwrap();
+        fl.seek(SeekFrom::Start(5)).unwrap();
+        assert_eq!(fs.stats().unwrap().free_clusters, free_at_start - 3);
+
+        fl.set_len(cluster_size + 1).unwrap();
+        assert_eq!(fl.seek(SeekFrom::Current(0)).unwrap(), 5);
+        assert_eq!(fl.seek(SeekFrom::End(0)).unwrap(), cluster_size + 1);
+        assert_eq!(fs.stats().unwrap().free_clusters, free_at_start - 2);
+
+        fl.seek(SeekFrom::Start(5)).unwrap();
+        fl.set_len(cluster_size + 11).unwrap();
+        assert_eq!(fl.seek(SeekFrom::Current(0)).unwrap(), 5);
+        fl.seek(SeekFrom::Start(cluster_size)).unwrap();
+        let mut tail = Vec::new();
+        fl.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, [7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        fl.set_len(0).unwrap();
+        assert_eq!(fl.seek(SeekFrom::End(0)).unwrap(), 0);
+        drop(fl);
+        assert_eq!(fs.stats().unwrap().free_clusters, free_at_start);
+    }
+
+    #[test]
+    fn preallocation_reserves_zeroed_clusters() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let cluster_size = fs.stats().unwrap().cluster_size;
+        let mut dir = fs.root().unwrap();
+        // Leaves old data behind in free clusters.
+        dir.create_file("old.bin").unwrap().write_all(&vec![0xAA ; 4 * cluster_size as usize]).unwrap();
+        dir.remove_path("old.bin").unwrap();
+        let mut fl = dir.create_file("grow.bin").unwrap();
+        fl.write_all(b"abc").unwrap();
+        let free_before = fs.stats().unwrap().free_clusters;
+
+        let len = 3 * cluster_size + 10;
+        fl.preallocate(len, false).unwrap();
+        assert_eq!(fl.seek(SeekFrom::Current(0)).unwrap(), 3);
+        assert_eq!(fl.seek(SeekFrom::End(0)).unwrap(), len);
+        assert_eq!(fs.stats().unwrap().free_clusters, free_before - 3);
+
+        fl.write_all(b"tail").unwrap();
+        drop(fl);
+        assert_eq!(chain_of(&fs, "/grow.bin").len(), 4);
+        let mut contents = Vec::new();
+        dir.open_file("grow.bin").unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len() as u64, len + 4);
+        assert_eq!(&contents[.. 3], b"abc");
+        assert!(contents[3 .. len as usize].iter().all(|&byte| byte == 0));
+        assert_eq!(&contents[len as usize ..], b"tail");
+    }
+
+    #[test]
+    fn contiguous_preallocation_is_not_supported() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let cluster_size = fs.stats().unwrap().cluster_size;
+        let free_before = fs.stats().unwrap().free_clusters;
+        let mut dir = fs.root().unwrap();
+        let mut fl = dir.create_file("run.bin").unwrap();
+        let err = fl.preallocate(4 * cluster_size, true).err().unwrap();
+        assert_eq!(fs_error_kind(&err), Some(FsErrorKind::Unsupported));
+        assert_eq!(fl.seek(SeekFrom::End(0)).unwrap(), 0);
+        assert_eq!(fs.stats().unwrap().free_clusters, free_before);
+    }
+
+    #[test]
+    fn preallocation_fails_with_no_space_and_leaves_the_file_alone() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let stats = fs.stats().unwrap();
+        let too_big = (stats.free_clusters + 1) * stats.cluster_size;
+        let mut dir = fs.root().unwrap();
+        let mut fl = dir.create_file("huge.bin").unwrap();
+        let err = fl.preallocate(too_big, false).err().unwrap();
+        assert_eq!(fs_error_kind(&err), Some(FsErrorKind::NoSpace));
+        assert_eq!(fl.seek(SeekFrom::End(0)).unwrap(), 0);
+        assert_eq!(fs.stats().unwrap().free_clusters, stats.free_clusters);
+    }
+}
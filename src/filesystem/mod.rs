@@ -1,23 +1,30 @@
-use std::io::{Read, Write, Seek};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::fmt;
 use crate::buf_scsi::OffsetScsiDevice;
 use mbr_nostd::PartitionTableEntry;
 pub mod fatfs_rs;
 pub mod fatfs_raw;
+pub mod split;
+pub mod path;
+pub mod buffered;
+pub(crate) mod fat;
 
 
 pub trait FileSystemOps : Sized {
-    fn root(&mut self) -> Result<Directory, std::io::Error>;
+    /// Opens the root directory. Only a shared borrow is needed, so files and
+    /// directories can be opened while others are still open.
+    fn root(&self) -> Result<Directory, std::io::Error>;
     fn stats(&self) -> Result<FsStats, std::io::Error>;
     fn from_device(dev : OffsetScsiDevice, part : PartitionTableEntry) -> Result<Self, std::io::Error>;
 }
 
 pub enum FileSystem {
-    Fatfs(fatfs::FileSystem<OffsetScsiDevice>),
+    Fatfs(fatfs_rs::FatfsFileSystem),
     FatfsSys(fatfs_raw::FatfsSysFileSystem),
 }
 
 impl FileSystemOps for FileSystem {
-    fn root(&mut self) -> Result<Directory, std::io::Error> {
+    fn root(&self) -> Result<Directory, std::io::Error> {
         match self {
             FileSystem::Fatfs(f) => FileSystemOps::root(f),
             FileSystem::FatfsSys(f) => FileSystemOps::root(f),
@@ -38,11 +45,134 @@ impl FileSystemOps for FileSystem {
 
 pub trait FileOps : Read + Write + Seek {
     fn truncate(&mut self) -> Result<(), std::io::Error>;
+    /// Resizes the file to exactly `len` bytes without moving the file position.
+    /// Shrinking frees the clusters past the new end; growing fills the new space with zeros.
+    fn set_len(&mut self, len : u64) -> Result<(), std::io::Error>;
+    /// Grows the file to at least `len` bytes, reserving all of its clusters up front.
+    /// If `contiguous` is set the clusters are also guaranteed to form a single run;
+    /// backends that cannot lay one out fail with `FsErrorKind::Unsupported`.
+    /// Fails with a `no_space` error without changing the file if the volume cannot fit it.
+    /// Unlike `set_len`, the newly reserved bytes are not guaranteed to be zeroed.
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), std::io::Error>;
+}
+
+/// The access mode and creation behaviour to use when opening a file, mirroring
+/// `std::fs::OpenOptions`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct OpenOptions {
+    pub read : bool,
+    pub write : bool,
+    pub append : bool,
+    pub truncate : bool,
+    pub create : bool,
+    pub create_new : bool,
+}
+
+pub const O_RDONLY : u32 = 0x0000;
+pub const O_WRONLY : u32 = 0x0001;
+pub const O_RDWR : u32 = 0x0002;
+pub const O_ACCMODE : u32 = 0x0003;
+pub const O_APPEND : u32 = 0x0008;
+pub const O_CREAT : u32 = 0x0200;
+pub const O_TRUNC : u32 = 0x0400;
+pub const O_EXCL : u32 = 0x0800;
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    /// Read-write access to an existing file.
+    pub fn read_write() -> OpenOptions {
+        OpenOptions::new().read(true).write(true)
+    }
+
+    pub fn read(mut self, read : bool) -> OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(mut self, write : bool) -> OpenOptions {
+        self.write = write;
+        self
+    }
+
+    pub fn append(mut self, append : bool) -> OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(mut self, truncate : bool) -> OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(mut self, create : bool) -> OpenOptions {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(mut self, create_new : bool) -> OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Builds the options from newlib's `open` flags.
+    pub fn from_posix_flags(flags : u32) -> OpenOptions {
+        let (read, write) = match flags & O_ACCMODE {
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => (true, false),
+        };
+        OpenOptions {
+            read, 
+            write,
+            append : flags & O_APPEND != 0,
+            truncate : flags & O_TRUNC != 0,
+            create : flags & O_CREAT != 0,
+            create_new : flags & O_CREAT != 0 && flags & O_EXCL != 0,
+        }
+    }
+
+    /// The options to use when reopening a file that was already opened with these
+    /// options; that is, the same access mode without any creation or truncation.
+    pub fn for_reopen(self) -> OpenOptions {
+        OpenOptions {
+            truncate : false, 
+            create : false, 
+            create_new : false,
+            ..self
+        }
+    }
+
+    /// Whether opening a file with these options can change the volume.
+    pub fn modifies_volume(&self) -> bool {
+        self.write || self.create || self.create_new || self.truncate
+    }
+
+    pub fn validate(&self) -> Result<(), std::io::Error> {
+        if !self.read && !self.write {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Files must be opened for reading, writing, or both."));
+        }
+        if (self.truncate || self.append) && !self.write {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Truncating or appending requires write access."));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_read(&self) -> Result<(), std::io::Error> {
+        if self.read { Ok(()) } else { Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "File was not opened for reading.")) }
+    }
+
+    pub(crate) fn check_write(&self) -> Result<(), std::io::Error> {
+        if self.write { Ok(()) } else { Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "File was not opened for writing.")) }
+    }
 }
 
 pub enum File<'a> {
-    Fatfs(fatfs::File<'a, OffsetScsiDevice>),
+    Fatfs(fatfs_rs::FatfsFile<'a>),
     FatfsSys(fatfs_raw::FatfsSysFile),
+    Split(Box<split::SplitFile<'a>>),
 }
 
 impl <'a> Read for File<'a> {
@@ -50,6 +180,7 @@ impl <'a> Read for File<'a> {
         match self {
             File::Fatfs(f) => Read::read(f, buf),
             File::FatfsSys(f) => Read::read(f, buf),
+            File::Split(f) => Read::read(f.as_mut(), buf),
         }
     }
 }
@@ -58,12 +189,14 @@ impl <'a> Write for File<'a> {
         match self {
             File::Fatfs(f) => Write::write(f, buf),
             File::FatfsSys(f) => Write::write(f, buf),
+            File::Split(f) => Write::write(f.as_mut(), buf),
         }
     }
     fn flush(&mut self) -> Result<(), std::io::Error> {
         match self {
             File::Fatfs(f) => Write::flush(f),
             File::FatfsSys(f) => Write::flush(f),
+            File::Split(f) => Write::flush(f.as_mut()),
         }
     }
 }
@@ -73,6 +206,7 @@ impl <'a> Seek for File<'a> {
         match self {
             File::Fatfs(f) => Seek::seek(f, pos),
             File::FatfsSys(f) => Seek::seek(f, pos),
+            File::Split(f) => Seek::seek(f.as_mut(), pos),
         }
     }
 
@@ -83,18 +217,129 @@ impl <'a> FileOps for File<'a> {
         match self {
             File::Fatfs(f) => FileOps::truncate(f),
             File::FatfsSys(f) => FileOps::truncate(f),
+            File::Split(f) => FileOps::truncate(f.as_mut()),
         }
     }
+    fn set_len(&mut self, len : u64) -> Result<(), std::io::Error> {
+        match self {
+            File::Fatfs(f) => FileOps::set_len(f, len),
+            File::FatfsSys(f) => FileOps::set_len(f, len),
+            File::Split(f) => FileOps::set_len(f.as_mut(), len),
+        }
+    }
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), std::io::Error> {
+        match self {
+            File::Fatfs(f) => FileOps::preallocate(f, len, contiguous),
+            File::FatfsSys(f) => FileOps::preallocate(f, len, contiguous),
+            File::Split(f) => FileOps::preallocate(f.as_mut(), len, contiguous),
+        }
+    }
+
+}
+
+/// Filesystem failures that `std::io::ErrorKind` has no variant for. They are carried
+/// as the payload of an `ErrorKind::Other` error; see `fs_error` and `fs_error_kind`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum FsErrorKind {
+    /// The volume has no room for an allocation.
+    NoSpace,
+    IsADirectory,
+    NotADirectory,
+    DirectoryNotEmpty,
+    /// The entry is in use by an open handle.
+    Busy,
+    /// The volume is write protected.
+    ReadOnly,
+    /// The backend has no way to carry out the operation.
+    Unsupported,
+}
+
+impl fmt::Display for FsErrorKind {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let desc = match self {
+            FsErrorKind::NoSpace => "No space left on the volume.",
+            FsErrorKind::IsADirectory => "Entry is a directory.",
+            FsErrorKind::NotADirectory => "Entry is not a directory.",
+            FsErrorKind::DirectoryNotEmpty => "Directory is not empty.",
+            FsErrorKind::Busy => "Entry is in use.",
+            FsErrorKind::ReadOnly => "Volume is read-only.",
+            FsErrorKind::Unsupported => "Operation is not supported by the backend.",
+        };
+        write!(f, "{}", desc)
+    }
+}
+
+impl std::error::Error for FsErrorKind {}
+
+pub fn fs_error(kind : FsErrorKind) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, kind)
+}
+
+pub fn fs_error_kind(err : &std::io::Error) -> Option<FsErrorKind> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<FsErrorKind>()).cloned()
+}
+
+pub fn no_space() -> std::io::Error {
+    fs_error(FsErrorKind::NoSpace)
+}
+
+/// Writes `count` zero bytes to `output` at its current position.
+pub(crate) fn write_zeros<W : Write>(output : &mut W, count : u64) -> Result<(), std::io::Error> {
+    let zeros = [0u8 ; 512];
+    let mut remaining = count;
+    while remaining > 0 {
+        let chunk = remaining.min(zeros.len() as u64) as usize;
+        output.write_all(&zeros[.. chunk])?;
+        remaining -= chunk as u64;
+    }
+    Ok(())
+}
 
+/// Reads the volume label stored in the boot sector at the start of `device`, with
+/// its padding removed, then rewinds. exFAT keeps its label in the root directory
+/// instead, so those volumes read as unlabeled.
+pub fn boot_sector_label<T : Read + Seek>(device : &mut T) -> Result<String, std::io::Error> {
+    let mut boot_sector = [0u8 ; 512];
+    device.seek(SeekFrom::Start(0))?;
+    device.read_exact(&mut boot_sector)?;
+    device.seek(SeekFrom::Start(0))?;
+    if &boot_sector[3 .. 11] == b"EXFAT   " {
+        return Ok(String::new());
+    }
+    // FAT32 has no 16-bit FAT size and an extended BPB, which pushes the label back.
+    let sectors_per_fat_16 = u16::from(boot_sector[0x16]) | (u16::from(boot_sector[0x17]) << 8);
+    let label_offset = if sectors_per_fat_16 == 0 { 0x47 } else { 0x2B };
+    let label = &boot_sector[label_offset .. label_offset + 11];
+    Ok(String::from_utf8_lossy(label).trim_end_matches(' ').to_owned())
 }
 
-pub trait DirectoryOps : Sized {
-    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, std::io::Error>;
-    fn create_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, std::io::Error>;
-    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, std::io::Error>;
-    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, std::io::Error>;
+/// Implements `FileOps::set_len` on top of a backend's truncate-at-position operation.
+pub(crate) fn set_len_with<F, T>(file : &mut F, len : u64, truncate : T) -> Result<(), std::io::Error> 
+    where F : Write + Seek, T : FnOnce(&mut F) -> Result<(), std::io::Error> 
+{
+    let position = file.seek(SeekFrom::Current(0))?;
+    let cur_len = file.seek(SeekFrom::End(0))?;
+    if len < cur_len {
+        file.seek(SeekFrom::Start(len))?;
+        truncate(file)?;
+    }
+    else if len > cur_len {
+        write_zeros(file, len - cur_len)?;
+    }
+    file.seek(SeekFrom::Start(position))?;
+    Ok(())
+}
+
+pub trait DirectoryOps<'a> : Sized {
+    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, std::io::Error>;
+    fn create_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, std::io::Error>;
+    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, std::io::Error>;
+    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, std::io::Error>;
+    fn open_file_with<PathType : AsRef<str>>(&mut self, path :PathType, options : OpenOptions) -> Result<File<'a>, std::io::Error>;
     fn remove_path<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<(), std::io::Error>;
-    fn iter<'a>(&'a mut self) -> DirIter<'a>;
+    fn rename<SrcType : AsRef<str>, DstType : AsRef<str>>(&mut self, src : SrcType, dst : DstType) -> Result<(), std::io::Error>;
+    fn set_attributes<PathType : AsRef<str>>(&mut self, path :PathType, attributes : u8, mask : u8) -> Result<(), std::io::Error>;
+    fn iter<'b>(&'b mut self) -> DirIter<'b>;
 }
 
 pub enum Directory<'a> {
@@ -102,37 +347,55 @@ pub enum Directory<'a> {
     FatfsSys(fatfs_raw::FatfsSysDir),
 }
 
-impl <'a> DirectoryOps for Directory<'a> {
-    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, std::io::Error> {
+impl <'a> DirectoryOps<'a> for Directory<'a> {
+    fn open_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, std::io::Error> {
         match self {
             Directory::Fatfs(f) => DirectoryOps::open_directory(f, path),
             Directory::FatfsSys(f) => DirectoryOps::open_directory(f, path),
         }
     }
-    fn create_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory, std::io::Error> {
+    fn create_directory<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<Directory<'a>, std::io::Error> {
         match self {
             Directory::Fatfs(f) => DirectoryOps::create_directory(f, path),
             Directory::FatfsSys(f) => DirectoryOps::create_directory(f, path),
         }
     }
-    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, std::io::Error> {
+    fn open_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, std::io::Error> {
         match self {
             Directory::Fatfs(f) => DirectoryOps::open_file(f, path),
             Directory::FatfsSys(f) => DirectoryOps::open_file(f, path),
         }
     }
-    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File, std::io::Error> {
+    fn create_file<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<File<'a>, std::io::Error> {
         match self {
             Directory::Fatfs(f) => DirectoryOps::create_file(f, path),
             Directory::FatfsSys(f) => DirectoryOps::create_file(f, path),
         }
     }
+    fn open_file_with<PathType : AsRef<str>>(&mut self, path :PathType, options : OpenOptions) -> Result<File<'a>, std::io::Error> {
+        match self {
+            Directory::Fatfs(f) => DirectoryOps::open_file_with(f, path, options),
+            Directory::FatfsSys(f) => DirectoryOps::open_file_with(f, path, options),
+        }
+    }
     fn remove_path<PathType : AsRef<str>>(&mut self, path :PathType) -> Result<(), std::io::Error> {
         match self {
             Directory::Fatfs(f) => DirectoryOps::remove_path(f, path),
             Directory::FatfsSys(f) => DirectoryOps::remove_path(f, path),
         }
     }
+    fn rename<SrcType : AsRef<str>, DstType : AsRef<str>>(&mut self, src : SrcType, dst : DstType) -> Result<(), std::io::Error> {
+        match self {
+            Directory::Fatfs(f) => DirectoryOps::rename(f, src, dst),
+            Directory::FatfsSys(f) => DirectoryOps::rename(f, src, dst),
+        }
+    }
+    fn set_attributes<PathType : AsRef<str>>(&mut self, path :PathType, attributes : u8, mask : u8) -> Result<(), std::io::Error> {
+        match self {
+            Directory::Fatfs(f) => DirectoryOps::set_attributes(f, path, attributes, mask),
+            Directory::FatfsSys(f) => DirectoryOps::set_attributes(f, path, attributes, mask),
+        }
+    }
     fn iter<'b>(&'b mut self) -> DirIter<'b> {
         match self {
             Directory::Fatfs(f) => DirectoryOps::iter(f),
@@ -163,6 +426,7 @@ impl <'a> Iterator for DirIter<'a> {
 
 impl <'a> DirIterOps for DirIter<'a> {}
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct FsStats {
     pub cluster_size : u64, 
     pub free_clusters : u64,
@@ -174,6 +438,13 @@ pub struct DirEntryData {
     pub name : String, 
     pub len : usize,
     pub flags : u64, 
+    /// The raw FAT attribute byte of the entry; see the `ATTR_*` constants.
+    pub attributes : u8,
+    pub modified : Option<FatTimestamp>,
+    /// Not every backend tracks this; FatFs only reports the modification time.
+    pub created : Option<FatTimestamp>,
+    /// FAT only stores the date of the last access, so the time part is always midnight.
+    pub accessed : Option<FatTimestamp>,
 }
 
 impl DirEntryData {
@@ -181,8 +452,71 @@ impl DirEntryData {
         let flag_byte = ((self.flags & 0xf000) >> 12) as u8;
         flag_byte.into()
     }
+
+    pub fn has_attributes(&self, attributes : u8) -> bool {
+        self.attributes & attributes == attributes
+    }
+
+    /// Whether or not this is one of the `.` and `..` entries present in FAT subdirectories.
+    pub fn is_dot_entry(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+}
+
+/// A timestamp as stored in a FAT directory entry. FAT has no notion of timezones,
+/// so this is in whatever local time the writer used.
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct FatTimestamp {
+    pub year : u16,
+    pub month : u8,
+    pub day : u8,
+    pub hour : u8,
+    pub minute : u8,
+    pub second : u8,
 }
 
+impl FatTimestamp {
+    /// Decodes the packed date and time words of a FAT directory entry.
+    /// A zero date means the field was never set.
+    pub fn from_packed(date : u16, time : u16) -> Option<FatTimestamp> {
+        if date == 0 {
+            return None;
+        }
+        Some(FatTimestamp {
+            year : 1980 + (date >> 9),
+            month : ((date >> 5) & 0xF) as u8,
+            day : (date & 0x1F) as u8,
+            hour : (time >> 11) as u8,
+            minute : ((time >> 5) & 0x3F) as u8,
+            second : ((time & 0x1F) * 2) as u8,
+        })
+    }
+
+    /// Seconds since the Unix epoch, given how many seconds the timestamp's local
+    /// time is ahead of UTC.
+    pub fn to_unix(&self, utc_offset : i32) -> i64 {
+        // Days-from-civil, counting years from March so the leap day falls last.
+        let month = self.month.max(1).min(12) as i64;
+        let day = self.day.max(1) as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        days * 86_400 + seconds - utc_offset as i64
+    }
+}
+
+pub const ATTR_READ_ONLY : u8 = 0x01;
+pub const ATTR_HIDDEN : u8 = 0x02;
+pub const ATTR_SYSTEM : u8 = 0x04;
+pub const ATTR_VOLUME_ID : u8 = 0x08;
+pub const ATTR_DIRECTORY : u8 = 0x10;
+pub const ATTR_ARCHIVE : u8 = 0x20;
+
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
 pub enum DirEntryType {
     Unknown, 
@@ -231,3 +565,60 @@ impl From<DirEntryType> for u8 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(year : u16, month : u8, day : u8, hour : u8, minute : u8, second : u8) -> FatTimestamp {
+        FatTimestamp { year, month, day, hour, minute, second }
+    }
+
+    #[test]
+    fn packed_timestamps_decode_and_zero_dates_are_unset() {
+        assert_eq!(FatTimestamp::from_packed(0x4EEE, 0x6DAF), Some(timestamp(2019, 7, 14, 13, 45, 30)));
+        assert_eq!(FatTimestamp::from_packed(0x0021, 0), Some(timestamp(1980, 1, 1, 0, 0, 0)));
+        assert_eq!(FatTimestamp::from_packed(0, 0x6DAF), None);
+    }
+
+    #[test]
+    fn unix_time_counts_leap_days_and_the_utc_offset() {
+        assert_eq!(timestamp(1980, 1, 1, 0, 0, 0).to_unix(0), 315_532_800);
+        assert_eq!(timestamp(1999, 3, 1, 0, 0, 0).to_unix(0), 920_246_400);
+        assert_eq!(timestamp(2000, 2, 29, 12, 34, 56).to_unix(0), 951_827_696);
+        assert_eq!(timestamp(2107, 12, 31, 23, 59, 58).to_unix(0), 4_354_819_198);
+        // Local time ahead of UTC happened earlier in UTC, and behind it later.
+        assert_eq!(timestamp(2019, 7, 14, 13, 45, 30).to_unix(2 * 3600), 1_563_111_930 - 7200);
+        assert_eq!(timestamp(2019, 7, 14, 13, 45, 30).to_unix(-5 * 3600), 1_563_111_930 + 18_000);
+    }
+
+    #[test]
+    fn posix_flags_map_to_open_options() {
+        assert_eq!(OpenOptions::from_posix_flags(O_RDONLY), OpenOptions::new().read(true));
+        assert_eq!(OpenOptions::from_posix_flags(O_WRONLY | O_CREAT | O_TRUNC), OpenOptions::new().write(true).create(true).truncate(true));
+        assert_eq!(OpenOptions::from_posix_flags(O_RDWR | O_APPEND), OpenOptions::read_write().append(true));
+        assert_eq!(OpenOptions::from_posix_flags(O_RDWR | O_CREAT | O_EXCL), OpenOptions::read_write().create(true).create_new(true));
+        // O_EXCL means nothing without O_CREAT.
+        assert_eq!(OpenOptions::from_posix_flags(O_RDONLY | O_EXCL), OpenOptions::new().read(true));
+        // Both access bits set is not a valid mode; treat it as read-only rather than granting writes.
+        assert_eq!(OpenOptions::from_posix_flags(O_ACCMODE), OpenOptions::new().read(true));
+    }
+
+    #[test]
+    fn unusable_options_are_rejected() {
+        assert!(OpenOptions::new().validate().is_err());
+        assert!(OpenOptions::new().read(true).truncate(true).validate().is_err());
+        assert!(OpenOptions::new().read(true).append(true).validate().is_err());
+        assert!(OpenOptions::new().write(true).append(true).validate().is_ok());
+        assert!(OpenOptions::new().read(true).create(true).validate().is_ok());
+    }
+
+    #[test]
+    fn reopening_keeps_the_access_mode_only() {
+        let options = OpenOptions::from_posix_flags(O_WRONLY | O_APPEND | O_CREAT | O_EXCL | O_TRUNC);
+        assert_eq!(options.for_reopen(), OpenOptions::new().write(true).append(true));
+        assert!(options.modifies_volume());
+        assert!(!OpenOptions::new().read(true).create(false).modifies_volume());
+        assert!(OpenOptions::new().read(true).create(true).modifies_volume());
+    }
+}
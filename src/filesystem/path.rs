@@ -0,0 +1,221 @@
+//! Path parsing and normalization shared by every C entry point.
+//!
+//! Every path handed to a backend is normalized first: it is absolute from the
+//! volume root, uses single `/` separators, has no `.` or `..` components, has no
+//! trailing separator, and has been checked against FAT's naming rules.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::os::raw::c_char;
+
+/// The longest single name a FAT long file name entry can hold, in UTF-16 units.
+pub const MAX_COMPONENT_LEN : usize = 255;
+
+/// The longest full path we accept, matching newlib's `PATH_MAX`.
+pub const MAX_PATH_LEN : usize = 1024;
+
+/// Characters FAT forbids in long file names, besides control characters.
+const ILLEGAL_CHARS : &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The C caller passed a null pointer instead of a path.
+    NullPointer,
+    /// The path is not valid UTF-8.
+    InvalidUtf8,
+    /// The path has nothing left once the device prefix is removed.
+    Empty,
+    /// A component contains a character FAT cannot store.
+    InvalidCharacter(char),
+    /// A component is longer than `MAX_COMPONENT_LEN`; holds its actual length.
+    ComponentTooLong(usize),
+    /// The normalized path is longer than `MAX_PATH_LEN`; holds its actual length.
+    PathTooLong(usize),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::NullPointer => write!(f, "Path pointer was null."),
+            PathError::InvalidUtf8 => write!(f, "Path is not valid UTF-8."),
+            PathError::Empty => write!(f, "Path is empty."),
+            PathError::InvalidCharacter(c) => write!(f, "Path contains the illegal character {:?}.", c),
+            PathError::ComponentTooLong(len) => write!(f, "Path component is {} characters long; the maximum is {}.", len, MAX_COMPONENT_LEN),
+            PathError::PathTooLong(len) => write!(f, "Path is {} bytes long; the maximum is {}.", len, MAX_PATH_LEN),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<PathError> for io::Error {
+    fn from(err : PathError) -> io::Error {
+        let kind = match err {
+            PathError::Empty => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Removes a leading devoptab device name such as `usbfs:`, if there is one.
+pub fn strip_device(path : &str) -> &str {
+    match path.find(':') {
+        Some(idx) if !path[.. idx].contains('/') => &path[idx + 1 ..],
+        _ => path,
+    }
+}
+
+/// Checks a single path component against FAT's long file name rules.
+pub fn validate_component(name : &str) -> Result<(), PathError> {
+    if let Some(c) = name.chars().find(|c| (*c as u32) < 0x20 || ILLEGAL_CHARS.contains(c)) {
+        return Err(PathError::InvalidCharacter(c));
+    }
+    let len = name.encode_utf16().count();
+    if len > MAX_COMPONENT_LEN {
+        return Err(PathError::ComponentTooLong(len));
+    }
+    Ok(())
+}
+
+/// Resolves `path` against the already-normalized directory `base`, returning a
+/// normalized absolute path. `..` at the root stays at the root, as in POSIX.
+pub fn resolve(base : &str, path : &str) -> Result<String, PathError> {
+    let path = strip_device(path);
+    if path.is_empty() {
+        return Err(PathError::Empty);
+    }
+    let mut components : Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        components.extend(base.split('/').filter(|comp| !comp.is_empty()));
+    }
+    for comp in path.split('/') {
+        match comp {
+            "" | "." => {},
+            ".." => {
+                components.pop();
+            },
+            name => {
+                validate_component(name)?;
+                components.push(name);
+            }
+        }
+    }
+    let retval = format!("/{}", components.join("/"));
+    if retval.len() > MAX_PATH_LEN {
+        return Err(PathError::PathTooLong(retval.len()));
+    }
+    Ok(retval)
+}
+
+/// Normalizes `path`, treating relative paths as relative to the volume root.
+pub fn normalize(path : &str) -> Result<String, PathError> {
+    resolve("/", path)
+}
+
+/// Reads a NUL-terminated path passed in from C without normalizing it.
+pub unsafe fn c_path_str<'a>(ptr : *const u8) -> Result<&'a str, PathError> {
+    if ptr.is_null() {
+        return Err(PathError::NullPointer);
+    }
+    CStr::from_ptr(ptr as *const c_char).to_str().map_err(|_| PathError::InvalidUtf8)
+}
+
+/// Reads and normalizes a NUL-terminated path passed in from C.
+pub unsafe fn parse_c_path(ptr : *const u8) -> Result<String, PathError> {
+    normalize(c_path_str(ptr)?)
+}
+
+pub fn is_root(path : &str) -> bool {
+    path.trim_matches('/').is_empty()
+}
+
+/// Splits a normalized path into its parent directory and final component.
+/// The root splits into `("/", "")`.
+pub fn split_parent(path : &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1 ..]),
+        Some(idx) => (&path[.. idx], &path[idx + 1 ..]),
+        None => ("/", path),
+    }
+}
+
+/// Joins a directory path and a child name using `/` as the separator.
+pub fn join(parent : &str, name : &str) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_device_only_removes_a_leading_device_name() {
+        assert_eq!(strip_device("usbfs:/games/a.nsp"), "/games/a.nsp");
+        assert_eq!(strip_device("usbfs:relative"), "relative");
+        assert_eq!(strip_device("/games/a:b"), "/games/a:b");
+        assert_eq!(strip_device("/no/device"), "/no/device");
+        assert_eq!(strip_device("usbfs:"), "");
+    }
+
+    #[test]
+    fn resolve_normalizes_against_the_base() {
+        assert_eq!(resolve("/games", "a.nsp").unwrap(), "/games/a.nsp");
+        assert_eq!(resolve("/games", "/saves//slot/").unwrap(), "/saves/slot");
+        assert_eq!(resolve("/games/switch", "./../a.nsp").unwrap(), "/games/a.nsp");
+        assert_eq!(resolve("/games", "usbfs:/a.nsp").unwrap(), "/a.nsp");
+        assert_eq!(resolve("/", "usbfs:"), Err(PathError::Empty));
+        assert_eq!(resolve("/", ""), Err(PathError::Empty));
+    }
+
+    #[test]
+    fn dot_dot_at_the_root_stays_at_the_root() {
+        assert_eq!(resolve("/", "..").unwrap(), "/");
+        assert_eq!(resolve("/games", "../../..").unwrap(), "/");
+        assert_eq!(resolve("/", "/../../games/../saves").unwrap(), "/saves");
+        assert!(is_root(&normalize("usbfs:/..").unwrap()));
+    }
+
+    #[test]
+    fn illegal_characters_are_rejected() {
+        for c in &['"', '*', ':', '<', '>', '?', '\\', '|', '\u{1}', '\u{1f}'] {
+            let name = format!("bad{}name", c);
+            assert_eq!(validate_component(&name), Err(PathError::InvalidCharacter(*c)));
+            assert_eq!(resolve("/", &format!("/games/{}", name)), Err(PathError::InvalidCharacter(*c)));
+        }
+        assert!(validate_component("ok name (1).nsp").is_ok());
+        assert!(validate_component("日本語").is_ok());
+        let err : io::Error = PathError::InvalidCharacter('*').into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn length_limits_are_enforced() {
+        let longest = "a".repeat(MAX_COMPONENT_LEN);
+        assert!(validate_component(&longest).is_ok());
+        let too_long = "a".repeat(MAX_COMPONENT_LEN + 1);
+        assert_eq!(validate_component(&too_long), Err(PathError::ComponentTooLong(MAX_COMPONENT_LEN + 1)));
+        // Non-BMP characters take two UTF-16 units each.
+        let wide = "\u{1F600}".repeat(MAX_COMPONENT_LEN / 2 + 1);
+        assert_eq!(validate_component(&wide), Err(PathError::ComponentTooLong((MAX_COMPONENT_LEN / 2 + 1) * 2)));
+
+        let component = "a".repeat(99);
+        let fits = format!("/{}", vec![component.as_str() ; MAX_PATH_LEN / 100].join("/"));
+        assert_eq!(resolve("/", &fits).unwrap().len(), fits.len());
+        let over = format!("{}/{}", fits, component);
+        assert_eq!(resolve("/", &over), Err(PathError::PathTooLong(over.len())));
+        // The limit applies to the normalized path, not to what the caller passed in.
+        let with_dots = format!("{}/{}/..", fits, component);
+        assert!(resolve("/", &with_dots).is_ok());
+    }
+
+    #[test]
+    fn split_parent_and_join_round_trip() {
+        assert_eq!(split_parent("/games/a.nsp"), ("/games", "a.nsp"));
+        assert_eq!(split_parent("/a.nsp"), ("/", "a.nsp"));
+        assert_eq!(split_parent("/"), ("/", ""));
+        assert_eq!(join("/", "a.nsp"), "/a.nsp");
+        assert_eq!(join("/games/", "a.nsp"), "/games/a.nsp");
+    }
+}
@@ -0,0 +1,789 @@
+//! Support for Horizon's "split file" folders.
+//!
+//! FAT32 cannot store files larger than 4 GiB, so Horizon stores large NSPs and XCIs as
+//! a directory of numbered chunks (`00`, `01`, ...) with the FAT archive attribute set;
+//! the directory is then treated as a single file made of its chunks concatenated in order.
+
+use super::path;
+use super::{DirectoryOps, Directory, DirEntryData, DirEntryType, File, FileOps, FsErrorKind, OpenOptions, ATTR_ARCHIVE, ATTR_DIRECTORY, fs_error, write_zeros};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+
+/// The chunk size Horizon itself uses when splitting files.
+pub const DEFAULT_CHUNK_SIZE : u64 = 0xFFFF_0000;
+
+/// Parses the name of a split-file chunk into its index, or `None` if the name
+/// is not a chunk name.
+pub fn chunk_index(name : &str) -> Option<usize> {
+    if name.len() < 2 || !name.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/// Gets the name used for the chunk at index `idx`.
+pub fn chunk_name(idx : usize) -> String {
+    format!("{:02}", idx)
+}
+
+/// Checks whether a directory with the given children is laid out like a split-file
+/// container; that is, it contains only regular files named `00`, `01`, ... with
+/// no gaps in the numbering. Only the layout is checked; `is_split_directory` also
+/// requires the archive bit.
+pub fn is_split_listing(children : &[DirEntryData]) -> bool {
+    let mut indices = Vec::with_capacity(children.len());
+    for child in children.iter().filter(|c| !c.is_dot_entry()) {
+        if child.entry_type() != DirEntryType::RegularFile {
+            return false;
+        }
+        match chunk_index(&child.name) {
+            Some(idx) => indices.push(idx),
+            None => { return false; }
+        }
+    }
+    if indices.is_empty() {
+        return false;
+    }
+    indices.sort();
+    indices.iter().enumerate().all(|(expected, actual)| expected == *actual)
+}
+
+/// Lists the entries of the directory at `path`, relative to `root`.
+pub(crate) fn list_directory<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str) -> Result<Vec<DirEntryData>, io::Error> {
+    if path::is_root(path) {
+        Ok(root.iter().filter(|ent| !ent.is_dot_entry()).collect())
+    }
+    else {
+        let mut dir = root.open_directory(path)?;
+        let retval = dir.iter().filter(|ent| !ent.is_dot_entry()).collect();
+        Ok(retval)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ArchiveBitAction {
+    Set,
+    Clear,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ArchiveBitChange {
+    /// The path of the split-file directory.
+    pub path : String,
+    /// Whether the archive bit was set before the walk.
+    pub was_set : bool,
+    /// Whether the attribute was actually written; always false during dry runs.
+    pub applied : bool,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ArchiveBitReport {
+    /// Split-file directories whose archive bit needed changing.
+    pub changed : Vec<ArchiveBitChange>,
+    /// Split-file directories that already had the requested archive bit value.
+    pub unchanged : Vec<String>,
+    /// Directories that could not be read or updated, along with the error.
+    pub failed : Vec<(String, io::ErrorKind)>,
+}
+
+/// Walks the tree under `start`, setting or clearing the archive bit on every
+/// directory that looks like a split-file container.
+///
+/// Split-file containers are not descended into. If `dry_run` is true, no
+/// attributes are written and the report only lists what would have changed.
+pub fn fix_archive_bits<'a, D : DirectoryOps<'a>>(root : &mut D, start : &str, action : ArchiveBitAction, dry_run : bool) -> Result<ArchiveBitReport, io::Error> {
+    let mut report = ArchiveBitReport::default();
+    let mut pending = vec![start.to_owned()];
+    let want_set = action == ArchiveBitAction::Set;
+
+    while let Some(dir_path) = pending.pop() {
+        let entries = match list_directory(root, &dir_path) {
+            Ok(e) => e,
+            Err(e) => {
+                if dir_path == start {
+                    return Err(e);
+                }
+                report.failed.push((dir_path, e.kind()));
+                continue;
+            }
+        };
+
+        for ent in entries.into_iter().filter(|e| e.entry_type() == DirEntryType::Directory) {
+            let child_path = path::join(&dir_path, &ent.name);
+            let children = match list_directory(root, &child_path) {
+                Ok(c) => c,
+                Err(e) => {
+                    report.failed.push((child_path, e.kind()));
+                    continue;
+                }
+            };
+            if !is_split_listing(&children) {
+                pending.push(child_path);
+                continue;
+            }
+
+            let was_set = ent.has_attributes(ATTR_ARCHIVE);
+            if was_set == want_set {
+                report.unchanged.push(child_path);
+                continue;
+            }
+            let applied = if dry_run {
+                false
+            }
+            else {
+                let new_attrs = if want_set { ATTR_ARCHIVE } else { 0 };
+                match root.set_attributes(&child_path, new_attrs, ATTR_ARCHIVE) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        report.failed.push((child_path, e.kind()));
+                        continue;
+                    }
+                }
+            };
+            report.changed.push(ArchiveBitChange {
+                path : child_path,
+                was_set,
+                applied,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Checks whether the entry at `path` is a split file: a directory with the archive
+/// bit set, laid out as a split file. Like Horizon, directories without the archive
+/// bit are always treated as ordinary directories.
+pub fn is_split_directory<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str) -> bool {
+    if path::is_root(path) {
+        return false;
+    }
+    let (parent, name) = path::split_parent(path);
+    let lower_name = name.to_lowercase();
+    let ent = list_directory(root, parent).ok()
+        .and_then(|children| children.into_iter().find(|ent| ent.name.to_lowercase() == lower_name));
+    match ent {
+        Some(ent) => is_split_entry(root, path, &ent),
+        None => false,
+    }
+}
+
+/// Checks whether `ent`, the entry found at `path`, is a split file.
+fn is_split_entry<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str, ent : &DirEntryData) -> bool {
+    ent.entry_type() == DirEntryType::Directory
+        && ent.has_attributes(ATTR_ARCHIVE)
+        && list_directory(root, path).map(|children| is_split_listing(&children)).unwrap_or(false)
+}
+
+/// Opens the file at `path`, falling back to opening it as a `SplitFile` if
+/// `path` is a split-file directory.
+pub fn open_file_or_split<'a>(mut root : Directory<'a>, path : &str, options : OpenOptions) -> Result<File<'a>, io::Error> {
+    let err = match root.open_file_with(path, options) {
+        Ok(f) => { return Ok(f); },
+        Err(e) => e,
+    };
+    if !is_split_directory(&mut root, path) {
+        if !path::is_root(path) && root.open_directory(path).is_err() {
+            return Err(err);
+        }
+        return Err(fs_error(FsErrorKind::IsADirectory));
+    }
+    let split = SplitFile::open_with(root, path, options, None)?;
+    Ok(File::Split(Box::new(split)))
+}
+
+/// Looks up the entry at the normalized `path`, presenting split-file directories
+/// as the single regular file they represent.
+pub fn lookup<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str) -> Result<DirEntryData, io::Error> {
+    if path::is_root(path) {
+        return Ok(DirEntryData {
+            name : "/".to_owned(),
+            len : 0,
+            flags : ((u8::from(DirEntryType::Directory) as u64) << 12) | 0o666,
+            attributes : ATTR_DIRECTORY,
+            modified : None,
+            created : None,
+            accessed : None,
+        });
+    }
+    let (parent, name) = path::split_parent(path);
+    let children = match list_directory(root, parent) {
+        Ok(c) => c,
+        Err(e) => {
+            // Tell a missing parent apart from one that exists but is not a directory.
+            return match lookup(root, parent) {
+                Ok(ref ent) if ent.entry_type() != DirEntryType::Directory => Err(fs_error(FsErrorKind::NotADirectory)),
+                Ok(_) => Err(e),
+                Err(parent_err) => Err(parent_err),
+            };
+        }
+    };
+    // FAT names are case-insensitive.
+    let lower_name = name.to_lowercase();
+    let ent = children.into_iter()
+        .find(|ent| ent.name.to_lowercase() == lower_name)
+        .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+    present_entry(root, path, ent)
+}
+
+/// Lists the directory at the normalized `path` the way `lookup` would report
+/// each entry, without the `.` and `..` entries.
+pub fn read_directory<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str) -> Result<Vec<DirEntryData>, io::Error> {
+    list_directory(root, path)?.into_iter()
+        .map(|ent| {
+            let child = path::join(path, &ent.name);
+            present_entry(root, &child, ent)
+        })
+        .collect()
+}
+
+/// A read position in the listing of a directory. The listing is read in one pass
+/// the first time an entry is asked for and kept until `rewind`, so walking a
+/// directory costs a single `read_directory` no matter how many entries it has.
+/// Changes made to the directory in the meantime show up after a rewind.
+pub struct DirCursor {
+    path : String,
+    entries : Option<Vec<DirEntryData>>,
+    index : usize,
+}
+
+impl DirCursor {
+    pub fn new(path : String) -> DirCursor {
+        DirCursor {
+            path,
+            entries : None,
+            index : 0,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The next entry of the listing, or `None` once all of them have been returned.
+    pub fn next_entry<'a, D : DirectoryOps<'a>>(&mut self, root : &mut D) -> Result<Option<DirEntryData>, io::Error> {
+        if self.entries.is_none() {
+            self.entries = Some(read_directory(root, &self.path)?);
+        }
+        let next = self.entries.as_ref().and_then(|entries| entries.get(self.index)).cloned();
+        if next.is_some() {
+            self.index += 1;
+        }
+        Ok(next)
+    }
+
+    /// Goes back to the first entry, rereading the listing on the next call to `next_entry`.
+    pub fn rewind(&mut self) {
+        self.entries = None;
+        self.index = 0;
+    }
+}
+
+/// Turns the raw entry for a split-file directory at `path` into a regular file
+/// whose length is the sum of its chunks; other entries are returned unchanged.
+fn present_entry<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str, mut ent : DirEntryData) -> Result<DirEntryData, io::Error> {
+    if is_split_entry(root, path, &ent) {
+        let chunks = list_directory(root, path)?;
+        ent.len = chunks.iter().map(|chunk| chunk.len).sum();
+        ent.flags = (ent.flags & !0xF000) | ((u8::from(DirEntryType::RegularFile) as u64) << 12);
+        ent.attributes &= !ATTR_DIRECTORY;
+    }
+    Ok(ent)
+}
+
+/// Removes the file at `path`, including every chunk if it is a split file.
+/// Fails with `FsErrorKind::IsADirectory` for ordinary directories.
+pub fn remove_file_or_split<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str) -> Result<(), io::Error> {
+    let ent = lookup(root, path)?;
+    if ent.entry_type() == DirEntryType::Directory {
+        return Err(fs_error(FsErrorKind::IsADirectory));
+    }
+    if is_split_directory(root, path) {
+        for chunk in list_directory(root, path)? {
+            root.remove_path(path::join(path, &chunk.name))?;
+        }
+    }
+    root.remove_path(path)
+}
+
+/// Removes the directory at `path` if it is empty. Split files count as files here,
+/// so removing one with this fails with `FsErrorKind::NotADirectory`.
+pub fn remove_empty_directory<'a, D : DirectoryOps<'a>>(root : &mut D, path : &str) -> Result<(), io::Error> {
+    if path::is_root(path) {
+        return Err(fs_error(FsErrorKind::Busy));
+    }
+    let ent = lookup(root, path)?;
+    if ent.entry_type() != DirEntryType::Directory {
+        return Err(fs_error(FsErrorKind::NotADirectory));
+    }
+    if !list_directory(root, path)?.is_empty() {
+        return Err(fs_error(FsErrorKind::DirectoryNotEmpty));
+    }
+    root.remove_path(path)
+}
+
+/// Works out the chunk boundary of a split file from the lengths of its chunks. Every
+/// chunk but the last must fill the boundary exactly, and the last must not pass it.
+fn split_chunk_size(chunk_lens : &[u64], chunk_size : Option<u64>) -> Result<u64, io::Error> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Split file chunks are not laid out on a single chunk size.");
+    let (last, full) = chunk_lens.split_last().ok_or_else(malformed)?;
+    let chunk_size = match (chunk_size, full.first()) {
+        (Some(size), _) => size,
+        (None, Some(first)) => *first,
+        (None, None) => DEFAULT_CHUNK_SIZE,
+    };
+    if chunk_size == 0 || full.iter().any(|len| *len != chunk_size) || *last > chunk_size {
+        return Err(malformed());
+    }
+    Ok(chunk_size)
+}
+
+/// Presents a split-file directory as a single seekable file.
+///
+/// Every chunk but the last is exactly `chunk_size` bytes long; writes that pass
+/// the end of the last chunk's boundary create new chunks as needed.
+pub struct SplitFile<'a> {
+    root : Directory<'a>,
+    path : String,
+    chunk_size : u64,
+    chunk_lens : Vec<u64>,
+    position : u64,
+    options : OpenOptions,
+    current : Option<(usize, File<'a>)>,
+}
+
+impl <'a> SplitFile<'a> {
+    /// Opens the existing split file at `path`.
+    ///
+    /// If the file already has more than one chunk, the size of the first chunk is used
+    /// as the chunk boundary; otherwise `DEFAULT_CHUNK_SIZE` is used.
+    pub fn open(root : Directory<'a>, path : &str) -> Result<SplitFile<'a>, io::Error> {
+        SplitFile::open_with(root, path, OpenOptions::read_write(), None)
+    }
+
+    /// Opens the existing split file at `path` with the given options.
+    ///
+    /// If `chunk_size` is given, the file's chunks must already be laid out on that
+    /// boundary; otherwise it is worked out as in `open`. Files whose chunks do not
+    /// line up on a single boundary are rejected with `io::ErrorKind::InvalidData`.
+    pub fn open_with(mut root : Directory<'a>, path : &str, options : OpenOptions, chunk_size : Option<u64>) -> Result<SplitFile<'a>, io::Error> {
+        options.validate()?;
+        if options.create_new {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        if chunk_size == Some(0) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        if !is_split_directory(&mut root, path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Directory is not a split file."));
+        }
+        let children = list_directory(&mut root, path)?;
+        let mut chunk_lens = vec![0 ; children.len()];
+        for child in children.iter() {
+            if let Some(idx) = chunk_index(&child.name) {
+                chunk_lens[idx] = child.len as u64;
+            }
+        }
+        let chunk_size = split_chunk_size(&chunk_lens, chunk_size)?;
+        let mut retval = SplitFile {
+            root,
+            path : path.trim_end_matches('/').to_owned(),
+            chunk_size,
+            chunk_lens,
+            position : 0,
+            options,
+            current : None,
+        };
+        if options.truncate {
+            retval.truncate()?;
+        }
+        Ok(retval)
+    }
+
+    /// Creates a new, empty split file at `path` that will be broken into chunks of
+    /// `chunk_size` bytes.
+    pub fn create(mut root : Directory<'a>, path : &str, chunk_size : u64) -> Result<SplitFile<'a>, io::Error> {
+        if chunk_size == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let path = path.trim_end_matches('/').to_owned();
+        root.create_directory(&path)?;
+        // Without the archive bit the directory would not be seen as a split file.
+        let res = root.set_attributes(&path, ATTR_ARCHIVE, ATTR_ARCHIVE)
+            .and_then(|_| root.create_file(path::join(&path, &chunk_name(0))).map(|_| ()));
+        if let Err(e) = res {
+            let _ = root.remove_path(&path);
+            return Err(e);
+        }
+        Ok(SplitFile {
+            root,
+            path,
+            chunk_size,
+            chunk_lens : vec![0],
+            position : 0,
+            options : OpenOptions::read_write(),
+            current : None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.chunk_lens.iter().sum()
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_lens.len()
+    }
+
+    fn chunk_path(&self, idx : usize) -> String {
+        path::join(&self.path, &chunk_name(idx))
+    }
+
+    fn close_current(&mut self) -> Result<(), io::Error> {
+        if let Some((_, mut fl)) = self.current.take() {
+            fl.flush()?;
+        }
+        Ok(())
+    }
+
+    fn chunk_file(&mut self, idx : usize) -> Result<&mut File<'a>, io::Error> {
+        let is_loaded = self.current.as_ref().map(|(cur, _)| *cur == idx).unwrap_or(false);
+        if !is_loaded {
+            self.close_current()?;
+            let chunk_path = self.chunk_path(idx);
+            let chunk_options = OpenOptions::new().read(self.options.read).write(self.options.write);
+            let fl = self.root.open_file_with(chunk_path, chunk_options)?;
+            self.current = Some((idx, fl));
+        }
+        match self.current.as_mut() {
+            Some((_, fl)) => Ok(fl),
+            None => Err(io::Error::from(io::ErrorKind::Other)),
+        }
+    }
+
+    fn preallocate_chunk(&mut self, idx : usize, len : u64, contiguous : bool) -> Result<(), io::Error> {
+        if idx == self.chunk_lens.len() {
+            let next_path = self.chunk_path(idx);
+            self.root.create_file(next_path)?;
+            self.chunk_lens.push(0);
+        }
+        self.chunk_file(idx)?.preallocate(len, contiguous)?;
+        self.chunk_lens[idx] = self.chunk_lens[idx].max(len);
+        Ok(())
+    }
+
+    /// Makes sure chunks `0 ..= idx` exist, with every chunk before `idx` filled
+    /// out to the full chunk size.
+    fn ensure_chunks(&mut self, idx : usize) -> Result<(), io::Error> {
+        let last = self.chunk_lens.len() - 1;
+        for prev in last .. idx {
+            let missing = self.chunk_size.checked_sub(self.chunk_lens[prev])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Split file chunk is larger than the chunk size."))?;
+            if missing > 0 {
+                let fl = self.chunk_file(prev)?;
+                fl.seek(SeekFrom::End(0))?;
+                write_zeros(fl, missing)?;
+                self.chunk_lens[prev] = self.chunk_size;
+            }
+            let next_path = self.chunk_path(prev + 1);
+            self.root.create_file(next_path)?;
+            self.chunk_lens.push(0);
+        }
+        Ok(())
+    }
+}
+
+impl <'a> Read for SplitFile<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.options.check_read()?;
+        if buf.is_empty() || self.position >= self.len() {
+            return Ok(0);
+        }
+        let idx = (self.position / self.chunk_size) as usize;
+        let offset = self.position % self.chunk_size;
+        let available = self.chunk_lens[idx].saturating_sub(offset);
+        let to_read = (buf.len() as u64).min(available) as usize;
+        if to_read == 0 {
+            return Ok(0);
+        }
+        let read_count = {
+            let fl = self.chunk_file(idx)?;
+            fl.seek(SeekFrom::Start(offset))?;
+            fl.read(&mut buf[.. to_read])?
+        };
+        self.position += read_count as u64;
+        Ok(read_count)
+    }
+}
+
+impl <'a> Write for SplitFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.options.check_write()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.options.append {
+            self.position = self.len();
+        }
+        let idx = (self.position / self.chunk_size) as usize;
+        let offset = self.position % self.chunk_size;
+        self.ensure_chunks(idx)?;
+        let to_write = (buf.len() as u64).min(self.chunk_size - offset) as usize;
+        let chunk_len = self.chunk_lens[idx];
+        let written = {
+            let fl = self.chunk_file(idx)?;
+            if offset > chunk_len {
+                fl.seek(SeekFrom::End(0))?;
+                write_zeros(fl, offset - chunk_len)?;
+            }
+            fl.seek(SeekFrom::Start(offset))?;
+            fl.write(&buf[.. to_write])?
+        };
+        self.chunk_lens[idx] = chunk_len.max(offset + written as u64);
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self.current.as_mut() {
+            Some((_, fl)) => fl.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl <'a> Seek for SplitFile<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        let new_pos = match pos {
+            SeekFrom::Start(raw) => raw as i64,
+            SeekFrom::Current(raw) => self.position as i64 + raw,
+            SeekFrom::End(raw) => self.len() as i64 + raw,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+}
+
+impl <'a> FileOps for SplitFile<'a> {
+    fn truncate(&mut self) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        if self.position >= self.len() {
+            return Ok(());
+        }
+        let idx = (self.position / self.chunk_size) as usize;
+        let offset = self.position % self.chunk_size;
+        self.close_current()?;
+        while self.chunk_lens.len() > idx + 1 {
+            let last = self.chunk_lens.len() - 1;
+            let last_path = self.chunk_path(last);
+            self.root.remove_path(last_path)?;
+            self.chunk_lens.pop();
+        }
+        {
+            let fl = self.chunk_file(idx)?;
+            fl.seek(SeekFrom::Start(offset))?;
+            fl.truncate()?;
+        }
+        self.chunk_lens[idx] = offset;
+        Ok(())
+    }
+    fn set_len(&mut self, len : u64) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        let position = self.position;
+        let cur_len = self.len();
+        let append = self.options.append;
+        self.options.append = false;
+        let res = if len < cur_len {
+            self.position = len;
+            self.truncate()
+        }
+        else {
+            self.position = cur_len;
+            write_zeros(self, len - cur_len)
+        };
+        self.options.append = append;
+        self.position = position;
+        res
+    }
+    fn preallocate(&mut self, len : u64, contiguous : bool) -> Result<(), io::Error> {
+        self.options.check_write()?;
+        let cur_len = self.len();
+        if len <= cur_len {
+            return Ok(());
+        }
+        let last_idx = ((len - 1) / self.chunk_size) as usize;
+        let mut res = Ok(());
+        for idx in self.chunk_lens.len() - 1 ..= last_idx {
+            let target = (len - idx as u64 * self.chunk_size).min(self.chunk_size);
+            res = self.preallocate_chunk(idx, target, contiguous);
+            if res.is_err() {
+                break;
+            }
+        }
+        if res.is_err() {
+            // Give back whatever was reserved so a failure leaves the file as it was.
+            let position = self.position;
+            self.position = cur_len;
+            let _ = self.truncate();
+            self.position = position;
+        }
+        res
+    }
+}
+
+impl <'a> Drop for SplitFile<'a> {
+    fn drop(&mut self) {
+        let _ = self.close_current();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filesystem::{FileSystemOps, fs_error_kind};
+    use filesystem::fatfs_rs::format_test_volume;
+
+    const VOLUME_SIZE : usize = 4 * 1024 * 1024;
+
+    fn write_file(root : &mut Directory, path : &str, contents : &[u8]) {
+        let mut fl = root.create_file(path).unwrap();
+        fl.write_all(contents).unwrap();
+        fl.flush().unwrap();
+    }
+
+    fn attributes_of(root : &mut Directory, full_path : &str) -> u8 {
+        let (parent, name) = path::split_parent(full_path);
+        let children = list_directory(root, parent).unwrap();
+        children.into_iter().find(|ent| ent.name == name).unwrap().attributes
+    }
+
+    #[test]
+    fn archive_bits_are_only_changed_on_split_directories() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let mut root = fs.root().unwrap();
+        root.create_directory("/games").unwrap();
+        root.create_directory("/games/big.nsp").unwrap();
+        write_file(&mut root, "/games/big.nsp/00", b"first");
+        write_file(&mut root, "/games/big.nsp/01", b"second");
+        root.create_directory("/games/saves").unwrap();
+        write_file(&mut root, "/games/saves/slot", b"data");
+        root.create_directory("/games/gap").unwrap();
+        write_file(&mut root, "/games/gap/00", b"first");
+        write_file(&mut root, "/games/gap/02", b"third");
+
+        let dry_run = fix_archive_bits(&mut root, "/", ArchiveBitAction::Set, true).unwrap();
+        let expected = ArchiveBitChange { path : "/games/big.nsp".to_owned(), was_set : false, applied : false };
+        assert_eq!(dry_run.changed, vec![expected.clone()]);
+        assert!(dry_run.failed.is_empty());
+        assert_eq!(attributes_of(&mut root, "/games/big.nsp") & ATTR_ARCHIVE, 0);
+
+        let report = fix_archive_bits(&mut root, "/", ArchiveBitAction::Set, false).unwrap();
+        assert_eq!(report.changed, vec![ArchiveBitChange { applied : true, ..expected }]);
+        assert!(report.failed.is_empty());
+        assert_eq!(attributes_of(&mut root, "/games/big.nsp"), ATTR_DIRECTORY | ATTR_ARCHIVE);
+        assert_eq!(attributes_of(&mut root, "/games/saves") & ATTR_ARCHIVE, 0);
+        assert_eq!(attributes_of(&mut root, "/games/gap") & ATTR_ARCHIVE, 0);
+
+        let again = fix_archive_bits(&mut root, "/", ArchiveBitAction::Set, false).unwrap();
+        assert!(again.changed.is_empty());
+        assert_eq!(again.unchanged, vec!["/games/big.nsp".to_owned()]);
+
+        let cleared = fix_archive_bits(&mut root, "/games", ArchiveBitAction::Clear, false).unwrap();
+        assert_eq!(cleared.changed, vec![ArchiveBitChange { path : "/games/big.nsp".to_owned(), was_set : true, applied : true }]);
+        assert_eq!(attributes_of(&mut root, "/games/big.nsp"), ATTR_DIRECTORY);
+    }
+
+    #[test]
+    fn split_files_read_and_write_across_chunks() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let data : Vec<u8> = (0 .. 40).collect();
+        {
+            let mut split = SplitFile::create(fs.root().unwrap(), "/big.nsp", 16).unwrap();
+            split.write_all(&data).unwrap();
+            split.flush().unwrap();
+            assert_eq!(split.len(), 40);
+            assert_eq!(split.chunk_count(), 3);
+
+            let mut middle = [0 ; 20];
+            split.seek(SeekFrom::Start(10)).unwrap();
+            split.read_exact(&mut middle).unwrap();
+            assert_eq!(&middle[..], &data[10 .. 30]);
+        }
+
+        let mut root = fs.root().unwrap();
+        assert_eq!(attributes_of(&mut root, "/big.nsp"), ATTR_DIRECTORY | ATTR_ARCHIVE);
+        let ent = lookup(&mut root, "/big.nsp").unwrap();
+        assert_eq!(ent.entry_type(), DirEntryType::RegularFile);
+        assert_eq!(ent.attributes, ATTR_ARCHIVE);
+        assert_eq!(ent.len, 40);
+
+        let mut contents = Vec::new();
+        let mut fl = open_file_or_split(root, "/big.nsp", OpenOptions::new().read(true)).unwrap();
+        fl.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        drop(fl);
+
+        {
+            let mut split = SplitFile::open(fs.root().unwrap(), "/big.nsp").unwrap();
+            assert_eq!(split.chunk_size(), 16);
+            split.set_len(20).unwrap();
+            assert_eq!(split.chunk_count(), 2);
+        }
+        let mut root = fs.root().unwrap();
+        let lens : Vec<(String, u64)> = list_directory(&mut root, "/big.nsp").unwrap().into_iter()
+            .map(|chunk| (chunk.name, chunk.len))
+            .collect();
+        assert_eq!(lens, vec![("00".to_owned(), 16), ("01".to_owned(), 4)]);
+    }
+
+    #[test]
+    fn malformed_split_files_are_rejected() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        {
+            let mut root = fs.root().unwrap();
+            root.create_directory("/empty_first").unwrap();
+            root.set_attributes("/empty_first", ATTR_ARCHIVE, ATTR_ARCHIVE).unwrap();
+            write_file(&mut root, "/empty_first/00", b"");
+            write_file(&mut root, "/empty_first/01", b"abc");
+            root.create_directory("/uneven").unwrap();
+            root.set_attributes("/uneven", ATTR_ARCHIVE, ATTR_ARCHIVE).unwrap();
+            write_file(&mut root, "/uneven/00", &[1 ; 16]);
+            write_file(&mut root, "/uneven/01", &[2 ; 8]);
+            write_file(&mut root, "/uneven/02", &[3 ; 16]);
+        }
+        {
+            SplitFile::create(fs.root().unwrap(), "/good", 16).unwrap().write_all(&[4 ; 20]).unwrap();
+        }
+
+        for path in &["/empty_first", "/uneven"] {
+            let err = open_file_or_split(fs.root().unwrap(), path, OpenOptions::read_write()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", path);
+        }
+        let err = SplitFile::open_with(fs.root().unwrap(), "/good", OpenOptions::read_write(), Some(8)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(SplitFile::open_with(fs.root().unwrap(), "/good", OpenOptions::read_write(), Some(16)).is_ok());
+    }
+
+    #[test]
+    fn folders_without_the_archive_bit_are_not_split_files() {
+        let fs = format_test_volume(VOLUME_SIZE);
+        let mut root = fs.root().unwrap();
+        root.create_directory("/plain").unwrap();
+        write_file(&mut root, "/plain/00", b"first");
+        write_file(&mut root, "/plain/01", b"second");
+
+        assert!(!is_split_directory(&mut root, "/plain"));
+        assert_eq!(lookup(&mut root, "/plain").unwrap().entry_type(), DirEntryType::Directory);
+        let err = remove_file_or_split(&mut root, "/plain").err().unwrap();
+        assert_eq!(fs_error_kind(&err), Some(FsErrorKind::IsADirectory));
+        assert_eq!(list_directory(&mut root, "/plain").unwrap().len(), 2);
+
+        let err = open_file_or_split(root, "/plain", OpenOptions::read_write()).err().unwrap();
+        assert_eq!(fs_error_kind(&err), Some(FsErrorKind::IsADirectory));
+    }
+}
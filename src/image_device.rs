@@ -0,0 +1,104 @@
+use crate::buf_scsi::BlockDevice;
+use crate::vecwrapper::VecNewtype;
+
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// A `BlockDevice` backed by a disk image, such as a `std::fs::File` or an
+/// in-memory `Cursor`, so the filesystem layers can run without a USB drive.
+pub struct ImageDevice<T: Read + Write + Seek> {
+    image: T,
+    block_size: u32,
+    connected: bool,
+    unwritten: Vec<(u32, Vec<u8>)>,
+}
+
+impl<T: Read + Write + Seek> ImageDevice<T> {
+    pub fn new(image: T) -> Self {
+        ImageDevice::with_block_size(image, 512)
+    }
+
+    pub fn with_block_size(image: T, block_size: u32) -> Self {
+        ImageDevice {
+            image,
+            block_size,
+            connected: true,
+            unwritten: Vec::new(),
+        }
+    }
+
+    /// Makes the device act like a drive that was unplugged: every read and write
+    /// fails with `NotConnected` from now on.
+    pub fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn check_connected(&self) -> io::Result<()> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.image
+    }
+
+    pub fn into_inner(self) -> T {
+        self.image
+    }
+}
+
+impl<T: Read + Write + Seek> BlockDevice for ImageDevice<T> {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize> {
+        self.check_connected()?;
+        self.image.seek(SeekFrom::Start(offset as u64))?;
+        let start = buffer.inner.len();
+        buffer.inner.resize(buffer.fake_size, 0);
+        let mut read_count = 0;
+        while start + read_count < buffer.fake_size {
+            match self.image.read(&mut buffer.inner[start + read_count..])? {
+                0 => break,
+                n => read_count += n,
+            }
+        }
+        // Past the end of the image reads back as zeros, like an unwritten sector.
+        Ok(buffer.fake_size - start)
+    }
+
+    fn write(&mut self, offset: u32, buffer: &mut VecNewtype) -> io::Result<usize> {
+        self.check_connected()?;
+        self.image.seek(SeekFrom::Start(offset as u64))?;
+        self.image.write_all(&buffer.inner)?;
+        let written = buffer.inner.len();
+        buffer.inner.clear();
+        Ok(written)
+    }
+
+    fn block_count(&mut self) -> io::Result<u64> {
+        self.check_connected()?;
+        let len = self.image.seek(SeekFrom::End(0))?;
+        Ok(len / u64::from(self.block_size))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn keep_unwritten(&mut self, offset: u32, block: Vec<u8>) {
+        self.unwritten.push((offset, block));
+    }
+
+    fn take_unwritten(&mut self) -> Vec<(u32, Vec<u8>)> {
+        std::mem::replace(&mut self.unwritten, Vec::new())
+    }
+
+    fn unwritten_bytes(&self) -> u64 {
+        self.unwritten.iter().map(|(_, block)| block.len() as u64).sum()
+    }
+}
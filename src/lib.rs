@@ -17,6 +17,8 @@ use fatfs::{Dir, DirEntry, File, FileSystem, ReadWriteSeek};
 #[macro_use]
 extern crate lazy_static;
 pub mod buf_scsi;
+#[cfg(test)]
+mod image_device;
 pub mod usb_comm;
 pub mod vecwrapper;
 pub mod filesystem;
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::mem;
 use crate::aligned_slice::AlignedBuffer;
 use std::alloc::Layout;
@@ -9,6 +10,12 @@ use libnx_rs::usbhs::{
 };
 use libnx_rs::LibnxError;
 
+const USB_REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const USB_DESCRIPTOR_DEVICE: u8 = 0x01;
+const USB_DESCRIPTOR_STRING: u8 = 0x03;
+/// English (United States), which nearly every drive reports its strings in.
+const USB_LANGID_EN_US: u16 = 0x0409;
+
 pub struct ReadEndpoint(UsbEndpointDescriptor);
 
 pub struct WriteEndpoint(UsbEndpointDescriptor);
@@ -25,6 +32,8 @@ impl UsbClient {
             read_endpoint : read_handle,
             write_endpoint : write_handle,
             device_handle,
+            gone : Cell::new(false),
+            unwritten : Vec::new(),
         })
     }
 
@@ -55,6 +64,37 @@ impl UsbClient {
             .map_err(|e| format!("Write Error: {:?}", e))?;
         Ok(rval)
     }
+    /// Reads a standard descriptor over the default control pipe into `buffer`.
+    fn read_descriptor(&mut self, kind: u8, index: u8, lang: u16, buffer: &mut AlignedBuffer) -> Result<usize, LibnxError> {
+        let value = (u16::from(kind) << 8) | u16::from(index);
+        self.device_handle.ctrl_xfer(0x80, USB_REQUEST_GET_DESCRIPTOR, value, lang, buffer.as_slice_mut())
+    }
+
+    /// The serial number string the device reports, or `None` if it has none.
+    pub fn serial_number(&mut self) -> Result<Option<String>, LibnxError> {
+        let layout = Layout::from_size_align(0xFF, 0x1000).map_err(|e| LibnxError::from_msg(format!("{:?}", e)))?;
+        let mut buffer = AlignedBuffer::from_layout(layout)?;
+        let read = self.read_descriptor(USB_DESCRIPTOR_DEVICE, 0, 0, &mut buffer)?;
+        if read < 18 {
+            return Err(LibnxError::from_msg(format!("Device descriptor too short: {} bytes.", read)));
+        }
+        let index = buffer.as_slice()[16];
+        if index == 0 {
+            return Ok(None);
+        }
+        let read = self.read_descriptor(USB_DESCRIPTOR_STRING, index, USB_LANGID_EN_US, &mut buffer)?;
+        let len = (buffer.as_slice()[0] as usize).min(read);
+        if len < 2 {
+            return Ok(None);
+        }
+        let units: Vec<u16> = buffer.as_slice()[2..len]
+            .chunks(2)
+            .filter(|unit| unit.len() == 2)
+            .map(|unit| u16::from(unit[0]) | (u16::from(unit[1]) << 8))
+            .collect();
+        Ok(Some(String::from_utf16_lossy(&units)))
+    }
+
     pub fn from_interface(
         context: &mut UsbHsContext,
         interface: &Interface,
@@ -107,7 +147,34 @@ impl UsbClient {
     }
 
     pub fn is_connected(&self) -> bool {
-        self.device_handle.is_connected().unwrap_or(false)
+        if self.gone.get() {
+            return false;
+        }
+        let connected = self.device_handle.is_connected().unwrap_or(false);
+        if !connected {
+            self.gone.set(true);
+        }
+        connected
+    }
+
+    /// Keeps a block that was cached for `offset` on the device but never reached it.
+    pub fn keep_unwritten(&mut self, offset: u32, block: Vec<u8>) {
+        self.unwritten.push((offset, block));
+    }
+
+    pub fn take_unwritten(&mut self) -> Vec<(u32, Vec<u8>)> {
+        mem::replace(&mut self.unwritten, Vec::new())
+    }
+
+    pub fn unwritten_bytes(&self) -> u64 {
+        self.unwritten.iter().map(|(_, block)| block.len() as u64).sum()
+    }
+
+    /// The error for a transfer that failed, noting whether it failed because the
+    /// device went away.
+    fn transfer_error(&self, direction: scsi::UsbTransferDirection) -> scsi::ScsiError {
+        let _ = self.is_connected();
+        scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError { direction })
     }
 }
 
@@ -115,6 +182,11 @@ pub struct UsbClient {
     device_handle: ClientInterfaceSession,
     read_endpoint: ClientEndpointSession,
     write_endpoint: ClientEndpointSession,
+    /// Set once the device is found detached. A session never comes back, so from
+    /// then on transfers fail straight away instead of waiting on a dead endpoint.
+    gone: Cell<bool>,
+    /// Blocks from caches that were dropped after the device went away.
+    unwritten: Vec<(u32, Vec<u8>)>,
 }
 
 
@@ -124,16 +196,18 @@ impl Drop for UsbClient {
 
 impl scsi::CommunicationChannel for UsbClient {
     fn in_transfer<B: scsi::Buffer>(&mut self, buffer: &mut B) -> Result<usize, scsi::ScsiError> {
+        if self.gone.get() {
+            return Err(self.transfer_error(scsi::UsbTransferDirection::In));
+        }
         let to_get = buffer.capacity() - buffer.size();
         let shim_layout = Layout::from_size_align(to_get, 0x1000).map_err(|e| scsi::ScsiError::from_cause(scsi::ErrorCause::FlagError{flags : 0xEAFF}))?;
         let mut shim = AlignedBuffer::from_layout(shim_layout).map_err(|e| scsi::ScsiError::from_cause(scsi::ErrorCause::FlagError{flags : 0xEAFE}))?;
-        let rval = self
-            .pull_bytes(&mut shim)
-            .map_err(|e| {
-                scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError {
-                    direction: scsi::UsbTransferDirection::In,
-                })
-            })?;
+        let rval = match self.pull_bytes(&mut shim) {
+            Ok(r) => r,
+            Err(_e) => {
+                return Err(self.transfer_error(scsi::UsbTransferDirection::In));
+            }
+        };
 
         let mut idx = 0;
         while buffer.capacity() > buffer.size() {
@@ -145,6 +219,9 @@ impl scsi::CommunicationChannel for UsbClient {
     }
 
     fn out_transfer<B: scsi::Buffer>(&mut self, bytes: &mut B) -> Result<usize, scsi::ScsiError> {
+        if self.gone.get() {
+            return Err(self.transfer_error(scsi::UsbTransferDirection::Out));
+        }
         let shim_layout = Layout::from_size_align(bytes.size(), 0x1000).map_err(|e| scsi::ScsiError::from_cause(scsi::ErrorCause::FlagError{flags : 0xEAFF}))?;
         let mut shim = AlignedBuffer::from_layout(shim_layout).map_err(|e| scsi::ScsiError::from_cause(scsi::ErrorCause::FlagError{flags : 0xEAFE}))?;
         let mut idx = 0; 
@@ -153,13 +230,12 @@ impl scsi::CommunicationChannel for UsbClient {
             shim.as_slice_mut()[idx] = bt;
             idx += 1;
         }
-        let rval = self
-            .push_bytes(&shim)
-            .map_err(|e| {
-                scsi::ScsiError::from_cause(scsi::ErrorCause::UsbTransferError {
-                    direction: scsi::UsbTransferDirection::Out,
-                })
-            })?;
+        let rval = match self.push_bytes(&shim) {
+            Ok(r) => r,
+            Err(_e) => {
+                return Err(self.transfer_error(scsi::UsbTransferDirection::Out));
+            }
+        };
         Ok(rval)
     }
 }